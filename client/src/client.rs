@@ -1,47 +1,697 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    fmt, io,
+    net::{SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use message::{
-    attribute::Value,
-    header::{Header, HeaderType},
-    Message,
+    attribute::{
+        AlternateDomain, AlternateServer, ChangedAddress, ErrorCode, MappedAddress, Software,
+    },
+    header::{Class, Header, HeaderType},
+    transport, Message,
+};
+
+use crate::{
+    connection::{Connection, Transport},
+    ice::{self, Gatherer},
+    load, nat, punch,
+    turn::{is_timeout, Allocation, TurnClient},
 };
 
+/// How many keepalive indications are sent between two binding requests
+const RECHECK_EVERY: u32 = 5;
+
+/// How often the TURN proxy refreshes its allocation, permission and
+/// channel, well within the shortest of their lifetimes
+const TURN_REFRESH: Duration = Duration::from_secs(60);
+
+/// How long a server gets to answer before the next one is tried
+const SERVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long each NAT classification test waits for an answer, RFC 3489
+/// section 9.3
+const NAT_TEST_TIMEOUT: Duration = Duration::from_millis(9500);
+
+/// How long to wait for the peer to turn up at the rendezvous
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to probe the peer before giving up on a direct path
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First retransmission timeout of binding requests over UDP, doubled on
+/// every retransmission, RFC 8489 section 6.2.1
+const RTO: Duration = Duration::from_millis(500);
+
+/// Redirects followed in a row before giving up, besides never going back
+/// to a server already tried
+pub(crate) const MAX_REDIRECTS: usize = 5;
+
 pub struct Client {
     addrs: [SocketAddr; 4],
+    /// Candidates for the primary address, in the order they're tried
+    servers: Vec<SocketAddr>,
     credential: Option<Credential>,
+    transport: Transport,
+    /// Where UDP goes out from instead of a socket of its own each time
+    socket: Option<Arc<dyn transport::Transport>>,
 }
 
 impl Client {
     pub fn new(addrs: [SocketAddr; 4]) -> Self {
         Self {
             addrs,
+            servers: vec![addrs[0]],
             credential: None,
+            transport: Transport::Udp,
+            socket: None,
+        }
+    }
+
+    /// Replace the primary address, the one binding requests, keepalives
+    /// and TURN go to
+    pub fn set_server(&mut self, server: SocketAddr) {
+        self.set_servers(vec![server]);
+    }
+
+    /// Servers to try in turn, as found by `StunUri::discover`. The first
+    /// one to answer becomes the primary address.
+    pub fn set_servers(&mut self, servers: Vec<SocketAddr>) {
+        if let Some(&first) = servers.first() {
+            self.addrs[0] = first;
         }
+        self.servers = servers;
+    }
+
+    /// Transport for binding requests and keepalives
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Send UDP binding requests, NAT tests and hole punching probes from
+    /// `socket`, for running on a simulated network. TURN keeps sockets of
+    /// its own.
+    pub fn set_socket(&mut self, socket: Arc<dyn transport::Transport>) {
+        self.socket = Some(socket);
+    }
+
+    /// Long-term credential used for TURN
+    pub fn set_credential(&mut self, credential: Credential) {
+        self.credential = Some(credential);
+    }
+
+    /// A TURN client for the primary address, using the credential
+    pub fn turn(&self) -> io::Result<TurnClient> {
+        let credential = self.credential.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "TURN needs a credential")
+        })?;
+        if !matches!(self.transport, Transport::Udp) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the TURN client only runs over UDP",
+            ));
+        }
+        TurnClient::new(self.addrs[0], credential)
     }
 
     pub fn run(&mut self) {
+        let (_, binding) = self.connect().expect("binding request");
+        println!("My IP address is {:?}", binding.mapped.ip());
+        report_software(&binding);
+    }
+
+    /// Keep the NAT mapping towards the server open by sending a message
+    /// every `interval`, reporting whenever the mapped address changes.
+    ///
+    /// In `Keepalive::Indication` mode only every `RECHECK_EVERY`th message
+    /// is a binding request, the rest are indications the server drops.
+    pub fn keepalive(&mut self, interval: Duration, mode: Keepalive) {
         use HeaderType::*;
 
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("bind");
-        let header = Header::with_random_id(BindingRequest);
-        let message = Message::new(header, vec![]);
-        socket
-            .send_to(&message.encode(), self.addrs[0])
-            .expect("send to");
+        let (mut conn, first) = self.connect().expect("binding request");
+        println!("Mapped address is {}", first.mapped);
+        report_software(&first);
+        std::thread::sleep(interval);
+
+        let mut current = Some(first.mapped);
+        for tick in 1u32.. {
+            if mode == Keepalive::Indication && tick % RECHECK_EVERY != 0 {
+                let header = Header::with_random_id(BindingIndication);
+                let message = Message::new(header, vec![]);
+                conn.send(&message.encode()).expect("send");
+                std::thread::sleep(interval);
+                continue;
+            }
+
+            match binding(&mut conn, interval).map(|binding| binding.mapped) {
+                Ok(mapped) if current != Some(mapped) => {
+                    match current {
+                        Some(previous) => {
+                            println!("Mapped address changed: {} -> {}", previous, mapped)
+                        }
+                        None => println!("Mapped address is {}", mapped),
+                    }
+                    current = Some(mapped);
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("Binding request failed: {}", err),
+            }
+            std::thread::sleep(interval);
+        }
+    }
+
+    /// Relay a local UDP port through a TURN allocation: datagrams sent to
+    /// `listen` go out to `peer` from the relayed address, and the peer's
+    /// replies come back to whoever sent last.
+    pub fn turn_proxy(&mut self, listen: SocketAddr, peer: SocketAddr) {
+        let (mut turn, allocation) = self.allocate().expect("allocate");
+        println!("Relayed address is {}", allocation.relayed);
+        turn.bind_channel(peer).expect("channel bind");
+
+        let local = UdpSocket::bind(listen).expect("bind");
+        println!("Relaying {} <-> {}", local.local_addr().unwrap(), peer);
+        let source = Arc::new(Mutex::new(None));
+
+        let sender = turn.sender().expect("TURN sender");
+        let local_rx = local.try_clone().expect("clone socket");
+        let last_source = source.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            loop {
+                let (amt, src) = local_rx.recv_from(&mut buf).expect("recv data");
+                *last_source.lock().unwrap() = Some(src);
+                if let Err(err) = sender.send_to(&buf[..amt], peer) {
+                    eprintln!("Sending to {} failed: {}", peer, err);
+                }
+            }
+        });
+
+        turn.set_read_timeout(Some(Duration::from_secs(1)));
+        let mut refresh_at = Instant::now() + TURN_REFRESH;
+        let mut buf = [0; 2048];
+        loop {
+            match turn.recv_from(&mut buf) {
+                Ok((amt, from)) if from == peer => {
+                    if let Some(src) = *source.lock().unwrap() {
+                        local.send_to(&buf[..amt], src).expect("send to");
+                    }
+                }
+                Ok(_) => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(err) => panic!("TURN receive failed: {}", err),
+            }
+
+            if Instant::now() >= refresh_at {
+                turn.refresh(allocation.lifetime).expect("refresh");
+                // Rebinding the channel refreshes its permission as well
+                turn.bind_channel(peer).expect("channel bind");
+                refresh_at = Instant::now() + TURN_REFRESH;
+            }
+        }
+    }
+
+    /// Print this host's ICE candidates as SDP lines, asking the primary
+    /// address for a server reflexive one, and for a relayed one too when
+    /// there's a credential
+    pub fn candidates(&mut self) {
+        let mut gatherer = Gatherer::new();
+        gatherer.add_stun_server(self.addrs[0]);
+        if let Some(credential) = &self.credential {
+            gatherer.add_turn_server(self.addrs[0], credential.clone());
+        }
+        let gathered = gatherer.gather().expect("gather candidates");
+        for candidate in &gathered.candidates {
+            println!("{}", candidate.to_sdp());
+        }
+    }
+
+    /// Print what kind of NAT we're behind, asking the primary address to
+    /// answer from its other addresses
+    pub fn nat(&mut self) {
+        let socket = self.udp_socket().expect("bind");
+        let nat = nat::classify(&*socket, self.addrs[0], NAT_TEST_TIMEOUT).expect("classify");
+        println!("NAT type: {}", nat);
+    }
+
+    /// Measure how many binding requests a second the primary address
+    /// answers, sending from `sockets` sockets for `duration`
+    pub fn load(&self, sockets: usize, duration: Duration) {
+        let report = load::run(self.addrs[0], sockets, duration).expect("load");
+        println!("{}", report);
+    }
+
+    /// Open a direct path to the peer that joins `session` at
+    /// `rendezvous`: learn our mapped address, swap it for the peer's, then
+    /// probe each other until a probe gets through
+    pub fn punch(&mut self, session: &str, rendezvous: SocketAddr) {
+        let socket = self.udp_socket().expect("bind");
+        let socket = &*socket;
+        let (_, mapped) = ice::reflexive(socket, self.addrs[0]).expect("binding request");
+        println!("Mapped address is {}", mapped);
+        let peer = punch::rendezvous(socket, rendezvous, session, mapped, RENDEZVOUS_TIMEOUT)
+            .expect("rendezvous");
+        println!("Peer is at {}", peer);
+        match punch::punch(socket, session, peer, PUNCH_TIMEOUT).expect("punch") {
+            Some(direct) => println!("Direct path to {} is open", direct),
+            None => println!("No direct path to {}, falling back to a TURN relay", peer),
+        }
+    }
+
+    /// The socket set with `set_socket`, or a new one for the primary
+    /// address's family
+    fn udp_socket(&self) -> io::Result<Arc<dyn transport::Transport>> {
+        if let Some(socket) = &self.socket {
+            return Ok(socket.clone());
+        }
+        let local = match self.addrs[0] {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        Ok(Arc::new(UdpSocket::bind(local)?))
+    }
+
+    /// Connect to the first server that answers a binding request, and
+    /// return its answer
+    fn connect(&mut self) -> io::Result<(Connection, Binding)> {
+        self.try_servers(|client| {
+            client.follow_redirects(|server, transport| {
+                let mut conn = match (&client.socket, transport) {
+                    (Some(socket), Transport::Udp) => Connection::over(socket.clone(), server),
+                    _ => Connection::open(server, transport)?,
+                };
+                let binding = binding(&mut conn, SERVER_TIMEOUT)?;
+                Ok((conn, binding))
+            })
+        })
+    }
+
+    /// Run `attempt` against the primary address and wherever 300 Try
+    /// Alternate responses send it after that, RFC 8489 section 10
+    fn follow_redirects<T>(
+        &self,
+        attempt: impl Fn(SocketAddr, &Transport) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut server = self.addrs[0];
+        let mut transport = self.transport.clone();
+        let mut visited = vec![server];
+        loop {
+            let err = match attempt(server, &transport) {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            let Some(redirect) = Redirect::of(&err) else {
+                return Err(err);
+            };
+            if visited.contains(&redirect.server) {
+                return Err(io::Error::other(format!(
+                    "redirect loop: {} sent us back to {}",
+                    server, redirect.server
+                )));
+            }
+            if visited.len() > MAX_REDIRECTS {
+                return Err(io::Error::other(format!(
+                    "too many redirects: gave up at {} after {}",
+                    redirect.server, MAX_REDIRECTS
+                )));
+            }
+            println!("{} redirected us to {}", server, redirect.server);
+            // The alternate's certificate is for the domain it comes with
+            if let (Transport::Tls(config) | Transport::Dtls(config), Some(domain)) =
+                (&mut transport, &redirect.domain)
+            {
+                config.server_name = domain.clone();
+            }
+            server = redirect.server;
+            visited.push(server);
+        }
+    }
+
+    /// Allocate a relay on the first server that answers
+    fn allocate(&mut self) -> io::Result<(TurnClient, Allocation)> {
+        self.try_servers(|client| {
+            let mut turn = client.turn()?;
+            let allocation = turn.allocate()?;
+            Ok((turn, allocation))
+        })
+    }
+
+    /// Run `attempt` with each server as the primary address until one
+    /// succeeds, keeping that one
+    fn try_servers<T>(&mut self, attempt: impl Fn(&Self) -> io::Result<T>) -> io::Result<T> {
+        let mut result = Err(io::Error::new(io::ErrorKind::NotFound, "no servers to try"));
+        for server in self.servers.clone() {
+            self.addrs[0] = server;
+            result = attempt(self);
+            match &result {
+                Ok(_) => break,
+                Err(err) => eprintln!("Giving up on {}: {}", server, err),
+            }
+        }
+        result
+    }
+}
+
+/// What a server said to a binding request
+pub(crate) struct Binding {
+    /// The address it saw us coming from
+    pub(crate) mapped: SocketAddrV4,
+    /// The server's other address, RFC 3489 section 11.2.3
+    pub(crate) changed: Option<SocketAddrV4>,
+    /// Which server software answered, if it said
+    pub(crate) software: Option<String>,
+}
+
+fn report_software(binding: &Binding) {
+    if let Some(software) = &binding.software {
+        println!("Answered by {}", software);
+    }
+}
 
-        let mut buf = [0; 1024];
-        let (amt, _) = socket.recv_from(&mut buf).unwrap();
+/// Ask the server on `conn` what our address is, sending the request again
+/// over UDP until an answer comes or `timeout` runs out
+fn binding(conn: &mut Connection, timeout: Duration) -> io::Result<Binding> {
+    use HeaderType::*;
 
-        let buf = &buf[..amt];
-        let message = Message::decode(buf);
+    let header = Header::with_random_id(BindingRequest);
+    let transaction_id = header.transaction_id;
+    let request = Message::new(header, vec![]).encode();
 
-        match &message.attributes[0].value {
-            Value::MappedAddress(mapped) => {
-                println!("My IP address is {:?}", mapped.address);
+    let deadline = Instant::now() + timeout;
+    let mut rto = match conn.is_unreliable() {
+        true => RTO,
+        false => timeout,
+    };
+    while Instant::now() < deadline {
+        conn.send(&request)?;
+        let resend_at = (Instant::now() + rto).min(deadline);
+        rto *= 2;
+        while let Some(wait) = resend_at
+            .checked_duration_since(Instant::now())
+            .filter(|wait| !wait.is_zero())
+        {
+            conn.set_read_timeout(Some(wait))?;
+            let data = match conn.recv() {
+                Ok(data) => data,
+                Err(err) if is_timeout(&err) => break,
+                Err(err) => return Err(err),
+            };
+            // Whatever doesn't decode is skipped, the answer may still come
+            let Ok(message) = Message::decode(&data) else {
+                continue;
+            };
+            // Stale responses to earlier requests may still be in flight
+            if message.header.transaction_id != transaction_id {
+                continue;
             }
-            _ => panic!("invalid attribute"),
+            return read_binding(message);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no binding response",
+    ))
+}
+
+/// The answer to a binding request, or the error it stands for
+pub(crate) fn read_binding(message: Message) -> io::Result<Binding> {
+    if message.header.header_type.class() == Class::ErrorResponse {
+        return Err(error_response(message));
+    }
+    let mapped = message
+        .get::<MappedAddress>()
+        .map(|value| SocketAddrV4::new(value.address, value.port));
+    let changed = message
+        .get::<ChangedAddress>()
+        .map(|value| SocketAddrV4::new(value.address, value.port));
+    let software = message
+        .get::<Software>()
+        .map(|value| value.software.clone());
+    let mapped = mapped.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "response has no mapped address")
+    })?;
+    Ok(Binding {
+        mapped,
+        changed,
+        software,
+    })
+}
+
+/// The error an error response stands for, a `Redirect` for 300 Try
+/// Alternate
+pub(crate) fn error_response(message: Message) -> io::Error {
+    let error = message.get::<ErrorCode>();
+    let server = message.get::<AlternateServer>().map(|value| value.address);
+    let domain = message
+        .get::<AlternateDomain>()
+        .map(|value| value.domain.clone());
+    match (error, server) {
+        (Some(error), Some(server)) if error.code == 300 => {
+            io::Error::other(Redirect { server, domain })
         }
+        (Some(error), _) => io::Error::other(format!("{} {}", error.code, error.reason)),
+        (None, _) => io::Error::new(
+            io::ErrorKind::InvalidData,
+            "error response has no error code",
+        ),
+    }
+}
+
+/// A 300 Try Alternate response, carried in an `io::Error`
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub server: SocketAddr,
+    /// Name to check the alternate's certificate against
+    pub domain: Option<String>,
+}
+
+impl Redirect {
+    pub fn of(err: &io::Error) -> Option<&Redirect> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "300 Try Alternate {}", self.server)
     }
 }
 
-pub struct Credential(String, String);
+impl std::error::Error for Redirect {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Keepalive {
+    /// Send binding indications, with a periodic request to re-check
+    Indication,
+    /// Send a binding request every time
+    Request,
+}
+
+/// Username and password for long-term credentials
+#[derive(Debug, Clone)]
+pub struct Credential(pub String, pub String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dns::{tests::srv, tests::stub_dns, DnsResolver, Record},
+        uri::StunUri,
+    };
+    use message::builder::MessageBuilder;
+    use natsim::network::{Conditions, Network};
+    use server::{
+        redirect::{Alternate, Redirect as Policy},
+        server::Server,
+    };
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn start_server(redirect: Option<Policy>) -> SocketAddr {
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.add_user("user".into(), "pass".into());
+        if let Some(redirect) = redirect {
+            server.set_redirect(redirect);
+        }
+        server.spawn();
+        server.local_addrs()[0]
+    }
+
+    fn maintenance(alternate: SocketAddr) -> Option<Policy> {
+        Some(Policy::Maintenance(Alternate::new(alternate)))
+    }
+
+    #[test]
+    fn test_servers_tried_in_turn() {
+        let server = start_server(None);
+        // Takes requests and never answers them
+        let silent = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let silent = silent.local_addr().unwrap();
+
+        let dns = stub_dns(vec![
+            (
+                "_stun._udp.example.test",
+                srv(1, 0, silent.port(), "silent.example.test"),
+            ),
+            (
+                "_stun._udp.example.test",
+                srv(2, 0, server.port(), "stun.example.test"),
+            ),
+            ("silent.example.test", Record::A(Ipv4Addr::LOCALHOST)),
+            ("stun.example.test", Record::A(Ipv4Addr::LOCALHOST)),
+        ]);
+        let uri: StunUri = "stun:example.test".parse().unwrap();
+        let servers = uri.discover(&DnsResolver::new(dns)).unwrap();
+        assert_eq!(servers, [silent, server]);
+
+        let mut client = Client::new([silent; 4]);
+        client.set_servers(servers);
+        let (_, binding) = client.connect().unwrap();
+        assert_eq!(*binding.mapped.ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(binding.software.as_deref(), Some(server::server::SOFTWARE));
+        assert_eq!(client.addrs[0], server);
+    }
+
+    #[test]
+    fn test_follows_redirects() {
+        let target = start_server(None);
+        let server = start_server(maintenance(target));
+        let mut client = Client::new([server; 4]);
+        let (mut conn, _) = client.connect().unwrap();
+        // The connection is to the server we were sent to
+        assert!(binding(&mut conn, Duration::from_secs(1)).is_ok());
+
+        // Two servers sending clients to each other
+        let a = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let b = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        redirector(a, b_addr);
+        redirector(b, a_addr);
+        let mut client = Client::new([a_addr; 4]);
+        let Err(err) = client.connect() else {
+            panic!("connected through a redirect loop");
+        };
+        assert!(err.to_string().contains("redirect loop"), "{}", err);
+
+        // A chain of servers each sending clients on to the next
+        let sockets: Vec<_> = (0..=MAX_REDIRECTS + 1)
+            .map(|_| UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap())
+            .collect();
+        let addrs: Vec<_> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+        for (socket, next) in sockets.into_iter().zip(&addrs[1..]) {
+            redirector(socket, *next);
+        }
+        let mut client = Client::new([addrs[0]; 4]);
+        let Err(err) = client.connect() else {
+            panic!("followed every redirect");
+        };
+        assert!(err.to_string().contains("too many redirects"), "{}", err);
+    }
+
+    /// Answers every request with 300 Try Alternate to `alternate`
+    fn redirector(socket: UdpSocket, alternate: SocketAddr) {
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            while let Ok((amt, src)) = socket.recv_from(&mut buf) {
+                let request = Message::decode(&buf[..amt]).unwrap();
+                let header = Header::new(
+                    HeaderType::BindingErrorResponse,
+                    request.header.transaction_id,
+                );
+                let response = MessageBuilder::with_header(header)
+                    .add(ErrorCode::new(300, "Try Alternate".into()))
+                    .add(AlternateServer::new(alternate))
+                    .build()
+                    .unwrap();
+                socket.send_to(&response.encode(), src).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_binding_skips_garbage() {
+        let socket = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let server = socket.local_addr().unwrap();
+        garbler(socket);
+        let mut client = Client::new([server; 4]);
+        let (_, binding) = client.connect().unwrap();
+        assert_eq!(*binding.mapped.ip(), Ipv4Addr::LOCALHOST);
+    }
+
+    /// Answers the first request with something that isn't STUN, and only
+    /// the ones sent again after it with a binding response
+    fn garbler(socket: UdpSocket) {
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            let mut garbled = false;
+            while let Ok((amt, src)) = socket.recv_from(&mut buf) {
+                if !std::mem::replace(&mut garbled, true) {
+                    socket.send_to(b"not stun", src).unwrap();
+                    continue;
+                }
+                let SocketAddr::V4(src_v4) = src else {
+                    continue;
+                };
+                let request = Message::decode(&buf[..amt]).unwrap();
+                let header =
+                    Header::new(HeaderType::BindingResponse, request.header.transaction_id);
+                let response = MessageBuilder::with_header(header)
+                    .add(MappedAddress::new(1, src_v4.port(), *src_v4.ip()))
+                    .build()
+                    .unwrap();
+                socket.send_to(&response.encode(), src).unwrap();
+            }
+        });
+    }
+
+    fn network_addr(last: u8, port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), port)
+    }
+
+    /// A server on `network`, and a client there pointed at it
+    fn on_network(network: &Network) -> (Client, SocketAddr) {
+        let addrs = [
+            network_addr(1, 3478),
+            network_addr(1, 3479),
+            network_addr(2, 3478),
+            network_addr(2, 3479),
+        ];
+        let sockets = addrs.map(|addr| {
+            let socket: Arc<dyn transport::Transport> = Arc::new(network.bind(addr).unwrap());
+            socket
+        });
+        Server::new(addrs).spawn_over(sockets);
+        let socket: Arc<dyn transport::Transport> =
+            Arc::new(network.bind(network_addr(100, 0)).unwrap());
+        let local = socket.local_addr().unwrap();
+        let mut client = Client::new(addrs);
+        client.set_socket(socket);
+        (client, local)
+    }
+
+    #[test]
+    fn test_binding_retransmitted() {
+        let network = Network::new(0);
+        let (mut client, local) = on_network(&network);
+        // The request and its first retransmission
+        network.drop_next(2);
+        let (_, binding) = client.connect().unwrap();
+        assert_eq!(SocketAddr::V4(binding.mapped), local);
+
+        // Late and duplicated answers to it don't get in the way
+        network.set_conditions(Conditions {
+            loss: 0.2,
+            delay: Duration::from_millis(10),
+            reorder: 0.2,
+        });
+        for _ in 0..3 {
+            let (_, binding) = client.connect().unwrap();
+            assert_eq!(SocketAddr::V4(binding.mapped), local);
+        }
+    }
+}
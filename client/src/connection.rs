@@ -0,0 +1,298 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use message::{packet::Packet, transport};
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+
+/// Port for STUN and TURN over TLS and DTLS, RFC 7350
+pub const TLS_PORT: u16 = 5349;
+
+/// Path MTU assumed for DTLS records, small enough to get through tunnels
+#[cfg(feature = "tls")]
+const DTLS_MTU: u32 = 1200;
+
+/// Handshakes give up if the server goes quiet for this long
+#[cfg(feature = "tls")]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+    Tls(TlsConfig),
+    Dtls(TlsConfig),
+}
+
+/// How the server's certificate is checked
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Name the certificate has to be valid for
+    pub server_name: String,
+    /// Trust the certificates in this PEM file instead of the system's
+    pub ca_file: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn new(server_name: String) -> Self {
+        Self {
+            server_name,
+            ca_file: None,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    fn connector(&self, method: SslMethod) -> io::Result<SslConnector> {
+        let mut builder = SslConnector::builder(method)?;
+        if let Some(ca_file) = &self.ca_file {
+            builder.set_ca_file(ca_file)?;
+        }
+        Ok(builder.build())
+    }
+}
+
+/// A connection to a server over any of the transports STUN runs on. Every
+/// send and recv carries one whole message or ChannelData frame.
+pub enum Connection {
+    /// Only datagrams from the server are taken
+    Udp(Arc<dyn transport::Transport>, SocketAddr),
+    /// Streams keep what they read past the last frame
+    Tcp(TcpStream, Vec<u8>),
+    #[cfg(feature = "tls")]
+    Tls(SslStream<TcpStream>, Vec<u8>),
+    #[cfg(feature = "tls")]
+    Dtls(SslStream<Datagrams>),
+}
+
+impl Connection {
+    pub fn open(server: SocketAddr, transport: &Transport) -> io::Result<Self> {
+        let unspecified = match server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        match transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind(unspecified)?;
+                socket.connect(server)?;
+                Ok(Connection::over(Arc::new(socket), server))
+            }
+            Transport::Tcp => Ok(Connection::Tcp(TcpStream::connect(server)?, Vec::new())),
+            #[cfg(feature = "tls")]
+            Transport::Tls(config) => {
+                let stream = TcpStream::connect(server)?;
+                stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+                let stream = config
+                    .connector(SslMethod::tls_client())?
+                    .connect(&config.server_name, stream)
+                    .map_err(io::Error::other)?;
+                Ok(Connection::Tls(stream, Vec::new()))
+            }
+            #[cfg(feature = "tls")]
+            Transport::Dtls(config) => {
+                let socket = UdpSocket::bind(unspecified)?;
+                socket.connect(server)?;
+                socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+                let mut ssl = config
+                    .connector(SslMethod::dtls())?
+                    .configure()?
+                    .into_ssl(&config.server_name)?;
+                ssl.set_mtu(DTLS_MTU)?;
+                let mut stream =
+                    SslStream::new(ssl, Datagrams(socket)).map_err(io::Error::other)?;
+                stream.connect().map_err(io::Error::other)?;
+                Ok(Connection::Dtls(stream))
+            }
+            #[cfg(not(feature = "tls"))]
+            Transport::Tls(_) | Transport::Dtls(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "built without the tls feature",
+            )),
+        }
+    }
+
+    /// Talk to `server` over UDP from `socket`, which can be shared
+    pub fn over(socket: Arc<dyn transport::Transport>, server: SocketAddr) -> Self {
+        Connection::Udp(socket, server)
+    }
+
+    /// Whether requests over the connection can get lost, and have to be
+    /// sent again
+    pub fn is_unreliable(&self) -> bool {
+        matches!(self, Connection::Udp(..))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Udp(socket, _) => socket.set_read_timeout(timeout),
+            Connection::Tcp(stream, _) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream, _) => stream.get_ref().set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Connection::Dtls(stream) => stream.get_ref().0.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::Udp(socket, server) => socket.send_to(data, *server).map(|_| ()),
+            Connection::Tcp(stream, _) => stream.write_all(data),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream, _) => stream.write_all(data),
+            #[cfg(feature = "tls")]
+            Connection::Dtls(stream) => stream.write_all(data),
+        }
+    }
+
+    pub fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = [0; 2048];
+        let (stream, buf): (&mut dyn Read, _) = match self {
+            Connection::Udp(socket, server) => loop {
+                let (amt, from) = socket.recv_from(&mut chunk)?;
+                if from == *server {
+                    return Ok(chunk[..amt].to_vec());
+                }
+            },
+            #[cfg(feature = "tls")]
+            Connection::Dtls(stream) => {
+                let amt = stream.read(&mut chunk)?;
+                return Ok(chunk[..amt].to_vec());
+            }
+            Connection::Tcp(stream, buf) => (stream, buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream, buf) => (stream, buf),
+        };
+        loop {
+            if let Some(len) = Packet::frame_len(buf).filter(|len| buf.len() >= *len) {
+                return Ok(buf.drain(..len).collect());
+            }
+            match stream.read(&mut chunk)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                amt => buf.extend_from_slice(&chunk[..amt]),
+            }
+        }
+    }
+}
+
+/// A connected UDP socket as a stream of datagrams, for DTLS to run on
+#[cfg(feature = "tls")]
+pub struct Datagrams(UdpSocket);
+
+#[cfg(feature = "tls")]
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+    use message::{
+        header::{Header, HeaderType},
+        Message,
+    };
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder},
+    };
+    use server::{server::Server, tls::Certificate};
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// Self-signed certificate for localhost, written out as PEM files
+    fn self_signed() -> (PathBuf, PathBuf) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("totem-client-{}", nanos));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_file, cert.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_file, key_file)
+    }
+
+    fn start_tls_server() -> (SocketAddr, PathBuf) {
+        let (cert, key) = self_signed();
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        let certificate = Certificate::from_pem_files(&cert, &key).unwrap();
+        server.enable_tls(SocketAddr::new(LOCALHOST, 0), certificate);
+        server.spawn();
+        (server.tls_addr().unwrap(), cert)
+    }
+
+    #[test]
+    fn test_binding_over_tls_and_dtls() {
+        let (server, ca_file) = start_tls_server();
+        let mut config = TlsConfig::new("localhost".into());
+        config.ca_file = Some(ca_file);
+
+        for transport in [Transport::Tls(config.clone()), Transport::Dtls(config)] {
+            let mut conn = Connection::open(server, &transport).unwrap();
+            conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let header = Header::with_random_id(HeaderType::BindingRequest);
+            let transaction_id = header.transaction_id;
+            conn.send(&Message::new(header, vec![]).encode()).unwrap();
+
+            let response = Message::decode(&conn.recv().unwrap()).unwrap();
+            assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+            assert_eq!(response.header.transaction_id, transaction_id);
+        }
+    }
+
+    #[test]
+    fn test_tls_checks_server_name() {
+        let (server, ca_file) = start_tls_server();
+        let mut config = TlsConfig::new("stun.example.com".into());
+        config.ca_file = Some(ca_file);
+        assert!(Connection::open(server, &Transport::Tls(config)).is_err());
+    }
+}
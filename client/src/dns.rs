@@ -0,0 +1,498 @@
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use rand::Rng;
+
+const DNS_PORT: u16 = 53;
+
+/// How long a query waits for its answer, and how often it's sent
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_ATTEMPTS: u32 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// An SRV record, RFC 2782
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// "." when the service is decidedly not available at the domain
+    pub target: String,
+}
+
+/// Where SRV records and addresses are looked up, so discovery can be
+/// pointed at any name server or replaced altogether
+pub trait Resolver {
+    /// SRV records for `name`, empty if it has none
+    fn srv(&self, name: &str) -> io::Result<Vec<Srv>>;
+
+    /// Addresses of `host` from its A and AAAA records, with `port`
+    fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Asks a name server over UDP, just enough DNS for finding servers
+pub struct DnsResolver {
+    server: SocketAddr,
+    timeout: Duration,
+}
+
+impl DnsResolver {
+    pub fn new(server: SocketAddr) -> Self {
+        Self {
+            server,
+            timeout: QUERY_TIMEOUT,
+        }
+    }
+
+    /// The first name server in /etc/resolv.conf
+    pub fn system() -> io::Result<Self> {
+        let conf = fs::read_to_string("/etc/resolv.conf")?;
+        conf.lines()
+            .filter_map(|line| line.strip_prefix("nameserver"))
+            .find_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .map(|ip| Self::new(SocketAddr::new(ip, DNS_PORT)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no name server in resolv.conf"))
+    }
+
+    /// How long to wait for an answer before asking again
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn query(&self, name: &str, qtype: u16) -> io::Result<Vec<Record>> {
+        let local = match self.server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(self.server)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+
+        let id = rand::random::<u16>();
+        let query = encode_query(id, name, qtype)?;
+        let mut buf = [0; 4096];
+        for _ in 0..QUERY_ATTEMPTS {
+            socket.send(&query)?;
+            loop {
+                match socket.recv(&mut buf) {
+                    // Late answers to an earlier query are dropped
+                    Ok(amt) if amt < 2 || buf[..2] != id.to_be_bytes() => continue,
+                    Ok(amt) => return decode_response(&buf[..amt]),
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} didn't answer for {}", self.server, name),
+        ))
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn srv(&self, name: &str) -> io::Result<Vec<Srv>> {
+        let records = self.query(name, TYPE_SRV)?;
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Srv(srv) => Some(srv),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let mut addrs = Vec::new();
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            for record in self.query(host, qtype)? {
+                match record {
+                    Record::A(ip) => addrs.push(SocketAddr::new(ip.into(), port)),
+                    Record::Aaaa(ip) => addrs.push(SocketAddr::new(ip.into(), port)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+/// Put SRV records in the order they're to be tried, RFC 2782: lowest
+/// priority first, then at random within a priority, weighted by weight
+pub fn order(mut records: Vec<Srv>) -> Vec<Srv> {
+    records.sort_by_key(|srv| srv.priority);
+    let mut rng = rand::thread_rng();
+    let mut ordered = Vec::with_capacity(records.len());
+    while let Some(first) = records.first() {
+        let priority = first.priority;
+        let end = records
+            .iter()
+            .position(|srv| srv.priority != priority)
+            .unwrap_or(records.len());
+        let mut group: Vec<_> = records.drain(..end).collect();
+        // Zero weights go first, so they're only picked when the draw is 0
+        group.sort_by_key(|srv| srv.weight != 0);
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|srv| u32::from(srv.weight)).sum();
+            let draw = rng.gen_range(0..=total);
+            let mut sum = 0;
+            let index = group
+                .iter()
+                .position(|srv| {
+                    sum += u32::from(srv.weight);
+                    sum >= draw
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+/// The answers we care about, anything else is `Other`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Record {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Srv(Srv),
+    Other,
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut query, name)?;
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid domain name {:?}", name),
+            ));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+/// Answers in a response, none if the name doesn't exist
+fn decode_response(data: &[u8]) -> io::Result<Vec<Record>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response");
+    let mut reader = Reader { data, pos: 0 };
+    let header = reader.bytes(12).ok_or_else(invalid)?;
+    if header[2] & 0x80 == 0 {
+        return Err(invalid());
+    }
+    match header[3] & 0x0F {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(vec![]),
+        rcode => {
+            return Err(io::Error::other(format!(
+                "name server failed with rcode {}",
+                rcode
+            )))
+        }
+    }
+
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+    for _ in 0..questions {
+        reader.name().ok_or_else(invalid)?;
+        reader.bytes(4).ok_or_else(invalid)?;
+    }
+    (0..answers)
+        .map(|_| reader.record().ok_or_else(invalid))
+        .collect()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A domain name, following compression pointers, RFC 1035 section 4.1.4
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumps = 0;
+        loop {
+            let len = usize::from(*self.data.get(pos)?);
+            match len {
+                0 => {
+                    if jumps == 0 {
+                        self.pos = pos + 1;
+                    }
+                    break;
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let low = usize::from(*self.data.get(pos + 1)?);
+                    if jumps == 0 {
+                        self.pos = pos + 2;
+                    }
+                    // Pointers could go round in circles
+                    jumps += 1;
+                    if jumps > 16 {
+                        return None;
+                    }
+                    pos = (len & 0x3F) << 8 | low;
+                }
+                len if len < 64 => {
+                    let label = self.data.get(pos + 1..pos + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                _ => return None,
+            }
+        }
+        if labels.is_empty() {
+            return Some(".".into());
+        }
+        Some(labels.join("."))
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        self.bytes(4)?; // TTL
+        let len = usize::from(self.u16()?);
+        let end = self.pos + len;
+        let rdata = self.data.get(self.pos..end)?;
+        let record = match (rtype, class) {
+            (TYPE_A, CLASS_IN) => Record::A(<[u8; 4]>::try_from(rdata).ok()?.into()),
+            (TYPE_AAAA, CLASS_IN) => Record::Aaaa(<[u8; 16]>::try_from(rdata).ok()?.into()),
+            (TYPE_SRV, CLASS_IN) => {
+                let mut srv = Reader {
+                    data: &self.data[..end],
+                    pos: self.pos,
+                };
+                Record::Srv(Srv {
+                    priority: srv.u16()?,
+                    weight: srv.u16()?,
+                    port: srv.u16()?,
+                    target: srv.name()?,
+                })
+            }
+            _ => Record::Other,
+        };
+        self.pos = end;
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A name server on localhost answering from a fixed zone, NXDOMAIN
+    /// for names it has nothing for
+    pub(crate) fn stub_dns(zone: Vec<(&str, Record)>) -> SocketAddr {
+        let zone: Vec<_> = zone
+            .into_iter()
+            .map(|(name, record)| (name.to_ascii_lowercase(), record))
+            .collect();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((amt, src)) = socket.recv_from(&mut buf) {
+                let query = &buf[..amt];
+                let mut reader = Reader {
+                    data: query,
+                    pos: 12,
+                };
+                let name = reader.name().unwrap().to_ascii_lowercase();
+                let qtype = reader.u16().unwrap();
+                let question = &query[12..reader.pos + 2];
+
+                let known = zone.iter().any(|(owner, _)| *owner == name);
+                let answers: Vec<_> = zone
+                    .iter()
+                    .filter(|(owner, record)| *owner == name && record_type(record) == qtype)
+                    .collect();
+                let mut response = query[..2].to_vec();
+                response.extend_from_slice(&[0x81, if known { 0x80 } else { 0x83 }, 0, 1]);
+                response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+                response.extend_from_slice(&[0, 0, 0, 0]);
+                response.extend_from_slice(question);
+                for (_, record) in answers {
+                    // Owner name compressed to the one in the question
+                    response.extend_from_slice(&[0xC0, 12]);
+                    response.extend_from_slice(&qtype.to_be_bytes());
+                    response.extend_from_slice(&CLASS_IN.to_be_bytes());
+                    response.extend_from_slice(&60u32.to_be_bytes());
+                    let rdata = encode_rdata(record);
+                    response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                    response.extend_from_slice(&rdata);
+                }
+                socket.send_to(&response, src).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn record_type(record: &Record) -> u16 {
+        match record {
+            Record::A(_) => TYPE_A,
+            Record::Aaaa(_) => TYPE_AAAA,
+            Record::Srv(_) => TYPE_SRV,
+            Record::Other => 0,
+        }
+    }
+
+    fn encode_rdata(record: &Record) -> Vec<u8> {
+        match record {
+            Record::A(ip) => ip.octets().to_vec(),
+            Record::Aaaa(ip) => ip.octets().to_vec(),
+            Record::Srv(srv) => {
+                let mut rdata = Vec::new();
+                rdata.extend_from_slice(&srv.priority.to_be_bytes());
+                rdata.extend_from_slice(&srv.weight.to_be_bytes());
+                rdata.extend_from_slice(&srv.port.to_be_bytes());
+                if srv.target == "." {
+                    rdata.push(0);
+                } else {
+                    encode_name(&mut rdata, &srv.target).unwrap();
+                }
+                rdata
+            }
+            Record::Other => unreachable!(),
+        }
+    }
+
+    pub(crate) fn srv(priority: u16, weight: u16, port: u16, target: &str) -> Record {
+        Record::Srv(Srv {
+            priority,
+            weight,
+            port,
+            target: target.into(),
+        })
+    }
+
+    #[test]
+    fn test_srv_and_address_lookups() {
+        let server = stub_dns(vec![
+            (
+                "_stun._udp.example.test",
+                srv(10, 5, 3478, "a.example.test"),
+            ),
+            (
+                "_stun._udp.example.test",
+                srv(20, 0, 3479, "b.example.test"),
+            ),
+            ("a.example.test", Record::A(Ipv4Addr::new(192, 0, 2, 1))),
+            (
+                "a.example.test",
+                Record::Aaaa("2001:db8::1".parse().unwrap()),
+            ),
+        ]);
+        let resolver = DnsResolver::new(server);
+
+        let mut records = resolver.srv("_STUN._udp.example.test.").unwrap();
+        records.sort_by_key(|srv| srv.priority);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].target, "a.example.test");
+        assert_eq!((records[1].port, records[1].weight), (3479, 0));
+
+        assert_eq!(
+            resolver.lookup("a.example.test", 3478).unwrap(),
+            vec![
+                "192.0.2.1:3478".parse().unwrap(),
+                "[2001:db8::1]:3478".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            resolver.lookup("192.0.2.7", 80).unwrap(),
+            vec!["192.0.2.7:80".parse().unwrap()]
+        );
+        assert!(resolver.srv("_stun._udp.missing.test").unwrap().is_empty());
+        assert!(resolver.lookup("example.test", 3478).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_query_times_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut resolver = DnsResolver::new(silent.local_addr().unwrap());
+        resolver.set_timeout(Duration::from_millis(50));
+        let err = resolver.srv("_stun._udp.example.test").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_decode_rejects_pointer_loops() {
+        // One answer whose name points at itself
+        let response = [
+            0, 0, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0, 0xC0, 12, 0, 1, 0, 1,
+        ];
+        let err = decode_response(&response).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_order_by_priority_then_weight() {
+        let record = |priority, weight, target: &str| Srv {
+            priority,
+            weight,
+            port: 3478,
+            target: target.into(),
+        };
+        let records = vec![
+            record(20, 0, "d"),
+            record(10, 0, "a"),
+            record(10, 100, "b"),
+            record(10, 100, "c"),
+        ];
+        for _ in 0..20 {
+            let ordered: Vec<_> = order(records.clone())
+                .into_iter()
+                .map(|srv| srv.target)
+                .collect();
+            assert_eq!(ordered.len(), 4);
+            assert_eq!(ordered[3], "d");
+            let mut first = ordered[..3].to_vec();
+            first.sort();
+            assert_eq!(first, ["a", "b", "c"]);
+        }
+        assert!(order(vec![]).is_empty());
+    }
+}
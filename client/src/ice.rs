@@ -0,0 +1,557 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use message::{
+    header::{Header, HeaderType},
+    transport::Transport,
+    Message,
+};
+
+use crate::{
+    client::{read_binding, Credential},
+    turn::{is_timeout, TurnClient},
+};
+
+/// Initial retransmission timeout of binding requests, doubled after every
+/// attempt
+const RTO: Duration = Duration::from_millis(250);
+const MAX_TRANSMISSIONS: u32 = 3;
+
+/// How a candidate was found, RFC 8445 section 5.1.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandidateType {
+    /// An address of a local interface
+    Host,
+    /// A host candidate as a STUN server saw it from outside the NAT
+    ServerReflexive,
+    /// Learned from a connectivity check rather than gathered
+    PeerReflexive,
+    /// A TURN server's relayed address
+    Relayed,
+}
+
+impl CandidateType {
+    /// Recommended type preference, RFC 8445 section 5.1.2.2
+    pub const fn preference(self) -> u32 {
+        match self {
+            CandidateType::Host => 126,
+            CandidateType::PeerReflexive => 110,
+            CandidateType::ServerReflexive => 100,
+            CandidateType::Relayed => 0,
+        }
+    }
+
+    /// Name in `a=candidate` lines, RFC 8839 section 5.1
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            CandidateType::Host => "host",
+            CandidateType::ServerReflexive => "srflx",
+            CandidateType::PeerReflexive => "prflx",
+            CandidateType::Relayed => "relay",
+        }
+    }
+}
+
+/// A UDP candidate for one component of a data stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub foundation: String,
+    pub component: u16,
+    pub priority: u32,
+    pub address: SocketAddr,
+    pub kind: CandidateType,
+    /// Address packets for the candidate are sent from, the host candidate
+    /// for server reflexive ones and the relayed address for relayed ones
+    pub base: SocketAddr,
+    /// The host address behind a server reflexive candidate, or the mapped
+    /// address behind a relayed one
+    pub related: Option<SocketAddr>,
+}
+
+impl Candidate {
+    /// Priority from the type preference, the local preference telling
+    /// apart candidates of the same type, and the component, RFC 8445
+    /// section 5.1.2.1
+    pub const fn priority(kind: CandidateType, local_preference: u16, component: u16) -> u32 {
+        (kind.preference() << 24) | ((local_preference as u32) << 8) | (256 - component as u32)
+    }
+
+    /// The candidate as an SDP attribute line
+    pub fn to_sdp(&self) -> String {
+        format!("a={}", self)
+    }
+}
+
+/// `candidate:` attribute value, RFC 8839 section 5.1
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "candidate:{} {} UDP {} {} {} typ {}",
+            self.foundation,
+            self.component,
+            self.priority,
+            self.address.ip(),
+            self.address.port(),
+            self.kind.as_str()
+        )?;
+        if let Some(related) = self.related {
+            write!(f, " raddr {} rport {}", related.ip(), related.port())?;
+        }
+        Ok(())
+    }
+}
+
+/// Candidates and what has to be kept open for them to stay valid
+pub struct Gathered {
+    /// Highest priority first
+    pub candidates: Vec<Candidate>,
+    /// Sockets of the host candidates, which server reflexive candidates
+    /// share
+    pub sockets: Vec<UdpSocket>,
+    /// Allocations of the relayed candidates
+    pub relays: Vec<TurnClient>,
+}
+
+/// Gathers host, server reflexive and relayed candidates for one component
+pub struct Gatherer {
+    /// Addresses for host candidates, the local interfaces' when unset
+    addresses: Option<Vec<IpAddr>>,
+    stun_servers: Vec<SocketAddr>,
+    turn_servers: Vec<(SocketAddr, Credential)>,
+    component: u16,
+}
+
+impl Gatherer {
+    pub fn new() -> Self {
+        Self {
+            addresses: None,
+            stun_servers: Vec::new(),
+            turn_servers: Vec::new(),
+            component: 1,
+        }
+    }
+
+    /// Use these addresses for host candidates instead of the local
+    /// interfaces'
+    pub fn set_addresses(&mut self, addresses: Vec<IpAddr>) {
+        self.addresses = Some(addresses);
+    }
+
+    /// Ask this server for server reflexive candidates
+    pub fn add_stun_server(&mut self, server: SocketAddr) {
+        self.stun_servers.push(server);
+    }
+
+    /// Allocate a relayed candidate on this server
+    pub fn add_turn_server(&mut self, server: SocketAddr, credential: Credential) {
+        self.turn_servers.push((server, credential));
+    }
+
+    /// Component the candidates are for, 1 for RTP and 2 for RTCP
+    pub fn set_component(&mut self, component: u16) {
+        self.component = component;
+    }
+
+    /// Bind a socket for every host address, and ask the servers about
+    /// them, the sockets and TURN servers at once but one server at a time
+    /// per socket, so no read takes another server's answer or timeout.
+    /// Servers that fail are skipped, with a message.
+    pub fn gather(&self) -> io::Result<Gathered> {
+        let addresses = match &self.addresses {
+            Some(addresses) => addresses.clone(),
+            None => local_addresses()?,
+        };
+        let sockets = addresses
+            .iter()
+            .map(|ip| UdpSocket::bind(SocketAddr::new(*ip, 0)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let (reflexive, relays) = thread::scope(|scope| {
+            let queries: Vec<_> = sockets
+                .iter()
+                .map(|socket| {
+                    let servers: Vec<_> = self
+                        .stun_servers
+                        .iter()
+                        .filter(|server| {
+                            socket
+                                .local_addr()
+                                .is_ok_and(|addr| addr.is_ipv4() == server.is_ipv4())
+                        })
+                        .collect();
+                    scope.spawn(move || {
+                        servers
+                            .into_iter()
+                            .map(|server| (*server, reflexive(socket, *server)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            let allocations: Vec<_> = self
+                .turn_servers
+                .iter()
+                .map(|(server, credential)| {
+                    let credential = credential.clone();
+                    (*server, scope.spawn(move || relay(*server, credential)))
+                })
+                .collect();
+
+            let reflexive = collect(queries.into_iter().flat_map(join));
+            let relays = collect(
+                allocations
+                    .into_iter()
+                    .map(|(server, handle)| (server, join(handle))),
+            );
+            (reflexive, relays)
+        });
+
+        // Each with the server it came from, for its foundation
+        let mut found = Vec::new();
+        for (index, socket) in sockets.iter().enumerate() {
+            let base = socket.local_addr()?;
+            let local_preference = u16::MAX - index as u16;
+            let host = self.candidate(CandidateType::Host, local_preference, base, base, None);
+            found.push((host, None));
+            for (server, (from, mapped)) in &reflexive {
+                if *from == base {
+                    let candidate = self.candidate(
+                        CandidateType::ServerReflexive,
+                        local_preference,
+                        *mapped,
+                        base,
+                        Some(base),
+                    );
+                    found.push((candidate, Some(server.ip())));
+                }
+            }
+        }
+        let mut turn_clients = Vec::new();
+        for (index, (server, (turn, relayed, mapped))) in relays.into_iter().enumerate() {
+            let local_preference = u16::MAX - index as u16;
+            let candidate = self.candidate(
+                CandidateType::Relayed,
+                local_preference,
+                relayed,
+                relayed,
+                mapped,
+            );
+            found.push((candidate, Some(server.ip())));
+            turn_clients.push(turn);
+        }
+
+        let mut foundations = Foundations::default();
+        let mut candidates: Vec<_> = found
+            .into_iter()
+            .map(|(mut candidate, server)| {
+                candidate.foundation = foundations.get(candidate.kind, candidate.base.ip(), server);
+                candidate
+            })
+            .collect();
+
+        // Server reflexive candidates the same as their host candidate,
+        // when there's no NAT, are redundant, RFC 8445 section 5.1.3
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.priority));
+        let mut seen = Vec::new();
+        candidates.retain(|candidate| {
+            let key = (candidate.address, candidate.base);
+            let redundant = seen.contains(&key);
+            seen.push(key);
+            !redundant
+        });
+
+        Ok(Gathered {
+            candidates,
+            sockets,
+            relays: turn_clients,
+        })
+    }
+
+    /// A candidate of this gatherer's component, its foundation still to
+    /// be filled in
+    fn candidate(
+        &self,
+        kind: CandidateType,
+        local_preference: u16,
+        address: SocketAddr,
+        base: SocketAddr,
+        related: Option<SocketAddr>,
+    ) -> Candidate {
+        Candidate {
+            foundation: String::new(),
+            component: self.component,
+            priority: Candidate::priority(kind, local_preference, self.component),
+            address,
+            kind,
+            base,
+            related,
+        }
+    }
+}
+
+impl Default for Gatherer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Candidates share a foundation when they have the same type, base
+/// address and server, RFC 8445 section 5.1.1.3
+#[derive(Default)]
+struct Foundations(HashMap<(CandidateType, IpAddr, Option<IpAddr>), String>);
+
+impl Foundations {
+    fn get(&mut self, kind: CandidateType, base: IpAddr, server: Option<IpAddr>) -> String {
+        let next = (self.0.len() + 1).to_string();
+        self.0.entry((kind, base, server)).or_insert(next).clone()
+    }
+}
+
+fn join<T>(handle: thread::ScopedJoinHandle<T>) -> T {
+    handle.join().expect("gathering thread panicked")
+}
+
+/// Results from the servers that succeeded, by server
+fn collect<T>(
+    results: impl IntoIterator<Item = (SocketAddr, io::Result<T>)>,
+) -> Vec<(SocketAddr, T)> {
+    results
+        .into_iter()
+        .filter_map(|(server, result)| match result {
+            Ok(result) => Some((server, result)),
+            Err(err) => {
+                eprintln!("Gathering from {} failed: {}", server, err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The address `server` sees `socket` coming from, along with the socket's
+/// own address
+pub(crate) fn reflexive(
+    socket: &dyn Transport,
+    server: SocketAddr,
+) -> io::Result<(SocketAddr, SocketAddr)> {
+    let header = Header::with_random_id(HeaderType::BindingRequest);
+    let transaction_id = header.transaction_id;
+    let request = Message::new(header, vec![]).encode();
+
+    let mut buf = [0; 2048];
+    let mut timeout = RTO;
+    for _ in 0..MAX_TRANSMISSIONS {
+        socket.send_to(&request, server)?;
+        socket.set_read_timeout(Some(timeout))?;
+        loop {
+            let (amt, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if is_timeout(&err) => break,
+                Err(err) => return Err(err),
+            };
+            // Other servers' answers arrive on the same socket
+            let Ok(response) = Message::decode(&buf[..amt]) else {
+                continue;
+            };
+            if from != server || response.header.transaction_id != transaction_id {
+                continue;
+            }
+            let binding = read_binding(response)?;
+            return Ok((socket.local_addr()?, SocketAddr::V4(binding.mapped)));
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no binding response",
+    ))
+}
+
+/// An allocation on `server`, with its relayed and mapped addresses
+fn relay(
+    server: SocketAddr,
+    credential: Credential,
+) -> io::Result<(TurnClient, SocketAddr, Option<SocketAddr>)> {
+    let mut turn = TurnClient::new(server, credential)?;
+    let allocation = turn.allocate()?;
+    Ok((turn, allocation.relayed, allocation.mapped))
+}
+
+/// Addresses of the interfaces that are up, leaving out loopback and IPv6
+/// link-local addresses, RFC 8445 section 5.1.1.1
+#[cfg(unix)]
+pub fn local_addresses() -> io::Result<Vec<IpAddr>> {
+    let mut ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills in a list we own until freeifaddrs
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addresses = Vec::new();
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() {
+        // SAFETY: every entry in the list is valid until freeifaddrs
+        let ifaddr = unsafe { &*cursor };
+        cursor = ifaddr.ifa_next;
+        let up = ifaddr.ifa_flags & libc::IFF_UP as libc::c_uint != 0;
+        if !up || ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        // SAFETY: the family says which kind of address ifa_addr points to
+        let ip = match i32::from(unsafe { (*ifaddr.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::from(addr.sin_addr.s_addr.to_ne_bytes())
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::from(addr.sin6_addr.s6_addr)
+            }
+            _ => continue,
+        };
+        let link_local = match ip {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(ip) => ip.segments()[0] & 0xFFC0 == 0xFE80,
+        };
+        if !ip.is_loopback() && !ip.is_unspecified() && !link_local && !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+    }
+    // SAFETY: the list came from getifaddrs and isn't used past here
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addresses)
+}
+
+#[cfg(not(unix))]
+pub fn local_addresses() -> io::Result<Vec<IpAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "listing interfaces is only supported on unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::attribute::{MappedAddress, Value};
+    use server::server::Server;
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn test_candidate_priority_and_sdp() {
+        let priority = Candidate::priority(CandidateType::Host, 65535, 1);
+        assert_eq!(priority, 2_130_706_431);
+        assert_eq!(
+            Candidate::priority(CandidateType::Relayed, 65535, 2),
+            16_777_214
+        );
+
+        let candidate = Candidate {
+            foundation: "2".into(),
+            component: 1,
+            priority: Candidate::priority(CandidateType::ServerReflexive, 65535, 1),
+            address: "203.0.113.5:40000".parse().unwrap(),
+            kind: CandidateType::ServerReflexive,
+            base: "192.168.1.2:5000".parse().unwrap(),
+            related: Some("192.168.1.2:5000".parse().unwrap()),
+        };
+        assert_eq!(
+            candidate.to_sdp(),
+            "a=candidate:2 1 UDP 1694498815 203.0.113.5 40000 typ srflx \
+             raddr 192.168.1.2 rport 5000"
+        );
+    }
+
+    #[test]
+    fn test_local_addresses_skip_loopback() {
+        let addresses = local_addresses().unwrap();
+        assert!(addresses.iter().all(|ip| !ip.is_loopback()));
+    }
+
+    #[test]
+    fn test_gather_host_reflexive_and_relayed() {
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.add_user("user".into(), "pass".into());
+        server.spawn();
+        let server = server.local_addrs()[0];
+        let nat = nat_server(MappedAddress::new(1, 40000, Ipv4Addr::new(203, 0, 113, 5)));
+
+        let mut gatherer = Gatherer::new();
+        gatherer.set_addresses(vec![LOCALHOST]);
+        gatherer.add_stun_server(nat);
+        // Sees us as we are, so its candidate is redundant
+        gatherer.add_stun_server(server);
+        gatherer.add_turn_server(server, Credential("user".into(), "pass".into()));
+        let gathered = gatherer.gather().unwrap();
+        assert_eq!(gathered.sockets.len(), 1);
+        assert_eq!(gathered.relays.len(), 1);
+
+        let host = gathered.sockets[0].local_addr().unwrap();
+        let kinds: Vec<_> = gathered.candidates.iter().map(|c| c.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                CandidateType::Host,
+                CandidateType::ServerReflexive,
+                CandidateType::Relayed
+            ]
+        );
+        let [host_candidate, reflexive, relayed] = &gathered.candidates[..] else {
+            unreachable!();
+        };
+        assert_eq!(host_candidate.address, host);
+        assert_eq!(reflexive.address, "203.0.113.5:40000".parse().unwrap());
+        assert_eq!(reflexive.related, Some(host));
+        assert_eq!(
+            relayed.related.unwrap().port(),
+            gathered.relays[0].local_addr().unwrap().port()
+        );
+        // Different types never share a foundation
+        assert_ne!(host_candidate.foundation, reflexive.foundation);
+        assert_ne!(reflexive.foundation, relayed.foundation);
+    }
+
+    #[test]
+    fn test_gather_from_servers_sharing_a_socket() {
+        let mut gatherer = Gatherer::new();
+        gatherer.set_addresses(vec![LOCALHOST]);
+        for port in 40000..40004 {
+            let mapped = MappedAddress::new(1, port, Ipv4Addr::new(203, 0, 113, 5));
+            gatherer.add_stun_server(nat_server(mapped));
+        }
+        let gathered = gatherer.gather().unwrap();
+        let mut ports: Vec<_> = gathered
+            .candidates
+            .iter()
+            .filter(|c| c.kind == CandidateType::ServerReflexive)
+            .map(|c| c.address.port())
+            .collect();
+        ports.sort();
+        assert_eq!(ports, [40000, 40001, 40002, 40003]);
+    }
+
+    /// A STUN server behind a pretend NAT, answering every request with
+    /// `mapped`
+    fn nat_server(mapped: MappedAddress) -> SocketAddr {
+        let socket = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            while let Ok((amt, src)) = socket.recv_from(&mut buf) {
+                let request = Message::decode(&buf[..amt]).unwrap();
+                let header =
+                    Header::new(HeaderType::BindingResponse, request.header.transaction_id);
+                let mapped = MappedAddress::new(1, mapped.port, mapped.address);
+                let response =
+                    Message::new(header, vec![Value::MappedAddress(mapped).into_attribute()]);
+                socket.send_to(&response.encode(), src).unwrap();
+            }
+        });
+        addr
+    }
+}
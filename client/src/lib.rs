@@ -0,0 +1,9 @@
+pub mod client;
+pub mod connection;
+pub mod dns;
+pub mod ice;
+pub mod load;
+pub mod nat;
+pub mod punch;
+pub mod turn;
+pub mod uri;
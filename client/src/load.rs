@@ -0,0 +1,125 @@
+//! A load generator, binding requests from many sockets at once to measure
+//! how many a server answers a second
+
+use std::{
+    fmt, io,
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use message::{
+    header::{Header, HeaderType},
+    Message,
+};
+
+use crate::turn::is_timeout;
+
+/// Requests each socket keeps unanswered at a time
+const WINDOW: usize = 16;
+/// How long a socket waits for an answer before taking its requests for
+/// lost and sending new ones
+const LOSS_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What a run of the load generator saw
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Report {
+    pub sent: u64,
+    pub answered: u64,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// Answers per second
+    pub fn rate(&self) -> f64 {
+        self.answered as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} binding requests answered in {:.1}s, {:.0} a second",
+            self.answered,
+            self.sent,
+            self.elapsed.as_secs_f64(),
+            self.rate()
+        )
+    }
+}
+
+/// Send binding requests to `server` for `duration` from `sockets` sockets,
+/// each on a thread of its own with up to `WINDOW` requests unanswered.
+/// Requests are encoded once and only their transaction ids change.
+pub fn run(server: SocketAddr, sockets: usize, duration: Duration) -> io::Result<Report> {
+    let start = Instant::now();
+    let deadline = start + duration;
+    let threads: Vec<_> = (0..sockets.max(1))
+        .map(|_| thread::spawn(move || blast(server, deadline)))
+        .collect();
+    let mut report = Report::default();
+    for thread in threads {
+        let (sent, answered) = thread.join().expect("thread join")?;
+        report.sent += sent;
+        report.answered += answered;
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// One socket's share of the load, how many requests it sent and how many
+/// were answered
+fn blast(server: SocketAddr, deadline: Instant) -> io::Result<(u64, u64)> {
+    let local = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(LOSS_TIMEOUT))?;
+
+    // The magic cookie and four random bytes stay the same, the rest of
+    // the transaction id counts requests
+    let header = Header::with_random_id(HeaderType::BindingRequest);
+    let mut request = Message::new(header, vec![]).encode();
+    let response_type = (HeaderType::BindingResponse as u16).to_be_bytes();
+    let (mut sent, mut answered, mut in_flight) = (0u64, 0, 0);
+    let mut buf = [0; 2048];
+    while Instant::now() < deadline {
+        while in_flight < WINDOW {
+            request[12..20].copy_from_slice(&sent.to_be_bytes());
+            socket.send(&request)?;
+            sent += 1;
+            in_flight += 1;
+        }
+        match socket.recv(&mut buf) {
+            Ok(amt) if amt >= 20 && buf[..2] == response_type && buf[4..12] == request[4..12] => {
+                answered += 1;
+                in_flight = in_flight.saturating_sub(1);
+            }
+            Ok(_) => {}
+            Err(err) if is_timeout(&err) => in_flight = 0,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((sent, answered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::server::Server;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_load_is_answered() {
+        let mut server = Server::new([SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0); 4]);
+        server.set_workers(2);
+        server.spawn();
+        let report = run(server.local_addrs()[0], 2, Duration::from_millis(300)).unwrap();
+        assert!(report.answered > 0);
+        assert!(report.answered <= report.sent);
+        assert!(report.rate() > 0.0);
+    }
+}
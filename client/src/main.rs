@@ -1,8 +1,25 @@
-mod client;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use message::punch::RENDEZVOUS_PORT;
 
-use client::Client;
+use client::{
+    client::{Client, Credential, Keepalive},
+    connection::Transport,
+    dns::DnsResolver,
+    uri::StunUri,
+};
+
+const USAGE: &str = "usage: client [--server URI] [--ca FILE]
+              [keepalive [--interval SECS] [--requests]]
+       client [--server URI] candidates [--user USERNAME:PASSWORD]
+       client [--server URI] nat
+       client [--server URI] load [--sockets N] [--seconds SECS]
+       client [--server URI] punch --session NAME [--rendezvous ADDR]
+       client [--server URI] turn-proxy --user USERNAME:PASSWORD --listen ADDR --peer ADDR";
 
 fn main() {
     let a1 = IpAddr::V4(Ipv4Addr::new(172, 19, 0, 2));
@@ -16,5 +33,116 @@ fn main() {
         SocketAddr::new(a2, p1),
         SocketAddr::new(a2, p2),
     ]);
-    client.run();
+
+    let mut args = std::env::args().skip(1).peekable();
+    // Server options come before the command. A stun:, stuns:, turn: or
+    // turns: URI picks the server and the transport to reach it with.
+    let mut server = None;
+    let mut ca_file = None;
+    while let Some(arg) = args.next_if(|arg| arg.starts_with("--")) {
+        match arg.as_str() {
+            "--server" => {
+                let uri = args.next().expect(USAGE);
+                let uri: StunUri = uri.parse().unwrap_or_else(|err| panic!("{}: {}", uri, err));
+                server = Some(uri);
+            }
+            "--ca" => ca_file = Some(PathBuf::from(args.next().expect(USAGE))),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    if let Some(uri) = server {
+        // Domains are looked up through SRV records when there's a name
+        // server to ask
+        let servers = match DnsResolver::system() {
+            Ok(resolver) => uri.discover(&resolver),
+            Err(_) => uri.resolve(),
+        };
+        client.set_servers(servers.unwrap_or_else(|err| panic!("{}: {}", uri, err)));
+        let mut transport = uri.transport();
+        if let Transport::Tls(config) | Transport::Dtls(config) = &mut transport {
+            config.ca_file = ca_file;
+        }
+        client.set_transport(transport);
+    }
+
+    match args.next().as_deref() {
+        None => client.run(),
+        Some("keepalive") => {
+            let mut interval = Duration::from_secs(15);
+            let mut mode = Keepalive::Indication;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--interval" => {
+                        let secs = args.next().and_then(|secs| secs.parse().ok());
+                        interval = Duration::from_secs(secs.expect(USAGE));
+                    }
+                    "--requests" => mode = Keepalive::Request,
+                    _ => panic!("{}", USAGE),
+                }
+            }
+            client.keepalive(interval, mode);
+        }
+        Some("candidates") => {
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--user" => {
+                        let user = args.next().expect(USAGE);
+                        let (username, password) = user.split_once(':').expect(USAGE);
+                        client.set_credential(Credential(username.into(), password.into()));
+                    }
+                    _ => panic!("{}", USAGE),
+                }
+            }
+            client.candidates();
+        }
+        Some("nat") => client.nat(),
+        Some("load") => {
+            let mut sockets = 8;
+            let mut duration = Duration::from_secs(10);
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--sockets" => sockets = args.next().and_then(|n| n.parse().ok()).expect(USAGE),
+                    "--seconds" => {
+                        let secs = args.next().and_then(|secs| secs.parse().ok());
+                        duration = Duration::from_secs(secs.expect(USAGE));
+                    }
+                    _ => panic!("{}", USAGE),
+                }
+            }
+            client.load(sockets, duration);
+        }
+        Some("punch") => {
+            let mut session = None;
+            // The rendezvous runs next to STUN by default
+            let mut rendezvous = SocketAddr::new(a1, RENDEZVOUS_PORT);
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--session" => session = args.next(),
+                    "--rendezvous" => {
+                        rendezvous = args.next().and_then(|addr| addr.parse().ok()).expect(USAGE)
+                    }
+                    _ => panic!("{}", USAGE),
+                }
+            }
+            client.punch(&session.expect(USAGE), rendezvous);
+        }
+        Some("turn-proxy") => {
+            let mut listen = None;
+            let mut peer = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--user" => {
+                        let user = args.next().expect(USAGE);
+                        let (username, password) = user.split_once(':').expect(USAGE);
+                        client.set_credential(Credential(username.into(), password.into()));
+                    }
+                    "--listen" => listen = args.next().and_then(|addr| addr.parse().ok()),
+                    "--peer" => peer = args.next().and_then(|addr| addr.parse().ok()),
+                    _ => panic!("{}", USAGE),
+                }
+            }
+            client.turn_proxy(listen.expect(USAGE), peer.expect(USAGE));
+        }
+        Some(_) => panic!("{}", USAGE),
+    }
 }
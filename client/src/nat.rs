@@ -0,0 +1,187 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use message::{
+    attribute::{ChangeRequest, Value},
+    header::{Header, HeaderType},
+    transport::Transport,
+    Message,
+};
+
+use crate::{client::read_binding, turn::is_timeout};
+
+/// First retransmission timeout of a test, doubled up to `MAX_RTO`, RFC 3489
+/// section 9.3
+const RTO: Duration = Duration::from_millis(100);
+const MAX_RTO: Duration = Duration::from_millis(1600);
+
+/// What sits between us and the internet, RFC 3489 section 5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// UDP doesn't get through at all
+    Blocked,
+    /// No NAT and no firewall
+    Open,
+    /// No NAT, but only answers from where we sent to get in
+    SymmetricFirewall,
+    /// One mapping per socket, anyone can send to it
+    FullCone,
+    /// One mapping per socket, hosts we sent to can send to it
+    RestrictedCone,
+    /// One mapping per socket, only the host and port we sent to can send
+    /// to it
+    PortRestrictedCone,
+    /// A new mapping for every destination
+    Symmetric,
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NatType::Blocked => "UDP blocked",
+            NatType::Open => "open internet",
+            NatType::SymmetricFirewall => "symmetric UDP firewall",
+            NatType::FullCone => "full cone NAT",
+            NatType::RestrictedCone => "restricted cone NAT",
+            NatType::PortRestrictedCone => "port restricted cone NAT",
+            NatType::Symmetric => "symmetric NAT",
+        })
+    }
+}
+
+/// Classify the NAT in front of `socket` with the tests of RFC 3489 section
+/// 10.1, against a `server` that answers CHANGE-REQUEST. Each test waits up
+/// to `timeout` for an answer.
+pub fn classify(
+    socket: &dyn Transport,
+    server: SocketAddr,
+    timeout: Duration,
+) -> io::Result<NatType> {
+    // Test I
+    let Some(first) = test(socket, server, None, timeout)? else {
+        return Ok(NatType::Blocked);
+    };
+    let changed = first.changed.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "server has no other address to answer from",
+        )
+    })?;
+    // Test II
+    let change_both = ChangeRequest::new(true, true);
+    let answered = test(socket, server, Some(change_both), timeout)?.is_some();
+    if first.mapped == socket.local_addr()? {
+        return Ok(match answered {
+            true => NatType::Open,
+            false => NatType::SymmetricFirewall,
+        });
+    }
+    if answered {
+        return Ok(NatType::FullCone);
+    }
+    // Test I again, to the other address
+    let Some(second) = test(socket, changed, None, timeout)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no answer from the server's other address",
+        ));
+    };
+    if second.mapped != first.mapped {
+        return Ok(NatType::Symmetric);
+    }
+    // Test III
+    let change_port = ChangeRequest::new(false, true);
+    Ok(match test(socket, server, Some(change_port), timeout)? {
+        Some(_) => NatType::RestrictedCone,
+        None => NatType::PortRestrictedCone,
+    })
+}
+
+/// What a server said to one of the tests
+struct Answer {
+    mapped: SocketAddr,
+    changed: Option<SocketAddr>,
+}
+
+/// Send a binding request to `server`, asking it to answer from another
+/// address with `change`, until an answer comes or `timeout` runs out. The
+/// answer is matched by transaction alone, it's meant to come from
+/// elsewhere.
+fn test(
+    socket: &dyn Transport,
+    server: SocketAddr,
+    change: Option<ChangeRequest>,
+    timeout: Duration,
+) -> io::Result<Option<Answer>> {
+    let header = Header::with_random_id(HeaderType::BindingRequest);
+    let transaction_id = header.transaction_id;
+    let attributes = change
+        .map(|change| Value::ChangeRequest(change).into_attribute())
+        .into_iter()
+        .collect();
+    let request = Message::new(header, attributes).encode();
+
+    let deadline = Instant::now() + timeout;
+    let mut rto = RTO;
+    let mut buf = [0; 2048];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        socket.send_to(&request, server)?;
+        let resend_at = (now + rto).min(deadline);
+        rto = (rto * 2).min(MAX_RTO);
+        while let Some(wait) = resend_at
+            .checked_duration_since(Instant::now())
+            .filter(|wait| !wait.is_zero())
+        {
+            socket.set_read_timeout(Some(wait))?;
+            let amt = match socket.recv_from(&mut buf) {
+                Ok((amt, _)) => amt,
+                Err(err) if is_timeout(&err) => break,
+                Err(err) => return Err(err),
+            };
+            // Late answers to earlier tests arrive on the same socket
+            let Ok(response) = Message::decode(&buf[..amt]) else {
+                continue;
+            };
+            if response.header.transaction_id != transaction_id {
+                continue;
+            }
+            let binding = read_binding(response)?;
+            return Ok(Some(Answer {
+                mapped: SocketAddr::V4(binding.mapped),
+                changed: binding.changed.map(SocketAddr::V4),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::server::Server;
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn test_classify_without_nat() {
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.spawn();
+        let socket = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let server = server.local_addrs()[0];
+        let nat = classify(&socket, server, Duration::from_millis(500)).unwrap();
+        assert_eq!(nat, NatType::Open);
+
+        // Nobody listening at all
+        let silent = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let nobody = silent.local_addr().unwrap();
+        let nat = classify(&socket, nobody, Duration::from_millis(300)).unwrap();
+        assert_eq!(nat, NatType::Blocked);
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use message::{punch::Punch, transport::Transport};
+
+use crate::turn::is_timeout;
+
+/// How often registrations and probes are sent again
+const RESEND: Duration = Duration::from_millis(200);
+
+/// How long probes keep being answered after ours got through, so the peer
+/// hears back too
+const LINGER: Duration = Duration::from_secs(1);
+
+/// Register `mapped` for `session` with the rendezvous service until it
+/// says where the peer is, returning the peer's mapped address
+pub fn rendezvous(
+    socket: &dyn Transport,
+    rendezvous: SocketAddr,
+    session: &str,
+    mapped: SocketAddr,
+    timeout: Duration,
+) -> io::Result<SocketAddr> {
+    if !Punch::valid_session(session) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "session names can't contain whitespace",
+        ));
+    }
+    let register = Punch::Register {
+        session: session.into(),
+        mapped,
+    }
+    .encode();
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        socket.send_to(&register, rendezvous)?;
+        match recv_until(socket, Instant::now() + RESEND)? {
+            Some((Punch::Peer(peer), from)) if from == rendezvous => return Ok(peer),
+            Some((Punch::Full, from)) if from == rendezvous => {
+                return Err(io::Error::other(format!("session {} is full", session)))
+            }
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the peer never registered",
+    ))
+}
+
+/// Probe `peer` from `socket` until a probe is acknowledged, answering the
+/// peer's probes meanwhile. Returns the address the acknowledgement came
+/// from, or None if the path never opened in `timeout`.
+pub fn punch(
+    socket: &dyn Transport,
+    session: &str,
+    peer: SocketAddr,
+    timeout: Duration,
+) -> io::Result<Option<SocketAddr>> {
+    let probe = Punch::Probe {
+        session: session.into(),
+    }
+    .encode();
+    let deadline = Instant::now() + timeout;
+    let mut direct = None;
+    let mut linger_until = deadline;
+    while Instant::now() < linger_until {
+        if direct.is_none() {
+            socket.send_to(&probe, peer)?;
+        }
+        let resend_at = (Instant::now() + RESEND).min(linger_until);
+        while let Some((punch, from)) = recv_until(socket, resend_at)? {
+            match punch {
+                // Answered wherever it came from, a NAT may have mapped the
+                // peer differently towards us than towards the server
+                Punch::Probe { session: theirs } if theirs == session => {
+                    let ack = Punch::Ack { session: theirs };
+                    socket.send_to(&ack.encode(), from)?;
+                }
+                Punch::Ack { session: theirs } if theirs == session && direct.is_none() => {
+                    direct = Some(from);
+                    linger_until = Instant::now() + LINGER;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(direct)
+}
+
+/// The next punch message to arrive on `socket` before `deadline`, skipping
+/// anything else
+fn recv_until(
+    socket: &dyn Transport,
+    deadline: Instant,
+) -> io::Result<Option<(Punch, SocketAddr)>> {
+    let mut buf = [0; 512];
+    loop {
+        let Some(timeout) = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|timeout| !timeout.is_zero())
+        else {
+            return Ok(None);
+        };
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv_from(&mut buf) {
+            Ok((amt, from)) => {
+                if let Some(punch) = Punch::decode(&buf[..amt]) {
+                    return Ok(Some((punch, from)));
+                }
+            }
+            Err(err) if is_timeout(&err) => return Ok(None),
+            // ICMP for probes that got to the peer's host before its socket
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ice::reflexive;
+    use natsim::{Nat, NatConfig};
+    use server::server::Server;
+    use std::{
+        net::{IpAddr, Ipv4Addr, UdpSocket},
+        thread,
+    };
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn start_rendezvous() -> SocketAddr {
+        start_server().1
+    }
+
+    /// A STUN server that's also a rendezvous, returns both addresses
+    fn start_server() -> (SocketAddr, SocketAddr) {
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.enable_rendezvous(SocketAddr::new(LOCALHOST, 0));
+        server.spawn();
+        (server.local_addrs()[0], server.rendezvous_addr().unwrap())
+    }
+
+    fn bind() -> UdpSocket {
+        UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap()
+    }
+
+    /// Meet the peer at `server` and punch through to it
+    fn peer(server: SocketAddr, socket: UdpSocket) -> thread::JoinHandle<Option<SocketAddr>> {
+        thread::spawn(move || {
+            let mapped = socket.local_addr().unwrap();
+            let timeout = Duration::from_secs(5);
+            let peer = rendezvous(&socket, server, "test", mapped, timeout).unwrap();
+            punch(&socket, "test", peer, Duration::from_secs(2)).unwrap()
+        })
+    }
+
+    /// Meet the peer from behind `nat` with the address `stun` sees, like
+    /// a peer that doesn't know its NAT would
+    fn peer_behind(
+        nat: &Nat,
+        (stun, server): (SocketAddr, SocketAddr),
+    ) -> thread::JoinHandle<Option<SocketAddr>> {
+        let socket = nat.socket();
+        thread::spawn(move || {
+            let (_, mapped) = reflexive(&socket, stun).unwrap();
+            let timeout = Duration::from_secs(5);
+            let peer = rendezvous(&socket, server, "test", mapped, timeout).unwrap();
+            punch(&socket, "test", peer, Duration::from_secs(2)).unwrap()
+        })
+    }
+
+    fn loopback(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, last))
+    }
+
+    #[test]
+    fn test_punch_through_port_restricted_cones() {
+        let server = start_server();
+        let a_nat = Nat::new(loopback(30), NatConfig::port_restricted_cone());
+        let b_nat = Nat::new(loopback(31), NatConfig::port_restricted_cone());
+        let a = peer_behind(&a_nat, server);
+        let b = peer_behind(&b_nat, server);
+        // Each gets through to the mapping the other's NAT kept for it
+        let a_direct = a.join().unwrap().expect("no direct path from a");
+        let b_direct = b.join().unwrap().expect("no direct path from b");
+        assert_eq!(a_direct.ip(), b_nat.public_ip());
+        assert_eq!(b_direct.ip(), a_nat.public_ip());
+    }
+
+    #[test]
+    fn test_punch_between_symmetric_nats_falls_back() {
+        let server = start_server();
+        let a_nat = Nat::new(loopback(32), NatConfig::symmetric());
+        let b_nat = Nat::new(loopback(33), NatConfig::symmetric());
+        let a = peer_behind(&a_nat, server);
+        let b = peer_behind(&b_nat, server);
+        // The mappings the server saw are closed to the peer, so a relay
+        // is the only way
+        assert_eq!(a.join().unwrap(), None);
+        assert_eq!(b.join().unwrap(), None);
+    }
+
+    #[test]
+    fn test_peers_meet_and_punch() {
+        let server = start_rendezvous();
+        let (a, b) = (bind(), bind());
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let a = peer(server, a);
+        let b = peer(server, b);
+        assert_eq!(a.join().unwrap(), Some(b_addr));
+        assert_eq!(b.join().unwrap(), Some(a_addr));
+    }
+
+    #[test]
+    fn test_punch_gives_up_on_silent_peer() {
+        let socket = bind();
+        let silent = bind();
+        let peer = silent.local_addr().unwrap();
+        let direct = punch(&socket, "test", peer, Duration::from_millis(500)).unwrap();
+        assert_eq!(direct, None);
+    }
+}
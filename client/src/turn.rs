@@ -0,0 +1,466 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use message::{
+    attribute::{
+        AlternateServer, Attribute, ChannelNumber, Data, ErrorCode, Lifetime, MessageIntegrity,
+        Nonce, Realm, RequestedTransport, Username, Value, XorMappedAddress, XorPeerAddress,
+        XorRelayedAddress,
+    },
+    header::{Class, Header, HeaderType},
+    packet::Packet,
+    Message,
+};
+
+use crate::client::{error_response, Credential, Redirect, MAX_REDIRECTS};
+
+/// Initial retransmission timeout, doubled after every attempt
+const RTO: Duration = Duration::from_millis(500);
+const MAX_TRANSMISSIONS: u32 = 4;
+
+/// Result of a successful Allocate request
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    /// Address peers send to, to reach this client
+    pub relayed: SocketAddr,
+    /// This client's address as seen by the server
+    pub mapped: Option<SocketAddr>,
+    pub lifetime: u32,
+}
+
+/// Realm and nonce the server challenged us with, and the key they give
+struct Auth {
+    realm: String,
+    nonce: String,
+    key: [u8; 16],
+}
+
+/// A TURN client (RFC 8656) holding one allocation on a server over UDP
+pub struct TurnClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    credential: Credential,
+    auth: Option<Auth>,
+    read_timeout: Option<Duration>,
+    channels: HashMap<SocketAddr, u16>,
+    /// Data that arrived from peers while waiting for a response
+    pending: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+impl TurnClient {
+    pub fn new(server: SocketAddr, credential: Credential) -> io::Result<Self> {
+        Ok(Self {
+            socket: bind(server)?,
+            server,
+            credential,
+            auth: None,
+            read_timeout: None,
+            channels: HashMap::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The server holding the allocation, which redirects can change
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Timeout for `recv_from`, requests use their own retransmission timer
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Allocate a relay, following 300 Try Alternate to other servers but
+    /// never back to one already tried
+    pub fn allocate(&mut self) -> io::Result<Allocation> {
+        let mut visited = vec![self.server];
+        let response = loop {
+            let err = match self.request(HeaderType::AllocateRequest, |_| {
+                let transport = RequestedTransport::new(RequestedTransport::UDP);
+                vec![Value::RequestedTransport(transport).into_attribute()]
+            }) {
+                Ok(response) => break response,
+                Err(err) => err,
+            };
+            match Redirect::of(&err) {
+                Some(redirect)
+                    if !visited.contains(&redirect.server) && visited.len() <= MAX_REDIRECTS =>
+                {
+                    if redirect.server.is_ipv4() != self.server.is_ipv4() {
+                        self.socket = bind(redirect.server)?;
+                    }
+                    self.server = redirect.server;
+                    // The new server challenges us with its own realm and nonce
+                    self.auth = None;
+                    visited.push(self.server);
+                }
+                _ => return Err(err),
+            }
+        };
+
+        let tx_id = &response.header.transaction_id;
+        let relayed = response
+            .get::<XorRelayedAddress>()
+            .map(|value| value.addr(tx_id));
+        let mapped = response
+            .get::<XorMappedAddress>()
+            .map(|value| value.addr(tx_id));
+        let lifetime = response.get::<Lifetime>().map_or(0, |value| value.lifetime);
+        let relayed = relayed.ok_or_else(|| invalid_data("response has no relayed address"))?;
+        Ok(Allocation {
+            relayed,
+            mapped,
+            lifetime,
+        })
+    }
+
+    /// Extend the allocation by `lifetime` seconds, or delete it with 0.
+    /// Returns the lifetime the server granted.
+    pub fn refresh(&mut self, lifetime: u32) -> io::Result<u32> {
+        let response = self.request(HeaderType::RefreshRequest, |_| {
+            vec![Value::Lifetime(Lifetime::new(lifetime)).into_attribute()]
+        })?;
+        Ok(response
+            .get::<Lifetime>()
+            .map_or(lifetime, |value| value.lifetime))
+    }
+
+    /// Allow `peer` to send to the relayed address, permissions last five
+    /// minutes and are refreshed by calling this again
+    pub fn create_permission(&mut self, peer: SocketAddr) -> io::Result<()> {
+        self.request(HeaderType::CreatePermissionRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(peer, tx_id)).into_attribute()]
+        })?;
+        Ok(())
+    }
+
+    /// Bind a channel to `peer`, or refresh the existing binding. Data to
+    /// and from the peer then goes in ChannelData frames.
+    pub fn bind_channel(&mut self, peer: SocketAddr) -> io::Result<u16> {
+        let channel = match self.channels.get(&peer) {
+            Some(channel) => *channel,
+            None => 0x4000 + self.channels.len() as u16,
+        };
+        self.request(HeaderType::ChannelBindRequest, |tx_id| {
+            vec![
+                Value::ChannelNumber(ChannelNumber::new(channel)).into_attribute(),
+                Value::XorPeerAddress(XorPeerAddress::new(peer, tx_id)).into_attribute(),
+            ]
+        })?;
+        self.channels.insert(peer, channel);
+        Ok(channel)
+    }
+
+    /// Send `data` to `peer` through the relay
+    pub fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<()> {
+        self.sender()?.send_to(data, peer)
+    }
+
+    /// A handle that can send through the relay from another thread, with
+    /// the channels bound so far
+    pub fn sender(&self) -> io::Result<TurnSender> {
+        Ok(TurnSender {
+            socket: self.socket.try_clone()?,
+            server: self.server,
+            channels: self.channels.clone(),
+        })
+    }
+
+    /// Receive data a peer sent to the relayed address
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if let Some((peer, data)) = self.pending.pop_front() {
+            return Ok((copy(&data, buf), peer));
+        }
+
+        self.socket.set_read_timeout(self.read_timeout)?;
+        let mut packet = [0; 2048];
+        loop {
+            let (amt, from) = self.socket.recv_from(&mut packet)?;
+            if from != self.server {
+                continue;
+            }
+            if let Some((peer, data)) = self.peer_data(&packet[..amt]) {
+                return Ok((copy(&data, buf), peer));
+            }
+        }
+    }
+
+    /// Peer address and payload of a Data indication or ChannelData frame
+    fn peer_data(&self, data: &[u8]) -> Option<(SocketAddr, Vec<u8>)> {
+        match Packet::decode(data).ok()? {
+            Packet::ChannelData { channel, payload } => {
+                let peer = self
+                    .channels
+                    .iter()
+                    .find_map(|(peer, bound)| (*bound == channel).then_some(*peer))?;
+                Some((peer, payload.to_vec()))
+            }
+            Packet::Stun(message) if message.header.header_type == HeaderType::DataIndication => {
+                let tx_id = message.header.transaction_id;
+                let peer = message.get::<XorPeerAddress>()?.addr(&tx_id);
+                let payload = message.get::<Data>()?.data.clone();
+                Some((peer, payload))
+            }
+            Packet::Stun(_) => None,
+        }
+    }
+
+    /// Send an authenticated request, answering the server's challenge if
+    /// needed. `attributes` gets the transaction id to XOR addresses with.
+    fn request(
+        &mut self,
+        header_type: HeaderType,
+        attributes: impl Fn(&[u8; 16]) -> Vec<Attribute>,
+    ) -> io::Result<Message> {
+        // The first attempt may be challenged, or find its nonce stale
+        for _ in 0..3 {
+            let header = Header::with_random_id(header_type);
+            let tx_id = header.transaction_id;
+            let mut message = Message::new(header, attributes(&tx_id));
+            if let Some(auth) = &self.auth {
+                message.attributes.extend([
+                    Value::Username(Username::new(self.credential.0.clone())).into_attribute(),
+                    Value::Realm(Realm::new(auth.realm.clone())).into_attribute(),
+                    Value::Nonce(Nonce::new(auth.nonce.clone())).into_attribute(),
+                ]);
+                message.add_integrity(&auth.key);
+            }
+
+            let (response, data) = self.transact(&message)?;
+            if response.header.header_type.class() == Class::SuccessResponse {
+                if let Some(auth) = &self.auth {
+                    if !Message::verify_integrity(&data, &auth.key) {
+                        return Err(invalid_data("response failed integrity check"));
+                    }
+                }
+                return Ok(response);
+            }
+            if response.get::<AlternateServer>().is_some() {
+                // Only a server that knows our key gets to send us elsewhere
+                if let Some(auth) = &self.auth {
+                    if !Message::verify_integrity(&data, &auth.key) {
+                        return Err(invalid_data("redirect failed integrity check"));
+                    }
+                }
+                return Err(error_response(response));
+            }
+
+            let realm = response.get::<Realm>().map(|value| value.realm.clone());
+            let nonce = response.get::<Nonce>().map(|value| value.nonce.clone());
+            let error = response.get::<ErrorCode>();
+            let error = error.ok_or_else(|| invalid_data("error response has no error code"))?;
+            let challenged = error.code == 401 && self.auth.is_none() || error.code == 438;
+            match (challenged, realm, nonce) {
+                (true, Some(realm), Some(nonce)) => {
+                    let (username, password) = (&self.credential.0, &self.credential.1);
+                    let key = MessageIntegrity::long_term_key(username, &realm, password);
+                    self.auth = Some(Auth { realm, nonce, key });
+                }
+                _ => return Err(io::Error::other(format!("{} {}", error.code, error.reason))),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "server kept rejecting our credentials",
+        ))
+    }
+
+    /// Send `message` until its response arrives, queueing any peer data
+    /// that shows up in the meantime
+    fn transact(&mut self, message: &Message) -> io::Result<(Message, Vec<u8>)> {
+        let tx_id = message.header.transaction_id;
+        let data = message.encode();
+        let mut rto = RTO;
+        let mut buf = [0; 2048];
+        for _ in 0..MAX_TRANSMISSIONS {
+            self.socket.send_to(&data, self.server)?;
+            let deadline = Instant::now() + rto;
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                self.socket
+                    .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+                let (amt, from) = match self.socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(err) if is_timeout(&err) => break,
+                    Err(err) => return Err(err),
+                };
+                if from != self.server {
+                    continue;
+                }
+                if let Some(received) = self.peer_data(&buf[..amt]) {
+                    self.pending.push_back(received);
+                    continue;
+                }
+                match Message::decode(&buf[..amt]) {
+                    Ok(response) if response.header.transaction_id == tx_id => {
+                        return Ok((response, buf[..amt].to_vec()))
+                    }
+                    _ => continue,
+                }
+            }
+            rto *= 2;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no response from TURN server",
+        ))
+    }
+}
+
+/// Sending half of a `TurnClient`
+pub struct TurnSender {
+    socket: UdpSocket,
+    server: SocketAddr,
+    channels: HashMap<SocketAddr, u16>,
+}
+
+impl TurnSender {
+    /// Send `data` to `peer`, over its channel if one is bound and in a
+    /// Send indication otherwise
+    pub fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<()> {
+        let packet = match self.channels.get(&peer) {
+            Some(channel) => Packet::ChannelData {
+                channel: *channel,
+                payload: data,
+            }
+            .encode(),
+            None => {
+                let header = Header::with_random_id(HeaderType::SendIndication);
+                let peer = XorPeerAddress::new(peer, &header.transaction_id);
+                let attributes = vec![
+                    Value::XorPeerAddress(peer).into_attribute(),
+                    Value::Data(Data::new(data.to_vec())).into_attribute(),
+                ];
+                Message::new(header, attributes).encode()
+            }
+        };
+        self.socket.send_to(&packet, self.server)?;
+        Ok(())
+    }
+}
+
+/// A socket to talk to `server` from, in its address family
+fn bind(server: SocketAddr) -> io::Result<UdpSocket> {
+    let local = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    UdpSocket::bind(local)
+}
+
+fn copy(data: &[u8], buf: &mut [u8]) -> usize {
+    let amt = data.len().min(buf.len());
+    buf[..amt].copy_from_slice(&data[..amt]);
+    amt
+}
+
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::{
+        redirect::{Alternate, Redirect},
+        server::Server,
+    };
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn start_server() -> SocketAddr {
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.add_user("user".into(), "pass".into());
+        server.spawn();
+        server.local_addrs()[0]
+    }
+
+    fn bind() -> UdpSocket {
+        let socket = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn test_turn_client_relays_through_server() {
+        let server = start_server();
+        let mut turn = TurnClient::new(server, Credential("user".into(), "pass".into())).unwrap();
+        turn.set_read_timeout(Some(Duration::from_millis(500)));
+        let allocation = turn.allocate().unwrap();
+        assert_eq!(allocation.lifetime, 600);
+        assert_eq!(
+            allocation.mapped.unwrap().port(),
+            turn.local_addr().unwrap().port()
+        );
+
+        let peer = bind();
+        let peer_addr = peer.local_addr().unwrap();
+        let mut buf = [0; 64];
+
+        // Send and Data indications
+        turn.create_permission(peer_addr).unwrap();
+        turn.send_to(b"hello", peer_addr).unwrap();
+        let (amt, from) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..amt], from), (&b"hello"[..], allocation.relayed));
+
+        peer.send_to(b"world", allocation.relayed).unwrap();
+        let (amt, from) = turn.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..amt], from), (&b"world"[..], peer_addr));
+
+        // ChannelData, with data arriving while a request is in flight
+        assert_eq!(turn.bind_channel(peer_addr).unwrap(), 0x4000);
+        turn.sender().unwrap().send_to(b"ping", peer_addr).unwrap();
+        let (amt, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], b"ping");
+
+        peer.send_to(b"pong", allocation.relayed).unwrap();
+        assert_eq!(turn.refresh(1200).unwrap(), 1200);
+        let (amt, from) = turn.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..amt], from), (&b"pong"[..], peer_addr));
+
+        assert_eq!(turn.refresh(0).unwrap(), 0);
+        assert!(turn.create_permission(peer_addr).is_err());
+    }
+
+    #[test]
+    fn test_turn_client_wrong_password() {
+        let server = start_server();
+        let mut turn = TurnClient::new(server, Credential("user".into(), "nope".into())).unwrap();
+        let err = turn.allocate().unwrap_err();
+        assert!(err.to_string().starts_with("401"), "{}", err);
+    }
+
+    #[test]
+    fn test_turn_client_follows_redirect() {
+        let target = start_server();
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.add_user("user".into(), "pass".into());
+        server.set_redirect(Redirect::Overload {
+            max_allocations: 0,
+            alternate: Alternate::new(target),
+        });
+        server.spawn();
+
+        let credential = Credential("user".into(), "pass".into());
+        let mut turn = TurnClient::new(server.local_addrs()[0], credential).unwrap();
+        turn.allocate().unwrap();
+        assert_eq!(turn.server(), target);
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+use crate::{
+    connection::{TlsConfig, Transport, TLS_PORT},
+    dns::{self, Resolver},
+};
+
+/// Port for STUN and TURN over UDP and TCP, RFC 8489 section 18.7
+pub const DEFAULT_PORT: u16 = 3478;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Stun,
+    Stuns,
+    Turn,
+    Turns,
+}
+
+impl Scheme {
+    pub fn is_secure(self) -> bool {
+        matches!(self, Scheme::Stuns | Scheme::Turns)
+    }
+
+    pub fn is_turn(self) -> bool {
+        matches!(self, Scheme::Turn | Scheme::Turns)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Stun => "stun",
+            Scheme::Stuns => "stuns",
+            Scheme::Turn => "turn",
+            Scheme::Turns => "turns",
+        }
+    }
+}
+
+/// Transport requested with the `transport` parameter of a TURN URI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// A `stun:` or `stuns:` URI from RFC 7064, or a `turn:` or `turns:` URI
+/// from RFC 7065:
+///
+/// ```text
+/// stun:stun.example.org
+/// stuns:stun.example.org:5349
+/// turn:192.0.2.1?transport=tcp
+/// turns:[2001:db8::1]?transport=udp
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StunUri {
+    pub scheme: Scheme,
+    /// Domain name or IP address, without the brackets around IPv6
+    pub host: String,
+    pub port: Option<u16>,
+    /// Only TURN URIs have one
+    pub protocol: Option<Protocol>,
+}
+
+impl StunUri {
+    /// The explicit port, or the default for the scheme
+    pub fn port(&self) -> u16 {
+        let default = if self.scheme.is_secure() {
+            TLS_PORT
+        } else {
+            DEFAULT_PORT
+        };
+        self.port.unwrap_or(default)
+    }
+
+    /// How to reach the server. Secure schemes run over TLS, or over DTLS
+    /// when a TURN URI asks for UDP (RFC 7350), and check the certificate
+    /// against the host.
+    pub fn transport(&self) -> Transport {
+        let tls = || TlsConfig::new(self.host.clone());
+        match (self.scheme.is_secure(), self.protocol) {
+            (false, Some(Protocol::Tcp)) => Transport::Tcp,
+            (false, _) => Transport::Udp,
+            (true, Some(Protocol::Udp)) => Transport::Dtls(tls()),
+            (true, _) => Transport::Tls(tls()),
+        }
+    }
+
+    /// Look the host up, IP addresses resolve to themselves
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok((self.host.as_str(), self.port())
+            .to_socket_addrs()?
+            .collect())
+    }
+
+    /// Find the servers behind the URI, in the order they're to be tried.
+    /// A domain without a port is looked up through SRV records first,
+    /// falling back to its A and AAAA records on the default port.
+    pub fn discover(&self, resolver: &dyn Resolver) -> io::Result<Vec<SocketAddr>> {
+        if self.port.is_some() || self.host.parse::<IpAddr>().is_ok() {
+            return resolver.lookup(&self.host, self.port());
+        }
+        let records = resolver.srv(&self.srv_name())?;
+        if records.is_empty() {
+            return resolver.lookup(&self.host, self.port());
+        }
+
+        let mut addrs = Vec::new();
+        let mut failure = None;
+        // A target of "." says the service isn't offered at all
+        for srv in dns::order(records)
+            .into_iter()
+            .filter(|srv| srv.target != ".")
+        {
+            match resolver.lookup(&srv.target, srv.port) {
+                Ok(found) => addrs.extend(found),
+                Err(err) => failure = Some(err),
+            }
+        }
+        match failure {
+            Some(err) if addrs.is_empty() => Err(err),
+            None if addrs.is_empty() => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no servers for {}", self),
+            )),
+            _ => Ok(addrs),
+        }
+    }
+
+    /// Name of the SRV records for the URI, like `_stuns._tcp.example.org`
+    /// for `stuns:example.org`, RFC 7064 and RFC 7065
+    pub fn srv_name(&self) -> String {
+        let protocol = match self.transport() {
+            Transport::Udp | Transport::Dtls(_) => "udp",
+            Transport::Tcp | Transport::Tls(_) => "tcp",
+        };
+        format!("_{}._{}.{}", self.scheme.as_str(), protocol, self.host)
+    }
+}
+
+impl FromStr for StunUri {
+    type Err = UriError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = uri.split_once(':').ok_or(UriError::MissingScheme)?;
+        let scheme = match scheme.to_ascii_lowercase().as_str() {
+            "stun" => Scheme::Stun,
+            "stuns" => Scheme::Stuns,
+            "turn" => Scheme::Turn,
+            "turns" => Scheme::Turns,
+            _ => return Err(UriError::UnknownScheme(scheme.into())),
+        };
+        // These URIs have no authority component, so no "//" or userinfo
+        if rest.starts_with("//") || rest.contains('@') {
+            return Err(UriError::InvalidHost);
+        }
+
+        let (authority, query) = match rest.split_once('?') {
+            Some((authority, query)) => (authority, Some(query)),
+            None => (rest, None),
+        };
+        let protocol = match query {
+            None => None,
+            Some(_) if !scheme.is_turn() => return Err(UriError::UnexpectedQuery),
+            Some(query) => {
+                let (key, value) = query.split_once('=').ok_or(UriError::UnexpectedQuery)?;
+                if !key.eq_ignore_ascii_case("transport") {
+                    return Err(UriError::UnexpectedQuery);
+                }
+                match value.to_ascii_lowercase().as_str() {
+                    "udp" => Some(Protocol::Udp),
+                    "tcp" => Some(Protocol::Tcp),
+                    _ => return Err(UriError::UnsupportedTransport(value.into())),
+                }
+            }
+        };
+
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(literal) => {
+                let (ip, port) = literal.split_once(']').ok_or(UriError::InvalidHost)?;
+                ip.parse::<std::net::Ipv6Addr>()
+                    .map_err(|_| UriError::InvalidHost)?;
+                (ip.to_string(), port)
+            }
+            None => {
+                let end = authority.find(':').unwrap_or(authority.len());
+                (reg_name(&authority[..end])?, &authority[end..])
+            }
+        };
+        let port = match port {
+            "" => None,
+            port => {
+                let digits = port.strip_prefix(':').ok_or(UriError::InvalidHost)?;
+                Some(digits.parse().map_err(|_| UriError::InvalidPort)?)
+            }
+        };
+
+        Ok(Self {
+            scheme,
+            host,
+            port,
+            protocol,
+        })
+    }
+}
+
+/// An IPv4 address or a registered name, percent-decoded, RFC 3986
+/// section 3.2.2
+fn reg_name(host: &str) -> Result<String, UriError> {
+    let mut decoded = Vec::with_capacity(host.len());
+    let mut bytes = host.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+                let [Some(high), Some(low)] = hex else {
+                    return Err(UriError::InvalidHost);
+                };
+                let hex = std::str::from_utf8(&[high, low])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(UriError::InvalidHost)?;
+                decoded.push(hex);
+            }
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' => decoded.push(byte),
+            byte if byte.is_ascii_alphanumeric() => decoded.push(byte),
+            _ => return Err(UriError::InvalidHost),
+        }
+    }
+    let host = String::from_utf8(decoded).map_err(|_| UriError::InvalidHost)?;
+    if host.is_empty() {
+        return Err(UriError::InvalidHost);
+    }
+    Ok(host)
+}
+
+impl fmt::Display for StunUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = self.scheme.as_str();
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => write!(f, "{}:[{}]", scheme, ip)?,
+            _ => write!(f, "{}:{}", scheme, self.host)?,
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        match self.protocol {
+            Some(Protocol::Udp) => write!(f, "?transport=udp"),
+            Some(Protocol::Tcp) => write!(f, "?transport=tcp"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriError {
+    MissingScheme,
+    UnknownScheme(String),
+    InvalidHost,
+    InvalidPort,
+    /// A query other than `transport` on a TURN URI, or any on a STUN one
+    UnexpectedQuery,
+    UnsupportedTransport(String),
+}
+
+impl fmt::Display for UriError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UriError::MissingScheme => write!(f, "missing scheme"),
+            UriError::UnknownScheme(scheme) => write!(f, "unknown scheme {:?}", scheme),
+            UriError::InvalidHost => write!(f, "invalid host"),
+            UriError::InvalidPort => write!(f, "invalid port"),
+            UriError::UnexpectedQuery => write!(f, "unexpected query"),
+            UriError::UnsupportedTransport(transport) => {
+                write!(f, "unsupported transport {:?}", transport)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UriError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{tests::srv, tests::stub_dns, DnsResolver, Record};
+    use std::net::Ipv4Addr;
+
+    fn parse(uri: &str) -> StunUri {
+        uri.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_stun_uris() {
+        let uri = parse("stun:example.org");
+        assert_eq!(uri.scheme, Scheme::Stun);
+        assert_eq!(uri.host, "example.org");
+        assert_eq!((uri.port, uri.port()), (None, 3478));
+        assert!(matches!(uri.transport(), Transport::Udp));
+
+        let uri = parse("STUNS:example.org:8000");
+        assert_eq!(uri.scheme, Scheme::Stuns);
+        assert_eq!(uri.port(), 8000);
+        match uri.transport() {
+            Transport::Tls(config) => assert_eq!(config.server_name, "example.org"),
+            transport => panic!("stuns: isn't TLS: {:?}", transport),
+        }
+
+        assert_eq!(parse("stuns:example.org").port(), 5349);
+        assert_eq!(parse("stun:192.0.2.1:3479").host, "192.0.2.1");
+        assert_eq!(parse("stun:[2001:db8::1]:3478").host, "2001:db8::1");
+        assert_eq!(parse("stun:ex%61mple.org").host, "example.org");
+    }
+
+    #[test]
+    fn test_parse_turn_uris() {
+        let uri = parse("turn:example.org?transport=udp");
+        assert_eq!(uri.scheme, Scheme::Turn);
+        assert_eq!(uri.protocol, Some(Protocol::Udp));
+        assert!(matches!(uri.transport(), Transport::Udp));
+
+        assert!(matches!(
+            parse("turn:example.org?transport=tcp").transport(),
+            Transport::Tcp
+        ));
+        assert!(matches!(
+            parse("turns:example.org").transport(),
+            Transport::Tls(_)
+        ));
+        assert!(matches!(
+            parse("turns:[::1]:443?transport=udp").transport(),
+            Transport::Dtls(_)
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_uris() {
+        let error = |uri: &str| uri.parse::<StunUri>().unwrap_err();
+        assert_eq!(error("example.org"), UriError::MissingScheme);
+        assert_eq!(
+            error("http:example.org"),
+            UriError::UnknownScheme("http".into())
+        );
+        assert_eq!(error("stun://example.org"), UriError::InvalidHost);
+        assert_eq!(error("stun:user@example.org"), UriError::InvalidHost);
+        assert_eq!(error("stun:"), UriError::InvalidHost);
+        assert_eq!(error("stun:[::1"), UriError::InvalidHost);
+        assert_eq!(error("stun:example.org:99999"), UriError::InvalidPort);
+        assert_eq!(error("stun:example.org:"), UriError::InvalidPort);
+        assert_eq!(
+            error("stun:example.org?transport=udp"),
+            UriError::UnexpectedQuery
+        );
+        assert_eq!(
+            error("turn:example.org?transport=sctp"),
+            UriError::UnsupportedTransport("sctp".into())
+        );
+    }
+
+    #[test]
+    fn test_uri_display_round_trip() {
+        for uri in [
+            "stun:example.org",
+            "stuns:example.org:5349",
+            "turn:[2001:db8::1]:3478?transport=tcp",
+            "turns:192.0.2.1?transport=udp",
+        ] {
+            assert_eq!(parse(uri).to_string(), uri);
+        }
+    }
+
+    #[test]
+    fn test_resolve_ip_literal() {
+        assert_eq!(
+            parse("stun:127.0.0.1").resolve().unwrap(),
+            vec!["127.0.0.1:3478".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_srv_names() {
+        assert_eq!(
+            parse("stun:example.org").srv_name(),
+            "_stun._udp.example.org"
+        );
+        assert_eq!(
+            parse("stuns:example.org").srv_name(),
+            "_stuns._tcp.example.org"
+        );
+        assert_eq!(
+            parse("turn:example.org?transport=tcp").srv_name(),
+            "_turn._tcp.example.org"
+        );
+        assert_eq!(
+            parse("turns:example.org?transport=udp").srv_name(),
+            "_turns._udp.example.org"
+        );
+    }
+
+    #[test]
+    fn test_discover() {
+        let server = stub_dns(vec![
+            ("_stun._udp.example.test", srv(1, 0, 3478, "a.example.test")),
+            ("_stun._udp.example.test", srv(2, 0, 3479, "b.example.test")),
+            ("a.example.test", Record::A(Ipv4Addr::new(192, 0, 2, 1))),
+            ("b.example.test", Record::A(Ipv4Addr::new(192, 0, 2, 2))),
+            ("_stuns._tcp.none.test", srv(0, 0, 0, ".")),
+            ("other.test", Record::A(Ipv4Addr::new(192, 0, 2, 3))),
+        ]);
+        let resolver = DnsResolver::new(server);
+        let discover = |uri: &str| parse(uri).discover(&resolver);
+
+        assert_eq!(
+            discover("stun:example.test").unwrap(),
+            vec![
+                "192.0.2.1:3478".parse().unwrap(),
+                "192.0.2.2:3479".parse().unwrap()
+            ]
+        );
+        // Without SRV records, or with a port, only addresses are looked up
+        assert_eq!(
+            discover("stun:other.test").unwrap(),
+            vec!["192.0.2.3:3478".parse().unwrap()]
+        );
+        assert_eq!(
+            discover("stun:a.example.test:4000").unwrap(),
+            vec!["192.0.2.1:4000".parse().unwrap()]
+        );
+        assert_eq!(
+            discover("stuns:none.test").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,35 +1,111 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-#[derive(Debug)]
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use bytes::{buf::UninitSlice, BufMut};
+
+use crate::{error::Error, header::Header};
+
+/// Attribute values are padded to a multiple of four bytes on the wire
+pub(crate) const fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// The first `N` bytes of a value, which is malformed if it's any shorter
+fn fixed<const N: usize>(data: &[u8], attr_type: AttrType) -> Result<[u8; N], Error> {
+    data.get(..N)
+        .and_then(|data| data.try_into().ok())
+        .ok_or(Error::InvalidValue(attr_type as u16))
+}
+
+/// A value that has to be UTF-8
+fn text(data: &[u8], attr_type: AttrType) -> Result<String, Error> {
+    String::from_utf8(data.to_vec()).map_err(|_| Error::InvalidValue(attr_type as u16))
+}
+
+/// Counts what's written to it without keeping any of it, for lengths that
+/// have to be known before encoding
+#[derive(Default)]
+struct Counter {
+    len: usize,
+    scratch: [u8; 32],
+}
+
+unsafe impl BufMut for Counter {
+    fn remaining_mut(&self) -> usize {
+        usize::MAX - self.len
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.len += cnt;
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        UninitSlice::new(&mut self.scratch)
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        self.len += src.len();
+    }
+
+    fn put_bytes(&mut self, _val: u8, cnt: usize) {
+        self.len += cnt;
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Attribute {
-    pub attr_type: AttrType,
+    /// The type on the wire, one of `AttrType` unless the value is raw
+    pub attr_type: u16,
     pub value: Value,
 }
 
 impl Attribute {
     pub const fn new(attr_type: AttrType, value: Value) -> Self {
-        Self { attr_type, value }
+        Self {
+            attr_type: attr_type as u16,
+            value,
+        }
     }
 
-    /// Decode an attribute from a byte slice
-    /// Returns the attribute and the number of bytes consumed
-    pub fn decode(data: &[u8]) -> (Self, usize) {
-        let attr_type = AttrType::from_be_bytes([data[0], data[1]]);
-        let length = u16::from_be_bytes([data[2], data[3]]);
-        let data = &data[4..(4 + length as usize)];
-        let value = Value::decode(attr_type, data);
-        (Attribute { attr_type, value }, 4 + length as usize)
+    /// Decode an attribute from a byte slice, types this crate doesn't know
+    /// are kept raw. Returns the attribute and the number of bytes
+    /// consumed, padding included.
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), Error> {
+        if data.len() < 4 {
+            return Err(Error::Truncated);
+        }
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let value = data.get(4..(4 + length)).ok_or(Error::Truncated)?;
+        let attr_type = u16::from_be_bytes([data[0], data[1]]);
+        let value = match AttrType::from_u16(attr_type) {
+            Ok(known) => Value::decode(known, value)?,
+            Err(_) => Value::Raw(RawAttribute::new(attr_type, value.to_vec())),
+        };
+        Ok((Attribute { attr_type, value }, 4 + padded(length)))
     }
 
     pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.extend_from_slice((self.attr_type as u16).to_be_bytes().as_ref());
-
-        let value_bytes = self.value.encode();
-        buf.extend_from_slice(&(value_bytes.len() as u16).to_be_bytes());
-        buf.extend_from_slice(&value_bytes);
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf);
         buf
     }
+
+    /// Write the encoded attribute to `buf`, padding included
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        let length = self.value.encoded_len();
+        buf.put_u16(self.attr_type);
+        buf.put_u16(length as u16);
+        self.value.encode_to(buf);
+        buf.put_bytes(0, padded(length) - length);
+    }
+
+    /// Length on the wire, padding included
+    pub fn encoded_len(&self) -> usize {
+        4 + padded(self.value.encoded_len())
+    }
 }
 
 #[repr(u16)]
@@ -46,15 +122,40 @@ pub enum AttrType {
     ErrorCode = 0x0009,
     UnknownAttributes = 0x000A,
     ReflectedFrom = 0x000B,
+    ChannelNumber = 0x000C,
+    Lifetime = 0x000D,
+    XorPeerAddress = 0x0012,
+    Data = 0x0013,
+    Realm = 0x0014,
+    Nonce = 0x0015,
+    XorRelayedAddress = 0x0016,
+    RequestedTransport = 0x0019,
+    XorMappedAddress = 0x0020,
+    RequestedAddressFamily = 0x0017,
+    EvenPort = 0x0018,
+    DontFragment = 0x001A,
+    ReservationToken = 0x0022,
+    AdditionalAddressFamily = 0x8000,
+    AddressErrorCode = 0x8001,
+    Icmp = 0x8004,
+    ConnectionId = 0x002A,
+    AlternateServer = 0x8023,
+    AlternateDomain = 0x8003,
+    Software = 0x8022,
+    Priority = 0x0024,
+    UseCandidate = 0x0025,
+    Fingerprint = 0x8028,
+    IceControlled = 0x8029,
+    IceControlling = 0x802A,
 }
 
 impl AttrType {
-    pub const fn from_be_bytes(bytes: [u8; 2]) -> AttrType {
+    pub const fn from_be_bytes(bytes: [u8; 2]) -> Result<AttrType, Error> {
         AttrType::from_u16(u16::from_be_bytes(bytes))
     }
 
-    pub const fn from_u16(value: u16) -> AttrType {
-        match value {
+    pub const fn from_u16(value: u16) -> Result<AttrType, Error> {
+        let attr_type = match value {
             0x0001 => AttrType::MappedAddress,
             0x0002 => AttrType::ResponseAddress,
             0x0003 => AttrType::ChangeRequest,
@@ -66,12 +167,38 @@ impl AttrType {
             0x0009 => AttrType::ErrorCode,
             0x000A => AttrType::UnknownAttributes,
             0x000B => AttrType::ReflectedFrom,
-            _ => panic!("Unknown attribute type"),
-        }
+            0x000C => AttrType::ChannelNumber,
+            0x000D => AttrType::Lifetime,
+            0x0012 => AttrType::XorPeerAddress,
+            0x0013 => AttrType::Data,
+            0x0014 => AttrType::Realm,
+            0x0015 => AttrType::Nonce,
+            0x0016 => AttrType::XorRelayedAddress,
+            0x0019 => AttrType::RequestedTransport,
+            0x0020 => AttrType::XorMappedAddress,
+            0x0017 => AttrType::RequestedAddressFamily,
+            0x0018 => AttrType::EvenPort,
+            0x001A => AttrType::DontFragment,
+            0x0022 => AttrType::ReservationToken,
+            0x8000 => AttrType::AdditionalAddressFamily,
+            0x8001 => AttrType::AddressErrorCode,
+            0x8004 => AttrType::Icmp,
+            0x002A => AttrType::ConnectionId,
+            0x8023 => AttrType::AlternateServer,
+            0x8003 => AttrType::AlternateDomain,
+            0x8022 => AttrType::Software,
+            0x0024 => AttrType::Priority,
+            0x0025 => AttrType::UseCandidate,
+            0x8028 => AttrType::Fingerprint,
+            0x8029 => AttrType::IceControlled,
+            0x802A => AttrType::IceControlling,
+            _ => return Err(Error::UnknownAttribute(value)),
+        };
+        Ok(attr_type)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Value {
     MappedAddress(MappedAddress),
     ResponseAddress(ResponseAddress),
@@ -84,40 +211,140 @@ pub enum Value {
     ErrorCode(ErrorCode),
     UnknownAttributes(UnknownAttributes),
     ReflectedFrom(ReflectedFrom),
+    ChannelNumber(ChannelNumber),
+    Lifetime(Lifetime),
+    XorPeerAddress(XorPeerAddress),
+    Data(Data),
+    Realm(Realm),
+    Nonce(Nonce),
+    XorRelayedAddress(XorRelayedAddress),
+    RequestedTransport(RequestedTransport),
+    XorMappedAddress(XorMappedAddress),
+    RequestedAddressFamily(RequestedAddressFamily),
+    EvenPort(EvenPort),
+    DontFragment(DontFragment),
+    ReservationToken(ReservationToken),
+    AdditionalAddressFamily(AdditionalAddressFamily),
+    AddressErrorCode(AddressErrorCode),
+    Icmp(Icmp),
+    ConnectionId(ConnectionId),
+    AlternateServer(AlternateServer),
+    AlternateDomain(AlternateDomain),
+    Software(Software),
+    Priority(Priority),
+    UseCandidate(UseCandidate),
+    Fingerprint(Fingerprint),
+    IceControlled(IceControlled),
+    IceControlling(IceControlling),
+    /// An attribute this crate doesn't know, as it was on the wire
+    Raw(RawAttribute),
 }
 
 impl Value {
-    pub fn decode(attr_type: AttrType, data: &[u8]) -> Value {
-        match attr_type {
-            AttrType::MappedAddress => Value::MappedAddress(MappedAddress::decode(data)),
-            AttrType::ResponseAddress => Value::ResponseAddress(ResponseAddress::decode(data)),
-            AttrType::ChangeRequest => Value::ChangeRequest(ChangeRequest::decode(data)),
-            AttrType::SourceAddress => Value::SourceAddress(SourceAddress::decode(data)),
-            AttrType::ChangedAddress => Value::ChangedAddress(ChangedAddress::decode(data)),
-            AttrType::Username => Value::Username(Username::decode(data)),
-            AttrType::Password => Value::Password(Password::decode(data)),
-            AttrType::MessageIntegrity => Value::MessageIntegrity(MessageIntegrity::decode(data)),
-            AttrType::ErrorCode => Value::ErrorCode(ErrorCode::decode(data)),
+    pub fn decode(attr_type: AttrType, data: &[u8]) -> Result<Value, Error> {
+        let value = match attr_type {
+            AttrType::MappedAddress => Value::MappedAddress(MappedAddress::decode(data)?),
+            AttrType::ResponseAddress => Value::ResponseAddress(ResponseAddress::decode(data)?),
+            AttrType::ChangeRequest => Value::ChangeRequest(ChangeRequest::decode(data)?),
+            AttrType::SourceAddress => Value::SourceAddress(SourceAddress::decode(data)?),
+            AttrType::ChangedAddress => Value::ChangedAddress(ChangedAddress::decode(data)?),
+            AttrType::Username => Value::Username(Username::decode(data)?),
+            AttrType::Password => Value::Password(Password::decode(data)?),
+            AttrType::MessageIntegrity => Value::MessageIntegrity(MessageIntegrity::decode(data)?),
+            AttrType::ErrorCode => Value::ErrorCode(ErrorCode::decode(data)?),
             AttrType::UnknownAttributes => {
-                Value::UnknownAttributes(UnknownAttributes::decode(data))
+                Value::UnknownAttributes(UnknownAttributes::decode(data)?)
             }
-            AttrType::ReflectedFrom => Value::ReflectedFrom(ReflectedFrom::decode(data)),
-        }
+            AttrType::ReflectedFrom => Value::ReflectedFrom(ReflectedFrom::decode(data)?),
+            AttrType::ChannelNumber => Value::ChannelNumber(ChannelNumber::decode(data)?),
+            AttrType::Lifetime => Value::Lifetime(Lifetime::decode(data)?),
+            AttrType::XorPeerAddress => Value::XorPeerAddress(XorPeerAddress::decode(data)?),
+            AttrType::Data => Value::Data(Data::decode(data)?),
+            AttrType::Realm => Value::Realm(Realm::decode(data)?),
+            AttrType::Nonce => Value::Nonce(Nonce::decode(data)?),
+            AttrType::XorRelayedAddress => {
+                Value::XorRelayedAddress(XorRelayedAddress::decode(data)?)
+            }
+            AttrType::RequestedTransport => {
+                Value::RequestedTransport(RequestedTransport::decode(data)?)
+            }
+            AttrType::XorMappedAddress => Value::XorMappedAddress(XorMappedAddress::decode(data)?),
+            AttrType::RequestedAddressFamily => {
+                Value::RequestedAddressFamily(RequestedAddressFamily::decode(data)?)
+            }
+            AttrType::EvenPort => Value::EvenPort(EvenPort::decode(data)?),
+            AttrType::DontFragment => Value::DontFragment(DontFragment::decode(data)?),
+            AttrType::ReservationToken => Value::ReservationToken(ReservationToken::decode(data)?),
+            AttrType::AdditionalAddressFamily => {
+                Value::AdditionalAddressFamily(AdditionalAddressFamily::decode(data)?)
+            }
+            AttrType::AddressErrorCode => Value::AddressErrorCode(AddressErrorCode::decode(data)?),
+            AttrType::Icmp => Value::Icmp(Icmp::decode(data)?),
+            AttrType::ConnectionId => Value::ConnectionId(ConnectionId::decode(data)?),
+            AttrType::AlternateServer => Value::AlternateServer(AlternateServer::decode(data)?),
+            AttrType::AlternateDomain => Value::AlternateDomain(AlternateDomain::decode(data)?),
+            AttrType::Software => Value::Software(Software::decode(data)?),
+            AttrType::Priority => Value::Priority(Priority::decode(data)?),
+            AttrType::UseCandidate => Value::UseCandidate(UseCandidate::decode(data)?),
+            AttrType::Fingerprint => Value::Fingerprint(Fingerprint::decode(data)?),
+            AttrType::IceControlled => Value::IceControlled(IceControlled::decode(data)?),
+            AttrType::IceControlling => Value::IceControlling(IceControlling::decode(data)?),
+        };
+        Ok(value)
     }
 
     pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    /// Length of the value on the wire, padding left out
+    pub fn encoded_len(&self) -> usize {
+        let mut counter = Counter::default();
+        self.encode_to(&mut counter);
+        counter.len
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
         match self {
-            Value::MappedAddress(value) => value.encode(),
-            Value::ResponseAddress(value) => value.encode(),
-            Value::ChangeRequest(value) => value.encode(),
-            Value::SourceAddress(value) => value.encode(),
-            Value::ChangedAddress(value) => value.encode(),
-            Value::Username(value) => value.encode(),
-            Value::Password(value) => value.encode(),
-            Value::MessageIntegrity(value) => value.encode(),
-            Value::ErrorCode(value) => value.encode(),
-            Value::UnknownAttributes(value) => value.encode(),
-            Value::ReflectedFrom(value) => value.encode(),
+            Value::MappedAddress(value) => value.encode_to(buf),
+            Value::ResponseAddress(value) => value.encode_to(buf),
+            Value::ChangeRequest(value) => value.encode_to(buf),
+            Value::SourceAddress(value) => value.encode_to(buf),
+            Value::ChangedAddress(value) => value.encode_to(buf),
+            Value::Username(value) => value.encode_to(buf),
+            Value::Password(value) => value.encode_to(buf),
+            Value::MessageIntegrity(value) => value.encode_to(buf),
+            Value::ErrorCode(value) => value.encode_to(buf),
+            Value::UnknownAttributes(value) => value.encode_to(buf),
+            Value::ReflectedFrom(value) => value.encode_to(buf),
+            Value::ChannelNumber(value) => value.encode_to(buf),
+            Value::Lifetime(value) => value.encode_to(buf),
+            Value::XorPeerAddress(value) => value.encode_to(buf),
+            Value::Data(value) => value.encode_to(buf),
+            Value::Realm(value) => value.encode_to(buf),
+            Value::Nonce(value) => value.encode_to(buf),
+            Value::XorRelayedAddress(value) => value.encode_to(buf),
+            Value::RequestedTransport(value) => value.encode_to(buf),
+            Value::XorMappedAddress(value) => value.encode_to(buf),
+            Value::RequestedAddressFamily(value) => value.encode_to(buf),
+            Value::EvenPort(value) => value.encode_to(buf),
+            Value::DontFragment(value) => value.encode_to(buf),
+            Value::ReservationToken(value) => value.encode_to(buf),
+            Value::AdditionalAddressFamily(value) => value.encode_to(buf),
+            Value::AddressErrorCode(value) => value.encode_to(buf),
+            Value::Icmp(value) => value.encode_to(buf),
+            Value::ConnectionId(value) => value.encode_to(buf),
+            Value::AlternateServer(value) => value.encode_to(buf),
+            Value::AlternateDomain(value) => value.encode_to(buf),
+            Value::Software(value) => value.encode_to(buf),
+            Value::Priority(value) => value.encode_to(buf),
+            Value::UseCandidate(value) => value.encode_to(buf),
+            Value::Fingerprint(value) => value.encode_to(buf),
+            Value::IceControlled(value) => value.encode_to(buf),
+            Value::IceControlling(value) => value.encode_to(buf),
+            Value::Raw(value) => buf.put_slice(&value.value),
         }
     }
 
@@ -134,11 +361,164 @@ impl Value {
             Value::ErrorCode(_) => Attribute::new(AttrType::ErrorCode, self),
             Value::UnknownAttributes(_) => Attribute::new(AttrType::UnknownAttributes, self),
             Value::ReflectedFrom(_) => Attribute::new(AttrType::ReflectedFrom, self),
+            Value::ChannelNumber(_) => Attribute::new(AttrType::ChannelNumber, self),
+            Value::Lifetime(_) => Attribute::new(AttrType::Lifetime, self),
+            Value::XorPeerAddress(_) => Attribute::new(AttrType::XorPeerAddress, self),
+            Value::Data(_) => Attribute::new(AttrType::Data, self),
+            Value::Realm(_) => Attribute::new(AttrType::Realm, self),
+            Value::Nonce(_) => Attribute::new(AttrType::Nonce, self),
+            Value::XorRelayedAddress(_) => Attribute::new(AttrType::XorRelayedAddress, self),
+            Value::RequestedTransport(_) => Attribute::new(AttrType::RequestedTransport, self),
+            Value::XorMappedAddress(_) => Attribute::new(AttrType::XorMappedAddress, self),
+            Value::RequestedAddressFamily(_) => {
+                Attribute::new(AttrType::RequestedAddressFamily, self)
+            }
+            Value::EvenPort(_) => Attribute::new(AttrType::EvenPort, self),
+            Value::DontFragment(_) => Attribute::new(AttrType::DontFragment, self),
+            Value::ReservationToken(_) => Attribute::new(AttrType::ReservationToken, self),
+            Value::AdditionalAddressFamily(_) => {
+                Attribute::new(AttrType::AdditionalAddressFamily, self)
+            }
+            Value::AddressErrorCode(_) => Attribute::new(AttrType::AddressErrorCode, self),
+            Value::Icmp(_) => Attribute::new(AttrType::Icmp, self),
+            Value::ConnectionId(_) => Attribute::new(AttrType::ConnectionId, self),
+            Value::AlternateServer(_) => Attribute::new(AttrType::AlternateServer, self),
+            Value::AlternateDomain(_) => Attribute::new(AttrType::AlternateDomain, self),
+            Value::Software(_) => Attribute::new(AttrType::Software, self),
+            Value::Priority(_) => Attribute::new(AttrType::Priority, self),
+            Value::UseCandidate(_) => Attribute::new(AttrType::UseCandidate, self),
+            Value::Fingerprint(_) => Attribute::new(AttrType::Fingerprint, self),
+            Value::IceControlled(_) => Attribute::new(AttrType::IceControlled, self),
+            Value::IceControlling(_) => Attribute::new(AttrType::IceControlling, self),
+            Value::Raw(RawAttribute { attr_type, .. }) => Attribute {
+                attr_type,
+                value: self,
+            },
         }
     }
 }
 
-#[derive(Debug)]
+/// The types attribute values come in, for building messages out of them
+/// and finding them in messages by type
+pub trait AttributeValue: Sized {
+    fn into_value(self) -> Value;
+
+    /// The value if it's of this type
+    fn from_value(value: &Value) -> Option<&Self>;
+}
+
+/// An attribute with its type, encoded and decoded in the context of the
+/// message carrying it, for the transaction id XOR-encoded addresses are
+/// keyed with. Implemented for every attribute this crate knows, and by
+/// other crates for vendor or experimental ones, which travel in messages
+/// as `RawAttribute`s.
+pub trait StunAttribute: Sized {
+    const TYPE: u16;
+
+    /// Write the value, without type, length or padding
+    fn encode(&self, header: &Header, buf: &mut Vec<u8>);
+
+    fn decode(value: &[u8], header: &Header) -> Result<Self, Error>;
+
+    /// Encode into a raw attribute for a message with `header`
+    fn to_raw(&self, header: &Header) -> RawAttribute {
+        let mut value = Vec::new();
+        self.encode(header, &mut value);
+        RawAttribute::new(Self::TYPE, value)
+    }
+}
+
+macro_rules! attribute_values {
+    ($($name:ident),* $(,)?) => {
+        $(
+            impl AttributeValue for $name {
+                fn into_value(self) -> Value {
+                    Value::$name(self)
+                }
+
+                fn from_value(value: &Value) -> Option<&Self> {
+                    match value {
+                        Value::$name(inner) => Some(inner),
+                        _ => None,
+                    }
+                }
+            }
+
+            impl StunAttribute for $name {
+                const TYPE: u16 = AttrType::$name as u16;
+
+                fn encode(&self, _header: &Header, buf: &mut Vec<u8>) {
+                    self.encode_to(buf)
+                }
+
+                fn decode(value: &[u8], _header: &Header) -> Result<Self, Error> {
+                    $name::decode(value)
+                }
+            }
+        )*
+    };
+}
+
+attribute_values!(
+    MappedAddress,
+    ResponseAddress,
+    ChangeRequest,
+    SourceAddress,
+    ChangedAddress,
+    Username,
+    Password,
+    MessageIntegrity,
+    ErrorCode,
+    UnknownAttributes,
+    ReflectedFrom,
+    ChannelNumber,
+    Lifetime,
+    XorPeerAddress,
+    Data,
+    Realm,
+    Nonce,
+    XorRelayedAddress,
+    RequestedTransport,
+    XorMappedAddress,
+    RequestedAddressFamily,
+    EvenPort,
+    DontFragment,
+    ReservationToken,
+    AdditionalAddressFamily,
+    AddressErrorCode,
+    Icmp,
+    ConnectionId,
+    AlternateServer,
+    AlternateDomain,
+    Software,
+    Priority,
+    UseCandidate,
+    Fingerprint,
+    IceControlled,
+    IceControlling
+);
+
+/// The type and value of an attribute as they are on the wire, for types
+/// this crate doesn't know
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawAttribute {
+    pub attr_type: u16,
+    pub value: Vec<u8>,
+}
+
+impl RawAttribute {
+    pub const fn new(attr_type: u16, value: Vec<u8>) -> Self {
+        RawAttribute { attr_type, value }
+    }
+
+    /// Comprehension-required types have to be understood for a request to
+    /// be processed, RFC 8489 section 14
+    pub const fn is_comprehension_required(&self) -> bool {
+        self.attr_type < 0x8000
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct MappedAddress {
     pub family: u8,
     pub port: u16,
@@ -154,24 +534,25 @@ impl MappedAddress {
         }
     }
 
-    pub fn decode(data: &[u8]) -> MappedAddress {
-        let family = data[1];
-        let port = u16::from_be_bytes([data[2], data[3]]);
-        let address = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
-        MappedAddress::new(family, port, address)
+    pub fn decode(data: &[u8]) -> Result<MappedAddress, Error> {
+        let [_, family, p0, p1, a, b, c, d] = fixed(data, AttrType::MappedAddress)?;
+        let address = Ipv4Addr::new(a, b, c, d);
+        Ok(MappedAddress::new(
+            family,
+            u16::from_be_bytes([p0, p1]),
+            address,
+        ))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.push(0);
-        buf.push(self.family);
-        buf.extend_from_slice(&self.port.to_be_bytes());
-        buf.extend_from_slice(&self.address.octets());
-        buf
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0);
+        buf.put_u8(self.family);
+        buf.put_slice(&self.port.to_be_bytes());
+        buf.put_slice(&self.address.octets());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ResponseAddress {
     pub family: u8,
     pub port: u16,
@@ -187,24 +568,25 @@ impl ResponseAddress {
         }
     }
 
-    pub fn decode(data: &[u8]) -> ResponseAddress {
-        let family = data[1];
-        let port = u16::from_be_bytes([data[2], data[3]]);
-        let address = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
-        ResponseAddress::new(family, port, address)
+    pub fn decode(data: &[u8]) -> Result<ResponseAddress, Error> {
+        let [_, family, p0, p1, a, b, c, d] = fixed(data, AttrType::ResponseAddress)?;
+        let address = Ipv4Addr::new(a, b, c, d);
+        Ok(ResponseAddress::new(
+            family,
+            u16::from_be_bytes([p0, p1]),
+            address,
+        ))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.push(0);
-        buf.push(self.family);
-        buf.extend_from_slice(&self.port.to_be_bytes());
-        buf.extend_from_slice(&self.address.octets());
-        buf
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0);
+        buf.put_u8(self.family);
+        buf.put_slice(&self.port.to_be_bytes());
+        buf.put_slice(&self.address.octets());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ChangedAddress {
     pub family: u8,
     pub port: u16,
@@ -220,24 +602,25 @@ impl ChangedAddress {
         }
     }
 
-    pub fn decode(data: &[u8]) -> ChangedAddress {
-        let family = data[1];
-        let port = u16::from_be_bytes([data[2], data[3]]);
-        let address = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
-        ChangedAddress::new(family, port, address)
+    pub fn decode(data: &[u8]) -> Result<ChangedAddress, Error> {
+        let [_, family, p0, p1, a, b, c, d] = fixed(data, AttrType::ChangedAddress)?;
+        let address = Ipv4Addr::new(a, b, c, d);
+        Ok(ChangedAddress::new(
+            family,
+            u16::from_be_bytes([p0, p1]),
+            address,
+        ))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.push(0);
-        buf.push(self.family);
-        buf.extend_from_slice(&self.port.to_be_bytes());
-        buf.extend_from_slice(&self.address.octets());
-        buf
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0);
+        buf.put_u8(self.family);
+        buf.put_slice(&self.port.to_be_bytes());
+        buf.put_slice(&self.address.octets());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ChangeRequest {
     pub change_ip: bool,
     pub change_port: bool,
@@ -251,29 +634,27 @@ impl ChangeRequest {
         }
     }
 
-    pub fn decode(data: &[u8]) -> ChangeRequest {
-        let change_ip = data[3] & 0x04 != 0;
-        let change_port = data[3] & 0x02 != 0;
-        ChangeRequest {
-            change_ip,
-            change_port,
-        }
+    pub fn decode(data: &[u8]) -> Result<ChangeRequest, Error> {
+        let [_, _, _, flags] = fixed(data, AttrType::ChangeRequest)?;
+        Ok(ChangeRequest {
+            change_ip: flags & 0x04 != 0,
+            change_port: flags & 0x02 != 0,
+        })
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.extend_from_slice(&[0, 0, 0, 0]);
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        let mut flags = 0;
         if self.change_ip {
-            buf[3] |= 0x04;
+            flags |= 0x04;
         }
         if self.change_port {
-            buf[3] |= 0x02;
+            flags |= 0x02;
         }
-        buf
+        buf.put_slice(&[0, 0, 0, flags]);
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct SourceAddress {
     pub family: u8,
     pub port: u16,
@@ -289,24 +670,25 @@ impl SourceAddress {
         }
     }
 
-    pub fn decode(data: &[u8]) -> SourceAddress {
-        let family = data[1];
-        let port = u16::from_be_bytes([data[2], data[3]]);
-        let address = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
-        SourceAddress::new(family, port, address)
+    pub fn decode(data: &[u8]) -> Result<SourceAddress, Error> {
+        let [_, family, p0, p1, a, b, c, d] = fixed(data, AttrType::SourceAddress)?;
+        let address = Ipv4Addr::new(a, b, c, d);
+        Ok(SourceAddress::new(
+            family,
+            u16::from_be_bytes([p0, p1]),
+            address,
+        ))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.push(0);
-        buf.push(self.family);
-        buf.extend_from_slice(&self.port.to_be_bytes());
-        buf.extend_from_slice(&self.address.octets());
-        buf
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0);
+        buf.put_u8(self.family);
+        buf.put_slice(&self.port.to_be_bytes());
+        buf.put_slice(&self.address.octets());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Username {
     pub username: String,
 }
@@ -316,17 +698,16 @@ impl Username {
         Username { username }
     }
 
-    pub fn decode(data: &[u8]) -> Username {
-        let username = String::from_utf8(data.to_vec()).unwrap();
-        Username::new(username)
+    pub fn decode(data: &[u8]) -> Result<Username, Error> {
+        text(data, AttrType::Username).map(Username::new)
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        self.username.as_bytes().to_vec()
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(self.username.as_bytes());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Password {
     pub password: String,
 }
@@ -336,17 +717,16 @@ impl Password {
         Password { password }
     }
 
-    pub fn decode(data: &[u8]) -> Password {
-        let password = String::from_utf8(data.to_vec()).unwrap();
-        Password::new(password)
+    pub fn decode(data: &[u8]) -> Result<Password, Error> {
+        text(data, AttrType::Password).map(Password::new)
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        self.password.as_bytes().to_vec()
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(self.password.as_bytes());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct MessageIntegrity {
     pub integrity: [u8; 20],
 }
@@ -356,18 +736,35 @@ impl MessageIntegrity {
         MessageIntegrity { integrity }
     }
 
-    pub fn decode(data: &[u8]) -> MessageIntegrity {
-        let mut integrity = [0; 20];
-        integrity.copy_from_slice(&data[0..20]);
-        MessageIntegrity::new(integrity)
+    pub fn decode(data: &[u8]) -> Result<MessageIntegrity, Error> {
+        fixed(data, AttrType::MessageIntegrity).map(MessageIntegrity::new)
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        self.integrity.to_vec()
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.integrity);
+    }
+
+    /// HMAC-SHA1 of `data` keyed with `key`
+    pub fn compute(key: &[u8], data: &[u8]) -> MessageIntegrity {
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+        mac.update(data);
+        MessageIntegrity::new(mac.finalize().into_bytes().into())
+    }
+
+    /// Check `data` against this integrity in constant time
+    pub fn verify(&self, key: &[u8], data: &[u8]) -> bool {
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+        mac.update(data);
+        mac.verify_slice(&self.integrity).is_ok()
+    }
+
+    /// Key for long-term credentials, MD5(username ":" realm ":" password)
+    pub fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+        Md5::digest(format!("{}:{}:{}", username, realm, password)).into()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ErrorCode {
     pub code: u16,
     pub reason: String,
@@ -378,19 +775,21 @@ impl ErrorCode {
         ErrorCode { code, reason }
     }
 
-    pub fn decode(data: &[u8]) -> ErrorCode {
-        let class = data[2];
-        let number = data[3];
-        let code = u16::from(class) * 100 + u16::from(number);
-        let reason = String::from_utf8(data[4..].to_vec()).unwrap();
-        ErrorCode::new(code, reason)
+    pub fn decode(data: &[u8]) -> Result<ErrorCode, Error> {
+        // The class is three bits and the number under 100, RFC 8489
+        // section 14.8
+        let [_, _, class, number] = fixed(data, AttrType::ErrorCode)?;
+        if number > 99 {
+            return Err(Error::InvalidValue(AttrType::ErrorCode as u16));
+        }
+        let code = u16::from(class & 0x07) * 100 + u16::from(number);
+        let reason = text(&data[4..], AttrType::ErrorCode)?;
+        Ok(ErrorCode::new(code, reason))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.extend_from_slice(&[0, 0, self.class(), self.number()]);
-        buf.extend_from_slice(self.reason.as_bytes());
-        buf
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[0, 0, self.class(), self.number()]);
+        buf.put_slice(self.reason.as_bytes());
     }
 
     pub const fn class(&self) -> u8 {
@@ -402,7 +801,7 @@ impl ErrorCode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct UnknownAttributes {
     pub attributes: Vec<u16>,
 }
@@ -412,24 +811,22 @@ impl UnknownAttributes {
         UnknownAttributes { attributes }
     }
 
-    pub fn decode(data: &[u8]) -> UnknownAttributes {
-        let mut attributes = Vec::new();
-        for i in 0..data.len() / 2 {
-            attributes.push(u16::from_be_bytes([data[i * 2], data[i * 2 + 1]]));
-        }
-        UnknownAttributes::new(attributes)
+    pub fn decode(data: &[u8]) -> Result<UnknownAttributes, Error> {
+        let attributes = data
+            .chunks_exact(2)
+            .map(|attr| u16::from_be_bytes([attr[0], attr[1]]))
+            .collect();
+        Ok(UnknownAttributes::new(attributes))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
         for attr in &self.attributes {
-            buf.extend_from_slice(&attr.to_be_bytes());
+            buf.put_slice(&attr.to_be_bytes());
         }
-        buf
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ReflectedFrom {
     pub family: u8,
     pub port: u16,
@@ -445,121 +842,1175 @@ impl ReflectedFrom {
         }
     }
 
-    pub fn decode(data: &[u8]) -> ReflectedFrom {
-        let family = data[1];
-        let port = u16::from_be_bytes([data[2], data[3]]);
-        let address = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
-        ReflectedFrom::new(family, port, address)
+    pub fn decode(data: &[u8]) -> Result<ReflectedFrom, Error> {
+        let [_, family, p0, p1, a, b, c, d] = fixed(data, AttrType::ReflectedFrom)?;
+        let address = Ipv4Addr::new(a, b, c, d);
+        Ok(ReflectedFrom::new(
+            family,
+            u16::from_be_bytes([p0, p1]),
+            address,
+        ))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.push(0);
-        buf.push(self.family);
-        buf.extend_from_slice(&self.port.to_be_bytes());
-        buf.extend_from_slice(&self.address.octets());
-        buf
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0);
+        buf.put_u8(self.family);
+        buf.put_slice(&self.port.to_be_bytes());
+        buf.put_slice(&self.address.octets());
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::net::Ipv4Addr;
+/// XOR a transport address with the magic cookie and transaction id,
+/// the operation is its own inverse
+fn xor_addr(addr: SocketAddr, transaction_id: &[u8; 16]) -> SocketAddr {
+    let port = addr.port() ^ u16::from_be_bytes([transaction_id[0], transaction_id[1]]);
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            for (octet, key) in octets.iter_mut().zip(transaction_id) {
+                *octet ^= key;
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            for (octet, key) in octets.iter_mut().zip(transaction_id) {
+                *octet ^= key;
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    };
+    SocketAddr::new(ip, port)
+}
 
-    #[test]
-    fn test_mapped_address_encode_decode() {
-        let mapped_address = MappedAddress::new(1, 8080, Ipv4Addr::new(192, 168, 0, 1));
-        let encoded = Value::MappedAddress(mapped_address).encode();
-        let decoded = Value::decode(AttrType::MappedAddress, &encoded);
+fn decode_addr(data: &[u8], attr_type: AttrType) -> Result<SocketAddr, Error> {
+    let [_, family, p0, p1] = fixed(data, attr_type)?;
+    let ip = match family {
+        0x02 => IpAddr::V6(Ipv6Addr::from(fixed::<16>(&data[4..], attr_type)?)),
+        _ => IpAddr::V4(Ipv4Addr::from(fixed::<4>(&data[4..], attr_type)?)),
+    };
+    Ok(SocketAddr::new(ip, u16::from_be_bytes([p0, p1])))
+}
 
-        if let Value::MappedAddress(decoded_address) = decoded {
-            assert_eq!(decoded_address.family, 1);
-            assert_eq!(decoded_address.port, 8080);
-            assert_eq!(decoded_address.address, Ipv4Addr::new(192, 168, 0, 1));
-        } else {
-            panic!("Decoded value is not a MappedAddress");
+fn encode_addr<B: BufMut>(addr: &SocketAddr, buf: &mut B) {
+    buf.put_u8(0);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.put_u8(0x01);
+            buf.put_slice(&addr.port().to_be_bytes());
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.put_u8(0x02);
+            buf.put_slice(&addr.port().to_be_bytes());
+            buf.put_slice(&ip.octets());
         }
     }
+}
 
-    #[test]
-    fn test_change_request_encode_decode() {
-        let change_request = ChangeRequest::new(true, false);
-        let encoded = Value::ChangeRequest(change_request).encode();
-        let decoded = Value::decode(AttrType::ChangeRequest, &encoded);
+#[derive(Debug, PartialEq)]
+pub struct ChannelNumber {
+    pub number: u16,
+}
 
-        if let Value::ChangeRequest(decoded_request) = decoded {
-            assert_eq!(decoded_request.change_ip, true);
-            assert_eq!(decoded_request.change_port, false);
-        } else {
-            panic!("Decoded value is not a ChangeRequest");
-        }
+impl ChannelNumber {
+    pub const fn new(number: u16) -> Self {
+        ChannelNumber { number }
     }
 
-    #[test]
-    fn test_username_encode_decode() {
-        let username = Username::new("testuser".to_string());
-        let encoded = Value::Username(username).encode();
-        let decoded = Value::decode(AttrType::Username, &encoded);
+    pub fn decode(data: &[u8]) -> Result<ChannelNumber, Error> {
+        fixed(data, AttrType::ChannelNumber)
+            .map(u16::from_be_bytes)
+            .map(ChannelNumber::new)
+    }
 
-        if let Value::Username(decoded_username) = decoded {
-            assert_eq!(decoded_username.username, "testuser");
-        } else {
-            panic!("Decoded value is not a Username");
-        }
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.number.to_be_bytes());
+        buf.put_slice(&[0, 0]);
     }
+}
 
-    #[test]
-    fn test_password_encode_decode() {
-        let password = Password::new("testpassword".to_string());
-        let encoded = Value::Password(password).encode();
-        let decoded = Value::decode(AttrType::Password, &encoded);
+#[derive(Debug, PartialEq)]
+pub struct Lifetime {
+    pub lifetime: u32,
+}
 
-        if let Value::Password(decoded_password) = decoded {
-            assert_eq!(decoded_password.password, "testpassword");
-        } else {
-            panic!("Decoded value is not a Password");
+impl Lifetime {
+    pub const fn new(lifetime: u32) -> Self {
+        Lifetime { lifetime }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Lifetime, Error> {
+        fixed(data, AttrType::Lifetime)
+            .map(u32::from_be_bytes)
+            .map(Lifetime::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.lifetime.to_be_bytes());
+    }
+}
+
+/// XOR-PEER-ADDRESS, `address` is kept XOR'ed as it is on the wire
+#[derive(Debug, PartialEq)]
+pub struct XorPeerAddress {
+    pub address: SocketAddr,
+}
+
+impl XorPeerAddress {
+    pub fn new(address: SocketAddr, transaction_id: &[u8; 16]) -> Self {
+        XorPeerAddress {
+            address: xor_addr(address, transaction_id),
         }
     }
 
-    #[test]
-    fn test_message_integrity_encode_decode() {
-        let integrity = [1u8; 20];
-        let message_integrity = MessageIntegrity::new(integrity);
-        let encoded = Value::MessageIntegrity(message_integrity).encode();
-        let decoded = Value::decode(AttrType::MessageIntegrity, &encoded);
+    pub fn addr(&self, transaction_id: &[u8; 16]) -> SocketAddr {
+        xor_addr(self.address, transaction_id)
+    }
 
-        if let Value::MessageIntegrity(decoded_integrity) = decoded {
-            assert_eq!(decoded_integrity.integrity, integrity);
-        } else {
-            panic!("Decoded value is not a MessageIntegrity");
+    pub fn decode(data: &[u8]) -> Result<XorPeerAddress, Error> {
+        let address = decode_addr(data, AttrType::XorPeerAddress)?;
+        Ok(XorPeerAddress { address })
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        encode_addr(&self.address, buf)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Data {
+    pub data: Vec<u8>,
+}
+
+impl Data {
+    pub const fn new(data: Vec<u8>) -> Self {
+        Data { data }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Data, Error> {
+        Ok(Data::new(data.to_vec()))
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.data);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Realm {
+    pub realm: String,
+}
+
+impl Realm {
+    pub const fn new(realm: String) -> Self {
+        Realm { realm }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Realm, Error> {
+        text(data, AttrType::Realm).map(Realm::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(self.realm.as_bytes());
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Nonce {
+    pub nonce: String,
+}
+
+impl Nonce {
+    pub const fn new(nonce: String) -> Self {
+        Nonce { nonce }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Nonce, Error> {
+        text(data, AttrType::Nonce).map(Nonce::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(self.nonce.as_bytes());
+    }
+}
+
+/// XOR-RELAYED-ADDRESS, `address` is kept XOR'ed as it is on the wire
+#[derive(Debug, PartialEq)]
+pub struct XorRelayedAddress {
+    pub address: SocketAddr,
+}
+
+impl XorRelayedAddress {
+    pub fn new(address: SocketAddr, transaction_id: &[u8; 16]) -> Self {
+        XorRelayedAddress {
+            address: xor_addr(address, transaction_id),
         }
     }
 
-    #[test]
-    fn test_error_code_encode_decode() {
-        let error_code = ErrorCode::new(400, "Bad Request".to_string());
-        let encoded = Value::ErrorCode(error_code).encode();
-        let decoded = Value::decode(AttrType::ErrorCode, &encoded);
+    pub fn addr(&self, transaction_id: &[u8; 16]) -> SocketAddr {
+        xor_addr(self.address, transaction_id)
+    }
 
-        if let Value::ErrorCode(decoded_error) = decoded {
-            assert_eq!(decoded_error.code, 400);
-            assert_eq!(decoded_error.reason, "Bad Request");
-        } else {
-            panic!("Decoded value is not an ErrorCode");
+    pub fn decode(data: &[u8]) -> Result<XorRelayedAddress, Error> {
+        let address = decode_addr(data, AttrType::XorRelayedAddress)?;
+        Ok(XorRelayedAddress { address })
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        encode_addr(&self.address, buf)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RequestedTransport {
+    pub protocol: u8,
+}
+
+impl RequestedTransport {
+    pub const TCP: u8 = 6;
+    pub const UDP: u8 = 17;
+
+    pub const fn new(protocol: u8) -> Self {
+        RequestedTransport { protocol }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<RequestedTransport, Error> {
+        let [value] = fixed(data, AttrType::RequestedTransport)?;
+        Ok(RequestedTransport::new(value))
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[self.protocol, 0, 0, 0]);
+    }
+}
+
+/// XOR-MAPPED-ADDRESS, `address` is kept XOR'ed as it is on the wire
+#[derive(Debug, PartialEq)]
+pub struct XorMappedAddress {
+    pub address: SocketAddr,
+}
+
+impl XorMappedAddress {
+    pub fn new(address: SocketAddr, transaction_id: &[u8; 16]) -> Self {
+        XorMappedAddress {
+            address: xor_addr(address, transaction_id),
         }
     }
 
-    #[test]
-    fn test_unknown_attributes_encode_decode() {
-        let unknown_attrs = UnknownAttributes::new(vec![0x0001, 0x0002, 0x0003]);
-        let encoded = Value::UnknownAttributes(unknown_attrs).encode();
-        let decoded = Value::decode(AttrType::UnknownAttributes, &encoded);
+    pub fn addr(&self, transaction_id: &[u8; 16]) -> SocketAddr {
+        xor_addr(self.address, transaction_id)
+    }
 
-        if let Value::UnknownAttributes(decoded_attrs) = decoded {
-            assert_eq!(decoded_attrs.attributes, vec![0x0001, 0x0002, 0x0003]);
-        } else {
-            panic!("Decoded value is not UnknownAttributes");
+    pub fn decode(data: &[u8]) -> Result<XorMappedAddress, Error> {
+        let address = decode_addr(data, AttrType::XorMappedAddress)?;
+        Ok(XorMappedAddress { address })
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        encode_addr(&self.address, buf)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RequestedAddressFamily {
+    pub family: u8,
+}
+
+impl RequestedAddressFamily {
+    pub const IPV4: u8 = 0x01;
+    pub const IPV6: u8 = 0x02;
+
+    pub const fn new(family: u8) -> Self {
+        RequestedAddressFamily { family }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<RequestedAddressFamily, Error> {
+        let [value] = fixed(data, AttrType::RequestedAddressFamily)?;
+        Ok(RequestedAddressFamily::new(value))
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[self.family, 0, 0, 0]);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct EvenPort {
+    /// Ask the server to also reserve the next higher port
+    pub reserve: bool,
+}
+
+impl EvenPort {
+    pub const fn new(reserve: bool) -> Self {
+        EvenPort { reserve }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<EvenPort, Error> {
+        let [value] = fixed(data, AttrType::EvenPort)?;
+        Ok(EvenPort::new(value & 0x80 != 0))
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(if self.reserve { 0x80 } else { 0 });
+    }
+}
+
+/// DONT-FRAGMENT has no value, its presence is the whole message
+#[derive(Debug, Default, PartialEq)]
+pub struct DontFragment;
+
+impl DontFragment {
+    pub const fn new() -> Self {
+        DontFragment
+    }
+
+    pub fn decode(_data: &[u8]) -> Result<DontFragment, Error> {
+        Ok(DontFragment)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, _buf: &mut B) {}
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ReservationToken {
+    pub token: [u8; 8],
+}
+
+impl ReservationToken {
+    pub const fn new(token: [u8; 8]) -> Self {
+        ReservationToken { token }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ReservationToken, Error> {
+        fixed(data, AttrType::ReservationToken).map(ReservationToken::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.token);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AdditionalAddressFamily {
+    pub family: u8,
+}
+
+impl AdditionalAddressFamily {
+    pub const fn new(family: u8) -> Self {
+        AdditionalAddressFamily { family }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<AdditionalAddressFamily, Error> {
+        let [value] = fixed(data, AttrType::AdditionalAddressFamily)?;
+        Ok(AdditionalAddressFamily::new(value))
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[self.family, 0, 0, 0]);
+    }
+}
+
+/// Why the allocation for one address family failed when the client asked
+/// for both
+#[derive(Debug, PartialEq)]
+pub struct AddressErrorCode {
+    pub family: u8,
+    pub code: u16,
+    pub reason: String,
+}
+
+impl AddressErrorCode {
+    pub const fn new(family: u8, code: u16, reason: String) -> Self {
+        AddressErrorCode {
+            family,
+            code,
+            reason,
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<AddressErrorCode, Error> {
+        let [family, _, class, number] = fixed(data, AttrType::AddressErrorCode)?;
+        if number > 99 {
+            return Err(Error::InvalidValue(AttrType::AddressErrorCode as u16));
+        }
+        let code = u16::from(class & 0x07) * 100 + u16::from(number);
+        let reason = text(&data[4..], AttrType::AddressErrorCode)?;
+        Ok(AddressErrorCode::new(family, code, reason))
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[self.family, 0, self.class(), self.number()]);
+        buf.put_slice(self.reason.as_bytes());
+    }
+
+    pub const fn class(&self) -> u8 {
+        (self.code / 100) as u8
+    }
+
+    pub const fn number(&self) -> u8 {
+        (self.code % 100) as u8
+    }
+}
+
+/// ICMP error the relay received from a peer
+#[derive(Debug, PartialEq)]
+pub struct Icmp {
+    /// 9 bits on the wire
+    pub icmp_type: u16,
+    /// 7 bits on the wire
+    pub code: u8,
+    pub error_data: u32,
+}
+
+impl Icmp {
+    pub const fn new(icmp_type: u16, code: u8, error_data: u32) -> Self {
+        Icmp {
+            icmp_type,
+            code,
+            error_data,
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Icmp, Error> {
+        let [_, _, t0, t1, e0, e1, e2, e3] = fixed(data, AttrType::Icmp)?;
+        let type_and_code = u16::from_be_bytes([t0, t1]);
+        let error_data = u32::from_be_bytes([e0, e1, e2, e3]);
+        Ok(Icmp::new(
+            type_and_code >> 7,
+            (type_and_code & 0x7F) as u8,
+            error_data,
+        ))
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        let type_and_code = (self.icmp_type & 0x1FF) << 7 | u16::from(self.code & 0x7F);
+        buf.put_slice(&[0, 0]);
+        buf.put_slice(&type_and_code.to_be_bytes());
+        buf.put_slice(&self.error_data.to_be_bytes());
+    }
+}
+
+/// Identifies a peer connection of a TCP allocation, RFC 6062
+#[derive(Debug, PartialEq)]
+pub struct ConnectionId {
+    pub connection_id: u32,
+}
+
+impl ConnectionId {
+    pub const fn new(connection_id: u32) -> Self {
+        ConnectionId { connection_id }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ConnectionId, Error> {
+        fixed(data, AttrType::ConnectionId)
+            .map(u32::from_be_bytes)
+            .map(ConnectionId::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.connection_id.to_be_bytes());
+    }
+}
+
+/// Server a 300 Try Alternate response sends the client to, encoded like
+/// MAPPED-ADDRESS but for either address family
+#[derive(Debug, PartialEq)]
+pub struct AlternateServer {
+    pub address: SocketAddr,
+}
+
+impl AlternateServer {
+    pub const fn new(address: SocketAddr) -> Self {
+        AlternateServer { address }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<AlternateServer, Error> {
+        decode_addr(data, AttrType::AlternateServer).map(AlternateServer::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        encode_addr(&self.address, buf)
+    }
+}
+
+/// Domain the alternate server's certificate is checked against, RFC 8489
+/// section 14.16
+#[derive(Debug, PartialEq)]
+pub struct AlternateDomain {
+    pub domain: String,
+}
+
+impl AlternateDomain {
+    pub const fn new(domain: String) -> Self {
+        AlternateDomain { domain }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<AlternateDomain, Error> {
+        text(data, AttrType::AlternateDomain).map(AlternateDomain::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(self.domain.as_bytes());
+    }
+}
+
+/// Who sent the message, like "totem 0.1.0", RFC 8489 section 14.14
+#[derive(Debug, PartialEq)]
+pub struct Software {
+    pub software: String,
+}
+
+impl Software {
+    /// The value has to be fewer than 128 characters, and no longer than
+    /// 763 bytes
+    pub const MAX_CHARS: usize = 127;
+    pub const MAX_BYTES: usize = 763;
+
+    pub fn new(software: String) -> Result<Self, Error> {
+        if software.len() > Self::MAX_BYTES || software.chars().count() > Self::MAX_CHARS {
+            return Err(Error::ValueTooLong(AttrType::Software as u16));
+        }
+        Ok(Software { software })
+    }
+
+    /// Values from senders that don't keep to the limits are cut short
+    pub fn decode(data: &[u8]) -> Result<Software, Error> {
+        let software = String::from_utf8_lossy(data);
+        Ok(Software {
+            software: Self::truncate(&software).into(),
+        })
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(Self::truncate(&self.software).as_bytes());
+    }
+
+    /// The longest prefix within both limits
+    fn truncate(software: &str) -> &str {
+        let end = software
+            .char_indices()
+            .map(|(index, c)| index + c.len_utf8())
+            .take(Self::MAX_CHARS)
+            .take_while(|end| *end <= Self::MAX_BYTES)
+            .last()
+            .unwrap_or(0);
+        &software[..end]
+    }
+}
+
+/// Priority of the peer reflexive candidate a connectivity check would
+/// discover, RFC 8445 section 7.1.1
+#[derive(Debug, PartialEq)]
+pub struct Priority {
+    pub priority: u32,
+}
+
+impl Priority {
+    pub const fn new(priority: u32) -> Self {
+        Priority { priority }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Priority, Error> {
+        fixed(data, AttrType::Priority)
+            .map(u32::from_be_bytes)
+            .map(Priority::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.priority.to_be_bytes());
+    }
+}
+
+/// USE-CANDIDATE has no value, the controlling agent nominates the pair
+/// the check is sent on by including it
+#[derive(Debug, Default, PartialEq)]
+pub struct UseCandidate;
+
+impl UseCandidate {
+    pub const fn new() -> Self {
+        UseCandidate
+    }
+
+    pub fn decode(_data: &[u8]) -> Result<UseCandidate, Error> {
+        Ok(UseCandidate)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, _buf: &mut B) {}
+}
+
+/// CRC-32 of the message up to this attribute XOR'ed with 0x5354554e,
+/// telling STUN apart from other protocols on the same port
+#[derive(Debug, PartialEq)]
+pub struct Fingerprint {
+    pub crc: u32,
+}
+
+impl Fingerprint {
+    const XOR: u32 = 0x5354_554E;
+
+    pub const fn new(crc: u32) -> Self {
+        Fingerprint { crc }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Fingerprint, Error> {
+        fixed(data, AttrType::Fingerprint)
+            .map(u32::from_be_bytes)
+            .map(Fingerprint::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.crc.to_be_bytes());
+    }
+
+    /// Fingerprint of `data`, the message with its length already counting
+    /// the fingerprint attribute
+    pub fn compute(data: &[u8]) -> Fingerprint {
+        Fingerprint::new(crc32(data) ^ Self::XOR)
+    }
+}
+
+/// CRC-32 as in ISO/HDLC and zlib, bit by bit since messages are small
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Sent by an agent that thinks it's controlled, with its tie-breaker for
+/// settling role conflicts
+#[derive(Debug, PartialEq)]
+pub struct IceControlled {
+    pub tie_breaker: u64,
+}
+
+impl IceControlled {
+    pub const fn new(tie_breaker: u64) -> Self {
+        IceControlled { tie_breaker }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<IceControlled, Error> {
+        fixed(data, AttrType::IceControlled)
+            .map(u64::from_be_bytes)
+            .map(IceControlled::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.tie_breaker.to_be_bytes());
+    }
+}
+
+/// Sent by an agent that thinks it's controlling, with its tie-breaker for
+/// settling role conflicts
+#[derive(Debug, PartialEq)]
+pub struct IceControlling {
+    pub tie_breaker: u64,
+}
+
+impl IceControlling {
+    pub const fn new(tie_breaker: u64) -> Self {
+        IceControlling { tie_breaker }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<IceControlling, Error> {
+        fixed(data, AttrType::IceControlling)
+            .map(u64::from_be_bytes)
+            .map(IceControlling::new)
+    }
+
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.tie_breaker.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const TRANSACTION_ID: [u8; 16] = [
+        0x21, 0x12, 0xA4, 0x42, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    ];
+
+    #[test]
+    fn test_mapped_address_encode_decode() {
+        let mapped_address = MappedAddress::new(1, 8080, Ipv4Addr::new(192, 168, 0, 1));
+        let encoded = Value::MappedAddress(mapped_address).encode();
+        let decoded = Value::decode(AttrType::MappedAddress, &encoded).unwrap();
+
+        if let Value::MappedAddress(decoded_address) = decoded {
+            assert_eq!(decoded_address.family, 1);
+            assert_eq!(decoded_address.port, 8080);
+            assert_eq!(decoded_address.address, Ipv4Addr::new(192, 168, 0, 1));
+        } else {
+            panic!("Decoded value is not a MappedAddress");
+        }
+    }
+
+    #[test]
+    fn test_change_request_encode_decode() {
+        let change_request = ChangeRequest::new(true, false);
+        let encoded = Value::ChangeRequest(change_request).encode();
+        let decoded = Value::decode(AttrType::ChangeRequest, &encoded).unwrap();
+
+        if let Value::ChangeRequest(decoded_request) = decoded {
+            assert!(decoded_request.change_ip);
+            assert!(!decoded_request.change_port);
+        } else {
+            panic!("Decoded value is not a ChangeRequest");
+        }
+    }
+
+    #[test]
+    fn test_username_encode_decode() {
+        let username = Username::new("testuser".to_string());
+        let encoded = Value::Username(username).encode();
+        let decoded = Value::decode(AttrType::Username, &encoded).unwrap();
+
+        if let Value::Username(decoded_username) = decoded {
+            assert_eq!(decoded_username.username, "testuser");
+        } else {
+            panic!("Decoded value is not a Username");
+        }
+    }
+
+    #[test]
+    fn test_password_encode_decode() {
+        let password = Password::new("testpassword".to_string());
+        let encoded = Value::Password(password).encode();
+        let decoded = Value::decode(AttrType::Password, &encoded).unwrap();
+
+        if let Value::Password(decoded_password) = decoded {
+            assert_eq!(decoded_password.password, "testpassword");
+        } else {
+            panic!("Decoded value is not a Password");
+        }
+    }
+
+    #[test]
+    fn test_message_integrity_encode_decode() {
+        let integrity = [1u8; 20];
+        let message_integrity = MessageIntegrity::new(integrity);
+        let encoded = Value::MessageIntegrity(message_integrity).encode();
+        let decoded = Value::decode(AttrType::MessageIntegrity, &encoded).unwrap();
+
+        if let Value::MessageIntegrity(decoded_integrity) = decoded {
+            assert_eq!(decoded_integrity.integrity, integrity);
+        } else {
+            panic!("Decoded value is not a MessageIntegrity");
+        }
+    }
+
+    #[test]
+    fn test_error_code_encode_decode() {
+        let error_code = ErrorCode::new(400, "Bad Request".to_string());
+        let encoded = Value::ErrorCode(error_code).encode();
+        let decoded = Value::decode(AttrType::ErrorCode, &encoded).unwrap();
+
+        if let Value::ErrorCode(decoded_error) = decoded {
+            assert_eq!(decoded_error.code, 400);
+            assert_eq!(decoded_error.reason, "Bad Request");
+        } else {
+            panic!("Decoded value is not an ErrorCode");
+        }
+    }
+
+    #[test]
+    fn test_unknown_attributes_encode_decode() {
+        let unknown_attrs = UnknownAttributes::new(vec![0x0001, 0x0002, 0x0003]);
+        let encoded = Value::UnknownAttributes(unknown_attrs).encode();
+        let decoded = Value::decode(AttrType::UnknownAttributes, &encoded).unwrap();
+
+        if let Value::UnknownAttributes(decoded_attrs) = decoded {
+            assert_eq!(decoded_attrs.attributes, vec![0x0001, 0x0002, 0x0003]);
+        } else {
+            panic!("Decoded value is not UnknownAttributes");
+        }
+    }
+
+    #[test]
+    fn test_attribute_padding() {
+        let attr = Value::Username(Username::new("abcde".to_string())).into_attribute();
+        let encoded = attr.encode();
+        assert_eq!(encoded.len(), 12);
+        assert_eq!(&encoded[2..4], &[0, 5]);
+
+        let (decoded, consumed) = Attribute::decode(&encoded).unwrap();
+        assert_eq!(consumed, 12);
+        if let Value::Username(username) = decoded.value {
+            assert_eq!(username.username, "abcde");
+        } else {
+            panic!("Decoded value is not a Username");
+        }
+    }
+
+    #[test]
+    fn test_xor_mapped_address_encode_decode() {
+        let addr: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let xor_mapped = XorMappedAddress::new(addr, &TRANSACTION_ID);
+        let encoded = Value::XorMappedAddress(xor_mapped).encode();
+        // Port and address go on the wire XOR'ed with the magic cookie
+        assert_eq!(&encoded[..4], &[0x00, 0x01, 0xA1, 0x47]);
+        assert_eq!(&encoded[4..], &[0xE1, 0x12, 0xA6, 0x43]);
+
+        let decoded = Value::decode(AttrType::XorMappedAddress, &encoded).unwrap();
+        if let Value::XorMappedAddress(decoded_address) = decoded {
+            assert_eq!(decoded_address.addr(&TRANSACTION_ID), addr);
+        } else {
+            panic!("Decoded value is not a XorMappedAddress");
+        }
+    }
+
+    #[test]
+    fn test_xor_mapped_address_ipv6_encode_decode() {
+        let addr: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let xor_mapped = XorMappedAddress::new(addr, &TRANSACTION_ID);
+        let encoded = Value::XorMappedAddress(xor_mapped).encode();
+        assert_eq!(encoded.len(), 20);
+
+        let decoded = Value::decode(AttrType::XorMappedAddress, &encoded).unwrap();
+        if let Value::XorMappedAddress(decoded_address) = decoded {
+            assert_eq!(decoded_address.addr(&TRANSACTION_ID), addr);
+        } else {
+            panic!("Decoded value is not a XorMappedAddress");
+        }
+    }
+
+    #[test]
+    fn test_message_integrity_long_term_key() {
+        // Credentials from RFC 5769 section 2.4, already SASLprep'ed
+        let key = MessageIntegrity::long_term_key(
+            "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}",
+            "example.org",
+            "TheMatrIX",
+        );
+        assert_eq!(
+            key,
+            [
+                0xE8, 0xCA, 0x7A, 0xD5, 0x9D, 0x5E, 0xB0, 0x51, 0x8E, 0x31, 0x29, 0x11, 0xD2, 0xDA,
+                0xB2, 0xA9
+            ]
+        );
+    }
+
+    #[test]
+    fn test_message_integrity_verify() {
+        let integrity = MessageIntegrity::compute(b"key", b"data");
+        assert!(integrity.verify(b"key", b"data"));
+        assert!(!integrity.verify(b"other key", b"data"));
+        assert!(!integrity.verify(b"key", b"other data"));
+    }
+    /// Encode through `Attribute` so padding is exercised too
+    fn round_trip(value: Value) -> Value {
+        let encoded = value.into_attribute().encode();
+        assert_eq!(encoded.len() % 4, 0);
+        let (decoded, consumed) = Attribute::decode(&encoded).unwrap();
+        assert_eq!(consumed, encoded.len());
+        decoded.value
+    }
+
+    #[test]
+    fn test_channel_number_encode_decode() {
+        let encoded = Value::ChannelNumber(ChannelNumber::new(0x4001)).encode();
+        assert_eq!(encoded, [0x40, 0x01, 0, 0]);
+
+        if let Value::ChannelNumber(decoded) =
+            round_trip(Value::ChannelNumber(ChannelNumber::new(0x4001)))
+        {
+            assert_eq!(decoded.number, 0x4001);
+        } else {
+            panic!("Decoded value is not a ChannelNumber");
+        }
+    }
+
+    #[test]
+    fn test_lifetime_encode_decode() {
+        if let Value::Lifetime(decoded) = round_trip(Value::Lifetime(Lifetime::new(3600))) {
+            assert_eq!(decoded.lifetime, 3600);
+        } else {
+            panic!("Decoded value is not a Lifetime");
+        }
+    }
+
+    #[test]
+    fn test_xor_peer_address_encode_decode() {
+        let addr: SocketAddr = "[2001:db8::1]:49152".parse().unwrap();
+        let peer = XorPeerAddress::new(addr, &TRANSACTION_ID);
+        assert_ne!(peer.address, addr);
+
+        if let Value::XorPeerAddress(decoded) = round_trip(Value::XorPeerAddress(peer)) {
+            assert_eq!(decoded.addr(&TRANSACTION_ID), addr);
+        } else {
+            panic!("Decoded value is not a XorPeerAddress");
+        }
+    }
+
+    #[test]
+    fn test_data_encode_decode() {
+        let data = Data::new(vec![1, 2, 3, 4, 5]);
+        let encoded = Value::Data(data).into_attribute().encode();
+        assert_eq!(&encoded[2..4], &[0, 5]);
+
+        if let Value::Data(decoded) = round_trip(Value::Data(Data::new(vec![1, 2, 3, 4, 5]))) {
+            assert_eq!(decoded.data, vec![1, 2, 3, 4, 5]);
+        } else {
+            panic!("Decoded value is not a Data");
+        }
+    }
+
+    #[test]
+    fn test_xor_relayed_address_encode_decode() {
+        let addr: SocketAddr = "203.0.113.5:50000".parse().unwrap();
+        let relayed = XorRelayedAddress::new(addr, &TRANSACTION_ID);
+
+        if let Value::XorRelayedAddress(decoded) = round_trip(Value::XorRelayedAddress(relayed)) {
+            assert_eq!(decoded.addr(&TRANSACTION_ID), addr);
+        } else {
+            panic!("Decoded value is not a XorRelayedAddress");
+        }
+    }
+
+    #[test]
+    fn test_requested_transport_encode_decode() {
+        let transport = RequestedTransport::new(RequestedTransport::UDP);
+        assert_eq!(Value::RequestedTransport(transport).encode(), [17, 0, 0, 0]);
+
+        let transport = RequestedTransport::new(RequestedTransport::UDP);
+        if let Value::RequestedTransport(decoded) = round_trip(Value::RequestedTransport(transport))
+        {
+            assert_eq!(decoded.protocol, RequestedTransport::UDP);
+        } else {
+            panic!("Decoded value is not a RequestedTransport");
+        }
+    }
+
+    #[test]
+    fn test_requested_address_family_encode_decode() {
+        let family = RequestedAddressFamily::new(RequestedAddressFamily::IPV6);
+        if let Value::RequestedAddressFamily(decoded) =
+            round_trip(Value::RequestedAddressFamily(family))
+        {
+            assert_eq!(decoded.family, RequestedAddressFamily::IPV6);
+        } else {
+            panic!("Decoded value is not a RequestedAddressFamily");
+        }
+    }
+
+    #[test]
+    fn test_even_port_encode_decode() {
+        assert_eq!(Value::EvenPort(EvenPort::new(true)).encode(), [0x80]);
+
+        if let Value::EvenPort(decoded) = round_trip(Value::EvenPort(EvenPort::new(true))) {
+            assert!(decoded.reserve);
+        } else {
+            panic!("Decoded value is not an EvenPort");
+        }
+        if let Value::EvenPort(decoded) = round_trip(Value::EvenPort(EvenPort::new(false))) {
+            assert!(!decoded.reserve);
+        } else {
+            panic!("Decoded value is not an EvenPort");
+        }
+    }
+
+    #[test]
+    fn test_dont_fragment_encode_decode() {
+        let encoded = Value::DontFragment(DontFragment::new())
+            .into_attribute()
+            .encode();
+        assert_eq!(encoded, [0x00, 0x1A, 0, 0]);
+
+        let decoded = round_trip(Value::DontFragment(DontFragment::new()));
+        assert!(matches!(decoded, Value::DontFragment(_)));
+    }
+
+    #[test]
+    fn test_reservation_token_encode_decode() {
+        let token = ReservationToken::new([1, 2, 3, 4, 5, 6, 7, 8]);
+        if let Value::ReservationToken(decoded) = round_trip(Value::ReservationToken(token)) {
+            assert_eq!(decoded.token, [1, 2, 3, 4, 5, 6, 7, 8]);
+        } else {
+            panic!("Decoded value is not a ReservationToken");
+        }
+    }
+
+    #[test]
+    fn test_additional_address_family_encode_decode() {
+        let family = AdditionalAddressFamily::new(RequestedAddressFamily::IPV6);
+        if let Value::AdditionalAddressFamily(decoded) =
+            round_trip(Value::AdditionalAddressFamily(family))
+        {
+            assert_eq!(decoded.family, RequestedAddressFamily::IPV6);
+        } else {
+            panic!("Decoded value is not an AdditionalAddressFamily");
+        }
+    }
+
+    #[test]
+    fn test_address_error_code_encode_decode() {
+        let error = AddressErrorCode::new(
+            RequestedAddressFamily::IPV6,
+            440,
+            "Address Family not Supported".to_string(),
+        );
+        if let Value::AddressErrorCode(decoded) = round_trip(Value::AddressErrorCode(error)) {
+            assert_eq!(decoded.family, RequestedAddressFamily::IPV6);
+            assert_eq!(decoded.code, 440);
+            assert_eq!(decoded.reason, "Address Family not Supported");
+        } else {
+            panic!("Decoded value is not an AddressErrorCode");
+        }
+    }
+
+    #[test]
+    fn test_icmp_encode_decode() {
+        // Destination unreachable, fragmentation needed, next-hop MTU 1400
+        let icmp = Icmp::new(3, 4, 1400);
+        assert_eq!(
+            Value::Icmp(Icmp::new(3, 4, 1400)).encode(),
+            [0, 0, 0x01, 0x84, 0, 0, 0x05, 0x78]
+        );
+
+        if let Value::Icmp(decoded) = round_trip(Value::Icmp(icmp)) {
+            assert_eq!(decoded.icmp_type, 3);
+            assert_eq!(decoded.code, 4);
+            assert_eq!(decoded.error_data, 1400);
+        } else {
+            panic!("Decoded value is not an Icmp");
+        }
+    }
+
+    #[test]
+    fn test_connection_id_encode_decode() {
+        let connection_id = ConnectionId::new(0xDEADBEEF);
+        if let Value::ConnectionId(decoded) = round_trip(Value::ConnectionId(connection_id)) {
+            assert_eq!(decoded.connection_id, 0xDEADBEEF);
+        } else {
+            panic!("Decoded value is not a ConnectionId");
+        }
+    }
+
+    #[test]
+    fn test_alternate_server_encode_decode() {
+        for addr in ["192.0.2.1:3478", "[2001:db8::1]:5349"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let encoded = Value::AlternateServer(AlternateServer::new(addr)).encode();
+            let decoded = Value::decode(AttrType::AlternateServer, &encoded).unwrap();
+
+            if let Value::AlternateServer(decoded_server) = decoded {
+                assert_eq!(decoded_server.address, addr);
+            } else {
+                panic!("Decoded value is not an AlternateServer");
+            }
+        }
+    }
+
+    #[test]
+    fn test_alternate_domain_encode_decode() {
+        let domain = AlternateDomain::new("stun2.example.com".to_string());
+        let encoded = Value::AlternateDomain(domain).encode();
+        let decoded = Value::decode(AttrType::AlternateDomain, &encoded).unwrap();
+
+        if let Value::AlternateDomain(decoded_domain) = decoded {
+            assert_eq!(decoded_domain.domain, "stun2.example.com");
+        } else {
+            panic!("Decoded value is not an AlternateDomain");
+        }
+    }
+
+    #[test]
+    fn test_software_encode_decode() {
+        let software = Software::new("totem 0.1.0".to_string()).unwrap();
+        let encoded = Value::Software(software).encode();
+        let decoded = Value::decode(AttrType::Software, &encoded).unwrap();
+
+        if let Value::Software(decoded_software) = decoded {
+            assert_eq!(decoded_software.software, "totem 0.1.0");
+        } else {
+            panic!("Decoded value is not a Software");
+        }
+    }
+
+    #[test]
+    fn test_software_limits() {
+        assert!(Software::new("a".repeat(127)).is_ok());
+        assert_eq!(
+            Software::new("a".repeat(128)).unwrap_err(),
+            Error::ValueTooLong(0x8022)
+        );
+        // Characters are counted, not bytes
+        assert!(Software::new("\u{1F980}".repeat(127)).is_ok());
+        assert!(Software::new("\u{1F980}".repeat(128)).is_err());
+
+        let decoded = Software::decode("\u{1F980}".repeat(200).as_bytes()).unwrap();
+        assert_eq!(decoded.software, "\u{1F980}".repeat(127));
+    }
+
+    #[test]
+    fn test_priority_encode_decode() {
+        let priority = Priority::new(0x6E0001FF);
+        if let Value::Priority(decoded) = round_trip(Value::Priority(priority)) {
+            assert_eq!(decoded.priority, 0x6E0001FF);
+        } else {
+            panic!("Decoded value is not a Priority");
+        }
+    }
+
+    #[test]
+    fn test_use_candidate_encode_decode() {
+        let encoded = Value::UseCandidate(UseCandidate::new())
+            .into_attribute()
+            .encode();
+        assert_eq!(encoded, [0x00, 0x25, 0, 0]);
+
+        let decoded = round_trip(Value::UseCandidate(UseCandidate::new()));
+        assert!(matches!(decoded, Value::UseCandidate(_)));
+    }
+
+    #[test]
+    fn test_ice_role_encode_decode() {
+        let controlled = IceControlled::new(0x932FF9B151263B36);
+        assert_eq!(
+            Value::IceControlled(IceControlled::new(0x932FF9B151263B36)).encode(),
+            [0x93, 0x2F, 0xF9, 0xB1, 0x51, 0x26, 0x3B, 0x36]
+        );
+        if let Value::IceControlled(decoded) = round_trip(Value::IceControlled(controlled)) {
+            assert_eq!(decoded.tie_breaker, 0x932FF9B151263B36);
+        } else {
+            panic!("Decoded value is not an IceControlled");
+        }
+
+        let controlling = IceControlling::new(1);
+        if let Value::IceControlling(decoded) = round_trip(Value::IceControlling(controlling)) {
+            assert_eq!(decoded.tie_breaker, 1);
+        } else {
+            panic!("Decoded value is not an IceControlling");
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_compute() {
+        // The standard CRC-32 check value
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(
+            Fingerprint::compute(b"123456789").crc,
+            0xCBF43926 ^ 0x5354554E
+        );
+
+        if let Value::Fingerprint(decoded) = round_trip(Value::Fingerprint(Fingerprint::new(7))) {
+            assert_eq!(decoded.crc, 7);
+        } else {
+            panic!("Decoded value is not a Fingerprint");
         }
     }
 }
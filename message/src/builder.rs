@@ -0,0 +1,196 @@
+//! Building messages an attribute at a time. MESSAGE-INTEGRITY and
+//! FINGERPRINT aren't added like other attributes, they're asked for and
+//! computed last in the order RFC 8489 section 14 puts them.
+
+use crate::{
+    attribute::{AttrType, AttributeValue, StunAttribute, Value},
+    error::Error,
+    header::{Header, HeaderType},
+    Message,
+};
+
+#[derive(Debug)]
+pub struct MessageBuilder {
+    message: Message,
+    integrity: Option<Vec<u8>>,
+    fingerprint: bool,
+    /// The first MESSAGE-INTEGRITY or FINGERPRINT added as a value
+    misplaced: Option<u16>,
+}
+
+impl MessageBuilder {
+    /// Start a message with a new random transaction id
+    pub fn new(header_type: HeaderType) -> Self {
+        Self::with_header(Header::with_random_id(header_type))
+    }
+
+    /// Start a message answering or retrying one with this header
+    pub fn with_header(header: Header) -> Self {
+        Self {
+            message: Message::new(header, vec![]),
+            integrity: None,
+            fingerprint: false,
+            misplaced: None,
+        }
+    }
+
+    // Chained like a builder, not an `Add` that would need a left-hand side
+    #[allow(clippy::should_implement_trait)]
+    pub fn add<T: AttributeValue>(self, value: T) -> Self {
+        self.add_value(value.into_value())
+    }
+
+    /// Like `add`, for values whose type is only known at runtime
+    pub fn add_value(mut self, value: Value) -> Self {
+        let attr = value.into_attribute();
+        let last = [AttrType::MessageIntegrity, AttrType::Fingerprint];
+        if last.iter().any(|last| *last as u16 == attr.attr_type) {
+            self.misplaced.get_or_insert(attr.attr_type);
+        } else {
+            self.message.attributes.push(attr);
+        }
+        self
+    }
+
+    /// Add an attribute of a type this crate doesn't know, encoded for this
+    /// message's transaction id
+    pub fn add_attribute<T: StunAttribute>(self, attr: &T) -> Self {
+        let raw = attr.to_raw(&self.message.header);
+        self.add_value(Value::Raw(raw))
+    }
+
+    /// Like `add`, only if there is a value
+    pub fn add_opt<T: AttributeValue>(self, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.add(value),
+            None => self,
+        }
+    }
+
+    /// End with MESSAGE-INTEGRITY keyed with `key`
+    pub fn integrity(mut self, key: &[u8]) -> Self {
+        self.integrity = Some(key.to_vec());
+        self
+    }
+
+    /// End with FINGERPRINT, after MESSAGE-INTEGRITY if there is one
+    pub fn fingerprint(mut self) -> Self {
+        self.fingerprint = true;
+        self
+    }
+
+    /// The message with MESSAGE-INTEGRITY and FINGERPRINT computed over
+    /// everything before them. Adding either as a value is an error, they
+    /// can't be computed before the rest of the message is known.
+    pub fn build(self) -> Result<Message, Error> {
+        if let Some(attr_type) = self.misplaced {
+            return Err(Error::Misplaced(attr_type));
+        }
+        let mut message = self.message;
+        if let Some(key) = self.integrity {
+            message.add_integrity(&key);
+        }
+        if self.fingerprint {
+            message.add_fingerprint();
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribute::{Fingerprint, MessageIntegrity, Software, Username, XorMappedAddress};
+
+    /// A vendor attribute, a counter XORed with the transaction id the way
+    /// XOR-MAPPED-ADDRESS hides its port
+    #[derive(Debug, PartialEq)]
+    struct Sequence(u32);
+
+    impl StunAttribute for Sequence {
+        const TYPE: u16 = 0xC123;
+
+        fn encode(&self, header: &Header, buf: &mut Vec<u8>) {
+            let key = u32::from_be_bytes(header.transaction_id[12..].try_into().unwrap());
+            buf.extend_from_slice(&(self.0 ^ key).to_be_bytes());
+        }
+
+        fn decode(value: &[u8], header: &Header) -> Result<Self, Error> {
+            let value: [u8; 4] = value.try_into().map_err(|_| Error::Truncated)?;
+            let key = u32::from_be_bytes(header.transaction_id[12..].try_into().unwrap());
+            Ok(Sequence(u32::from_be_bytes(value) ^ key))
+        }
+    }
+
+    #[test]
+    fn test_builder_finalizes_integrity_and_fingerprint() {
+        let header = Header::new(HeaderType::BindingRequest, [5; 16]);
+        let message = MessageBuilder::with_header(header)
+            .fingerprint()
+            .add(Username::new("alice".into()))
+            .integrity(b"key")
+            .add_opt(Software::new("test".into()).ok())
+            .add_opt(None::<XorMappedAddress>)
+            .build()
+            .unwrap();
+
+        let types: Vec<_> = message
+            .attributes
+            .iter()
+            .map(|attr| attr.attr_type)
+            .collect();
+        assert_eq!(types, [0x0006, 0x8022, 0x0008, 0x8028]);
+        let data = message.encode();
+        assert!(Message::verify_integrity(&data, b"key"));
+        assert!(Message::verify_fingerprint(&data));
+        assert_eq!(message.get::<Username>().unwrap().username, "alice");
+        assert!(message.get::<XorMappedAddress>().is_none());
+    }
+
+    #[test]
+    fn test_builder_rejects_integrity_and_fingerprint_values() {
+        let fingerprint = MessageBuilder::new(HeaderType::BindingRequest)
+            .add(Fingerprint::compute(&[]))
+            .build();
+        assert_eq!(fingerprint.unwrap_err(), Error::Misplaced(0x8028));
+
+        let integrity = MessageBuilder::new(HeaderType::BindingRequest)
+            .add(MessageIntegrity::compute(b"key", &[]))
+            .build();
+        assert_eq!(integrity.unwrap_err(), Error::Misplaced(0x0008));
+    }
+
+    #[test]
+    fn test_builder_carries_vendor_attributes() {
+        let header = Header::new(HeaderType::BindingRequest, [9; 16]);
+        let data = MessageBuilder::with_header(header)
+            .add_attribute(&Sequence(42))
+            .add(Username::new("alice".into()))
+            .fingerprint()
+            .build()
+            .unwrap()
+            .encode();
+        // XORed on the wire
+        assert_eq!(data[24..28], (42 ^ 0x09090909u32).to_be_bytes());
+
+        let message = Message::decode(&data).unwrap();
+        assert_eq!(message.get_attribute::<Sequence>(), Some(Ok(Sequence(42))));
+        assert_eq!(message.get::<Username>().unwrap().username, "alice");
+        // Attributes this crate knows can be read the same way
+        assert_eq!(
+            message
+                .get_attribute::<Username>()
+                .unwrap()
+                .unwrap()
+                .username,
+            "alice"
+        );
+        assert_eq!(message.encode(), data);
+
+        let misplaced =
+            MessageBuilder::with_header(Header::new(HeaderType::BindingRequest, [9; 16]))
+                .add_attribute(&Fingerprint::new(0))
+                .build();
+        assert_eq!(misplaced.unwrap_err(), Error::Misplaced(0x8028));
+    }
+}
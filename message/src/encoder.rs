@@ -0,0 +1,146 @@
+//! Writing a message an attribute at a time into a buffer the caller owns,
+//! for answers that shouldn't allocate. The length in the header is kept up
+//! to date as attributes are added.
+
+use crate::{
+    attribute::{padded, AttrType, Attribute, Fingerprint, MessageIntegrity},
+    error::Error,
+    header::Header,
+};
+
+pub struct Encoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Encoder<'b> {
+    /// Start a message with `header` at the start of `buf`
+    pub fn new(buf: &'b mut [u8], header: &Header) -> Result<Self, Error> {
+        let start = buf.get_mut(..20).ok_or(Error::BufferTooSmall)?;
+        start[..2].copy_from_slice(&(header.header_type as u16).to_be_bytes());
+        start[2..4].copy_from_slice(&[0, 0]);
+        start[4..].copy_from_slice(&header.transaction_id);
+        Ok(Self { buf, len: 20 })
+    }
+
+    pub fn add(&mut self, attr: &Attribute) -> Result<(), Error> {
+        let len = attr.encoded_len();
+        let mut out = self
+            .buf
+            .get_mut(self.len..self.len + len)
+            .ok_or(Error::BufferTooSmall)?;
+        attr.encode_to(&mut out);
+        self.grow(len);
+        Ok(())
+    }
+
+    /// Add an attribute whose value is already encoded, like a SOFTWARE
+    /// kept for every response
+    pub fn add_raw(&mut self, attr_type: AttrType, value: &[u8]) -> Result<(), Error> {
+        let len = 4 + padded(value.len());
+        let out = self
+            .buf
+            .get_mut(self.len..self.len + len)
+            .ok_or(Error::BufferTooSmall)?;
+        out[..2].copy_from_slice(&(attr_type as u16).to_be_bytes());
+        out[2..4].copy_from_slice(&(value.len() as u16).to_be_bytes());
+        out[4..4 + value.len()].copy_from_slice(value);
+        out[4 + value.len()..].fill(0);
+        self.grow(len);
+        Ok(())
+    }
+
+    /// Add MESSAGE-INTEGRITY over everything so far
+    pub fn add_integrity(&mut self, key: &[u8]) -> Result<(), Error> {
+        if self.buf.len() < self.len + 24 {
+            return Err(Error::BufferTooSmall);
+        }
+        // The length has to already account for the integrity attribute
+        self.set_length(self.len + 24);
+        let integrity = MessageIntegrity::compute(key, &self.buf[..self.len]);
+        self.add_raw(AttrType::MessageIntegrity, &integrity.integrity)
+    }
+
+    /// Add FINGERPRINT, which has to come last
+    pub fn add_fingerprint(&mut self) -> Result<(), Error> {
+        if self.buf.len() < self.len + 8 {
+            return Err(Error::BufferTooSmall);
+        }
+        self.set_length(self.len + 8);
+        let fingerprint = Fingerprint::compute(&self.buf[..self.len]);
+        self.add_raw(AttrType::Fingerprint, &fingerprint.crc.to_be_bytes())
+    }
+
+    /// The encoded message, at the start of the buffer
+    pub fn finish(self) -> &'b [u8] {
+        &self.buf[..self.len]
+    }
+
+    fn grow(&mut self, len: usize) {
+        self.len += len;
+        self.set_length(self.len);
+    }
+
+    /// Set the length in the header for a message `len` bytes long
+    fn set_length(&mut self, len: usize) {
+        self.buf[2..4].copy_from_slice(&((len - 20) as u16).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attribute::{Username, Value, XorMappedAddress},
+        header::HeaderType,
+        Message,
+    };
+
+    #[test]
+    fn test_encoder_matches_message_encode() {
+        let addr = "192.0.2.1:32853".parse().unwrap();
+        let mut message = Message::new(Header::new(HeaderType::BindingResponse, [3; 16]), vec![]);
+        let mut buf = [0; 256];
+        let mut encoder = Encoder::new(&mut buf, &message.header).unwrap();
+        for value in [
+            Value::XorMappedAddress(XorMappedAddress::new(addr, &[3; 16])),
+            Value::Username(Username::new("bob".into())),
+        ] {
+            let attr = value.into_attribute();
+            encoder.add(&attr).unwrap();
+            message.attributes.push(attr);
+        }
+        encoder.add_integrity(b"key").unwrap();
+        encoder.add_fingerprint().unwrap();
+        message.add_integrity(b"key");
+        message.add_fingerprint();
+
+        let encoded = encoder.finish();
+        assert_eq!(encoded, message.encode());
+        assert!(Message::verify_integrity(encoded, b"key"));
+        assert!(Message::verify_fingerprint(encoded));
+        assert_eq!(message.encode_to_slice(&mut [0; 256]), Ok(encoded.len()));
+    }
+
+    #[test]
+    fn test_encoder_buffer_too_small() {
+        let header = Header::new(HeaderType::BindingRequest, [3; 16]);
+        assert!(Encoder::new(&mut [0; 19], &header).is_err());
+
+        let mut buf = [0; 28];
+        let mut encoder = Encoder::new(&mut buf, &header).unwrap();
+        assert_eq!(
+            encoder.add_raw(AttrType::Software, b"four!"),
+            Err(Error::BufferTooSmall)
+        );
+        encoder.add_raw(AttrType::Software, b"four").unwrap();
+        assert_eq!(encoder.add_fingerprint(), Err(Error::BufferTooSmall));
+        assert_eq!(encoder.finish().len(), 28);
+
+        let message = Message::new(header, vec![]);
+        assert_eq!(
+            message.encode_to_slice(&mut [0; 19]),
+            Err(Error::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt;
+
+/// Errors returned while decoding a STUN message from the wire, or encoding
+/// one into a buffer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The buffer ended before the header or an attribute was complete
+    Truncated,
+    /// The message type is not one this crate knows about
+    UnknownMessageType(u16),
+    /// The attribute type is not one this crate knows about
+    UnknownAttribute(u16),
+    /// A ChannelData frame with a number outside 0x4000 through 0x4FFF
+    InvalidChannel(u16),
+    /// An attribute value over the length its RFC allows
+    ValueTooLong(u16),
+    /// An attribute value too short for its type, or text that isn't UTF-8
+    InvalidValue(u16),
+    /// The buffer given to encode into can't hold the message
+    BufferTooSmall,
+    /// An attribute where it can't go, like anything after FINGERPRINT
+    Misplaced(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "message truncated"),
+            Error::UnknownMessageType(t) => write!(f, "unknown message type {:#06x}", t),
+            Error::UnknownAttribute(t) => write!(f, "unknown attribute type {:#06x}", t),
+            Error::InvalidChannel(c) => write!(f, "invalid channel number {:#06x}", c),
+            Error::ValueTooLong(t) => write!(f, "value of attribute {:#06x} too long", t),
+            Error::InvalidValue(t) => write!(f, "malformed value of attribute {:#06x}", t),
+            Error::BufferTooSmall => write!(f, "buffer too small for the message"),
+            Error::Misplaced(t) => write!(f, "attribute {:#06x} out of place", t),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
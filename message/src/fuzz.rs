@@ -0,0 +1,212 @@
+//! What the fuzz targets in `fuzz/` check on every input, kept here so the
+//! inputs that once broke them are replayed by `cargo test` from
+//! `fuzz/regressions`. Decoding must never panic, and whatever decodes has
+//! to encode to bytes that decode and encode the same again.
+
+use crate::{
+    attribute::{AttrType, Value},
+    packet::Packet,
+    validate::Policy,
+    view::MessageRef,
+    Message,
+};
+
+/// Decode `data` every way a message can be
+pub fn decode_message(data: &[u8]) {
+    let _ = Packet::decode(data);
+    let _ = Message::verify_integrity(data, b"key");
+    let _ = Message::verify_fingerprint(data);
+    let _ = Message::validate(data, Policy::Strict);
+    let _ = Message::validate(data, Policy::Lenient);
+    let view = MessageRef::parse(data);
+    if let Ok(view) = view {
+        view.attributes().for_each(|attr| drop(attr.decode()));
+    }
+    let Ok(message) = Message::decode(data) else {
+        return;
+    };
+
+    let view = view.expect("a message that decodes is framed soundly");
+    let types = message.attributes.iter().map(|attr| attr.attr_type);
+    assert!(types.eq(view.attributes().map(|attr| attr.raw_type())));
+    let encoded = message.encode();
+    let decoded = Message::decode(&encoded).expect("an encoded message decodes");
+    assert_eq!(decoded.encode(), encoded);
+}
+
+/// Decode the rest of `data` as the value of the attribute type in its
+/// first two bytes
+pub fn decode_attribute(data: &[u8]) {
+    let Some((attr_type, value)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let Ok(attr_type) = AttrType::from_be_bytes(*attr_type) else {
+        return;
+    };
+    let Ok(value) = Value::decode(attr_type, value) else {
+        return;
+    };
+    let encoded = value.encode();
+    let decoded = Value::decode(attr_type, &encoded).expect("an encoded value decodes");
+    assert_eq!(decoded.encode(), encoded);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attribute::*,
+        header::{Header, HeaderType},
+    };
+    use proptest::{collection::vec, prelude::*, sample::Index};
+    use std::{
+        fs,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        path::Path,
+    };
+
+    const REGRESSIONS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/regressions");
+
+    fn text(max: usize) -> impl Strategy<Value = String> + Clone {
+        vec(any::<char>(), 0..=max).prop_map(String::from_iter)
+    }
+
+    fn bytes(max: usize) -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..=max)
+    }
+
+    /// Without the flow info and scope id of IPv6 socket addresses, which
+    /// aren't sent
+    fn addr() -> impl Strategy<Value = SocketAddr> + Clone {
+        prop_oneof![
+            any::<u32>().prop_map(|ip| IpAddr::V4(Ipv4Addr::from(ip))),
+            any::<u128>().prop_map(|ip| IpAddr::V6(Ipv6Addr::from(ip))),
+        ]
+        .prop_flat_map(|ip| any::<u16>().prop_map(move |port| SocketAddr::new(ip, port)))
+    }
+
+    fn ipv4() -> impl Strategy<Value = (u16, Ipv4Addr)> + Clone {
+        any::<(u16, u32)>().prop_map(|(port, ip)| (port, Ipv4Addr::from(ip)))
+    }
+
+    fn error_code() -> impl Strategy<Value = (u16, String)> + Clone {
+        (300..700u16, text(32))
+    }
+
+    /// A value of every type, as a sender keeping to the RFCs could send it
+    fn value(tx_id: [u8; 16]) -> impl Strategy<Value = Value> {
+        prop_oneof![
+            ipv4().prop_map(|(port, ip)| Value::MappedAddress(MappedAddress::new(1, port, ip))),
+            ipv4().prop_map(|(port, ip)| Value::ResponseAddress(ResponseAddress::new(1, port, ip))),
+            any::<(bool, bool)>()
+                .prop_map(|(ip, port)| Value::ChangeRequest(ChangeRequest::new(ip, port))),
+            ipv4().prop_map(|(port, ip)| Value::SourceAddress(SourceAddress::new(1, port, ip))),
+            ipv4().prop_map(|(port, ip)| Value::ChangedAddress(ChangedAddress::new(1, port, ip))),
+            text(64).prop_map(|name| Value::Username(Username::new(name))),
+            text(64).prop_map(|password| Value::Password(Password::new(password))),
+            any::<[u8; 20]>().prop_map(|hmac| Value::MessageIntegrity(MessageIntegrity::new(hmac))),
+            error_code().prop_map(|(code, reason)| Value::ErrorCode(ErrorCode::new(code, reason))),
+            vec(any::<u16>(), 0..8)
+                .prop_map(|types| Value::UnknownAttributes(UnknownAttributes::new(types))),
+            ipv4().prop_map(|(port, ip)| Value::ReflectedFrom(ReflectedFrom::new(1, port, ip))),
+            (0x4000..0x5000u16)
+                .prop_map(|channel| Value::ChannelNumber(ChannelNumber::new(channel))),
+            any::<u32>().prop_map(|lifetime| Value::Lifetime(Lifetime::new(lifetime))),
+            addr().prop_map(move |addr| Value::XorPeerAddress(XorPeerAddress::new(addr, &tx_id))),
+            bytes(256).prop_map(|data| Value::Data(Data::new(data))),
+            text(64).prop_map(|realm| Value::Realm(Realm::new(realm))),
+            text(64).prop_map(|nonce| Value::Nonce(Nonce::new(nonce))),
+            addr().prop_map(move |addr| {
+                Value::XorRelayedAddress(XorRelayedAddress::new(addr, &tx_id))
+            }),
+            any::<u8>().prop_map(|protocol| {
+                Value::RequestedTransport(RequestedTransport::new(protocol))
+            }),
+            addr().prop_map(move |addr| {
+                Value::XorMappedAddress(XorMappedAddress::new(addr, &tx_id))
+            }),
+            any::<u8>().prop_map(|family| {
+                Value::RequestedAddressFamily(RequestedAddressFamily::new(family))
+            }),
+            any::<bool>().prop_map(|reserve| Value::EvenPort(EvenPort::new(reserve))),
+            Just(()).prop_map(|()| Value::DontFragment(DontFragment::new())),
+            any::<[u8; 8]>()
+                .prop_map(|token| Value::ReservationToken(ReservationToken::new(token))),
+            any::<u8>().prop_map(|family| {
+                Value::AdditionalAddressFamily(AdditionalAddressFamily::new(family))
+            }),
+            (any::<u8>(), error_code()).prop_map(|(family, (code, reason))| {
+                Value::AddressErrorCode(AddressErrorCode::new(family, code, reason))
+            }),
+            (0..0x200u16, 0..0x80u8, any::<u32>())
+                .prop_map(|(kind, code, error)| { Value::Icmp(Icmp::new(kind, code, error)) }),
+            any::<u32>().prop_map(|id| Value::ConnectionId(ConnectionId::new(id))),
+            addr().prop_map(|addr| Value::AlternateServer(AlternateServer::new(addr))),
+            text(64).prop_map(|domain| Value::AlternateDomain(AlternateDomain::new(domain))),
+            text(Software::MAX_CHARS / 4)
+                .prop_map(|software| Value::Software(Software::new(software).unwrap())),
+            any::<u32>().prop_map(|priority| Value::Priority(Priority::new(priority))),
+            Just(()).prop_map(|()| Value::UseCandidate(UseCandidate::new())),
+            any::<u32>().prop_map(|crc| Value::Fingerprint(Fingerprint::new(crc))),
+            any::<u64>().prop_map(|tie| Value::IceControlled(IceControlled::new(tie))),
+            any::<u64>().prop_map(|tie| Value::IceControlling(IceControlling::new(tie))),
+            (any::<u16>(), bytes(32))
+                .prop_filter("a type we don't know", |(attr_type, _)| {
+                    AttrType::from_u16(*attr_type).is_err()
+                })
+                .prop_map(|(attr_type, value)| Value::Raw(RawAttribute::new(attr_type, value))),
+        ]
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        let header_type = (0..0x200u16).prop_filter_map("a message type", |header_type| {
+            HeaderType::from_u16(header_type).ok()
+        });
+        (header_type, any::<[u8; 16]>()).prop_flat_map(|(header_type, tx_id)| {
+            vec(value(tx_id), 0..12).prop_map(move |values| {
+                let attributes = values.into_iter().map(Value::into_attribute).collect();
+                Message::new(Header::new(header_type, tx_id), attributes)
+            })
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2000))]
+
+        #[test]
+        fn test_messages_round_trip(message in message()) {
+            let encoded = message.encode();
+            prop_assert_eq!(Message::decode(&encoded), Ok(message));
+            decode_message(&encoded);
+        }
+
+        #[test]
+        fn test_mutated_messages_never_panic(
+            message in message(),
+            mutations in vec((any::<Index>(), any::<u8>()), 1..4),
+            cut in any::<Index>(),
+        ) {
+            let mut encoded = message.encode();
+            for (i, byte) in mutations {
+                let i = i.index(encoded.len());
+                encoded[i] = byte;
+            }
+            encoded.truncate(cut.index(encoded.len() + 1));
+            decode_message(&encoded);
+            decode_attribute(&encoded[encoded.len().min(20)..]);
+        }
+    }
+
+    #[test]
+    fn test_fuzz_regressions() {
+        for target in ["decode_message", "decode_attribute"] {
+            for entry in fs::read_dir(Path::new(REGRESSIONS).join(target)).unwrap() {
+                let data = fs::read(entry.unwrap().path()).unwrap();
+                match target {
+                    "decode_message" => decode_message(&data),
+                    _ => decode_attribute(&data),
+                }
+            }
+        }
+    }
+}
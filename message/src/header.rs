@@ -1,4 +1,10 @@
-#[derive(Debug)]
+use crate::error::Error;
+
+/// Fixed value in the first four bytes of the transaction id of every
+/// RFC 5389 message, also the key XOR'ed into XOR-*-ADDRESS attributes
+pub const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xA4, 0x42];
+
+#[derive(Debug, PartialEq)]
 pub struct Header {
     pub header_type: HeaderType,
     pub transaction_id: [u8; 16],
@@ -13,7 +19,8 @@ impl Header {
     }
 
     pub fn with_random_id(header_type: HeaderType) -> Self {
-        let transaction_id: [u8; 16] = rand::random();
+        let mut transaction_id: [u8; 16] = rand::random();
+        transaction_id[..4].copy_from_slice(&MAGIC_COOKIE);
         Self {
             header_type,
             transaction_id,
@@ -25,27 +32,145 @@ impl Header {
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum HeaderType {
     BindingRequest = 0x0001,
+    BindingIndication = 0x0011,
     BindingResponse = 0x0101,
     BindingErrorResponse = 0x0111,
     SharedSecretRequest = 0x0002,
     SharedSecretResponse = 0x0102,
     SharedSecretErrorResponse = 0x0112,
+    AllocateRequest = 0x0003,
+    AllocateResponse = 0x0103,
+    AllocateErrorResponse = 0x0113,
+    RefreshRequest = 0x0004,
+    RefreshResponse = 0x0104,
+    RefreshErrorResponse = 0x0114,
+    SendIndication = 0x0016,
+    DataIndication = 0x0017,
+    CreatePermissionRequest = 0x0008,
+    CreatePermissionResponse = 0x0108,
+    CreatePermissionErrorResponse = 0x0118,
+    ChannelBindRequest = 0x0009,
+    ChannelBindResponse = 0x0109,
+    ChannelBindErrorResponse = 0x0119,
+    ConnectRequest = 0x000A,
+    ConnectResponse = 0x010A,
+    ConnectErrorResponse = 0x011A,
+    ConnectionBindRequest = 0x000B,
+    ConnectionBindResponse = 0x010B,
+    ConnectionBindErrorResponse = 0x011B,
+    ConnectionAttemptIndication = 0x001C,
 }
 
 impl HeaderType {
-    pub fn from_be_bytes(bytes: [u8; 2]) -> Self {
+    pub fn from_be_bytes(bytes: [u8; 2]) -> Result<Self, Error> {
         Self::from_u16(u16::from_be_bytes(bytes))
     }
 
-    pub fn from_u16(value: u16) -> Self {
+    pub fn from_u16(value: u16) -> Result<Self, Error> {
         match value {
-            0x0001 => Self::BindingRequest,
-            0x0101 => Self::BindingResponse,
-            0x0111 => Self::BindingErrorResponse,
-            0x0002 => Self::SharedSecretRequest,
-            0x0102 => Self::SharedSecretResponse,
-            0x0112 => Self::SharedSecretErrorResponse,
-            _ => panic!("Invalid HeaderType value: {}", value),
+            0x0001 => Ok(Self::BindingRequest),
+            0x0011 => Ok(Self::BindingIndication),
+            0x0101 => Ok(Self::BindingResponse),
+            0x0111 => Ok(Self::BindingErrorResponse),
+            0x0002 => Ok(Self::SharedSecretRequest),
+            0x0102 => Ok(Self::SharedSecretResponse),
+            0x0112 => Ok(Self::SharedSecretErrorResponse),
+            0x0003 => Ok(Self::AllocateRequest),
+            0x0103 => Ok(Self::AllocateResponse),
+            0x0113 => Ok(Self::AllocateErrorResponse),
+            0x0004 => Ok(Self::RefreshRequest),
+            0x0104 => Ok(Self::RefreshResponse),
+            0x0114 => Ok(Self::RefreshErrorResponse),
+            0x0016 => Ok(Self::SendIndication),
+            0x0017 => Ok(Self::DataIndication),
+            0x0008 => Ok(Self::CreatePermissionRequest),
+            0x0108 => Ok(Self::CreatePermissionResponse),
+            0x0118 => Ok(Self::CreatePermissionErrorResponse),
+            0x0009 => Ok(Self::ChannelBindRequest),
+            0x0109 => Ok(Self::ChannelBindResponse),
+            0x0119 => Ok(Self::ChannelBindErrorResponse),
+            0x000A => Ok(Self::ConnectRequest),
+            0x010A => Ok(Self::ConnectResponse),
+            0x011A => Ok(Self::ConnectErrorResponse),
+            0x000B => Ok(Self::ConnectionBindRequest),
+            0x010B => Ok(Self::ConnectionBindResponse),
+            0x011B => Ok(Self::ConnectionBindErrorResponse),
+            0x001C => Ok(Self::ConnectionAttemptIndication),
+            _ => Err(Error::UnknownMessageType(value)),
+        }
+    }
+
+    /// Class bits of the message type, C1 is bit 8 and C0 is bit 4
+    pub const fn class(self) -> Class {
+        let value = self as u16;
+        match ((value >> 7) & 0b10) | ((value >> 4) & 0b01) {
+            0b00 => Class::Request,
+            0b01 => Class::Indication,
+            0b10 => Class::SuccessResponse,
+            _ => Class::ErrorResponse,
         }
     }
+
+    /// Success response type for the same method
+    pub fn success_response(self) -> Result<Self, Error> {
+        Self::from_u16(self as u16 & !0x0110 | 0x0100)
+    }
+
+    /// Error response type for the same method
+    pub fn error_response(self) -> Result<Self, Error> {
+        Self::from_u16(self as u16 | 0x0110)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_type_class() {
+        assert_eq!(HeaderType::BindingRequest.class(), Class::Request);
+        assert_eq!(HeaderType::BindingIndication.class(), Class::Indication);
+        assert_eq!(HeaderType::BindingResponse.class(), Class::SuccessResponse);
+        assert_eq!(
+            HeaderType::BindingErrorResponse.class(),
+            Class::ErrorResponse
+        );
+    }
+
+    #[test]
+    fn test_header_type_responses() {
+        use HeaderType::*;
+        assert_eq!(AllocateRequest.success_response(), Ok(AllocateResponse));
+        assert_eq!(AllocateRequest.error_response(), Ok(AllocateErrorResponse));
+        assert_eq!(BindingRequest.error_response(), Ok(BindingErrorResponse));
+        assert!(SendIndication.success_response().is_err());
+        assert_eq!(ConnectRequest.success_response(), Ok(ConnectResponse));
+        assert_eq!(
+            ConnectionBindRequest.error_response(),
+            Ok(ConnectionBindErrorResponse)
+        );
+        assert_eq!(ConnectionAttemptIndication.class(), Class::Indication);
+    }
+
+    #[test]
+    fn test_header_with_random_id_has_magic_cookie() {
+        let header = Header::with_random_id(HeaderType::BindingRequest);
+        assert_eq!(header.transaction_id[..4], MAGIC_COOKIE);
+    }
+
+    #[test]
+    fn test_header_type_unknown() {
+        assert_eq!(
+            HeaderType::from_u16(0x0fff),
+            Err(Error::UnknownMessageType(0x0fff))
+        );
+    }
 }
@@ -0,0 +1,352 @@
+use std::{fmt, net::SocketAddr};
+
+use crate::{
+    attribute::{
+        ErrorCode, IceControlled, IceControlling, Priority, UseCandidate, Username, Value,
+        XorMappedAddress,
+    },
+    builder::MessageBuilder,
+    error::Error,
+    header::{Header, HeaderType},
+    Message,
+};
+
+/// Error code answering a check from an agent that claims the same role,
+/// RFC 8445 section 7.3.1.1
+pub const ROLE_CONFLICT: u16 = 487;
+
+/// An agent's username fragment and password, as exchanged over signalling
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub ufrag: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub const fn new(ufrag: String, password: String) -> Self {
+        Credentials { ufrag, password }
+    }
+}
+
+/// Which agent decides on the candidate pairs, with the tie-breaker that
+/// settles it when both think they do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Controlling(u64),
+    Controlled(u64),
+}
+
+impl Role {
+    /// What to do about a check from an agent in `theirs`, RFC 8445 section
+    /// 7.3.1.1. The agent with the larger tie-breaker keeps its role.
+    pub fn resolve(self, theirs: Role) -> Resolution {
+        match (self, theirs) {
+            (Role::Controlling(ours), Role::Controlling(theirs)) if ours >= theirs => {
+                Resolution::Reject
+            }
+            (Role::Controlled(ours), Role::Controlled(theirs)) if ours < theirs => {
+                Resolution::Reject
+            }
+            (Role::Controlling(ours), Role::Controlling(_)) => {
+                Resolution::Switch(Role::Controlled(ours))
+            }
+            (Role::Controlled(ours), Role::Controlled(_)) => {
+                Resolution::Switch(Role::Controlling(ours))
+            }
+            _ => Resolution::Agree,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Role::Controlling(tie_breaker) => {
+                Value::IceControlling(IceControlling::new(tie_breaker))
+            }
+            Role::Controlled(tie_breaker) => Value::IceControlled(IceControlled::new(tie_breaker)),
+        }
+    }
+}
+
+/// Outcome of comparing roles with the sender of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The roles differ, carry on
+    Agree,
+    /// Take the other role and answer the check as usual
+    Switch(Role),
+    /// Keep the role and answer with 487 Role Conflict
+    Reject,
+}
+
+/// What a connectivity check carries besides credentials
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Check {
+    /// Priority of the peer reflexive candidate the check would discover
+    pub priority: u32,
+    pub role: Role,
+    /// Set by the controlling agent to nominate the pair
+    pub nominate: bool,
+}
+
+/// Both agents' credentials for checks on a data stream. Checks sent are
+/// signed with the remote password and carry `remote:local` as USERNAME,
+/// checks received the other way round, responses are signed with the
+/// password of the agent answering.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub local: Credentials,
+    pub remote: Credentials,
+}
+
+impl Session {
+    pub const fn new(local: Credentials, remote: Credentials) -> Self {
+        Session { local, remote }
+    }
+
+    /// A Binding Request checking the pair it's sent on
+    pub fn request(&self, check: &Check) -> Message {
+        let username = format!("{}:{}", self.remote.ufrag, self.local.ufrag);
+        MessageBuilder::new(HeaderType::BindingRequest)
+            .add(Username::new(username))
+            .add(Priority::new(check.priority))
+            .add_value(check.role.into_value())
+            .add_opt(check.nominate.then(UseCandidate::new))
+            .integrity(self.remote.password.as_bytes())
+            .fingerprint()
+            .build()
+            .expect("only plain attributes added")
+    }
+
+    /// Decode and authenticate a check from the remote agent
+    pub fn read_request(&self, data: &[u8]) -> Result<(Message, Check), CheckError> {
+        let message = Message::decode(data)?;
+        if message.header.header_type != HeaderType::BindingRequest {
+            return Err(CheckError::NotACheck);
+        }
+        if !Message::verify_fingerprint(data) {
+            return Err(CheckError::Fingerprint);
+        }
+        let expected = format!("{}:{}", self.local.ufrag, self.remote.ufrag);
+        let username = message
+            .get::<Username>()
+            .map(|value| value.username.as_str());
+        let priority = message.get::<Priority>().map(|value| value.priority);
+        let role = match (
+            message.get::<IceControlling>(),
+            message.get::<IceControlled>(),
+        ) {
+            (Some(value), _) => Some(Role::Controlling(value.tie_breaker)),
+            (None, Some(value)) => Some(Role::Controlled(value.tie_breaker)),
+            (None, None) => None,
+        };
+        let nominate = message.get::<UseCandidate>().is_some();
+        if username != Some(expected.as_str())
+            || !Message::verify_integrity(data, self.local.password.as_bytes())
+        {
+            return Err(CheckError::Unauthorized);
+        }
+        let check = Check {
+            priority: priority.ok_or(CheckError::MissingAttribute("PRIORITY"))?,
+            role: role.ok_or(CheckError::MissingAttribute(
+                "ICE-CONTROLLING or ICE-CONTROLLED",
+            ))?,
+            nominate,
+        };
+        Ok((message, check))
+    }
+
+    /// The success response to a check, telling the sender the address it
+    /// came from
+    pub fn response(&self, request: &Message, source: SocketAddr) -> Message {
+        let transaction_id = request.header.transaction_id;
+        let mapped = XorMappedAddress::new(source, &transaction_id);
+        self.answer(
+            Header::new(HeaderType::BindingResponse, transaction_id),
+            vec![Value::XorMappedAddress(mapped)],
+        )
+    }
+
+    /// The 487 Role Conflict response to a check
+    pub fn role_conflict(&self, request: &Message) -> Message {
+        let error = ErrorCode::new(ROLE_CONFLICT, "Role Conflict".into());
+        self.answer(
+            Header::new(
+                HeaderType::BindingErrorResponse,
+                request.header.transaction_id,
+            ),
+            vec![Value::ErrorCode(error)],
+        )
+    }
+
+    fn answer(&self, header: Header, values: Vec<Value>) -> Message {
+        values
+            .into_iter()
+            .fold(
+                MessageBuilder::with_header(header),
+                MessageBuilder::add_value,
+            )
+            .integrity(self.local.password.as_bytes())
+            .fingerprint()
+            .build()
+            .expect("only plain attributes added")
+    }
+
+    /// Decode and authenticate the answer to one of our checks, returning
+    /// the address the remote agent saw it come from
+    pub fn read_response(&self, data: &[u8]) -> Result<SocketAddr, CheckError> {
+        let message = Message::decode(data)?;
+        let success = match message.header.header_type {
+            HeaderType::BindingResponse => true,
+            HeaderType::BindingErrorResponse => false,
+            _ => return Err(CheckError::NotACheck),
+        };
+        if !Message::verify_fingerprint(data) {
+            return Err(CheckError::Fingerprint);
+        }
+        if !Message::verify_integrity(data, self.remote.password.as_bytes()) {
+            return Err(CheckError::Unauthorized);
+        }
+        // Only what MESSAGE-INTEGRITY covers
+        if success {
+            let mapped = message
+                .get::<XorMappedAddress>()
+                .ok_or(CheckError::MissingAttribute("XOR-MAPPED-ADDRESS"))?;
+            return Ok(mapped.addr(&message.header.transaction_id));
+        }
+        match message.get::<ErrorCode>() {
+            Some(error) if error.code == ROLE_CONFLICT => Err(CheckError::RoleConflict),
+            Some(error) => Err(CheckError::Failed(error.code)),
+            None => Err(CheckError::MissingAttribute("ERROR-CODE")),
+        }
+    }
+}
+
+/// Why a connectivity check or its response was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckError {
+    Decode(Error),
+    /// Not a Binding Request, or a Binding response when one is expected
+    NotACheck,
+    /// FINGERPRINT is missing or doesn't match
+    Fingerprint,
+    /// USERNAME or MESSAGE-INTEGRITY is missing or wrong, answered with 401
+    Unauthorized,
+    /// Answered with 400
+    MissingAttribute(&'static str),
+    /// The remote agent answered with 487 Role Conflict
+    RoleConflict,
+    /// The remote agent answered with another error code
+    Failed(u16),
+}
+
+impl From<Error> for CheckError {
+    fn from(err: Error) -> Self {
+        CheckError::Decode(err)
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckError::Decode(err) => write!(f, "{}", err),
+            CheckError::NotACheck => write!(f, "not a connectivity check"),
+            CheckError::Fingerprint => write!(f, "missing or wrong FINGERPRINT"),
+            CheckError::Unauthorized => write!(f, "wrong USERNAME or MESSAGE-INTEGRITY"),
+            CheckError::MissingAttribute(name) => write!(f, "missing {}", name),
+            CheckError::RoleConflict => write!(f, "role conflict"),
+            CheckError::Failed(code) => write!(f, "check failed with error {}", code),
+        }
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> (Session, Session) {
+        let a = Credentials::new("evtj".into(), "VOkJxbRl1RmTxUk/WvJxBt".into());
+        let b = Credentials::new("h6vY".into(), "tBmfJ9sMfDkAE+cuMbNNYp".into());
+        (Session::new(a.clone(), b.clone()), Session::new(b, a))
+    }
+
+    #[test]
+    fn test_rfc5769_sample_request() {
+        // RFC 5769 section 2.1, sent by h6vY to evtj
+        let request = crate::vectors::fixture("rfc5769/request.hex");
+        let (evtj, _) = sessions();
+        let (_, check) = evtj.read_request(&request).unwrap();
+        assert_eq!(
+            check,
+            Check {
+                priority: 0x6E0001FF,
+                role: Role::Controlled(0x932FF9B151263B36),
+                nominate: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_round_trip() {
+        let (a, b) = sessions();
+        let check = Check {
+            priority: 1_853_824_767,
+            role: Role::Controlling(42),
+            nominate: true,
+        };
+        let request = a.request(&check).encode();
+        let (message, received) = b.read_request(&request).unwrap();
+        assert_eq!(received, check);
+        // Only the agent the check is for can read it
+        assert_eq!(
+            a.read_request(&request).unwrap_err(),
+            CheckError::Unauthorized
+        );
+
+        let source = "198.51.100.7:50000".parse().unwrap();
+        let response = b.response(&message, source).encode();
+        assert_eq!(a.read_response(&response), Ok(source));
+        assert_eq!(b.read_response(&response), Err(CheckError::Unauthorized));
+
+        let conflict = b.role_conflict(&message).encode();
+        assert_eq!(a.read_response(&conflict), Err(CheckError::RoleConflict));
+
+        // An address added after MESSAGE-INTEGRITY isn't vouched for
+        let header = Header::new(HeaderType::BindingResponse, message.header.transaction_id);
+        let mut unsigned = Message::new(header, vec![]);
+        unsigned.add_integrity(b.local.password.as_bytes());
+        let mapped = XorMappedAddress::new(source, &message.header.transaction_id);
+        unsigned
+            .attributes
+            .push(Value::XorMappedAddress(mapped).into_attribute());
+        unsigned.add_fingerprint();
+        assert_eq!(
+            a.read_response(&unsigned.encode()),
+            Err(CheckError::MissingAttribute("XOR-MAPPED-ADDRESS"))
+        );
+
+        let mut tampered = request.clone();
+        tampered[30] ^= 1;
+        assert_eq!(
+            b.read_request(&tampered).unwrap_err(),
+            CheckError::Fingerprint
+        );
+    }
+
+    #[test]
+    fn test_role_conflicts() {
+        use Resolution::*;
+        use Role::*;
+        assert_eq!(Controlling(1).resolve(Controlled(2)), Agree);
+        assert_eq!(Controlled(2).resolve(Controlling(1)), Agree);
+        // The larger tie-breaker keeps its role
+        assert_eq!(Controlling(2).resolve(Controlling(1)), Reject);
+        assert_eq!(
+            Controlling(1).resolve(Controlling(2)),
+            Switch(Controlled(1))
+        );
+        assert_eq!(Controlled(2).resolve(Controlled(1)), Switch(Controlling(2)));
+        assert_eq!(Controlled(1).resolve(Controlled(2)), Reject);
+    }
+}
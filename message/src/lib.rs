@@ -1,10 +1,32 @@
 pub mod attribute;
+pub mod builder;
+pub mod encoder;
+pub mod error;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzz;
 pub mod header;
+pub mod ice;
+pub mod packet;
+pub mod punch;
+pub mod transport;
+pub mod validate;
+#[cfg(test)]
+mod vectors;
+pub mod view;
+
+use bytes::BufMut;
 
-use attribute::Attribute;
+use attribute::{
+    padded, AttrType, Attribute, AttributeValue, Fingerprint, MessageIntegrity, StunAttribute,
+    Value,
+};
+use error::Error;
 use header::{Header, HeaderType};
+use validate::{Policy, Violation};
+use view::MessageRef;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Message {
     pub header: Header,
     pub attributes: Vec<Attribute>,
@@ -15,37 +37,186 @@ impl Message {
         Self { header, attributes }
     }
 
-    pub fn decode(data: &[u8]) -> Self {
-        let header_type = HeaderType::from_be_bytes([data[0], data[1]]);
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 20 {
+            return Err(Error::Truncated);
+        }
+        let header_type = HeaderType::from_be_bytes([data[0], data[1]])?;
         let message_length = u16::from_be_bytes([data[2], data[3]]);
         let transaction_id = data[4..20].try_into().unwrap();
         let header = Header::new(header_type, transaction_id);
 
-        let data = &data[20..];
+        let data = data
+            .get(20..20 + message_length as usize)
+            .ok_or(Error::Truncated)?;
+        // Attributes this crate doesn't know are kept raw, whoever handles
+        // the message decides what to do about them
         let mut attributes = Vec::new();
         let mut attr_read = 0;
-        while attr_read < message_length as usize {
-            let (attr, len) = Attribute::decode(&data[attr_read as usize..]);
+        while attr_read < data.len() {
+            let (attr, len) = Attribute::decode(&data[attr_read..])?;
             attributes.push(attr);
             attr_read += len;
         }
 
-        Self::new(header, attributes)
+        Ok(Self::new(header, attributes))
+    }
+
+    /// The first attribute of type `T`
+    pub fn get<T: AttributeValue>(&self) -> Option<&T> {
+        self.get_all().next()
+    }
+
+    /// Every attribute of type `T`, in order
+    pub fn get_all<'a, T: AttributeValue + 'a>(&'a self) -> impl Iterator<Item = &'a T> {
+        self.counted().filter_map(|attr| T::from_value(&attr.value))
+    }
+
+    /// The first attribute of a type implemented outside this crate,
+    /// decoded for this message's transaction id
+    pub fn get_attribute<T: StunAttribute>(&self) -> Option<Result<T, Error>> {
+        let attr = self.counted().find(|attr| attr.attr_type == T::TYPE)?;
+        Some(match &attr.value {
+            Value::Raw(raw) => T::decode(&raw.value, &self.header),
+            value => T::decode(&value.encode(), &self.header),
+        })
+    }
+
+    /// Comprehension-required attributes this crate doesn't know, which a
+    /// request has to be rejected for
+    pub fn unknown_required(&self) -> impl Iterator<Item = u16> + '_ {
+        self.attributes.iter().filter_map(|attr| match &attr.value {
+            Value::Raw(raw) if raw.is_comprehension_required() => Some(raw.attr_type),
+            _ => None,
+        })
+    }
+
+    /// The attributes that count, only FINGERPRINT after MESSAGE-INTEGRITY
+    /// and nothing after FINGERPRINT, RFC 8489 section 14
+    fn counted(&self) -> impl Iterator<Item = &Attribute> {
+        let position = |attr_type: AttrType| {
+            self.attributes
+                .iter()
+                .position(|attr| attr.attr_type == attr_type as u16)
+        };
+        let integrity = position(AttrType::MessageIntegrity).unwrap_or(usize::MAX);
+        let end = position(AttrType::Fingerprint).map_or(self.attributes.len(), |end| end + 1);
+        self.attributes[..end]
+            .iter()
+            .enumerate()
+            .filter(move |(index, attr)| {
+                *index <= integrity || matches!(attr.value, Value::Fingerprint(_))
+            })
+            .map(|(_, attr)| attr)
+    }
+
+    /// Append a MESSAGE-INTEGRITY attribute computed over the message as
+    /// encoded so far
+    pub fn add_integrity(&mut self, key: &[u8]) {
+        let mut data = self.encode();
+        // The length has to already account for the integrity attribute
+        let length = (data.len() - 20 + 24) as u16;
+        data[2..4].copy_from_slice(&length.to_be_bytes());
+
+        let integrity = MessageIntegrity::compute(key, &data);
+        self.attributes
+            .push(Value::MessageIntegrity(integrity).into_attribute());
+    }
+
+    /// Check the MESSAGE-INTEGRITY attribute of an encoded message, returns
+    /// false if there is none
+    pub fn verify_integrity(data: &[u8], key: &[u8]) -> bool {
+        let mut offset = 20;
+        while offset + 4 <= data.len() {
+            let attr_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            if attr_type == AttrType::MessageIntegrity as u16 {
+                let Some(integrity) = data.get(offset + 4..offset + 24) else {
+                    return false;
+                };
+                let mut covered = data[..offset].to_vec();
+                let length = (offset - 20 + 24) as u16;
+                covered[2..4].copy_from_slice(&length.to_be_bytes());
+                return MessageIntegrity::decode(integrity)
+                    .is_ok_and(|integrity| integrity.verify(key, &covered));
+            }
+            offset += 4 + padded(length);
+        }
+        false
+    }
+
+    /// Append a FINGERPRINT attribute, which has to come last
+    pub fn add_fingerprint(&mut self) {
+        let mut data = self.encode();
+        let length = (data.len() - 20 + 8) as u16;
+        data[2..4].copy_from_slice(&length.to_be_bytes());
+
+        let fingerprint = Fingerprint::compute(&data);
+        self.attributes
+            .push(Value::Fingerprint(fingerprint).into_attribute());
+    }
+
+    /// Check the FINGERPRINT attribute of an encoded message, returns false
+    /// if there is none or it isn't the last attribute
+    pub fn verify_fingerprint(data: &[u8]) -> bool {
+        let Some(offset) = data.len().checked_sub(8).filter(|offset| *offset >= 20) else {
+            return false;
+        };
+        let attr_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
+        let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]);
+        if attr_type != AttrType::Fingerprint as u16 || length != 4 {
+            return false;
+        }
+        let crc = Fingerprint::compute(&data[..offset]).crc;
+        Fingerprint::decode(&data[offset + 4..]).is_ok_and(|fingerprint| fingerprint.crc == crc)
+    }
+
+    /// Check an encoded message against `policy`, for what parsing lets
+    /// through
+    pub fn validate(data: &[u8], policy: Policy) -> Result<(), Violation> {
+        MessageRef::parse(data)?.validate(policy)
     }
 
     pub fn encode(&self) -> Vec<u8> {
-        let mut attr_data = Vec::new();
+        let mut data = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut data);
+        data
+    }
+
+    /// Append the encoded message to `buf`, so one buffer can be cleared
+    /// and reused for many messages
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        self.encode_to(buf).expect("a Vec grows to fit");
+    }
+
+    /// Write the encoded message to `buf`, returning its length. Nothing is
+    /// written if it doesn't fit.
+    pub fn encode_to<B: BufMut>(&self, buf: &mut B) -> Result<usize, Error> {
+        let len = self.encoded_len();
+        if buf.remaining_mut() < len {
+            return Err(Error::BufferTooSmall);
+        }
+        buf.put_u16(self.header.header_type as u16);
+        buf.put_u16((len - 20) as u16);
+        buf.put_slice(&self.header.transaction_id);
         for attr in &self.attributes {
-            attr_data.extend_from_slice(&attr.encode());
+            attr.encode_to(buf);
         }
-        let message_length = attr_data.len() as u16;
+        Ok(len)
+    }
 
-        let mut data = Vec::new();
-        data.extend_from_slice(&(self.header.header_type as u16).to_be_bytes());
-        data.extend_from_slice(&message_length.to_be_bytes());
-        data.extend_from_slice(&self.header.transaction_id);
-        data.extend_from_slice(&attr_data);
-        data
+    /// Like `encode_to`, into the start of `buf`
+    pub fn encode_to_slice(&self, mut buf: &mut [u8]) -> Result<usize, Error> {
+        self.encode_to(&mut buf)
+    }
+
+    /// Length on the wire, header included
+    pub fn encoded_len(&self) -> usize {
+        20 + self
+            .attributes
+            .iter()
+            .map(Attribute::encoded_len)
+            .sum::<usize>()
     }
 }
 
@@ -72,7 +243,7 @@ mod tests {
         let encoded = original_message.encode();
 
         // Decode the message
-        let decoded_message = Message::decode(&encoded);
+        let decoded_message = Message::decode(&encoded).unwrap();
 
         // Verify the header
         assert_eq!(
@@ -95,8 +266,8 @@ mod tests {
 
         // Check ChangeRequest
         if let Value::ChangeRequest(req) = &decoded_message.attributes[1].value {
-            assert_eq!(req.change_ip, true);
-            assert_eq!(req.change_port, false);
+            assert!(req.change_ip);
+            assert!(!req.change_port);
         } else {
             panic!("Second attribute is not ChangeRequest");
         }
@@ -116,4 +287,117 @@ mod tests {
             panic!("Fourth attribute is not ErrorCode");
         }
     }
+
+    #[test]
+    fn test_message_encode_decode_indication() {
+        let header = Header::new(HeaderType::BindingIndication, [2; 16]);
+        let encoded = Message::new(header, vec![]).encode();
+
+        let decoded_message = Message::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded_message.header.header_type,
+            HeaderType::BindingIndication
+        );
+        assert!(decoded_message.attributes.is_empty());
+    }
+
+    #[test]
+    fn test_message_integrity_round_trip() {
+        let header = Header::new(HeaderType::AllocateRequest, [3; 16]);
+        let attributes =
+            vec![Value::Username(Username::new("testuser".to_string())).into_attribute()];
+        let mut message = Message::new(header, attributes);
+        message.add_integrity(b"secret");
+
+        let encoded = message.encode();
+        assert!(Message::verify_integrity(&encoded, b"secret"));
+        assert!(!Message::verify_integrity(&encoded, b"wrong"));
+
+        let mut tampered = encoded.clone();
+        tampered[24] ^= 1;
+        assert!(!Message::verify_integrity(&tampered, b"secret"));
+    }
+
+    #[test]
+    fn test_message_fingerprint_round_trip() {
+        let header = Header::new(HeaderType::BindingRequest, [4; 16]);
+        let attributes =
+            vec![Value::Username(Username::new("testuser".to_string())).into_attribute()];
+        let mut message = Message::new(header, attributes);
+        message.add_integrity(b"secret");
+        message.add_fingerprint();
+
+        let encoded = message.encode();
+        assert!(Message::verify_fingerprint(&encoded));
+        // The fingerprint doesn't get in the way of integrity
+        assert!(Message::verify_integrity(&encoded, b"secret"));
+
+        let mut tampered = encoded.clone();
+        tampered[24] ^= 1;
+        assert!(!Message::verify_fingerprint(&tampered));
+        assert!(!Message::verify_fingerprint(&encoded[..encoded.len() - 8]));
+    }
+
+    #[test]
+    fn test_message_decode_keeps_unknown_attributes_raw() {
+        let header = Header::new(HeaderType::BindingRequest, [1; 16]);
+        let mut encoded = Message::new(header, vec![]).encode();
+        // Comprehension-optional, and not a type this crate knows
+        encoded.extend_from_slice(&[0xC0, 0xFF, 0x00, 0x03, b'a', b'b', b'c', 0]);
+        encoded[3] = 8;
+
+        let decoded_message = Message::decode(&encoded).unwrap();
+        assert!(matches!(
+            &decoded_message.attributes[0].value,
+            Value::Raw(raw) if raw.attr_type == 0xC0FF && raw.value == b"abc"
+        ));
+        assert_eq!(decoded_message.unknown_required().count(), 0);
+        assert_eq!(decoded_message.encode(), encoded);
+
+        // An unknown comprehension-required one is left for the caller to
+        // reject
+        encoded[20] = 0x7F;
+        let decoded_message = Message::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded_message.unknown_required().collect::<Vec<_>>(),
+            [0x7FFF]
+        );
+    }
+
+    #[test]
+    fn test_message_get_ignores_attributes_after_integrity() {
+        let header = Header::new(HeaderType::BindingRequest, [1; 16]);
+        let username = |name: &str| Value::Username(Username::new(name.into())).into_attribute();
+        let mut message = Message::new(header, vec![username("alice"), username("bob")]);
+        message.add_integrity(b"key");
+        message.attributes.push(username("mallory"));
+        message.add_fingerprint();
+        message.attributes.push(username("eve"));
+
+        let names: Vec<_> = message
+            .get_all::<Username>()
+            .map(|username| username.username.as_str())
+            .collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert_eq!(message.get::<Username>().unwrap().username, "alice");
+        assert!(message.get::<Fingerprint>().is_some());
+        assert!(message.get::<ChangeRequest>().is_none());
+    }
+
+    #[test]
+    fn test_message_decode_truncated() {
+        let header = Header::new(HeaderType::BindingResponse, [1; 16]);
+        let attributes =
+            vec![Value::Username(Username::new("testuser".to_string())).into_attribute()];
+        let encoded = Message::new(header, attributes).encode();
+
+        assert_eq!(
+            Message::decode(&encoded[..10]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(
+            Message::decode(&encoded[..26]).unwrap_err(),
+            Error::Truncated
+        );
+    }
 }
@@ -0,0 +1,175 @@
+use crate::{attribute::padded, error::Error, Message};
+
+/// Anything that can arrive on a TURN client's five-tuple. STUN messages
+/// and ChannelData frames share the socket and are told apart by the first
+/// two bits, 0b00 for STUN and 0b01 for ChannelData.
+#[derive(Debug)]
+pub enum Packet<'a> {
+    Stun(Message),
+    /// Application data on a channel, `payload` borrows from the buffer the
+    /// packet was decoded from
+    ChannelData {
+        channel: u16,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> Packet<'a> {
+    pub fn decode(data: &'a [u8]) -> Result<Self, Error> {
+        if data.first().is_some_and(|first| first & 0xC0 == 0x40) {
+            let header = data.get(..4).ok_or(Error::Truncated)?;
+            let channel = u16::from_be_bytes([header[0], header[1]]);
+            if !is_channel(channel) {
+                return Err(Error::InvalidChannel(channel));
+            }
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            // Anything after the payload is padding
+            let payload = data.get(4..4 + length).ok_or(Error::Truncated)?;
+            return Ok(Packet::ChannelData { channel, payload });
+        }
+        Message::decode(data).map(Packet::Stun)
+    }
+
+    /// Encode for a datagram transport, ChannelData isn't padded
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.encode_into(&mut data);
+        data
+    }
+
+    /// Like `encode`, appending to `buf` so it can be reused
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Packet::Stun(message) => message.encode_into(buf),
+            Packet::ChannelData { channel, payload } => {
+                buf.extend_from_slice(&channel.to_be_bytes());
+                buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+                buf.extend_from_slice(payload);
+            }
+        }
+    }
+
+    /// Encode for a stream transport, where ChannelData must be padded to a
+    /// multiple of four bytes so the next frame starts aligned
+    pub fn encode_padded(&self) -> Vec<u8> {
+        let mut data = self.encode();
+        data.resize(padded(data.len()), 0);
+        data
+    }
+
+    /// Length of the frame at the start of a stream, padding included,
+    /// once its four byte prefix is available
+    pub fn frame_len(data: &[u8]) -> Option<usize> {
+        let header = data.get(..4)?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        if header[0] & 0xC0 == 0x40 {
+            Some(4 + padded(length))
+        } else {
+            Some(20 + length)
+        }
+    }
+}
+
+/// Channel numbers a client can bind, RFC 8656 section 12
+pub const fn is_channel(number: u16) -> bool {
+    matches!(number, 0x4000..=0x4FFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attribute::{Username, Value},
+        header::{Header, HeaderType},
+    };
+
+    #[test]
+    fn test_packet_decode_stun() {
+        let header = Header::new(HeaderType::BindingRequest, [1; 16]);
+        let encoded = Message::new(header, vec![]).encode();
+
+        match Packet::decode(&encoded).unwrap() {
+            Packet::Stun(message) => {
+                assert_eq!(message.header.header_type, HeaderType::BindingRequest)
+            }
+            packet => panic!("Decoded packet is not STUN: {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_packet_encode_decode_channel_data() {
+        let packet = Packet::ChannelData {
+            channel: 0x4001,
+            payload: b"hello",
+        };
+        let encoded = packet.encode();
+        assert_eq!(encoded, [0x40, 0x01, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+
+        match Packet::decode(&encoded).unwrap() {
+            Packet::ChannelData { channel, payload } => {
+                assert_eq!(channel, 0x4001);
+                assert_eq!(payload, b"hello");
+                // The payload is a view into the original buffer
+                assert_eq!(payload.as_ptr(), encoded[4..].as_ptr());
+            }
+            packet => panic!("Decoded packet is not ChannelData: {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_packet_channel_data_padding() {
+        let packet = Packet::ChannelData {
+            channel: 0x4001,
+            payload: b"hello",
+        };
+        let encoded = packet.encode_padded();
+        assert_eq!(encoded.len(), 12);
+        assert_eq!(Packet::frame_len(&encoded), Some(12));
+
+        // Padding isn't part of the payload
+        match Packet::decode(&encoded).unwrap() {
+            Packet::ChannelData { payload, .. } => assert_eq!(payload, b"hello"),
+            packet => panic!("Decoded packet is not ChannelData: {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_packet_encode_into_reused_buffer() {
+        let header = Header::new(HeaderType::BindingRequest, [1; 16]);
+        let attributes = vec![Value::Username(Username::new("user".into())).into_attribute()];
+        let message = Message::new(header, attributes);
+        let channel_data = Packet::ChannelData {
+            channel: 0x4001,
+            payload: b"hello",
+        };
+
+        let mut buf = Vec::with_capacity(64);
+        let capacity = buf.capacity();
+        for packet in [Packet::Stun(message), channel_data] {
+            buf.clear();
+            packet.encode_into(&mut buf);
+            assert_eq!(buf, packet.encode());
+        }
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_packet_frame_len_stun() {
+        let header = Header::new(HeaderType::BindingRequest, [1; 16]);
+        let encoded = Message::new(header, vec![]).encode();
+        assert_eq!(Packet::frame_len(&encoded), Some(20));
+        assert_eq!(Packet::frame_len(&encoded[..3]), None);
+    }
+
+    #[test]
+    fn test_packet_decode_invalid_channel_data() {
+        assert_eq!(
+            Packet::decode(&[0x50, 0x00, 0, 0]).unwrap_err(),
+            Error::InvalidChannel(0x5000)
+        );
+        assert_eq!(
+            Packet::decode(&[0x40, 0x00, 0, 8, 1, 2]).unwrap_err(),
+            Error::Truncated
+        );
+    }
+}
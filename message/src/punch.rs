@@ -0,0 +1,96 @@
+use std::net::SocketAddr;
+
+/// Port the server's rendezvous service listens on next to STUN
+pub const RENDEZVOUS_PORT: u16 = 3480;
+
+/// Messages of the hole punching demo, one per datagram as a line of text.
+/// Clients register with the server's rendezvous service until it tells
+/// them where their peer is, then probe each other directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Punch {
+    /// Client to rendezvous, the address a STUN server mapped us to
+    Register { session: String, mapped: SocketAddr },
+    /// Rendezvous to client, the peer hasn't registered yet
+    Wait,
+    /// Rendezvous to client, where the peer said it's mapped to
+    Peer(SocketAddr),
+    /// Rendezvous to client, the session already has two peers
+    Full,
+    /// Client to client, asking for an `Ack`
+    Probe { session: String },
+    /// Client to client, a probe got through
+    Ack { session: String },
+}
+
+impl Punch {
+    /// Whether `session` can go in a message, it has to be one word
+    pub fn valid_session(session: &str) -> bool {
+        !session.is_empty() && !session.contains(char::is_whitespace)
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Punch> {
+        let text = std::str::from_utf8(data).ok()?;
+        let mut words = text.split(' ');
+        let punch = match (words.next()?, words.next(), words.next()) {
+            ("REGISTER", Some(session), Some(mapped)) => Punch::Register {
+                session: session.into(),
+                mapped: mapped.parse().ok()?,
+            },
+            ("WAIT", None, None) => Punch::Wait,
+            ("PEER", Some(peer), None) => Punch::Peer(peer.parse().ok()?),
+            ("FULL", None, None) => Punch::Full,
+            ("PROBE", Some(session), None) => Punch::Probe {
+                session: session.into(),
+            },
+            ("ACK", Some(session), None) => Punch::Ack {
+                session: session.into(),
+            },
+            _ => return None,
+        };
+        words.next().is_none().then_some(punch)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Punch::Register { session, mapped } => format!("REGISTER {} {}", session, mapped),
+            Punch::Wait => "WAIT".into(),
+            Punch::Peer(peer) => format!("PEER {}", peer),
+            Punch::Full => "FULL".into(),
+            Punch::Probe { session } => format!("PROBE {}", session),
+            Punch::Ack { session } => format!("ACK {}", session),
+        }
+        .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punch_encode_decode() {
+        let messages = [
+            Punch::Register {
+                session: "demo".into(),
+                mapped: "[2001:db8::1]:40000".parse().unwrap(),
+            },
+            Punch::Wait,
+            Punch::Peer("203.0.113.5:40000".parse().unwrap()),
+            Punch::Full,
+            Punch::Probe {
+                session: "demo".into(),
+            },
+            Punch::Ack {
+                session: "demo".into(),
+            },
+        ];
+        for message in messages {
+            assert_eq!(Punch::decode(&message.encode()), Some(message));
+        }
+        assert_eq!(Punch::decode(b"PEER nowhere"), None);
+        assert_eq!(Punch::decode(b"WAIT WAIT"), None);
+        // STUN on the same socket isn't mistaken for a probe
+        assert_eq!(Punch::decode(&[0, 1, 0, 0, 0x21, 0x12, 0xA4, 0x42]), None);
+        assert!(!Punch::valid_session("two words"));
+    }
+}
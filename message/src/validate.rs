@@ -0,0 +1,286 @@
+//! Checks on a message that parsed, for what parsing lets through. Parsing
+//! only makes sure the attributes fit, whether the values make sense and
+//! the message keeps to the rules of RFC 8489 is up to the `Policy` it's
+//! validated with. Checks read the encoded message, a decoded one no
+//! longer has its length or the families of its XOR'ed addresses.
+
+use std::fmt;
+
+use crate::{
+    attribute::AttrType,
+    error::Error,
+    header::MAGIC_COOKIE,
+    view::{AttributeRef, MessageRef},
+};
+
+/// How much of RFC 8489 a message has to keep to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Everything a sender is asked to do
+    Strict,
+    /// What RFC 3489 clients and sloppy encoders get wrong is let through,
+    /// no magic cookie, no padding after the last attribute, and
+    /// attributes after MESSAGE-INTEGRITY that are ignored anyway. Values
+    /// that can't mean anything are still rejected.
+    Lenient,
+}
+
+/// Why a message that parsed isn't valid, worded to be sent back as the
+/// reason of a 400
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    /// The message doesn't parse at all
+    Malformed(Error),
+    /// The transaction id doesn't start with the magic cookie
+    NoMagicCookie,
+    /// A message length that isn't a multiple of 4
+    Unaligned(u16),
+    /// A family other than IPv4 or IPv6, in an attribute of this type
+    AddressFamily(u16, u8),
+    /// An address of the wrong length for its family
+    AddressLength(u16),
+    /// An ERROR-CODE class outside 3 through 6
+    ErrorClass(u16, u8),
+    /// An ERROR-CODE number of 100 or more
+    ErrorNumber(u16, u8),
+    /// An attribute other than FINGERPRINT after MESSAGE-INTEGRITY, or
+    /// anything after FINGERPRINT
+    Misplaced(u16),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Malformed(err) => write!(f, "{}", err),
+            Violation::NoMagicCookie => write!(f, "no magic cookie"),
+            Violation::Unaligned(len) => write!(f, "length {} not a multiple of 4", len),
+            Violation::AddressFamily(t, family) => {
+                write!(f, "address family {:#04x} in attribute {:#06x}", family, t)
+            }
+            Violation::AddressLength(t) => {
+                write!(f, "address length wrong in attribute {:#06x}", t)
+            }
+            Violation::ErrorClass(t, class) => {
+                write!(f, "error class {} in attribute {:#06x}", class, t)
+            }
+            Violation::ErrorNumber(t, number) => {
+                write!(f, "error number {} in attribute {:#06x}", number, t)
+            }
+            Violation::Misplaced(t) => write!(f, "attribute {:#06x} out of place", t),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+impl From<Error> for Violation {
+    fn from(err: Error) -> Self {
+        Violation::Malformed(err)
+    }
+}
+
+pub(crate) fn validate(message: &MessageRef, policy: Policy) -> Result<(), Violation> {
+    let strict = policy == Policy::Strict;
+    let data = message.as_bytes();
+    if strict && data[4..8] != MAGIC_COOKIE {
+        return Err(Violation::NoMagicCookie);
+    }
+    let length = u16::from_be_bytes([data[2], data[3]]);
+    if strict && length & 0b11 != 0 {
+        return Err(Violation::Unaligned(length));
+    }
+
+    let mut integrity = false;
+    let mut fingerprint = false;
+    for attr in message.attributes() {
+        let attr_type = attr.raw_type();
+        let last = attr_type == AttrType::Fingerprint as u16;
+        if fingerprint || integrity && !last {
+            // Ignored by whoever reads the message, RFC 8489 section 14
+            match strict {
+                true => return Err(Violation::Misplaced(attr_type)),
+                false => continue,
+            }
+        }
+        integrity |= attr_type == AttrType::MessageIntegrity as u16;
+        fingerprint |= last;
+        check_value(&attr)?;
+    }
+    Ok(())
+}
+
+fn check_value(attr: &AttributeRef) -> Result<(), Violation> {
+    use AttrType::*;
+    let Ok(attr_type) = attr.attr_type() else {
+        return Ok(());
+    };
+    let value = attr.value();
+    let malformed = Violation::Malformed(Error::InvalidValue(attr_type as u16));
+    match attr_type {
+        MappedAddress | ResponseAddress | SourceAddress | ChangedAddress | ReflectedFrom
+        | XorPeerAddress | XorRelayedAddress | XorMappedAddress | AlternateServer => {
+            let family = *value.get(1).ok_or(malformed)?;
+            let len = match family {
+                0x01 => 8,
+                0x02 => 20,
+                _ => return Err(Violation::AddressFamily(attr_type as u16, family)),
+            };
+            if value.len() != len {
+                return Err(Violation::AddressLength(attr_type as u16));
+            }
+        }
+        RequestedAddressFamily | AdditionalAddressFamily => {
+            let family = *value.first().ok_or(malformed)?;
+            check_family(attr_type, family)?;
+        }
+        ErrorCode | AddressErrorCode => {
+            let [family, _, class, number] = *value.first_chunk().ok_or(malformed)?;
+            if matches!(attr_type, AddressErrorCode) {
+                check_family(attr_type, family)?;
+            }
+            let class = class & 0x07;
+            if !(3..=6).contains(&class) {
+                return Err(Violation::ErrorClass(attr_type as u16, class));
+            }
+            if number > 99 {
+                return Err(Violation::ErrorNumber(attr_type as u16, number));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_family(attr_type: AttrType, family: u8) -> Result<(), Violation> {
+    match family {
+        0x01 | 0x02 => Ok(()),
+        _ => Err(Violation::AddressFamily(attr_type as u16, family)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attribute::{ErrorCode, Username, XorMappedAddress},
+        builder::MessageBuilder,
+        header::{Header, HeaderType},
+        Message,
+    };
+    use Policy::*;
+
+    fn request() -> Vec<u8> {
+        let mut tx_id = [9; 16];
+        tx_id[..4].copy_from_slice(&MAGIC_COOKIE);
+        let addr = "192.0.2.1:32853".parse().unwrap();
+        MessageBuilder::with_header(Header::new(HeaderType::BindingRequest, tx_id))
+            .add(XorMappedAddress::new(addr, &tx_id))
+            .add(Username::new("alice".into()))
+            .integrity(b"key")
+            .fingerprint()
+            .build()
+            .unwrap()
+            .encode()
+    }
+
+    #[test]
+    fn test_validate_accepts_what_we_encode() {
+        let data = request();
+        assert_eq!(Message::validate(&data, Strict), Ok(()));
+        assert_eq!(Message::validate(&data, Lenient), Ok(()));
+
+        let error = MessageBuilder::new(HeaderType::BindingErrorResponse)
+            .add(ErrorCode::new(420, "Unknown Attribute".into()))
+            .build()
+            .unwrap()
+            .encode();
+        assert_eq!(Message::validate(&error, Strict), Ok(()));
+        assert_eq!(
+            Message::validate(&data[..19], Lenient),
+            Err(Violation::Malformed(Error::Truncated))
+        );
+    }
+
+    #[test]
+    fn test_validate_rfc3489_framing_only_when_lenient() {
+        let mut data = request();
+        data[4] = 0;
+        assert_eq!(
+            Message::validate(&data, Strict),
+            Err(Violation::NoMagicCookie)
+        );
+        assert_eq!(Message::validate(&data, Lenient), Ok(()));
+
+        // USERNAME of 5 bytes last, without its padding
+        let header = Header::new(HeaderType::BindingRequest, [7; 16]);
+        let mut data = MessageBuilder::with_header(header)
+            .add(Username::new("alice".into()))
+            .build()
+            .unwrap()
+            .encode();
+        data.truncate(data.len() - 3);
+        data[3] = 9;
+        assert_eq!(Message::validate(&data, Lenient), Ok(()));
+        data[4..8].copy_from_slice(&MAGIC_COOKIE);
+        assert_eq!(
+            Message::validate(&data, Strict),
+            Err(Violation::Unaligned(9))
+        );
+    }
+
+    #[test]
+    fn test_validate_values() {
+        // XOR-MAPPED-ADDRESS is the first attribute, its family at 25
+        let mut data = request();
+        data[25] = 0x03;
+        let family = Err(Violation::AddressFamily(0x0020, 0x03));
+        assert_eq!(Message::validate(&data, Lenient), family);
+        data[25] = 0x02;
+        let length = Err(Violation::AddressLength(0x0020));
+        assert_eq!(Message::validate(&data, Strict), length);
+
+        let mut error = MessageBuilder::new(HeaderType::BindingErrorResponse)
+            .add(ErrorCode::new(420, "Unknown Attribute".into()))
+            .build()
+            .unwrap()
+            .encode();
+        error[26] = 7;
+        let class = Err(Violation::ErrorClass(0x0009, 7));
+        assert_eq!(Message::validate(&error, Lenient), class);
+        error[26] = 4;
+        error[27] = 100;
+        let number = Message::validate(&error, Lenient).unwrap_err();
+        assert_eq!(number, Violation::ErrorNumber(0x0009, 100));
+        assert_eq!(number.to_string(), "error number 100 in attribute 0x0009");
+    }
+
+    #[test]
+    fn test_validate_attributes_after_integrity() {
+        let mut message = Message::decode(&request()).unwrap();
+        // USERNAME again, after MESSAGE-INTEGRITY and FINGERPRINT
+        let username = message.attributes[1].encode();
+        let mut data = message.encode();
+        data.extend_from_slice(&username);
+        let length = (data.len() - 20) as u16;
+        data[2..4].copy_from_slice(&length.to_be_bytes());
+        assert_eq!(
+            Message::validate(&data, Strict),
+            Err(Violation::Misplaced(0x0006))
+        );
+        assert_eq!(Message::validate(&data, Lenient), Ok(()));
+
+        // Ignored when lenient, even when it makes no sense
+        message.attributes.truncate(3);
+        let mut data = message.encode();
+        let mut xor = message.attributes[0].encode();
+        xor[5] = 0x03;
+        data.extend_from_slice(&xor);
+        let length = (data.len() - 20) as u16;
+        data[2..4].copy_from_slice(&length.to_be_bytes());
+        assert_eq!(
+            Message::validate(&data, Strict),
+            Err(Violation::Misplaced(0x0020))
+        );
+        assert_eq!(Message::validate(&data, Lenient), Ok(()));
+    }
+}
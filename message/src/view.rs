@@ -0,0 +1,290 @@
+//! Borrowed views of encoded messages. Parsing checks the header and that
+//! the attributes fit, then attributes are read straight out of the buffer
+//! as they're asked for, nothing is copied or allocated.
+
+use crate::{
+    attribute::{padded, AttrType, Value},
+    error::Error,
+    header::{Header, HeaderType},
+    validate::{self, Policy, Violation},
+    Message,
+};
+
+/// A STUN message still in the buffer it arrived in
+#[derive(Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    header_type: HeaderType,
+    /// The whole message, header included, without anything after it
+    data: &'a [u8],
+}
+
+impl<'a> MessageRef<'a> {
+    /// Check the header and walk the attributes without decoding their
+    /// values, anything after the length in the header is left out
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        let header = data.get(..20).ok_or(Error::Truncated)?;
+        let header_type = HeaderType::from_be_bytes([header[0], header[1]])?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let data = data.get(..20 + length).ok_or(Error::Truncated)?;
+
+        let mut offset = 20;
+        while offset < data.len() {
+            let attr = data.get(offset..offset + 4).ok_or(Error::Truncated)?;
+            let length = u16::from_be_bytes([attr[2], attr[3]]) as usize;
+            if offset + 4 + length > data.len() {
+                return Err(Error::Truncated);
+            }
+            offset += 4 + padded(length);
+        }
+        Ok(Self { header_type, data })
+    }
+
+    pub fn header_type(&self) -> HeaderType {
+        self.header_type
+    }
+
+    pub fn transaction_id(&self) -> &'a [u8; 16] {
+        self.data[4..20].try_into().unwrap()
+    }
+
+    /// The encoded message, as MESSAGE-INTEGRITY and FINGERPRINT are
+    /// checked against
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn attributes(&self) -> Attributes<'a> {
+        Attributes {
+            data: self.data,
+            offset: 20,
+        }
+    }
+
+    /// The first attribute of a type, later ones are ignored as RFC 8489
+    /// section 14 says, and so is anything after MESSAGE-INTEGRITY but
+    /// FINGERPRINT, like `Message::get` does
+    pub fn get(&self, attr_type: AttrType) -> Option<AttributeRef<'a>> {
+        self.counted()
+            .find(|attr| attr.raw_type() == attr_type as u16)
+    }
+
+    /// The attributes that count, only FINGERPRINT after MESSAGE-INTEGRITY
+    /// and nothing after FINGERPRINT, RFC 8489 section 14
+    fn counted(&self) -> impl Iterator<Item = AttributeRef<'a>> {
+        let (integrity, fingerprint) = (
+            AttrType::MessageIntegrity as u16,
+            AttrType::Fingerprint as u16,
+        );
+        let mut signed = false;
+        self.attributes()
+            .scan(false, move |done, attr| {
+                (!*done).then(|| {
+                    *done = attr.raw_type() == fingerprint;
+                    attr
+                })
+            })
+            .filter(move |attr| {
+                let counts = !signed || attr.raw_type() == fingerprint;
+                signed |= attr.raw_type() == integrity;
+                counts
+            })
+    }
+
+    /// Comprehension-required attributes this crate doesn't know, which a
+    /// request has to be rejected for
+    pub fn unknown_required(&self) -> impl Iterator<Item = u16> + 'a {
+        self.attributes()
+            .map(|attr| attr.raw_type())
+            .filter(|attr_type| *attr_type < 0x8000 && AttrType::from_u16(*attr_type).is_err())
+    }
+
+    /// Check the message against `policy`, still without allocating
+    pub fn validate(&self, policy: Policy) -> Result<(), Violation> {
+        validate::validate(self, policy)
+    }
+
+    /// Decode into an owned message, allocating its attributes
+    pub fn to_message(&self) -> Result<Message, Error> {
+        Message::decode(self.data)
+    }
+
+    pub fn header(&self) -> Header {
+        Header::new(self.header_type, *self.transaction_id())
+    }
+}
+
+/// The attributes of a `MessageRef`, in the order they were sent
+#[derive(Debug, Clone)]
+pub struct Attributes<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = AttributeRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The framing was checked when parsing
+        let attr = self.data.get(self.offset..self.offset + 4)?;
+        let attr_type = u16::from_be_bytes([attr[0], attr[1]]);
+        let length = u16::from_be_bytes([attr[2], attr[3]]) as usize;
+        let value = &self.data[self.offset + 4..self.offset + 4 + length];
+        let offset = self.offset;
+        self.offset += 4 + padded(length);
+        Some(AttributeRef {
+            attr_type,
+            value,
+            offset,
+        })
+    }
+}
+
+/// An attribute still in the buffer it arrived in
+#[derive(Debug, Clone, Copy)]
+pub struct AttributeRef<'a> {
+    attr_type: u16,
+    value: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> AttributeRef<'a> {
+    /// The type as sent, known to this crate or not
+    pub fn raw_type(&self) -> u16 {
+        self.attr_type
+    }
+
+    pub fn attr_type(&self) -> Result<AttrType, Error> {
+        AttrType::from_u16(self.attr_type)
+    }
+
+    /// The value without its padding
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
+
+    /// The value as text, for USERNAME, REALM, NONCE, SOFTWARE and the like
+    pub fn as_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.value).ok()
+    }
+
+    /// Where the attribute starts in the message, header included
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Decode the value into its owned form
+    pub fn decode(&self) -> Result<Value, Error> {
+        Value::decode(self.attr_type()?, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribute::{ChangeRequest, Realm, Software, Username};
+
+    fn encoded() -> Vec<u8> {
+        let header = Header::new(HeaderType::BindingRequest, [7; 16]);
+        let attributes = vec![
+            Value::Username(Username::new("alice".into())).into_attribute(),
+            Value::ChangeRequest(ChangeRequest::new(true, false)).into_attribute(),
+            Value::Software(Software::new("test".into()).unwrap()).into_attribute(),
+        ];
+        let mut message = Message::new(header, attributes);
+        message.add_fingerprint();
+        message.encode()
+    }
+
+    #[test]
+    fn test_message_ref_reads_attributes_in_place() {
+        let data = encoded();
+        let message = MessageRef::parse(&data).unwrap();
+        assert_eq!(message.header_type(), HeaderType::BindingRequest);
+        assert_eq!(message.transaction_id(), &[7; 16]);
+
+        let types: Vec<_> = message.attributes().map(|attr| attr.raw_type()).collect();
+        assert_eq!(types, [0x0006, 0x0003, 0x8022, 0x8028]);
+        let username = message.get(AttrType::Username).unwrap();
+        assert_eq!(username.as_str(), Some("alice"));
+        assert_eq!(username.offset(), 20);
+        // A view into the original buffer
+        assert_eq!(username.value().as_ptr(), data[24..].as_ptr());
+        assert!(matches!(
+            message.get(AttrType::ChangeRequest).unwrap().decode(),
+            Ok(Value::ChangeRequest(ChangeRequest {
+                change_ip: true,
+                change_port: false
+            }))
+        ));
+        assert!(message.get(AttrType::Realm).is_none());
+        assert!(Message::verify_fingerprint(message.as_bytes()));
+        assert_eq!(message.unknown_required().count(), 0);
+    }
+
+    #[test]
+    fn test_views_agree_on_trailing_attributes() {
+        let header = Header::new(HeaderType::BindingRequest, [7; 16]);
+        let attributes = vec![
+            Value::Username(Username::new("alice".into())).into_attribute(),
+            Value::ChangeRequest(ChangeRequest::new(false, false)).into_attribute(),
+        ];
+        let mut message = Message::new(header, attributes);
+        message.add_integrity(b"key");
+        message
+            .attributes
+            .push(Value::ChangeRequest(ChangeRequest::new(true, true)).into_attribute());
+        message.add_fingerprint();
+        message
+            .attributes
+            .push(Value::Realm(Realm::new("late".into())).into_attribute());
+        let data = message.encode();
+
+        let owned = Message::decode(&data).unwrap();
+        let view = MessageRef::parse(&data).unwrap();
+        for attr_type in [
+            AttrType::Username,
+            AttrType::ChangeRequest,
+            AttrType::MessageIntegrity,
+            AttrType::Fingerprint,
+            AttrType::Realm,
+        ] {
+            let from_owned = owned
+                .counted()
+                .find(|attr| attr.attr_type == attr_type as u16)
+                .map(|attr| &attr.value);
+            let from_view = view.get(attr_type).map(|attr| attr.decode().unwrap());
+            assert_eq!(from_owned, from_view.as_ref(), "{:?}", attr_type);
+        }
+        assert_eq!(
+            view.get(AttrType::ChangeRequest).unwrap().decode(),
+            Ok(Value::ChangeRequest(ChangeRequest::new(false, false)))
+        );
+        assert!(view.get(AttrType::Realm).is_none());
+    }
+
+    #[test]
+    fn test_message_ref_rejects_bad_framing() {
+        let data = encoded();
+        assert_eq!(
+            MessageRef::parse(&data[..data.len() - 1]).unwrap_err(),
+            Error::Truncated
+        );
+
+        // An attribute running past the end of the message
+        let mut overlong = data.clone();
+        overlong[22..24].copy_from_slice(&[0x01, 0x00]);
+        assert_eq!(MessageRef::parse(&overlong).unwrap_err(), Error::Truncated);
+
+        // Trailing bytes past the length aren't part of the message
+        let mut trailing = data.clone();
+        trailing.extend_from_slice(&[0; 8]);
+        let message = MessageRef::parse(&trailing).unwrap();
+        assert_eq!(message.as_bytes(), &data[..]);
+
+        // Unknown comprehension-required attributes are left for the caller
+        let mut unknown = data;
+        unknown[20..22].copy_from_slice(&[0x00, 0x7F]);
+        let message = MessageRef::parse(&unknown).unwrap();
+        assert_eq!(message.unknown_required().collect::<Vec<_>>(), [0x007F]);
+    }
+}
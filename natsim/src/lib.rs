@@ -0,0 +1,543 @@
+//! A NAT simulated in process, for testing NAT traversal without Docker and
+//! iptables. Sockets behind the NAT live in memory, and each mapping is a
+//! socket bound on the NAT's public IP, either a real UDP socket on a
+//! loopback address like 127.0.0.3 or one on an in-memory `Network`, so
+//! servers and peers outside talk to it as usual.
+
+pub mod network;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use message::transport::Transport;
+
+use crate::network::Network;
+
+/// The address sockets behind every NAT get, each on its own port
+const PRIVATE_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+const FIRST_PRIVATE_PORT: u16 = 50000;
+
+/// How often mapping threads check whether their mapping is still there
+const POLL: Duration = Duration::from_millis(50);
+
+/// Whether a socket keeps its mapping across destinations, RFC 4787
+/// section 4.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapping {
+    EndpointIndependent,
+    /// A new mapping for each destination IP
+    AddressDependent,
+    /// A new mapping for each destination IP and port
+    AddressAndPortDependent,
+}
+
+/// Who can send in through a mapping, RFC 4787 section 5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filtering {
+    /// Anyone
+    EndpointIndependent,
+    /// Any port on an IP the socket sent to
+    AddressDependent,
+    /// Only the IPs and ports the socket sent to
+    AddressAndPortDependent,
+}
+
+#[derive(Debug, Clone)]
+pub struct NatConfig {
+    pub mapping: Mapping,
+    pub filtering: Filtering,
+    /// Whether packets to the NAT's own public addresses are looped back in
+    pub hairpin: bool,
+    /// How long a mapping, and what it lets in, lasts after the last packet
+    /// out through it
+    pub timeout: Duration,
+}
+
+impl NatConfig {
+    pub fn full_cone() -> Self {
+        Self::new(Mapping::EndpointIndependent, Filtering::EndpointIndependent)
+    }
+
+    pub fn restricted_cone() -> Self {
+        Self::new(Mapping::EndpointIndependent, Filtering::AddressDependent)
+    }
+
+    pub fn port_restricted_cone() -> Self {
+        Self::new(
+            Mapping::EndpointIndependent,
+            Filtering::AddressAndPortDependent,
+        )
+    }
+
+    pub fn symmetric() -> Self {
+        Self::new(
+            Mapping::AddressAndPortDependent,
+            Filtering::AddressAndPortDependent,
+        )
+    }
+
+    /// No hairpinning, and mappings last two minutes like RFC 4787 asks
+    pub fn new(mapping: Mapping, filtering: Filtering) -> Self {
+        Self {
+            mapping,
+            filtering,
+            hairpin: false,
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+/// A NAT with a public IP, handing out sockets behind it
+pub struct Nat {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    public_ip: IpAddr,
+    config: NatConfig,
+    outside: Outside,
+    state: Mutex<State>,
+}
+
+/// Where the NAT's mappings are bound
+enum Outside {
+    Loopback,
+    Network(Network),
+}
+
+impl Outside {
+    fn bind(&self, ip: IpAddr) -> io::Result<Arc<dyn Transport>> {
+        let addr = SocketAddr::new(ip, 0);
+        Ok(match self {
+            Outside::Loopback => Arc::new(UdpSocket::bind(addr)?),
+            Outside::Network(network) => Arc::new(network.bind(addr)?),
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Sockets behind the NAT by private address, and where their
+    /// datagrams go
+    sockets: HashMap<SocketAddr, Sender<(Vec<u8>, SocketAddr)>>,
+    next_port: u16,
+    translations: Vec<Translation>,
+}
+
+/// A mapping from a private address to a public one
+struct Translation {
+    private: SocketAddr,
+    /// The destination the mapping is kept for, None if it's for all of
+    /// them
+    destination: Option<SocketAddr>,
+    socket: Arc<dyn Transport>,
+    public: SocketAddr,
+    /// Where the socket sent through the mapping, and when it last did
+    sent_to: HashMap<SocketAddr, Instant>,
+    refreshed: Instant,
+}
+
+impl Mapping {
+    /// Which destinations share a mapping with `to`
+    fn destination(self, to: SocketAddr) -> Option<SocketAddr> {
+        match self {
+            Mapping::EndpointIndependent => None,
+            Mapping::AddressDependent => Some(SocketAddr::new(to.ip(), 0)),
+            Mapping::AddressAndPortDependent => Some(to),
+        }
+    }
+}
+
+impl Filtering {
+    fn allows(self, translation: &Translation, from: SocketAddr) -> bool {
+        let mut sent_to = translation.sent_to.keys();
+        match self {
+            Filtering::EndpointIndependent => true,
+            Filtering::AddressDependent => sent_to.any(|to| to.ip() == from.ip()),
+            Filtering::AddressAndPortDependent => sent_to.any(|to| *to == from),
+        }
+    }
+}
+
+impl State {
+    /// Forget mappings and permissions nothing went out through in
+    /// `timeout`, their sockets close once their threads notice
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        self.translations.retain_mut(|translation| {
+            translation
+                .sent_to
+                .retain(|_, sent| now.duration_since(*sent) < timeout);
+            now.duration_since(translation.refreshed) < timeout
+        });
+    }
+}
+
+impl Nat {
+    /// A NAT whose mappings are UDP sockets bound on `public_ip`
+    pub fn new(public_ip: IpAddr, config: NatConfig) -> Self {
+        Self::with_outside(public_ip, config, Outside::Loopback)
+    }
+
+    /// A NAT whose mappings are bound on `public_ip` on `network`
+    pub fn on(network: &Network, public_ip: IpAddr, config: NatConfig) -> Self {
+        Self::with_outside(public_ip, config, Outside::Network(network.clone()))
+    }
+
+    fn with_outside(public_ip: IpAddr, config: NatConfig, outside: Outside) -> Self {
+        let state = State {
+            next_port: FIRST_PRIVATE_PORT,
+            ..Default::default()
+        };
+        Self {
+            inner: Arc::new(Inner {
+                public_ip,
+                config,
+                outside,
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    pub fn public_ip(&self) -> IpAddr {
+        self.inner.public_ip
+    }
+
+    /// A new socket behind the NAT, on a private address of its own
+    pub fn socket(&self) -> NatSocket {
+        let (sender, incoming) = mpsc::channel();
+        let mut state = self.inner.state.lock().unwrap();
+        let local = SocketAddr::new(PRIVATE_IP, state.next_port);
+        state.next_port += 1;
+        state.sockets.insert(local, sender);
+        NatSocket {
+            nat: self.inner.clone(),
+            local,
+            incoming: Mutex::new(incoming),
+            read_timeout: Mutex::new(None),
+        }
+    }
+}
+
+impl Inner {
+    /// Send `data` from the socket at `from` to `to`, as the NAT would
+    /// translate it
+    fn send(self: &Arc<Self>, from: SocketAddr, data: &[u8], to: SocketAddr) -> io::Result<()> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.expire(now, self.config.timeout);
+
+        // Another socket behind the same NAT, no translation needed
+        if let Some(socket) = state.sockets.get(&to) {
+            let _ = socket.send((data.to_vec(), from));
+            return Ok(());
+        }
+
+        let index = self.translate(&mut state, from, to, now)?;
+        if to.ip() == self.public_ip {
+            // Hairpinning, back in through the mapping at `to` as if it came
+            // from the sender's own public address
+            let public = state.translations[index].public;
+            let target = state
+                .translations
+                .iter()
+                .find(|target| target.public == to && self.config.filtering.allows(target, public));
+            if let Some(target) = target.filter(|_| self.config.hairpin) {
+                if let Some(socket) = state.sockets.get(&target.private) {
+                    let _ = socket.send((data.to_vec(), public));
+                }
+            }
+            return Ok(());
+        }
+        let socket = state.translations[index].socket.clone();
+        drop(state);
+        socket.send_to(data, to).map(|_| ())
+    }
+
+    /// The index of the mapping `from` sends to `to` through, made or
+    /// refreshed
+    fn translate(
+        self: &Arc<Self>,
+        state: &mut State,
+        from: SocketAddr,
+        to: SocketAddr,
+        now: Instant,
+    ) -> io::Result<usize> {
+        let destination = self.config.mapping.destination(to);
+        let existing = state.translations.iter().position(|translation| {
+            translation.private == from && translation.destination == destination
+        });
+        let index = match existing {
+            Some(index) => index,
+            None => {
+                let socket = self.outside.bind(self.public_ip)?;
+                socket.set_read_timeout(Some(POLL))?;
+                let public = socket.local_addr()?;
+                let (nat, inbound) = (Arc::downgrade(self), socket.clone());
+                thread::spawn(move || receive(nat, inbound));
+                state.translations.push(Translation {
+                    private: from,
+                    destination,
+                    socket,
+                    public,
+                    sent_to: HashMap::new(),
+                    refreshed: now,
+                });
+                state.translations.len() - 1
+            }
+        };
+        let translation = &mut state.translations[index];
+        translation.refreshed = now;
+        translation.sent_to.insert(to, now);
+        Ok(index)
+    }
+}
+
+/// Pass what arrives on a mapping's public socket to the socket behind it,
+/// as far as filtering allows, until the mapping or the NAT is gone
+fn receive(nat: Weak<Inner>, socket: Arc<dyn Transport>) {
+    let public = socket.local_addr().expect("local addr");
+    let mut buf = [0; 2048];
+    loop {
+        let received = socket.recv_from(&mut buf);
+        let Some(nat) = nat.upgrade() else {
+            return;
+        };
+        let mut state = nat.state.lock().unwrap();
+        state.expire(Instant::now(), nat.config.timeout);
+        let Some(translation) = state
+            .translations
+            .iter()
+            .find(|translation| translation.public == public)
+        else {
+            return;
+        };
+        let Ok((amt, from)) = received else {
+            continue;
+        };
+        if !nat.config.filtering.allows(translation, from) {
+            continue;
+        }
+        if let Some(inside) = state.sockets.get(&translation.private) {
+            let _ = inside.send((buf[..amt].to_vec(), from));
+        }
+    }
+}
+
+/// A socket behind a `Nat`
+pub struct NatSocket {
+    nat: Arc<Inner>,
+    local: SocketAddr,
+    incoming: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Transport for NatSocket {
+    fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.nat.send(self.local, buf, to)?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let incoming = self.incoming.lock().unwrap();
+        let received = match *self.read_timeout.lock().unwrap() {
+            Some(timeout) => incoming.recv_timeout(timeout),
+            None => incoming.recv().map_err(RecvTimeoutError::from),
+        };
+        let (data, from) = match received {
+            Ok(received) => received,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("the NAT holds the sender"),
+        };
+        // Like a datagram socket, whatever doesn't fit is lost
+        let amt = data.len().min(buf.len());
+        buf[..amt].copy_from_slice(&data[..amt]);
+        Ok((amt, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot set a 0 duration timeout",
+            ));
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+impl Drop for NatSocket {
+    /// Close the socket's mappings along with it
+    fn drop(&mut self) {
+        let mut state = self.nat.state.lock().unwrap();
+        state.sockets.remove(&self.local);
+        state
+            .translations
+            .retain(|translation| translation.private != self.local);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::nat::{classify, NatType};
+    use network::Conditions;
+    use server::server::Server;
+
+    fn loopback(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, last))
+    }
+
+    /// A server answering CHANGE-REQUEST from two IPs and two ports
+    fn start_server() -> SocketAddr {
+        let (a1, a2) = (
+            SocketAddr::new(loopback(1), 0),
+            SocketAddr::new(loopback(2), 0),
+        );
+        let mut server = Server::new([a1, a1, a2, a2]);
+        server.spawn();
+        server.local_addrs()[0]
+    }
+
+    /// A server on `network` answering CHANGE-REQUEST from two IPs and two
+    /// ports
+    fn start_server_on(network: &Network) -> SocketAddr {
+        let addr = |last, port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), port);
+        let addrs = [addr(1, 3478), addr(1, 3479), addr(2, 3478), addr(2, 3479)];
+        let sockets = addrs.map(|addr| {
+            let socket: Arc<dyn Transport> = Arc::new(network.bind(addr).unwrap());
+            socket
+        });
+        Server::new(addrs).spawn_over(sockets);
+        addrs[0]
+    }
+
+    /// Where `observer` sees `socket` coming from
+    fn mapped(socket: &NatSocket, observer: &UdpSocket) -> SocketAddr {
+        socket
+            .send_to(b"hello", observer.local_addr().unwrap())
+            .unwrap();
+        let mut buf = [0; 16];
+        observer.recv_from(&mut buf).unwrap().1
+    }
+
+    fn recv(socket: &NatSocket) -> Option<(Vec<u8>, SocketAddr)> {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buf = [0; 16];
+        let (amt, from) = socket.recv_from(&mut buf).ok()?;
+        Some((buf[..amt].to_vec(), from))
+    }
+
+    #[test]
+    fn test_classify() {
+        let server = start_server();
+        let address_dependent = NatConfig::new(
+            Mapping::AddressDependent,
+            Filtering::AddressAndPortDependent,
+        );
+        let nats = [
+            (NatConfig::full_cone(), NatType::FullCone),
+            (NatConfig::restricted_cone(), NatType::RestrictedCone),
+            (
+                NatConfig::port_restricted_cone(),
+                NatType::PortRestrictedCone,
+            ),
+            (NatConfig::symmetric(), NatType::Symmetric),
+            (address_dependent, NatType::Symmetric),
+        ];
+        for (i, (config, expected)) in nats.into_iter().enumerate() {
+            let nat = Nat::new(loopback(10 + i as u8), config);
+            let socket = nat.socket();
+            let found = classify(&socket, server, Duration::from_millis(300)).unwrap();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_classify_on_lossy_network() {
+        let network = Network::new(1);
+        network.set_conditions(Conditions {
+            loss: 0.1,
+            delay: Duration::from_millis(2),
+            reorder: 0.1,
+        });
+        let server = start_server_on(&network);
+        let nats = [
+            (NatConfig::full_cone(), NatType::FullCone),
+            (NatConfig::restricted_cone(), NatType::RestrictedCone),
+            (
+                NatConfig::port_restricted_cone(),
+                NatType::PortRestrictedCone,
+            ),
+            (NatConfig::symmetric(), NatType::Symmetric),
+        ];
+        for (i, (config, expected)) in nats.into_iter().enumerate() {
+            let public_ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1 + i as u8));
+            let nat = Nat::on(&network, public_ip, config);
+            let socket = nat.socket();
+            let found = classify(&socket, server, Duration::from_millis(500)).unwrap();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_mapping_timeout() {
+        let mut config = NatConfig::full_cone();
+        config.timeout = Duration::from_millis(200);
+        let nat = Nat::new(loopback(20), config);
+        let socket = nat.socket();
+        let peer = UdpSocket::bind(SocketAddr::new(loopback(1), 0)).unwrap();
+
+        let public = mapped(&socket, &peer);
+        assert_eq!(public.ip(), nat.public_ip());
+        peer.send_to(b"back", public).unwrap();
+        assert_eq!(recv(&socket).unwrap().0, b"back");
+
+        // Nothing went out for longer than the timeout, the mapping is gone
+        thread::sleep(Duration::from_millis(300));
+        peer.send_to(b"late", public).unwrap();
+        assert_eq!(recv(&socket), None);
+
+        let public = mapped(&socket, &peer);
+        peer.send_to(b"again", public).unwrap();
+        assert_eq!(recv(&socket).unwrap().0, b"again");
+    }
+
+    #[test]
+    fn test_hairpin() {
+        for hairpin in [true, false] {
+            let mut config = NatConfig::port_restricted_cone();
+            config.hairpin = hairpin;
+            let nat = Nat::new(loopback(21 + hairpin as u8), config);
+            let (a, b) = (nat.socket(), nat.socket());
+            let observer = UdpSocket::bind(SocketAddr::new(loopback(1), 0)).unwrap();
+            let (a_public, b_public) = (mapped(&a, &observer), mapped(&b, &observer));
+
+            // Each has to let the other in first
+            b.send_to(b"open", a_public).unwrap();
+            a.send_to(b"hairpin", b_public).unwrap();
+            let received = recv(&b);
+            match hairpin {
+                true => assert_eq!(received, Some((b"hairpin".to_vec(), a_public))),
+                false => assert_eq!(received, None),
+            }
+        }
+    }
+}
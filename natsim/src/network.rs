@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Condvar, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use message::transport::Transport;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Ports handed out to sockets bound to port 0, like the OS's ephemeral
+/// range
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// How much later than the rest a reordered datagram arrives
+const REORDER_DELAY: Duration = Duration::from_millis(30);
+
+/// What the network does to datagrams on their way
+#[derive(Debug, Clone, Default)]
+pub struct Conditions {
+    /// Chance of a datagram being lost, from 0 to 1
+    pub loss: f64,
+    /// How long every datagram takes to arrive
+    pub delay: Duration,
+    /// Chance of a datagram being held back so later ones overtake it
+    pub reorder: f64,
+}
+
+/// An in-memory network of datagram sockets. What gets lost or reordered
+/// is drawn from a seeded generator in the order datagrams are sent, so a
+/// test that sends in a fixed order sees the same thing every run.
+#[derive(Clone)]
+pub struct Network {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+}
+
+struct State {
+    sockets: HashMap<SocketAddr, Weak<Queue>>,
+    conditions: Conditions,
+    rng: StdRng,
+    /// Datagrams still to be dropped whatever the conditions
+    drop_next: usize,
+    next_port: u16,
+    /// Sent so far, breaks ties between datagrams due at the same time
+    sent: u64,
+}
+
+/// Datagrams on their way to a socket
+#[derive(Default)]
+struct Queue {
+    datagrams: Mutex<Vec<Datagram>>,
+    arrived: Condvar,
+}
+
+struct Datagram {
+    due: Instant,
+    sequence: u64,
+    data: Vec<u8>,
+    from: SocketAddr,
+}
+
+impl Network {
+    pub fn new(seed: u64) -> Self {
+        let state = State {
+            sockets: HashMap::new(),
+            conditions: Conditions::default(),
+            rng: StdRng::seed_from_u64(seed),
+            drop_next: 0,
+            next_port: FIRST_EPHEMERAL_PORT,
+            sent: 0,
+        };
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    pub fn set_conditions(&self, conditions: Conditions) {
+        self.inner.state.lock().unwrap().conditions = conditions;
+    }
+
+    /// Lose the next `count` datagrams sent by anyone
+    pub fn drop_next(&self, count: usize) {
+        self.inner.state.lock().unwrap().drop_next = count;
+    }
+
+    /// A socket on `addr`, on a free port if the port is 0
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<NetSocket> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.sockets.retain(|_, queue| queue.strong_count() > 0);
+        let local = match addr.port() {
+            0 => state.ephemeral(addr.ip())?,
+            _ if state.sockets.contains_key(&addr) => {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            _ => addr,
+        };
+        let queue = Arc::new(Queue::default());
+        state.sockets.insert(local, Arc::downgrade(&queue));
+        Ok(NetSocket {
+            network: self.inner.clone(),
+            local,
+            queue,
+            read_timeout: Mutex::new(None),
+        })
+    }
+}
+
+impl State {
+    fn ephemeral(&mut self, ip: IpAddr) -> io::Result<SocketAddr> {
+        for _ in FIRST_EPHEMERAL_PORT..=u16::MAX {
+            let addr = SocketAddr::new(ip, self.next_port);
+            self.next_port = self
+                .next_port
+                .checked_add(1)
+                .unwrap_or(FIRST_EPHEMERAL_PORT);
+            if !self.sockets.contains_key(&addr) {
+                return Ok(addr);
+            }
+        }
+        Err(io::ErrorKind::AddrInUse.into())
+    }
+}
+
+/// A socket on a `Network`
+pub struct NetSocket {
+    network: Arc<Inner>,
+    local: SocketAddr,
+    queue: Arc<Queue>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Transport for NetSocket {
+    /// Datagrams to nobody are lost without an error, like over UDP
+    fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        let mut state = self.network.state.lock().unwrap();
+        state.sent += 1;
+        let sequence = state.sent;
+        let conditions = state.conditions.clone();
+        let lost = match state.drop_next {
+            0 => state.rng.gen_bool(conditions.loss),
+            _ => {
+                state.drop_next -= 1;
+                true
+            }
+        };
+        let reordered = state.rng.gen_bool(conditions.reorder);
+        let Some(queue) = state.sockets.get(&to).and_then(Weak::upgrade) else {
+            return Ok(buf.len());
+        };
+        drop(state);
+        if lost {
+            return Ok(buf.len());
+        }
+
+        let mut due = Instant::now() + conditions.delay;
+        if reordered {
+            due += REORDER_DELAY;
+        }
+        queue.datagrams.lock().unwrap().push(Datagram {
+            due,
+            sequence,
+            data: buf.to_vec(),
+            from: self.local,
+        });
+        queue.arrived.notify_all();
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = self
+            .read_timeout
+            .lock()
+            .unwrap()
+            .map(|timeout| Instant::now() + timeout);
+        let mut datagrams = self.queue.datagrams.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let next = datagrams
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, datagram)| (datagram.due, datagram.sequence))
+                .map(|(index, datagram)| (index, datagram.due));
+            if let Some((index, _)) = next.filter(|(_, due)| *due <= now) {
+                let datagram = datagrams.swap_remove(index);
+                // Like a datagram socket, whatever doesn't fit is lost
+                let amt = datagram.data.len().min(buf.len());
+                buf[..amt].copy_from_slice(&datagram.data[..amt]);
+                return Ok((amt, datagram.from));
+            }
+            let wake = match (next.map(|(_, due)| due), deadline) {
+                (_, Some(deadline)) if deadline <= now => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+                }
+                (Some(due), Some(deadline)) => Some(due.min(deadline)),
+                (due, deadline) => due.or(deadline),
+            };
+            datagrams = match wake {
+                Some(wake) => {
+                    let (datagrams, _) = self
+                        .queue
+                        .arrived
+                        .wait_timeout(datagrams, wake - now)
+                        .unwrap();
+                    datagrams
+                }
+                None => self.queue.arrived.wait(datagrams).unwrap(),
+            };
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot set a 0 duration timeout",
+            ));
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(last: u8, port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), port)
+    }
+
+    fn recv(socket: &NetSocket) -> Option<Vec<u8>> {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 16];
+        let (amt, _) = socket.recv_from(&mut buf).ok()?;
+        Some(buf[..amt].to_vec())
+    }
+
+    /// Which of 20 datagrams arrive, in the order they do
+    fn arrivals(seed: u64, conditions: Conditions) -> Vec<u8> {
+        let network = Network::new(seed);
+        network.set_conditions(conditions);
+        let a = network.bind(addr(1, 0)).unwrap();
+        let b = network.bind(addr(2, 3478)).unwrap();
+        for i in 0..20 {
+            a.send_to(&[i], b.local_addr().unwrap()).unwrap();
+        }
+        std::iter::from_fn(|| recv(&b).map(|data| data[0])).collect()
+    }
+
+    #[test]
+    fn test_network_delivers() {
+        let network = Network::new(0);
+        let a = network.bind(addr(1, 0)).unwrap();
+        let b = network.bind(addr(2, 3478)).unwrap();
+        assert_eq!(a.local_addr().unwrap(), addr(1, FIRST_EPHEMERAL_PORT));
+        assert!(network.bind(addr(2, 3478)).is_err());
+
+        a.send_to(b"ping", addr(2, 3478)).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(b.recv_from(&mut buf).unwrap(), (4, a.local_addr().unwrap()));
+        assert_eq!(&buf[..4], b"ping");
+
+        network.drop_next(1);
+        a.send_to(b"lost", addr(2, 3478)).unwrap();
+        a.send_to(b"kept", addr(2, 3478)).unwrap();
+        assert_eq!(recv(&b).unwrap(), b"kept");
+        assert_eq!(recv(&b), None);
+
+        // Nobody there, nothing happens
+        a.send_to(b"void", addr(3, 3478)).unwrap();
+    }
+
+    #[test]
+    fn test_network_conditions_are_repeatable() {
+        let conditions = Conditions {
+            loss: 0.3,
+            delay: Duration::from_millis(5),
+            reorder: 0.2,
+        };
+        let first = arrivals(7, conditions.clone());
+        assert_eq!(first, arrivals(7, conditions));
+        assert!(first.len() < 20);
+        assert!(first.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+}
@@ -0,0 +1,273 @@
+//! UDP a batch at a time on Linux, recvmmsg takes in whatever datagrams are
+//! waiting with one call and sendmmsg sends the replies to all of them with
+//! another. The buffers of a worker are allocated once and reused for every
+//! batch.
+
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    ptr,
+    sync::{Arc, Mutex},
+};
+
+use socket2::SockAddr;
+
+use crate::{
+    server::{Reply, Request},
+    turn::Turn,
+};
+
+/// Datagrams received or sent per system call
+pub(crate) const BATCH: usize = 32;
+/// Longest datagram taken in, the rest of a longer one is lost
+const DATAGRAM: usize = 2048;
+
+/// Datagrams received together, in buffers kept from one batch to the next
+pub(crate) struct Inbox {
+    buffers: Vec<[u8; DATAGRAM]>,
+    lens: [usize; BATCH],
+    addrs: [libc::sockaddr_storage; BATCH],
+    addr_lens: [libc::socklen_t; BATCH],
+    received: usize,
+}
+
+impl Inbox {
+    pub(crate) fn new() -> Self {
+        Self {
+            buffers: vec![[0; DATAGRAM]; BATCH],
+            lens: [0; BATCH],
+            // An all zero sockaddr_storage is a valid, unspecified address
+            addrs: unsafe { mem::zeroed() },
+            addr_lens: [0; BATCH],
+            received: 0,
+        }
+    }
+
+    /// Wait for a datagram on `sock`, then take whatever else has already
+    /// arrived without waiting, up to `BATCH` in all
+    pub(crate) fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        let mut iovecs: [libc::iovec; BATCH] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        for (i, (iovec, header)) in iovecs.iter_mut().zip(&mut headers).enumerate() {
+            iovec.iov_base = self.buffers[i].as_mut_ptr().cast();
+            iovec.iov_len = DATAGRAM;
+            header.msg_hdr.msg_name = ptr::addr_of_mut!(self.addrs[i]).cast();
+            header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+        let received = loop {
+            // Safety: every header points at a buffer and an address of
+            // this inbox, which outlive the call
+            let received = unsafe {
+                libc::recvmmsg(
+                    sock.as_raw_fd(),
+                    headers.as_mut_ptr(),
+                    BATCH as _,
+                    libc::MSG_WAITFORONE as _,
+                    ptr::null_mut(),
+                )
+            };
+            match received {
+                -1 if io::Error::last_os_error().kind() == ErrorKind::Interrupted => continue,
+                -1 => return Err(io::Error::last_os_error()),
+                received => break received as usize,
+            }
+        };
+        for (i, header) in headers[..received].iter().enumerate() {
+            self.lens[i] = (header.msg_len as usize).min(DATAGRAM);
+            self.addr_lens[i] = header.msg_hdr.msg_namelen;
+        }
+        self.received = received;
+        Ok(received)
+    }
+
+    /// The datagrams of the last batch and who sent them
+    pub(crate) fn datagrams(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        (0..self.received).filter_map(|i| {
+            // Safety: the kernel wrote an address of this length
+            let addr = unsafe { SockAddr::new(self.addrs[i], self.addr_lens[i]) };
+            Some((&self.buffers[i][..self.lens[i]], addr.as_socket()?))
+        })
+    }
+}
+
+/// Replies waiting to go out together, encoded into buffers kept from one
+/// batch to the next
+pub struct Outbox {
+    sock: Arc<UdpSocket>,
+    queue: Mutex<Queue>,
+}
+
+struct Queue {
+    buffers: Vec<Vec<u8>>,
+    addrs: Vec<SockAddr>,
+    queued: usize,
+}
+
+impl Outbox {
+    pub(crate) fn new(sock: Arc<UdpSocket>) -> Self {
+        let unspecified = SockAddr::from(SocketAddr::from(([0, 0, 0, 0], 0)));
+        let queue = Queue {
+            buffers: (0..BATCH).map(|_| Vec::with_capacity(DATAGRAM)).collect(),
+            addrs: vec![unspecified; BATCH],
+            queued: 0,
+        };
+        Self {
+            sock,
+            queue: Mutex::new(queue),
+        }
+    }
+
+    pub(crate) fn socket(&self) -> &Arc<UdpSocket> {
+        &self.sock
+    }
+
+    /// Queue a datagram for `to`, written into a reused buffer by `encode`.
+    /// What's queued is sent first if the batch is full, an error is about
+    /// those earlier datagrams.
+    pub(crate) fn push(&self, to: SocketAddr, encode: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let flushed = match queue.queued {
+            BATCH => queue.flush(&self.sock),
+            _ => Ok(()),
+        };
+        let i = queue.queued;
+        queue.buffers[i].clear();
+        encode(&mut queue.buffers[i]);
+        queue.addrs[i] = SockAddr::from(to);
+        queue.queued += 1;
+        flushed
+    }
+
+    /// Send everything queued. A packet that can't be sent is dropped like
+    /// a lost datagram, the first such error is returned.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.queue.lock().unwrap().flush(&self.sock)
+    }
+}
+
+impl Queue {
+    fn flush(&mut self, sock: &UdpSocket) -> io::Result<()> {
+        let queued = mem::take(&mut self.queued);
+        let mut iovecs: [libc::iovec; BATCH] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        for (i, (iovec, header)) in iovecs.iter_mut().zip(&mut headers).enumerate() {
+            iovec.iov_base = self.buffers[i].as_mut_ptr().cast();
+            iovec.iov_len = self.buffers[i].len();
+            header.msg_hdr.msg_name = self.addrs[i].as_ptr() as *mut _;
+            header.msg_hdr.msg_namelen = self.addrs[i].len();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        let mut result = Ok(());
+        let mut sent = 0;
+        while sent < queued {
+            // Safety: the headers point at buffers and addresses of this
+            // queue, which outlive the call
+            let count = unsafe {
+                libc::sendmmsg(
+                    sock.as_raw_fd(),
+                    headers[sent..].as_mut_ptr(),
+                    (queued - sent) as _,
+                    0,
+                )
+            };
+            match count {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == ErrorKind::Interrupted {
+                        continue;
+                    }
+                    // Only the first packet left failed, skip it
+                    sent += 1;
+                    result = result.and(Err(err));
+                }
+                count => sent += count as usize,
+            }
+        }
+        result
+    }
+}
+
+/// Like `listen_udp`, a batch at a time. Replies to a batch go out once the
+/// whole batch is handled.
+pub(crate) fn listen(turn: Arc<Turn>, sock: Arc<UdpSocket>) {
+    let local = sock.local_addr().expect("local addr");
+    println!("Listening on {:?} (batched)", local);
+
+    let outbox = Arc::new(Outbox::new(sock.clone()));
+    let reply = Reply::Batched(outbox.clone());
+    let mut inbox = Inbox::new();
+    loop {
+        inbox.recv(&sock).expect("recv data");
+        for (data, src) in inbox.datagrams() {
+            Request::new(&reply, &turn, local, src).handle(data);
+        }
+        if let Err(err) = outbox.flush() {
+            eprintln!("Replying from {} failed: {}", local, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::{
+        header::{Header, HeaderType},
+        packet::Packet,
+        Message,
+    };
+    use std::{net::Ipv4Addr, time::Duration};
+
+    fn bind() -> UdpSocket {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn test_batch_round_trip() {
+        let (server, client) = (Arc::new(bind()), bind());
+        let (server_addr, client_addr) =
+            (server.local_addr().unwrap(), client.local_addr().unwrap());
+
+        // More than a batch, so the outbox has to flush on its own too
+        let outbox = Outbox::new(server.clone());
+        let mut transactions = Vec::new();
+        for _ in 0..BATCH + 3 {
+            let header = Header::with_random_id(HeaderType::BindingRequest);
+            transactions.push(header.transaction_id);
+            let packet = Packet::Stun(Message::new(header, vec![]));
+            outbox
+                .push(client_addr, |buf| packet.encode_into(buf))
+                .unwrap();
+        }
+        outbox.flush().unwrap();
+        let mut buf = [0; 2048];
+        for transaction_id in transactions {
+            let (amt, from) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(from, server_addr);
+            let message = Message::decode(&buf[..amt]).unwrap();
+            assert_eq!(message.header.transaction_id, transaction_id);
+        }
+
+        // What's waiting is taken in one call, with who sent it
+        for i in 0..5u8 {
+            client.send_to(&[i; 8], server_addr).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        let mut inbox = Inbox::new();
+        assert_eq!(inbox.recv(&server).unwrap(), 5);
+        let datagrams: Vec<_> = inbox.datagrams().collect();
+        for (i, (data, from)) in datagrams.into_iter().enumerate() {
+            assert_eq!(data, [i as u8; 8]);
+            assert_eq!(from, client_addr);
+        }
+    }
+}
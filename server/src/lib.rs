@@ -0,0 +1,10 @@
+#[cfg(target_os = "linux")]
+mod batch;
+pub mod redirect;
+pub mod rendezvous;
+#[cfg(feature = "async")]
+mod runtime;
+pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod turn;
@@ -1,7 +1,23 @@
-mod server;
-
-use server::Server;
+use message::{punch::RENDEZVOUS_PORT, validate::Policy};
+#[cfg(feature = "tls")]
+use server::tls::Certificate;
+use server::{
+    redirect::{Alternate, Redirect},
+    server::Server,
+};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: server [--user USERNAME:PASSWORD]... [--cert FILE --key FILE]
+              [--software TEXT] [--strict] [--rendezvous] [--workers N] [--async]
+              [--maintenance ALT | --overload ALLOCATIONS ALT | --pool self|ALT...
+               | --region NETWORK ALT...]
+ALT is ADDR or ADDR/DOMAIN, --cert and --key need the tls feature";
+
+fn alternate(arg: Option<String>) -> Alternate {
+    arg.and_then(|arg| arg.parse().ok()).expect(USAGE)
+}
 
 fn main() {
     let a1 = IpAddr::V4(Ipv4Addr::new(172, 19, 0, 2));
@@ -15,5 +31,106 @@ fn main() {
         SocketAddr::new(a2, p1),
         SocketAddr::new(a2, p2),
     ]);
-    server.run();
+
+    #[cfg(feature = "tls")]
+    let (mut cert, mut key) = (None, None);
+    let mut redirect = None;
+    let mut on_tokio = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // TURN users, authenticated with long-term credentials
+            "--user" => {
+                let user = args.next().expect(USAGE);
+                let (username, password) = user.split_once(':').expect(USAGE);
+                server.add_user(username.into(), password.into());
+            }
+            // PEM certificate chain and key for TLS and DTLS on port 5349, in
+            // builds with the tls feature
+            #[cfg(feature = "tls")]
+            "--cert" => cert = args.next().map(PathBuf::from),
+            #[cfg(feature = "tls")]
+            "--key" => key = args.next().map(PathBuf::from),
+            // SOFTWARE in responses, none if empty
+            "--software" => {
+                let software = args.next().expect(USAGE);
+                let software = (!software.is_empty()).then_some(software);
+                server.set_software(software).expect("SOFTWARE");
+            }
+            // Answer requests that don't keep to RFC 8489 to the letter with
+            // 400, RFC 3489 clients included
+            "--strict" => server.set_policy(Policy::Strict),
+            // UDP sockets per address, each with its thread or task
+            "--workers" => {
+                let workers = args.next().and_then(|n| n.parse().ok()).expect(USAGE);
+                server.set_workers(workers);
+            }
+            // Serve UDP and TCP from tokio tasks, in builds with the async
+            // feature
+            "--async" if cfg!(feature = "async") => on_tokio = true,
+            // Introduce hole punching peers to each other
+            "--rendezvous" => server.enable_rendezvous(SocketAddr::new(a1, RENDEZVOUS_PORT)),
+            // Send clients elsewhere with 300 Try Alternate
+            "--maintenance" => redirect = Some(Redirect::Maintenance(alternate(args.next()))),
+            "--overload" => {
+                let max_allocations = args.next().and_then(|n| n.parse().ok()).expect(USAGE);
+                let alternate = alternate(args.next());
+                redirect = Some(Redirect::Overload {
+                    max_allocations,
+                    alternate,
+                });
+            }
+            // The whole pool in the same order on every server, "self" is
+            // where this one goes
+            "--pool" => {
+                let pool = redirect.get_or_insert(Redirect::Pool {
+                    servers: Vec::new(),
+                    own: usize::MAX,
+                });
+                let Redirect::Pool { servers, own } = pool else {
+                    panic!("{}", USAGE)
+                };
+                match args.next().as_deref() {
+                    Some("self") => {
+                        *own = servers.len();
+                        servers.push(Alternate::new(SocketAddr::new(a1, p1)));
+                    }
+                    arg => servers.push(alternate(arg.map(String::from))),
+                }
+            }
+            "--region" => {
+                let regions = redirect.get_or_insert(Redirect::Networks(Vec::new()));
+                let Redirect::Networks(networks) = regions else {
+                    panic!("{}", USAGE)
+                };
+                let network = args.next().and_then(|net| net.parse().ok()).expect(USAGE);
+                networks.push((network, alternate(args.next())));
+            }
+            _ => panic!("{}", USAGE),
+        }
+    }
+    #[cfg(feature = "tls")]
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let certificate = Certificate::from_pem_files(&cert, &key).expect("certificate");
+            server.enable_tls(SocketAddr::new(a1, 5349), certificate);
+        }
+        (None, None) => {}
+        _ => panic!("{}", USAGE),
+    }
+    match redirect {
+        Some(Redirect::Pool {
+            own: usize::MAX, ..
+        }) => panic!("--pool needs self"),
+        Some(redirect) => server.set_redirect(redirect),
+        None => {}
+    }
+    if on_tokio {
+        #[cfg(feature = "async")]
+        tokio::runtime::Runtime::new()
+            .expect("tokio runtime")
+            .block_on(server.run_async());
+    } else {
+        server.run();
+    }
 }
@@ -0,0 +1,240 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// A server clients are sent to with 300 Try Alternate, RFC 8489 section 10
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alternate {
+    pub address: SocketAddr,
+    /// Name its certificate is valid for, sent in ALTERNATE-DOMAIN so
+    /// clients over TLS and DTLS can check it
+    pub domain: Option<String>,
+}
+
+impl Alternate {
+    pub const fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            domain: None,
+        }
+    }
+}
+
+/// `ADDR` or `ADDR/DOMAIN`
+impl FromStr for Alternate {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, domain) = match s.split_once('/') {
+            Some((address, domain)) if !domain.is_empty() => (address, Some(domain.into())),
+            Some(_) => return Err(ParseError(s.into())),
+            None => (s, None),
+        };
+        let address = address.parse().map_err(|_| ParseError(s.into()))?;
+        Ok(Self { address, domain })
+    }
+}
+
+/// An IP network in CIDR notation, `192.0.2.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError(s.into());
+        let (addr, prefix) = s.split_once('/').ok_or_else(invalid)?;
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// When the server sends clients elsewhere instead of serving them. Only
+/// Binding and Allocate requests are redirected, existing allocations stay
+/// where they are.
+#[derive(Debug, Clone)]
+pub enum Redirect {
+    /// Send everyone to the alternate, to drain this server for maintenance
+    Maintenance(Alternate),
+    /// Send new allocations to the alternate once this many are held
+    Overload {
+        max_allocations: usize,
+        alternate: Alternate,
+    },
+    /// Spread clients evenly over a pool of servers by a hash of their IP
+    /// address. Every server lists the whole pool in the same order, so a
+    /// client lands in the same place whichever it asks, `own` is where
+    /// this server is in it.
+    Pool { servers: Vec<Alternate>, own: usize },
+    /// Send clients to the server for the first network they're in, say
+    /// the one for their region, and serve the rest here
+    Networks(Vec<(Network, Alternate)>),
+}
+
+impl Redirect {
+    /// Where to send the client at `client`, if anywhere. `allocations` is
+    /// how many this server holds when the request is for another one.
+    pub fn alternate(&self, client: IpAddr, allocations: Option<usize>) -> Option<&Alternate> {
+        match self {
+            Redirect::Maintenance(alternate) => Some(alternate),
+            Redirect::Overload {
+                max_allocations,
+                alternate,
+            } => allocations
+                .filter(|allocations| allocations >= max_allocations)
+                .and(Some(alternate)),
+            Redirect::Pool { servers, own } => {
+                let index = (fnv1a(client) % servers.len().max(1) as u64) as usize;
+                servers.get(index).filter(|_| index != *own)
+            }
+            Redirect::Networks(networks) => networks
+                .iter()
+                .find_map(|(network, alternate)| network.contains(client).then_some(alternate)),
+        }
+    }
+}
+
+/// FNV-1a over the address, a hash that's the same on every server and
+/// every build, unlike the standard library's
+fn fnv1a(ip: IpAddr) -> u64 {
+    let hash = |octets: &[u8]| {
+        octets.iter().fold(0xcbf2_9ce4_8422_2325, |hash, octet| {
+            (hash ^ u64::from(*octet)).wrapping_mul(0x0100_0000_01b3)
+        })
+    };
+    match ip {
+        IpAddr::V4(ip) => hash(&ip.octets()),
+        IpAddr::V6(ip) => hash(&ip.octets()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid redirect target {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alternate(s: &str) -> Alternate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_alternates_and_networks() {
+        let parsed = alternate("192.0.2.1:3478/stun2.example.com");
+        assert_eq!(parsed.address, "192.0.2.1:3478".parse().unwrap());
+        assert_eq!(parsed.domain.as_deref(), Some("stun2.example.com"));
+        assert_eq!(alternate("[2001:db8::1]:3478").domain, None);
+        assert!("192.0.2.1".parse::<Alternate>().is_err());
+        assert!("192.0.2.1:3478/".parse::<Alternate>().is_err());
+
+        let network: Network = "192.0.2.0/24".parse().unwrap();
+        assert!(network.contains("192.0.2.200".parse().unwrap()));
+        assert!(!network.contains("192.0.3.1".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+        let everything: Network = "::/0".parse().unwrap();
+        assert!(everything.contains("2001:db8::1".parse().unwrap()));
+        assert!("192.0.2.0/33".parse::<Network>().is_err());
+        assert!("192.0.2.0".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_overload_counts_allocations() {
+        let redirect = Redirect::Overload {
+            max_allocations: 2,
+            alternate: alternate("192.0.2.1:3478"),
+        };
+        let client = "198.51.100.1".parse().unwrap();
+        assert_eq!(redirect.alternate(client, Some(1)), None);
+        assert!(redirect.alternate(client, Some(2)).is_some());
+        // Binding requests don't add to the load
+        assert_eq!(redirect.alternate(client, None), None);
+    }
+
+    #[test]
+    fn test_pool_spreads_clients() {
+        let servers = vec![
+            alternate("192.0.2.1:3478"),
+            alternate("192.0.2.2:3478"),
+            alternate("192.0.2.3:3478"),
+        ];
+        let pool: Vec<_> = (0..servers.len())
+            .map(|own| Redirect::Pool {
+                servers: servers.clone(),
+                own,
+            })
+            .collect();
+        let mut counts = [0; 3];
+        for host in 0..=255 {
+            let client = IpAddr::from([198, 51, 100, host]);
+            // Every server agrees on where the client goes, and keeps it
+            // if that's itself
+            let home = pool[0].alternate(client, None).unwrap_or(&servers[0]);
+            let index = servers.iter().position(|server| server == home).unwrap();
+            for (own, redirect) in pool.iter().enumerate() {
+                let target = redirect.alternate(client, None);
+                assert_eq!(target, (own != index).then_some(home));
+            }
+            counts[index] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 50), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_networks_pick_by_client_address() {
+        let redirect = Redirect::Networks(vec![
+            (
+                "198.51.100.0/24".parse().unwrap(),
+                alternate("192.0.2.1:3478"),
+            ),
+            (
+                "2001:db8::/32".parse().unwrap(),
+                alternate("[2001:db8::1]:3478"),
+            ),
+        ]);
+        let target = redirect.alternate("198.51.100.7".parse().unwrap(), None);
+        assert_eq!(target.unwrap().address, "192.0.2.1:3478".parse().unwrap());
+        assert!(redirect
+            .alternate("203.0.113.1".parse().unwrap(), None)
+            .is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use message::punch::Punch;
+
+/// Sessions are forgotten this long after the first peer registers
+const SESSION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Introduces the two peers of a hole punching session to each other. Peers
+/// are told apart by the address they register from, and told the mapped
+/// address the other one registered.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<String, Session>,
+}
+
+struct Session {
+    created: Instant,
+    /// Source address and claimed mapped address of each peer
+    peers: Vec<(SocketAddr, SocketAddr)>,
+}
+
+impl Sessions {
+    /// The answer to `src` registering `mapped` for `session`. Registering
+    /// again is how peers wait, so it's answered the same way every time.
+    pub fn register(
+        &mut self,
+        session: String,
+        src: SocketAddr,
+        mapped: SocketAddr,
+        now: Instant,
+    ) -> Punch {
+        self.sessions
+            .retain(|_, session| now.duration_since(session.created) < SESSION_LIFETIME);
+        let session = self.sessions.entry(session).or_insert_with(|| Session {
+            created: now,
+            peers: Vec::new(),
+        });
+        match session.peers.iter().position(|(peer, _)| *peer == src) {
+            Some(index) => session.peers[index].1 = mapped,
+            None if session.peers.len() < 2 => session.peers.push((src, mapped)),
+            None => return Punch::Full,
+        }
+        match session.peers.iter().find(|(peer, _)| *peer != src) {
+            Some((_, mapped)) => Punch::Peer(*mapped),
+            None => Punch::Wait,
+        }
+    }
+}
+
+pub(crate) fn listen(sock: UdpSocket) {
+    println!("Rendezvous on {:?}", sock.local_addr().expect("local addr"));
+    let mut sessions = Sessions::default();
+    let mut buf = [0; 512];
+    loop {
+        let (amt, src) = sock.recv_from(&mut buf).expect("recv data");
+        let Some(Punch::Register { session, mapped }) = Punch::decode(&buf[..amt]) else {
+            continue;
+        };
+        let reply = sessions.register(session, src, mapped, Instant::now());
+        if let Err(err) = sock.send_to(&reply.encode(), src) {
+            eprintln!("Rendezvous reply to {} failed: {}", src, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_sessions_pair_peers() {
+        let mut sessions = Sessions::default();
+        let now = Instant::now();
+        let (a, b, c) = (
+            addr("192.0.2.1:1"),
+            addr("192.0.2.2:2"),
+            addr("192.0.2.3:3"),
+        );
+        let (a_mapped, b_mapped) = (addr("203.0.113.1:10"), addr("203.0.113.2:20"));
+
+        let register = |sessions: &mut Sessions, src, mapped| {
+            sessions.register("demo".into(), src, mapped, now)
+        };
+        assert_eq!(register(&mut sessions, a, a_mapped), Punch::Wait);
+        assert_eq!(register(&mut sessions, a, a_mapped), Punch::Wait);
+        assert_eq!(register(&mut sessions, b, b_mapped), Punch::Peer(a_mapped));
+        assert_eq!(register(&mut sessions, a, a_mapped), Punch::Peer(b_mapped));
+        assert_eq!(register(&mut sessions, c, a_mapped), Punch::Full);
+
+        // Another session is separate, and old ones are forgotten
+        let later = now + SESSION_LIFETIME;
+        assert_eq!(
+            sessions.register("other".into(), c, a_mapped, now),
+            Punch::Wait
+        );
+        assert_eq!(
+            sessions.register("demo".into(), c, a_mapped, later),
+            Punch::Wait
+        );
+    }
+}
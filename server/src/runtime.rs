@@ -0,0 +1,332 @@
+//! The UDP and TCP listeners on tokio, a task per socket and per connection
+//! instead of a thread. Requests go through the same handler as on the
+//! blocking path, the few that bind sockets, start relay threads or wait on
+//! a peer are handed to tokio's blocking threads so no task stalls on them.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use message::{header::HeaderType, transport::Transport, view::MessageRef};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    runtime::Handle,
+    sync::mpsc::{self, error::TrySendError},
+    time::timeout,
+};
+
+use crate::{
+    server::{next_frame, Connections, Reply, Request},
+    turn::Turn,
+};
+
+/// Datagrams a UDP socket has no room for yet
+const BACKLOG: usize = 1024;
+
+/// Frames waiting to be written out on a TCP connection, past this the
+/// client isn't reading
+const QUEUED_FRAMES: usize = 64;
+
+/// A tokio UDP socket, for replies, CHANGE-REQUEST answers and relayed data
+/// sent from the handler and relay threads. Receives never wait, the
+/// listener awaits instead. Sends the socket has no room for wait in a
+/// backlog, sent by `send_backlog` as room frees up.
+pub(crate) struct AsyncUdp {
+    pub(crate) socket: UdpSocket,
+    backlog: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+}
+
+/// The other end of an `AsyncUdp`'s backlog
+pub(crate) struct Backlog(mpsc::Receiver<(Vec<u8>, SocketAddr)>);
+
+impl AsyncUdp {
+    /// Hand a socket bound the blocking way over to tokio
+    pub(crate) fn from_std(sock: std::net::UdpSocket) -> io::Result<(Self, Backlog)> {
+        sock.set_nonblocking(true)?;
+        let (backlog, waiting) = mpsc::channel(BACKLOG);
+        let socket = UdpSocket::from_std(sock)?;
+        Ok((AsyncUdp { socket, backlog }, Backlog(waiting)))
+    }
+}
+
+impl Transport for AsyncUdp {
+    fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        // Straight out, unless datagrams are already waiting
+        if self.backlog.capacity() == self.backlog.max_capacity() {
+            match self.socket.try_send_to(buf, to) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                sent => return sent,
+            }
+        }
+        queue(&self.backlog, (buf.to_vec(), to))?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.try_recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "reads on a tokio socket are awaited",
+        ))
+    }
+}
+
+/// Hand a listener bound the blocking way over to tokio
+pub(crate) fn listener(listener: std::net::TcpListener) -> io::Result<TcpListener> {
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Queue `item` for the task sending it out. Threads outside the runtime,
+/// like relays, wait for room, the runtime's own can't and get WouldBlock
+/// while the queue is full.
+pub(crate) fn queue<T>(queue: &mpsc::Sender<T>, item: T) -> io::Result<()> {
+    if Handle::try_current().is_err() {
+        return queue
+            .blocking_send(item)
+            .map_err(|_| ErrorKind::BrokenPipe.into());
+    }
+    queue.try_send(item).map_err(|err| match err {
+        TrySendError::Full(_) => ErrorKind::WouldBlock.into(),
+        TrySendError::Closed(_) => ErrorKind::BrokenPipe.into(),
+    })
+}
+
+/// Send the datagrams `sock` had no room for, as room frees up
+pub(crate) async fn send_backlog(sock: Arc<AsyncUdp>, Backlog(mut waiting): Backlog) {
+    while let Some((datagram, to)) = waiting.recv().await {
+        if let Err(err) = sock.socket.send_to(&datagram, to).await {
+            eprintln!("Sending to {} failed: {}", to, err);
+        }
+    }
+}
+
+pub(crate) async fn listen_udp(turn: Arc<Turn>, sock: Arc<AsyncUdp>) {
+    let local = sock.socket.local_addr().expect("local addr");
+    println!("Listening on {:?} (async)", local);
+
+    let reply = Reply::Udp(sock.clone());
+    let mut buf = [0; 2048];
+    loop {
+        let (amt, src) = match sock.socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // ICMP errors for earlier replies, nothing to do with this one
+            Err(err) => {
+                eprintln!("Receiving on {} failed: {}", local, err);
+                continue;
+            }
+        };
+        handle(&reply, &turn, local, src, &buf[..amt]).await;
+    }
+}
+
+pub(crate) async fn listen_tcp(
+    turn: Arc<Turn>,
+    listener: TcpListener,
+    connections: Arc<Connections>,
+) {
+    println!(
+        "Listening on {:?} (TCP, async)",
+        listener.local_addr().unwrap()
+    );
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                eprintln!("Accepting connection failed: {}", err);
+                continue;
+            }
+        };
+        if connections.open.fetch_add(1, Ordering::SeqCst) >= connections.max {
+            connections.open.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("Too many connections, closing {:?}", stream.peer_addr());
+            continue;
+        }
+        let (turn, connections) = (turn.clone(), connections.clone());
+        tokio::spawn(async move {
+            serve_tcp(turn, stream, connections.idle_timeout).await;
+            connections.open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Handle one client connection like the blocking `serve_tcp`, with a task
+/// of its own writing out the frames the handler queues. TCP allocations
+/// need data connections, which only the blocking path binds, so they're
+/// refused along with Connect and ConnectionBind.
+async fn serve_tcp(turn: Arc<Turn>, stream: TcpStream, idle_timeout: Duration) {
+    let (Ok(src), Ok(local)) = (stream.peer_addr(), stream.local_addr()) else {
+        return;
+    };
+    let (mut reader, mut writer) = stream.into_split();
+    let (frames, mut queued) = mpsc::channel::<Vec<u8>>(QUEUED_FRAMES);
+    let writing = tokio::spawn(async move {
+        while let Some(frame) = queued.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let reply = Reply::Queued(frames);
+    let request = Request::new(&reply, &turn, local, src);
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        while let Some(frame) = next_frame(&mut buf) {
+            handle(&reply, &turn, local, src, &frame).await;
+        }
+        match timeout(idle_timeout, reader.read(&mut chunk)).await {
+            Ok(Ok(0) | Err(_)) => break,
+            Ok(Ok(amt)) => buf.extend_from_slice(&chunk[..amt]),
+            Err(_) if request.controls_allocation() => {}
+            Err(_) => break,
+        }
+    }
+
+    // An allocation lives only as long as its control connection, and
+    // relays may still hold the queue so the writer is stopped outright
+    turn.allocations().remove(&request.five_tuple());
+    writing.abort();
+}
+
+/// Handle a STUN message or ChannelData frame like `Request::handle`, on
+/// tokio's blocking threads if handling it can take a while
+async fn handle(reply: &Reply, turn: &Arc<Turn>, local: SocketAddr, src: SocketAddr, data: &[u8]) {
+    if !blocks(data) {
+        return Request::new(reply, turn, local, src).handle(data);
+    }
+    let (reply, turn, data) = (reply.clone(), turn.clone(), data.to_vec());
+    let handling =
+        tokio::task::spawn_blocking(move || Request::new(&reply, &turn, local, src).handle(&data));
+    if let Err(err) = handling.await {
+        eprintln!("Handling a request from {} failed: {}", src, err);
+    }
+}
+
+/// Allocate binds a relay and starts its threads, Connect waits for a peer
+/// to accept
+fn blocks(data: &[u8]) -> bool {
+    MessageRef::parse(data).is_ok_and(|request| {
+        matches!(
+            request.header_type(),
+            HeaderType::AllocateRequest | HeaderType::ConnectRequest
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use message::{
+        header::{Header, HeaderType},
+        Message,
+    };
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::runtime::Runtime;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn binding_request() -> Vec<u8> {
+        Message::new(Header::with_random_id(HeaderType::BindingRequest), vec![]).encode()
+    }
+
+    #[test]
+    fn test_async_udp_keeps_what_it_has_no_room_for() {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let receiver = std::net::UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+            receiver
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let to = receiver.local_addr().unwrap();
+            let sock = std::net::UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+            let (sock, backlog) = AsyncUdp::from_std(sock).unwrap();
+            let sock = Arc::new(sock);
+
+            // Until tokio sees the socket writable every send would block
+            for i in 0..16 {
+                assert_eq!(sock.send_to(&[i], to).unwrap(), 1);
+            }
+            tokio::spawn(send_backlog(sock, backlog));
+            let received = tokio::task::spawn_blocking(move || {
+                let mut buf = [0; 16];
+                (0..16)
+                    .map(|_| receiver.recv(&mut buf).map(|_| buf[0]).unwrap())
+                    .collect::<Vec<_>>()
+            });
+            assert_eq!(received.await.unwrap(), (0..16).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn test_queue_waits_only_outside_the_runtime() {
+        let runtime = Runtime::new().unwrap();
+        let (frames, mut queued) = mpsc::channel(1);
+        queue(&frames, 1).unwrap();
+        let full = runtime.block_on(async { queue(&frames, 2) });
+        assert_eq!(full.unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // A relay thread waits for the frame ahead of it to go out
+        let relay = std::thread::spawn(move || queue(&frames, 3));
+        assert_eq!(runtime.block_on(queued.recv()), Some(1));
+        relay.join().unwrap().unwrap();
+        assert_eq!(runtime.block_on(queued.recv()), Some(3));
+        assert_eq!(runtime.block_on(queued.recv()), None);
+    }
+
+    #[test]
+    fn test_async_binding_over_udp_and_tcp() {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+            server.set_idle_timeout(Duration::from_millis(200));
+            server.spawn_async().await;
+            let addrs = server.local_addrs();
+
+            let socket = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0))
+                .await
+                .unwrap();
+            let request = binding_request();
+            socket.send_to(&request, addrs[0]).await.unwrap();
+            let mut buf = [0; 2048];
+            let (amt, _) = socket.recv_from(&mut buf).await.unwrap();
+            let response = Message::decode(&buf[..amt]).unwrap();
+            assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+            assert_eq!(response.header.transaction_id[4..], request[8..20]);
+
+            // Two requests in one write, then the idle connection is closed
+            let mut stream = TcpStream::connect(addrs[1]).await.unwrap();
+            let (first, second) = (binding_request(), binding_request());
+            stream
+                .write_all(&[first.clone(), second.clone()].concat())
+                .await
+                .unwrap();
+            for request in [first, second] {
+                let mut header = [0; 20];
+                stream.read_exact(&mut header).await.unwrap();
+                let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).await.unwrap();
+                let response = Message::decode(&[&header[..], &body].concat()).unwrap();
+                assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+                assert_eq!(response.header.transaction_id[4..], request[8..20]);
+            }
+            let closed = timeout(Duration::from_secs(1), stream.read(&mut buf)).await;
+            assert!(matches!(closed, Ok(Ok(0))));
+        });
+    }
+}
@@ -1,108 +1,2490 @@
 use std::{
     collections::HashMap,
-    net::{IpAddr, SocketAddr, UdpSocket},
-    sync::{Arc, Mutex},
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::Duration,
 };
 
 use message::{
-    attribute::{ErrorCode, MappedAddress, Value},
-    header::{Header, HeaderType},
+    attribute::{
+        AlternateDomain, AlternateServer, AttrType, Attribute, ChangedAddress, ChannelNumber,
+        ConnectionId, Data, ErrorCode, Lifetime, MappedAddress, MessageIntegrity, Nonce, Realm,
+        RequestedAddressFamily, RequestedTransport, Software, UnknownAttributes, Username, Value,
+        XorMappedAddress, XorPeerAddress, XorRelayedAddress,
+    },
+    encoder::Encoder,
+    error::Error,
+    header::{Class, Header, HeaderType},
+    packet::{self, Packet},
+    transport,
+    validate::{Policy, Violation},
+    view::MessageRef,
     Message,
 };
 
-type UserMap = Arc<Mutex<HashMap<String, String>>>;
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslContext, SslStream};
+use socket2::{Domain, Protocol, Socket, Type};
+#[cfg(feature = "tls")]
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+
+#[cfg(target_os = "linux")]
+use crate::batch::{self, Outbox};
+#[cfg(feature = "async")]
+use crate::runtime::{self, AsyncUdp};
+#[cfg(feature = "tls")]
+use crate::tls::{self, Certificate, Channel};
+use crate::{
+    redirect::{Alternate, Redirect},
+    rendezvous,
+    turn::{self, Allocation, FiveTuple, Relay, Transport, Turn},
+};
+
+/// Read on every authenticated request and written only while configuring,
+/// so lookups don't wait on each other
+pub type UserMap = Arc<RwLock<HashMap<String, String>>>;
+
+/// The way back to a client, over the transport its request came in on
+#[derive(Clone)]
+pub enum Reply {
+    Udp(Arc<dyn transport::Transport>),
+    /// Shared by the connection and its relay threads, the lock keeps
+    /// their frames from interleaving on the stream
+    Tcp(Arc<Mutex<TcpStream>>),
+    #[cfg(feature = "tls")]
+    Tls(Arc<Mutex<SslStream<Channel>>>),
+    #[cfg(feature = "tls")]
+    Dtls(Arc<Mutex<SslStream<Channel>>>),
+    /// A UDP socket read with recvmmsg, replies are queued until the batch
+    /// they answer is handled
+    #[cfg(target_os = "linux")]
+    Batched(Arc<Outbox>),
+    /// A TCP connection served by a tokio task, frames are queued for the
+    /// task writing them out
+    #[cfg(feature = "async")]
+    Queued(tokio::sync::mpsc::Sender<Vec<u8>>),
+}
+
+impl Reply {
+    pub fn transport(&self) -> Transport {
+        match self {
+            Reply::Udp(_) => Transport::Udp,
+            #[cfg(target_os = "linux")]
+            Reply::Batched(_) => Transport::Udp,
+            Reply::Tcp(_) => Transport::Tcp,
+            #[cfg(feature = "tls")]
+            Reply::Tls(_) => Transport::Tls,
+            #[cfg(feature = "tls")]
+            Reply::Dtls(_) => Transport::Dtls,
+            #[cfg(feature = "async")]
+            Reply::Queued(_) => Transport::Tcp,
+        }
+    }
+
+    /// Whether data connections for TCP allocations can be bound next to
+    /// this connection, which only blocking TCP connections can
+    fn binds_data_connections(&self) -> bool {
+        matches!(self, Reply::Tcp(_))
+    }
+
+    /// The way back for a relay thread, which sends whenever a peer does
+    /// and can't wait for a batch to be handled
+    fn unbatched(&self) -> Reply {
+        match self {
+            #[cfg(target_os = "linux")]
+            Reply::Batched(outbox) => Reply::Udp(outbox.socket().clone()),
+            reply => reply.clone(),
+        }
+    }
+
+    /// Send a packet to the client at `to`, which is implied on a stream or
+    /// session
+    pub fn send(&self, packet: Packet, to: SocketAddr) -> io::Result<()> {
+        match self {
+            Reply::Udp(socket) => socket.send_to(&packet.encode(), to).map(|_| ()),
+            #[cfg(target_os = "linux")]
+            Reply::Batched(outbox) => outbox.push(to, |buf| packet.encode_into(buf)),
+            Reply::Tcp(stream) => stream.lock().unwrap().write_all(&packet.encode_padded()),
+            #[cfg(feature = "tls")]
+            Reply::Tls(session) => session.lock().unwrap().write_all(&packet.encode_padded()),
+            #[cfg(feature = "tls")]
+            Reply::Dtls(session) => session.lock().unwrap().write_all(&packet.encode()),
+            #[cfg(feature = "async")]
+            Reply::Queued(frames) => runtime::queue(frames, packet.encode_padded()),
+        }
+    }
+
+    /// Send a STUN message that's already encoded, which needs no padding
+    /// on a stream
+    pub fn send_encoded(&self, data: &[u8], to: SocketAddr) -> io::Result<()> {
+        match self {
+            Reply::Udp(socket) => socket.send_to(data, to).map(|_| ()),
+            #[cfg(target_os = "linux")]
+            Reply::Batched(outbox) => outbox.push(to, |buf| buf.extend_from_slice(data)),
+            Reply::Tcp(stream) => stream.lock().unwrap().write_all(data),
+            #[cfg(feature = "tls")]
+            Reply::Tls(session) | Reply::Dtls(session) => session.lock().unwrap().write_all(data),
+            #[cfg(feature = "async")]
+            Reply::Queued(frames) => runtime::queue(frames, data.to_vec()),
+        }
+    }
+}
+
+/// TCP connections that send nothing for this long are closed, unless they
+/// control an allocation
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_CONNECTIONS: usize = 1024;
+
+/// Room for the largest binding response, with a SOFTWARE of 763 bytes
+const BINDING_RESPONSE_BUF: usize = 1024;
+
+/// What the server says it is in the SOFTWARE attribute of its responses
+pub const SOFTWARE: &str = concat!("totem ", env!("CARGO_PKG_VERSION"));
 
 pub struct Server {
     sockets: [SocketAddr; 4],
     users: UserMap,
+    idle_timeout: Duration,
+    max_connections: usize,
+    #[cfg(feature = "tls")]
+    tls: Option<(SocketAddr, Certificate)>,
+    redirect: Option<Redirect>,
+    software: Option<String>,
+    policy: Policy,
+    rendezvous: Option<SocketAddr>,
+    workers: usize,
 }
 
 impl Server {
     pub fn new(sockets: [SocketAddr; 4]) -> Self {
         Self {
             sockets,
-            users: Arc::new(Mutex::new(HashMap::new())),
+            users: Arc::new(RwLock::new(HashMap::new())),
+            idle_timeout: IDLE_TIMEOUT,
+            max_connections: MAX_CONNECTIONS,
+            #[cfg(feature = "tls")]
+            tls: None,
+            redirect: None,
+            software: Some(SOFTWARE.into()),
+            policy: Policy::Lenient,
+            rendezvous: None,
+            workers: 1,
+        }
+    }
+
+    /// Add a long-term credential, TURN allocations are only handed out to
+    /// known users
+    pub fn add_user(&mut self, username: String, password: String) {
+        self.users.write().unwrap().insert(username, password);
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Cap on open TCP connections across all addresses, connections past
+    /// it are closed right after being accepted
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Also offer STUN and TURN over TLS and DTLS on `addr`, usually on
+    /// port 5349, presenting `certificate`
+    #[cfg(feature = "tls")]
+    pub fn enable_tls(&mut self, addr: SocketAddr, certificate: Certificate) {
+        self.tls = Some((addr, certificate));
+    }
+
+    /// Send clients to other servers with 300 Try Alternate, as `redirect`
+    /// decides
+    pub fn set_redirect(&mut self, redirect: Redirect) {
+        self.redirect = Some(redirect);
+    }
+
+    /// SOFTWARE to put in responses instead of `SOFTWARE`, or none with
+    /// None. It has to be fewer than 128 characters.
+    pub fn set_software(&mut self, software: Option<String>) -> Result<(), Error> {
+        if let Some(software) = &software {
+            Software::new(software.clone())?;
         }
+        self.software = software;
+        Ok(())
+    }
+
+    /// How closely requests have to keep to RFC 8489, those that don't are
+    /// answered with 400. Lenient by default, for RFC 3489 clients.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Also introduce hole punching peers to each other on `addr`, usually
+    /// on `message::punch::RENDEZVOUS_PORT`
+    pub fn enable_rendezvous(&mut self, addr: SocketAddr) {
+        self.rendezvous = Some(addr);
+    }
+
+    /// UDP sockets per address, each read by a thread or task of its own.
+    /// Past one they share the address with SO_REUSEPORT, and the OS
+    /// spreads clients across them.
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers.max(1);
+    }
+
+    pub fn local_addrs(&self) -> [SocketAddr; 4] {
+        self.sockets
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls.as_ref().map(|(addr, _)| *addr)
+    }
+
+    pub fn rendezvous_addr(&self) -> Option<SocketAddr> {
+        self.rendezvous
     }
 
     pub fn run(&mut self) {
-        let mut threads = Vec::with_capacity(self.sockets.len());
-        for socket in self.sockets {
-            let users = self.users.clone();
-            let sock = UdpSocket::bind(socket).expect("Socket failed to bind");
-            let tid = std::thread::spawn(move || listen_udp(users, sock));
-            threads.push(tid);
-        }
-        for thread in threads {
+        for thread in self.spawn() {
             thread.join().expect("thread join");
         }
     }
+
+    /// Serve STUN and TURN over `sockets` instead of binding the configured
+    /// addresses, over UDP only. For running on a simulated network, the
+    /// addresses are replaced with the sockets' own.
+    pub fn spawn_over(
+        &mut self,
+        sockets: [Arc<dyn transport::Transport>; 4],
+    ) -> Vec<JoinHandle<()>> {
+        for (addr, sock) in self.sockets.iter_mut().zip(&sockets) {
+            *addr = sock.local_addr().expect("local addr");
+        }
+        let turn = self.turn(sockets.to_vec());
+        sockets
+            .into_iter()
+            .map(|sock| {
+                let turn = turn.clone();
+                std::thread::spawn(move || listen_udp(turn, sock))
+            })
+            .collect()
+    }
+
+    /// The state shared by every socket, with a thread dropping its
+    /// expired allocations
+    fn turn(&self, udp: Vec<Arc<dyn transport::Transport>>) -> Arc<Turn> {
+        let turn = Arc::new(Turn::new(
+            self.users.clone(),
+            self.redirect.clone(),
+            self.software.clone(),
+            self.policy,
+            udp,
+        ));
+        let sweeping = Arc::downgrade(&turn);
+        std::thread::spawn(move || turn::sweep(sweeping));
+        turn
+    }
+
+    fn connections(&self) -> Arc<Connections> {
+        Arc::new(Connections {
+            idle_timeout: self.idle_timeout,
+            max: self.max_connections,
+            open: AtomicUsize::new(0),
+        })
+    }
+
+    /// Bind all sockets and start listening on them, over UDP and TCP on
+    /// the same addresses. The configured addresses are replaced with the
+    /// ones actually bound.
+    pub fn spawn(&mut self) -> Vec<JoinHandle<()>> {
+        let reuse_port = self.workers > 1;
+        let mut bound = Vec::with_capacity(self.sockets.len());
+        for socket in self.sockets.iter_mut() {
+            let (sock, listener) = bind(*socket, reuse_port).expect("Socket failed to bind");
+            *socket = sock.local_addr().expect("local addr");
+            let mut workers = vec![Arc::new(sock)];
+            for _ in 1..self.workers {
+                let sock = bind_udp(*socket, true).expect("Socket failed to bind");
+                workers.push(Arc::new(sock));
+            }
+            bound.push((workers, listener));
+        }
+        // Any of an address's sockets can answer CHANGE-REQUEST
+        let udp = bound.iter().map(|(workers, _)| {
+            let sock: Arc<dyn transport::Transport> = workers[0].clone();
+            sock
+        });
+        let turn = self.turn(udp.collect());
+        let connections = self.connections();
+        let mut threads = Vec::with_capacity(self.sockets.len() * (self.workers + 1));
+        for (workers, listener) in bound {
+            for sock in workers {
+                let udp_turn = turn.clone();
+                threads.push(std::thread::spawn(move || listen_bound(udp_turn, sock)));
+            }
+            let (tcp_turn, connections) = (turn.clone(), connections.clone());
+            threads.push(std::thread::spawn(move || {
+                listen_tcp(tcp_turn, listener, "TCP", connections, serve_tcp)
+            }));
+        }
+        threads.extend(self.spawn_others(turn, connections));
+        threads
+    }
+
+    /// Like `spawn`, with a tokio task per socket and per TCP connection
+    /// instead of a thread. TLS, DTLS and the rendezvous still get threads
+    /// of their own, which are waited on from tokio's blocking threads so
+    /// the tasks returned cover them, and a runtime dropped waits for them
+    /// too. Has to be called on a tokio runtime.
+    #[cfg(feature = "async")]
+    pub async fn spawn_async(&mut self) -> Vec<tokio::task::JoinHandle<()>> {
+        let reuse_port = self.workers > 1;
+        let mut bound = Vec::with_capacity(self.sockets.len());
+        for socket in self.sockets.iter_mut() {
+            let (sock, listener) = bind(*socket, reuse_port).expect("Socket failed to bind");
+            *socket = sock.local_addr().expect("local addr");
+            let mut workers = vec![sock];
+            for _ in 1..self.workers {
+                workers.push(bind_udp(*socket, true).expect("Socket failed to bind"));
+            }
+            let workers: Vec<_> = workers
+                .into_iter()
+                .map(|sock| {
+                    let (sock, backlog) = AsyncUdp::from_std(sock).expect("tokio socket");
+                    (Arc::new(sock), backlog)
+                })
+                .collect();
+            let listener = runtime::listener(listener).expect("tokio listener");
+            bound.push((workers, listener));
+        }
+        // Any of an address's sockets can answer CHANGE-REQUEST
+        let udp = bound.iter().map(|(workers, _)| {
+            let sock: Arc<dyn transport::Transport> = workers[0].0.clone();
+            sock
+        });
+        let turn = self.turn(udp.collect());
+        let connections = self.connections();
+        let mut tasks = Vec::with_capacity(self.sockets.len() * (self.workers * 2 + 1));
+        for (workers, listener) in bound {
+            for (sock, backlog) in workers {
+                tasks.push(tokio::spawn(runtime::send_backlog(sock.clone(), backlog)));
+                tasks.push(tokio::spawn(runtime::listen_udp(turn.clone(), sock)));
+            }
+            let listening = runtime::listen_tcp(turn.clone(), listener, connections.clone());
+            tasks.push(tokio::spawn(listening));
+        }
+        let threads = self.spawn_others(turn, connections);
+        tasks.extend(threads.into_iter().map(|thread| {
+            tokio::task::spawn_blocking(move || thread.join().expect("thread join"))
+        }));
+        tasks
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn run_async(&mut self) {
+        for task in self.spawn_async().await {
+            task.await.expect("task join");
+        }
+    }
+
+    /// Start TLS, DTLS and the rendezvous if they're enabled
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn spawn_others(
+        &mut self,
+        turn: Arc<Turn>,
+        connections: Arc<Connections>,
+    ) -> Vec<JoinHandle<()>> {
+        let mut threads = Vec::new();
+        #[cfg(feature = "tls")]
+        if let Some((addr, certificate)) = &mut self.tls {
+            let (sock, listener) = bind(*addr, false).expect("Socket failed to bind");
+            *addr = sock.local_addr().expect("local addr");
+
+            let (dtls_turn, context) = (turn.clone(), certificate.dtls.clone());
+            let dtls_connections = connections.clone();
+            threads.push(std::thread::spawn(move || {
+                listen_dtls(dtls_turn, sock, context, dtls_connections)
+            }));
+            let (tls_turn, context) = (turn.clone(), certificate.tls.clone());
+            let serve =
+                move |turn, stream, idle_timeout| serve_tls(turn, stream, &context, idle_timeout);
+            threads.push(std::thread::spawn(move || {
+                listen_tcp(tls_turn, listener, "TLS", connections, serve)
+            }));
+        }
+
+        if let Some(addr) = &mut self.rendezvous {
+            let sock = UdpSocket::bind(*addr).expect("Socket failed to bind");
+            *addr = sock.local_addr().expect("local addr");
+            threads.push(std::thread::spawn(move || rendezvous::listen(sock)));
+        }
+        threads
+    }
 }
 
-fn listen_udp(users: UserMap, sock: UdpSocket) {
-    println!("Listening on {:?}", sock.local_addr().unwrap());
+/// Bind UDP and TCP on the same address. A port picked by the OS for UDP
+/// can already be in use for TCP, in which case both are bound again.
+fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<(UdpSocket, TcpListener)> {
+    let mut attempts = 0;
+    loop {
+        let sock = bind_udp(addr, reuse_port)?;
+        match TcpListener::bind(sock.local_addr()?) {
+            Ok(listener) => return Ok((sock, listener)),
+            Err(err) if err.kind() == ErrorKind::AddrInUse && addr.port() == 0 && attempts < 8 => {
+                attempts += 1
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// A UDP socket that other sockets can bind the same address as, if they
+/// also ask to with `reuse_port`
+fn bind_udp(addr: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(unix)]
+    sock.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+/// Listen on a socket the server bound itself, in batches where the OS
+/// has recvmmsg
+fn listen_bound(turn: Arc<Turn>, sock: Arc<UdpSocket>) {
+    #[cfg(target_os = "linux")]
+    batch::listen(turn, sock);
+    #[cfg(not(target_os = "linux"))]
+    listen_udp(turn, sock);
+}
+
+fn listen_udp(turn: Arc<Turn>, sock: Arc<dyn transport::Transport>) {
+    let local = sock.local_addr().expect("local addr");
+    println!("Listening on {:?}", local);
 
-    let mut buf = [0; 1024];
+    let reply = Reply::Udp(sock.clone());
+    let mut buf = [0; 2048];
     loop {
         let (amt, src) = sock.recv_from(&mut buf).expect("recv data");
-        let request = Request::new(&sock, users.clone(), src);
-        assert!(amt <= 1024, "request too big");
+        let request = Request::new(&reply, &turn, local, src);
+        assert!(amt <= 2048, "request too big");
+        request.handle(&buf[..amt]);
+    }
+}
+
+/// Demultiplex DTLS clients by address, each gets a thread that owns its
+/// session and is fed the datagrams its client sends. A session is only
+/// kept once its client returns a cookie, and counts as a connection.
+#[cfg(feature = "tls")]
+fn listen_dtls(
+    turn: Arc<Turn>,
+    sock: UdpSocket,
+    context: SslContext,
+    connections: Arc<Connections>,
+) {
+    let local = sock.local_addr().expect("local addr");
+    println!("Listening on {:?} (DTLS)", local);
+
+    let sock = Arc::new(sock);
+    let sessions: Arc<Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>> = Default::default();
+    let mut buf = [0; 2048];
+    loop {
+        let (amt, src) = sock.recv_from(&mut buf).expect("recv data");
+        let datagram = buf[..amt].to_vec();
+
+        let mut clients = sessions.lock().unwrap();
+        if let Some(client) = clients.get(&src) {
+            let _ = client.send(datagram);
+            continue;
+        }
+        let session = match tls::dtls_accept(&context, sock.clone(), src, datagram) {
+            Ok(Some(session)) => session,
+            Ok(None) => continue,
+            Err(err) => {
+                eprintln!("DTLS session for {} failed: {}", src, err);
+                continue;
+            }
+        };
+        if connections.open.fetch_add(1, Ordering::SeqCst) >= connections.max {
+            connections.open.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("Too many connections, dropping DTLS client {}", src);
+            continue;
+        }
+        let (client, datagrams) = mpsc::channel();
+        clients.insert(src, client);
+
+        let (turn, sessions, connections) = (turn.clone(), sessions.clone(), connections.clone());
+        std::thread::spawn(move || {
+            let idle_timeout = connections.idle_timeout;
+            serve_dtls(turn, session, local, src, datagrams, idle_timeout);
+            sessions.lock().unwrap().remove(&src);
+            connections.open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+#[cfg(feature = "tls")]
+fn serve_dtls(
+    turn: Arc<Turn>,
+    session: SslStream<Channel>,
+    local: SocketAddr,
+    src: SocketAddr,
+    datagrams: Receiver<Vec<u8>>,
+    idle_timeout: Duration,
+) {
+    let session = Arc::new(Mutex::new(session));
+    let reply = Reply::Dtls(session.clone());
+    let request = Request::new(&reply, &turn, local, src);
+
+    loop {
+        let datagram = match datagrams.recv_timeout(idle_timeout) {
+            Ok(datagram) => datagram,
+            Err(RecvTimeoutError::Timeout) if request.controls_allocation() => continue,
+            Err(_) => break,
+        };
+        let received = tls::receive(&mut session.lock().unwrap(), datagram);
+        match received {
+            // Every record holds one whole message
+            Ok(records) => records.iter().for_each(|record| request.handle(record)),
+            Err(err) => {
+                eprintln!("Closing DTLS session with {}: {}", src, err);
+                break;
+            }
+        }
+    }
+
+    turn.allocations().remove(&request.five_tuple());
+}
+
+/// Limits shared by the TCP listeners
+pub(crate) struct Connections {
+    pub(crate) idle_timeout: Duration,
+    pub(crate) max: usize,
+    pub(crate) open: AtomicUsize,
+}
+
+/// Accept connections, each served by `serve` on a thread of its own
+fn listen_tcp<F>(
+    turn: Arc<Turn>,
+    listener: TcpListener,
+    transport: &str,
+    connections: Arc<Connections>,
+    serve: F,
+) where
+    F: Fn(Arc<Turn>, TcpStream, Duration) + Clone + Send + 'static,
+{
+    println!(
+        "Listening on {:?} ({})",
+        listener.local_addr().unwrap(),
+        transport
+    );
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Accepting connection failed: {}", err);
+                continue;
+            }
+        };
+        if connections.open.fetch_add(1, Ordering::SeqCst) >= connections.max {
+            connections.open.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("Too many connections, closing {:?}", stream.peer_addr());
+            continue;
+        }
+        let (turn, serve, connections) = (turn.clone(), serve.clone(), connections.clone());
+        std::thread::spawn(move || {
+            serve(turn, stream, connections.idle_timeout);
+            connections.open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Handle one client connection. Frames are delimited by their own length
+/// field and a read can end anywhere in one, so bytes are buffered until a
+/// whole frame is in.
+fn serve_tcp(turn: Arc<Turn>, mut stream: TcpStream, idle_timeout: Duration) {
+    let (Ok(src), Ok(local), Ok(writer), Ok(())) = (
+        stream.peer_addr(),
+        stream.local_addr(),
+        stream.try_clone(),
+        stream.set_read_timeout(Some(idle_timeout)),
+    ) else {
+        return;
+    };
+    let reply = Reply::Tcp(Arc::new(Mutex::new(writer)));
+    let request = Request::new(&reply, &turn, local, src);
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        while let Some(frame) = next_frame(&mut buf) {
+            match Message::decode(&frame) {
+                Ok(message) if message.header.header_type == HeaderType::ConnectionBindRequest => {
+                    // A bound data connection stops carrying STUN, from here
+                    // on it is spliced to the peer
+                    if let Some((id, peer)) = request.handle_connection_bind(message, &frame) {
+                        let _ = stream.set_read_timeout(None);
+                        return turn::pipe(turn.clone(), id, stream, buf, peer);
+                    }
+                }
+                _ => request.handle(&frame),
+            }
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(amt) => buf.extend_from_slice(&chunk[..amt]),
+            Err(err) if is_timeout(&err) && request.controls_allocation() => {}
+            Err(_) => break,
+        }
+    }
+
+    // An allocation lives only as long as its control connection
+    turn.allocations().remove(&request.five_tuple());
+}
+
+/// Like `serve_tcp`, with the bytes read decrypted first. Data connections
+/// for TCP allocations aren't supported over TLS.
+#[cfg(feature = "tls")]
+fn serve_tls(turn: Arc<Turn>, mut stream: TcpStream, context: &SslContext, idle_timeout: Duration) {
+    let (Ok(src), Ok(local), Ok(writer), Ok(())) = (
+        stream.peer_addr(),
+        stream.local_addr(),
+        stream.try_clone(),
+        stream.set_read_timeout(Some(idle_timeout)),
+    ) else {
+        return;
+    };
+    let session = match tls::tls_session(context, writer) {
+        Ok(session) => Arc::new(Mutex::new(session)),
+        Err(err) => return eprintln!("TLS session for {} failed: {}", src, err),
+    };
+    let reply = Reply::Tls(session.clone());
+    let request = Request::new(&reply, &turn, local, src);
 
-        let buf = &mut buf[..amt];
-        let message = Message::decode(&buf);
-        request.dispatch(message);
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let amt = match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(amt) => amt,
+            Err(err) if is_timeout(&err) && request.controls_allocation() => continue,
+            Err(_) => break,
+        };
+        let received = tls::receive(&mut session.lock().unwrap(), chunk[..amt].to_vec());
+        match received {
+            Ok(records) => records
+                .iter()
+                .for_each(|record| buf.extend_from_slice(record)),
+            Err(err) => {
+                eprintln!("Closing TLS connection with {}: {}", src, err);
+                break;
+            }
+        }
+        while let Some(frame) = next_frame(&mut buf) {
+            request.handle(&frame);
+        }
     }
+
+    turn.allocations().remove(&request.five_tuple());
+}
+
+/// Take the first frame off a stream's buffer, once it's all there
+pub(crate) fn next_frame(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = Packet::frame_len(buf).filter(|len| buf.len() >= *len)?;
+    Some(buf.drain(..len).collect())
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
 }
 
-struct Request<'a> {
-    socket: &'a UdpSocket,
-    _users: UserMap,
+pub(crate) struct Request<'a> {
+    reply: &'a Reply,
+    turn: &'a Arc<Turn>,
+    local: SocketAddr,
     src: SocketAddr,
 }
 
 impl<'a> Request<'a> {
-    fn new(socket: &'a UdpSocket, users: UserMap, src: SocketAddr) -> Self {
+    pub(crate) fn new(
+        reply: &'a Reply,
+        turn: &'a Arc<Turn>,
+        local: SocketAddr,
+        src: SocketAddr,
+    ) -> Self {
         Self {
-            socket,
-            _users: users,
+            reply,
+            turn,
+            local,
             src,
         }
     }
 
-    fn dispatch(&self, message: Message) {
+    /// Handle a STUN message or ChannelData frame
+    pub(crate) fn handle(&self, data: &[u8]) {
+        // Binding requests, most of what a busy server gets, are answered
+        // from the buffer they came in without decoding them
+        if let Ok(request) = MessageRef::parse(data) {
+            if let Err(violation) = request.validate(self.turn.policy()) {
+                return self.reject(&request.header(), violation);
+            }
+            if request.header_type() == HeaderType::BindingRequest {
+                return self.handle_binding(request);
+            }
+        }
+        match Packet::decode(data) {
+            Ok(Packet::Stun(message)) => {
+                let unknown: Vec<_> = message.unknown_required().collect();
+                match unknown.is_empty() {
+                    true => self.dispatch(message, data),
+                    false => self.reject_unknown(&message.header, unknown),
+                }
+            }
+            Ok(Packet::ChannelData { channel, payload }) => {
+                self.handle_channel_data(channel, payload)
+            }
+            Err(err) => eprintln!("Dropping packet from {}: {}", self.src, err),
+        }
+    }
+
+    fn dispatch(&self, message: Message, data: &[u8]) {
         match &message.header.header_type {
-            HeaderType::BindingRequest => self.handle_binding(message),
-            HeaderType::SharedSecretRequest => self.handle_shared(message),
-            _ => panic!("invalid request"),
+            // RFC 5389 removed Shared Secret, it's only refused
+            HeaderType::SharedSecretRequest => {
+                self.send_error(&message.header, 400, "Shared Secret not supported", vec![])
+            }
+            HeaderType::AllocateRequest => self.handle_allocate(message, data),
+            HeaderType::RefreshRequest => self.handle_refresh(message, data),
+            HeaderType::CreatePermissionRequest => self.handle_create_permission(message, data),
+            HeaderType::ChannelBindRequest => self.handle_channel_bind(message, data),
+            HeaderType::SendIndication => self.handle_send(message),
+            HeaderType::ConnectRequest => self.handle_connect(message, data),
+            // Binding requests are picked up before decoding, and binding a
+            // data connection only makes sense on its own blocking TCP
+            // connection, those are picked up before dispatch
+            HeaderType::ConnectionBindRequest if self.reply.binds_data_connections() => {
+                self.send_error(&message.header, 400, "Bad Request", vec![])
+            }
+            HeaderType::ConnectionBindRequest => {
+                self.send_error(&message.header, 400, "TCP Allocations Need TCP", vec![])
+            }
+            // Indications are never answered, receiving one is enough to keep
+            // the client's NAT binding alive
+            HeaderType::BindingIndication => {}
+            header_type => eprintln!("Ignoring {:?} from {}", header_type, self.src),
+        }
+    }
+
+    /// Answer a request with comprehension-required attributes we don't
+    /// know with 420 and which they are, RFC 8489 section 7.3.1. Other
+    /// messages are dropped.
+    fn reject_unknown(&self, header: &Header, unknown: Vec<u16>) {
+        match header.header_type.class() {
+            Class::Request => {
+                let unknown = UnknownAttributes::new(unknown);
+                let attributes = vec![Value::UnknownAttributes(unknown).into_attribute()];
+                self.send_error(header, 420, "Unknown Attribute", attributes)
+            }
+            _ => {
+                let err = Error::UnknownAttribute(unknown[0]);
+                eprintln!("Dropping packet from {}: {}", self.src, err)
+            }
+        }
+    }
+
+    /// Answer a request that parsed but isn't valid with 400 and why, other
+    /// messages are dropped
+    fn reject(&self, header: &Header, violation: Violation) {
+        match header.header_type.class() {
+            Class::Request => self.send_error(header, 400, &violation.to_string(), vec![]),
+            _ => eprintln!("Dropping packet from {}: {}", self.src, violation),
         }
     }
 
-    fn handle_binding(&self, message: Message) {
+    /// Answer a binding request without allocating, into a buffer on the
+    /// stack. Redirects and errors are rare and go the usual way.
+    fn handle_binding(&self, request: MessageRef) {
         use HeaderType::*;
-        let tx_id = message.header.transaction_id;
+        // Empty, and not allocated, unless there's something to answer
+        let unknown: Vec<_> = request.unknown_required().collect();
+        if !unknown.is_empty() {
+            return self.reject_unknown(&request.header(), unknown);
+        }
+        let tx_id = *request.transaction_id();
+        if let Some(alternate) = self.turn.alternate(self.src.ip(), false) {
+            return self.send_redirect(&request.header(), alternate, None);
+        }
 
         let ip = match self.src.ip() {
             IpAddr::V4(v4) => v4,
             _ => {
                 let header = Header::new(BindingErrorResponse, tx_id);
                 let err = Value::ErrorCode(ErrorCode::new(400, "ipv4 only".into()));
-                let message = Message::new(header, vec![err.into_attribute()]);
-                self.socket
-                    .send_to(&message.encode(), self.src)
-                    .expect("send to");
-                return;
+                let message = self.response(header, vec![err.into_attribute()]);
+                return self.send(message);
+            }
+        };
+
+        // RFC 3489 section 8.1, answer from another address so the client
+        // can tell how its NAT filters
+        let change = match request
+            .get(AttrType::ChangeRequest)
+            .map(|attr| attr.decode())
+        {
+            Some(Ok(Value::ChangeRequest(change))) => Some((change.change_ip, change.change_port)),
+            _ => None,
+        };
+        let changed = match change {
+            Some((change_ip, change_port)) if change_ip || change_port => {
+                if self.reply.transport() != Transport::Udp {
+                    return self.send_error(&request.header(), 400, "Bad Request", vec![]);
+                }
+                match self.turn.changed_socket(self.local, change_ip, change_port) {
+                    Some((_, sock)) => Some(sock),
+                    None => {
+                        let unknown = UnknownAttributes::new(vec![AttrType::ChangeRequest as u16]);
+                        let attributes = vec![Value::UnknownAttributes(unknown).into_attribute()];
+                        let header = request.header();
+                        return self.send_error(&header, 420, "Unknown Attribute", attributes);
+                    }
+                }
             }
+            _ => None,
+        };
+
+        let mut buf = [0; BINDING_RESPONSE_BUF];
+        let response = match self.binding_response(&mut buf, tx_id, ip) {
+            Ok(response) => response,
+            Err(err) => return eprintln!("Answering {} failed: {}", self.src, err),
+        };
+        let sent = match changed {
+            Some(sock) => sock.send_to(response, self.src).map(|_| ()),
+            None => self.reply.send_encoded(response, self.src),
         };
+        if let Err(err) = sent {
+            eprintln!("Replying to {} failed: {}", self.src, err);
+        }
+    }
 
-        let header = Header::new(BindingResponse, tx_id);
+    /// MAPPED-ADDRESS, CHANGED-ADDRESS over UDP, and SOFTWARE
+    fn binding_response<'b>(
+        &self,
+        buf: &'b mut [u8],
+        tx_id: [u8; 16],
+        ip: Ipv4Addr,
+    ) -> Result<&'b [u8], Error> {
+        let header = Header::new(HeaderType::BindingResponse, tx_id);
+        let mut response = Encoder::new(buf, &header)?;
         let mapped = Value::MappedAddress(MappedAddress::new(1, self.src.port(), ip));
-        let message = Message::new(header, vec![mapped.into_attribute()]);
-        self.socket
-            .send_to(&message.encode(), self.src)
-            .expect("send to");
+        response.add(&mapped.into_attribute())?;
+        if self.reply.transport() == Transport::Udp {
+            let other = self.turn.changed_socket(self.local, true, true);
+            if let Some((SocketAddr::V4(other), _)) = other {
+                let other = ChangedAddress::new(1, other.port(), *other.ip());
+                response.add(&Value::ChangedAddress(other).into_attribute())?;
+            }
+        }
+        if let Some(software) = self.turn.software() {
+            response.add_raw(AttrType::Software, software.as_bytes())?;
+        }
+        Ok(response.finish())
+    }
+
+    fn handle_allocate(&self, message: Message, data: &[u8]) {
+        let Some(key) = self.authenticate(&message, data) else {
+            return;
+        };
+        let five_tuple = self.five_tuple();
+        if self.turn.allocations().contains_key(&five_tuple) {
+            return self.send_error(&message.header, 437, "Allocation Mismatch", vec![]);
+        }
+        if let Some(alternate) = self.turn.alternate(self.src.ip(), true) {
+            return self.send_redirect(&message.header, alternate, Some(&key));
+        }
+
+        let transport = message
+            .get::<RequestedTransport>()
+            .map(|transport| transport.protocol);
+        // TCP allocations are controlled over TCP, RFC 6062 section 5.1
+        let transport = match transport {
+            Some(RequestedTransport::UDP) => Transport::Udp,
+            Some(RequestedTransport::TCP) if self.reply.binds_data_connections() => Transport::Tcp,
+            Some(RequestedTransport::TCP) => {
+                return self.send_error(&message.header, 400, "TCP Allocations Need TCP", vec![])
+            }
+            Some(_) => {
+                return self.send_error(
+                    &message.header,
+                    442,
+                    "Unsupported Transport Protocol",
+                    vec![],
+                )
+            }
+            None => {
+                return self.send_error(&message.header, 400, "Missing REQUESTED-TRANSPORT", vec![])
+            }
+        };
+        // Relays are always in the family of the address the client reached
+        let family = match five_tuple.server {
+            SocketAddr::V4(_) => RequestedAddressFamily::IPV4,
+            SocketAddr::V6(_) => RequestedAddressFamily::IPV6,
+        };
+        let requested_family = message
+            .get::<RequestedAddressFamily>()
+            .map(|requested| requested.family);
+        if requested_family.is_some_and(|requested| requested != family) {
+            return self.send_error(&message.header, 440, "Address Family not Supported", vec![]);
+        }
+        // Relay sockets can't set the DF bit, so DONT-FRAGMENT is treated
+        // as an unknown comprehension-required attribute
+        if message
+            .attributes
+            .iter()
+            .any(|attr| matches!(attr.value, Value::DontFragment(_)))
+        {
+            let unknown = UnknownAttributes::new(vec![AttrType::DontFragment as u16]);
+            let attributes = vec![Value::UnknownAttributes(unknown).into_attribute()];
+            return self.send_error(&message.header, 420, "Unknown Attribute", attributes);
+        }
+        let lifetime = requested_lifetime(&message)
+            .unwrap_or(turn::DEFAULT_LIFETIME)
+            .clamp(turn::DEFAULT_LIFETIME, turn::MAX_LIFETIME);
+
+        // The allocation goes in before its relay thread starts, so the
+        // thread doesn't mistake it for deleted
+        let addr = SocketAddr::new(five_tuple.server.ip(), 0);
+        let (turn, reply) = (self.turn.clone(), self.reply.unbatched());
+        let relayed = match transport {
+            Transport::Tcp => turn::relay_listener(addr).and_then(|listener| {
+                let relayed = listener.local_addr()?;
+                let allocation = Allocation::new(Relay::Tcp(relayed), lifetime);
+                self.turn.allocations().insert(five_tuple, allocation);
+                std::thread::spawn(move || turn::accept(turn, five_tuple, listener, reply));
+                Ok(relayed)
+            }),
+            _ => UdpSocket::bind(addr).and_then(|relay| {
+                let relayed = relay.local_addr()?;
+                let allocation = Allocation::new(Relay::Udp(relay.try_clone()?), lifetime);
+                self.turn.allocations().insert(five_tuple, allocation);
+                std::thread::spawn(move || turn::relay(turn, five_tuple, relay, reply));
+                Ok(relayed)
+            }),
+        };
+        let relayed = match relayed {
+            Ok(relayed) => relayed,
+            Err(err) => {
+                eprintln!("Allocating relay for {} failed: {}", self.src, err);
+                return self.send_error(&message.header, 508, "Insufficient Capacity", vec![]);
+            }
+        };
+
+        let tx_id = &message.header.transaction_id;
+        let attributes = vec![
+            Value::XorRelayedAddress(XorRelayedAddress::new(relayed, tx_id)).into_attribute(),
+            Value::Lifetime(Lifetime::new(lifetime)).into_attribute(),
+            Value::XorMappedAddress(XorMappedAddress::new(self.src, tx_id)).into_attribute(),
+        ];
+        self.send_success(&message, attributes, &key);
+    }
+
+    fn handle_refresh(&self, message: Message, data: &[u8]) {
+        let Some(key) = self.authenticate(&message, data) else {
+            return;
+        };
+        let lifetime = match requested_lifetime(&message) {
+            Some(0) => 0,
+            lifetime => lifetime
+                .unwrap_or(turn::DEFAULT_LIFETIME)
+                .clamp(turn::DEFAULT_LIFETIME, turn::MAX_LIFETIME),
+        };
+
+        let mut allocations = self.turn.allocations();
+        let five_tuple = self.five_tuple();
+        let Some(allocation) = allocations.get_mut(&five_tuple) else {
+            drop(allocations);
+            return self.send_error(&message.header, 437, "Allocation Mismatch", vec![]);
+        };
+        if lifetime == 0 {
+            allocations.remove(&five_tuple);
+        } else {
+            allocation.refresh(lifetime);
+        }
+        drop(allocations);
+
+        let attributes = vec![Value::Lifetime(Lifetime::new(lifetime)).into_attribute()];
+        self.send_success(&message, attributes, &key);
+    }
+
+    fn handle_create_permission(&self, message: Message, data: &[u8]) {
+        let Some(key) = self.authenticate(&message, data) else {
+            return;
+        };
+        let tx_id = &message.header.transaction_id;
+        let peers: Vec<_> = message
+            .attributes
+            .iter()
+            .filter_map(|attr| match &attr.value {
+                Value::XorPeerAddress(peer) => Some(peer.addr(tx_id)),
+                _ => None,
+            })
+            .collect();
+        if peers.is_empty() {
+            return self.send_error(&message.header, 400, "Missing XOR-PEER-ADDRESS", vec![]);
+        }
+
+        let mut allocations = self.turn.allocations();
+        let Some(allocation) = allocations.get_mut(&self.five_tuple()) else {
+            drop(allocations);
+            return self.send_error(&message.header, 437, "Allocation Mismatch", vec![]);
+        };
+        for peer in peers {
+            allocation.add_permission(peer.ip());
+        }
+        drop(allocations);
+
+        self.send_success(&message, vec![], &key);
+    }
+
+    fn handle_channel_bind(&self, message: Message, data: &[u8]) {
+        let Some(key) = self.authenticate(&message, data) else {
+            return;
+        };
+        let tx_id = &message.header.transaction_id;
+        let number = message.get::<ChannelNumber>().map(|number| number.number);
+        let peer = message.get::<XorPeerAddress>().map(|peer| peer.addr(tx_id));
+        let (Some(number), Some(peer)) = (number.filter(|n| packet::is_channel(*n)), peer) else {
+            return self.send_error(&message.header, 400, "Bad Request", vec![]);
+        };
+
+        let mut allocations = self.turn.allocations();
+        let Some(allocation) = allocations.get_mut(&self.five_tuple()) else {
+            drop(allocations);
+            return self.send_error(&message.header, 437, "Allocation Mismatch", vec![]);
+        };
+        // Channels carry datagrams, TCP allocations have none to carry
+        if matches!(allocation.relay, Relay::Tcp(_)) {
+            drop(allocations);
+            return self.send_error(&message.header, 400, "Bad Request", vec![]);
+        }
+        let bound = allocation.bind_channel(number, peer);
+        drop(allocations);
+
+        if !bound {
+            return self.send_error(&message.header, 400, "Channel Already Bound", vec![]);
+        }
+        self.send_success(&message, vec![], &key);
+    }
+
+    fn handle_send(&self, message: Message) {
+        let tx_id = &message.header.transaction_id;
+        let peer = message.get::<XorPeerAddress>().map(|peer| peer.addr(tx_id));
+        let data = message.get::<Data>().map(|data| &data.data);
+        let (Some(peer), Some(data)) = (peer, data) else {
+            return;
+        };
+        self.relay_to_peer(peer, data);
+    }
+
+    fn handle_channel_data(&self, number: u16, payload: &[u8]) {
+        let peer = {
+            let allocations = self.turn.allocations();
+            allocations
+                .get(&self.five_tuple())
+                .and_then(|allocation| allocation.channel_peer(number))
+        };
+        if let Some(peer) = peer {
+            self.relay_to_peer(peer, payload);
+        }
+    }
+
+    /// Send `data` out of the client's relay socket, if it has one with a
+    /// permission for the peer. Anything else is silently dropped.
+    fn relay_to_peer(&self, peer: SocketAddr, data: &[u8]) {
+        let allocations = self.turn.allocations();
+        let Some(allocation) = allocations.get(&self.five_tuple()) else {
+            return;
+        };
+        if !allocation.has_permission(peer.ip()) {
+            return;
+        }
+        let Relay::Udp(relay) = &allocation.relay else {
+            return;
+        };
+        if let Err(err) = relay.send_to(data, peer) {
+            eprintln!("Relaying to {} failed: {}", peer, err);
+        }
+    }
+
+    /// Open a connection to a peer from a TCP allocation, RFC 6062
+    /// section 5.2. The client then binds a data connection to it.
+    fn handle_connect(&self, message: Message, data: &[u8]) {
+        // The peer connection could never be bound to a data connection
+        if !self.reply.binds_data_connections() {
+            return self.send_error(&message.header, 400, "TCP Allocations Need TCP", vec![]);
+        }
+        let Some(key) = self.authenticate(&message, data) else {
+            return;
+        };
+        let tx_id = &message.header.transaction_id;
+        let Some(peer) = message.get::<XorPeerAddress>().map(|peer| peer.addr(tx_id)) else {
+            return self.send_error(&message.header, 400, "Missing XOR-PEER-ADDRESS", vec![]);
+        };
+
+        let five_tuple = self.five_tuple();
+        let relayed =
+            self.turn
+                .allocations()
+                .get(&five_tuple)
+                .map(|allocation| match allocation.relay {
+                    Relay::Tcp(relayed) => Some(relayed),
+                    Relay::Udp(_) => None,
+                });
+        let relayed = match relayed {
+            Some(Some(relayed)) => relayed,
+            Some(None) => return self.send_error(&message.header, 400, "Bad Request", vec![]),
+            None => return self.send_error(&message.header, 437, "Allocation Mismatch", vec![]),
+        };
+        if self.turn.has_connection(five_tuple, peer) {
+            return self.send_error(&message.header, 446, "Connection Already Exists", vec![]);
+        }
+
+        let stream = match turn::connect(relayed, peer) {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Connecting {} to {} failed: {}", relayed, peer, err);
+                return self.send_error(
+                    &message.header,
+                    447,
+                    "Connection Timeout or Failure",
+                    vec![],
+                );
+            }
+        };
+        let id = self.turn.add_connection(five_tuple, peer, stream);
+        let attributes = vec![Value::ConnectionId(ConnectionId::new(id)).into_attribute()];
+        self.send_success(&message, attributes, &key);
+    }
+
+    /// Bind this connection to a pending peer connection, RFC 6062
+    /// section 5.4. Returns the connection id and the peer's stream to
+    /// splice this one to.
+    fn handle_connection_bind(&self, message: Message, data: &[u8]) -> Option<(u32, TcpStream)> {
+        let key = self.authenticate(&message, data)?;
+        let id = message.get::<ConnectionId>().map(|id| id.connection_id);
+        // A control connection can't double as a data connection
+        let is_control = self.turn.allocations().contains_key(&self.five_tuple());
+        let Some((id, peer)) = id
+            .filter(|_| !is_control)
+            .and_then(|id| Some((id, self.turn.bind_connection(id)?)))
+        else {
+            self.send_error(&message.header, 400, "Bad Request", vec![]);
+            return None;
+        };
+        self.send_success(&message, vec![], &key);
+        Some((id, peer))
+    }
+
+    /// Long-term credential check, RFC 8489 section 9.2.3. Answers with an
+    /// error and returns None if the request can't go any further.
+    fn authenticate(&self, message: &Message, data: &[u8]) -> Option<[u8; 16]> {
+        let challenge = || {
+            vec![
+                Value::Realm(Realm::new(turn::REALM.into())).into_attribute(),
+                Value::Nonce(Nonce::new(self.turn.nonce())).into_attribute(),
+            ]
+        };
+
+        // Only what MESSAGE-INTEGRITY covers, a NONCE appended after it
+        // mustn't stand in for the one that was signed
+        let username = message.get::<Username>().map(|value| &value.username);
+        let nonce = message.get::<Nonce>().map(|value| &value.nonce);
+
+        if message.get::<MessageIntegrity>().is_none() {
+            self.send_error(&message.header, 401, "Unauthorized", challenge());
+            return None;
+        }
+        let (Some(username), Some(nonce)) = (username, nonce) else {
+            self.send_error(&message.header, 400, "Bad Request", vec![]);
+            return None;
+        };
+        if !self.turn.nonce_is_valid(nonce) {
+            self.send_error(&message.header, 438, "Stale Nonce", challenge());
+            return None;
+        }
+        let key = self
+            .turn
+            .password(username)
+            .map(|password| MessageIntegrity::long_term_key(username, turn::REALM, &password))
+            .filter(|key| Message::verify_integrity(data, key));
+        if key.is_none() {
+            self.send_error(&message.header, 401, "Unauthorized", challenge());
+        }
+        key
+    }
+
+    /// Idle connections are kept open while they control an allocation,
+    /// its own lifetime decides when it goes
+    pub(crate) fn controls_allocation(&self) -> bool {
+        self.turn.allocations().contains_key(&self.five_tuple())
+    }
+
+    pub(crate) fn five_tuple(&self) -> FiveTuple {
+        FiveTuple {
+            client: self.src,
+            server: self.local,
+            transport: self.reply.transport(),
+        }
+    }
+
+    /// A response to the client, saying what software sent it
+    fn response(&self, header: Header, mut attributes: Vec<Attribute>) -> Message {
+        if let Some(software) = self.turn.software() {
+            let software = Software::new(software.into()).expect("checked when configured");
+            attributes.push(Value::Software(software).into_attribute());
+        }
+        Message::new(header, attributes)
+    }
+
+    fn send(&self, message: Message) {
+        if let Err(err) = self.reply.send(Packet::Stun(message), self.src) {
+            eprintln!("Replying to {} failed: {}", self.src, err);
+        }
+    }
+
+    fn send_success(&self, request: &Message, attributes: Vec<Attribute>, key: &[u8]) {
+        let header_type = request
+            .header
+            .header_type
+            .success_response()
+            .expect("requests have a success response");
+        let header = Header::new(header_type, request.header.transaction_id);
+        let mut message = self.response(header, attributes);
+        message.add_integrity(key);
+        self.send(message);
+    }
+
+    fn send_error(&self, request: &Header, code: u16, reason: &str, attributes: Vec<Attribute>) {
+        let header_type = request
+            .header_type
+            .error_response()
+            .expect("requests have an error response");
+        let header = Header::new(header_type, request.transaction_id);
+        let mut attributes = attributes;
+        attributes.insert(
+            0,
+            Value::ErrorCode(ErrorCode::new(code, reason.into())).into_attribute(),
+        );
+        let message = self.response(header, attributes);
+        self.send(message);
+    }
+
+    /// 300 Try Alternate, RFC 8489 section 10. Authenticated if the request
+    /// was, clients only trust a redirect they can check.
+    fn send_redirect(&self, request: &Header, alternate: &Alternate, key: Option<&[u8]>) {
+        let header_type = request
+            .header_type
+            .error_response()
+            .expect("requests have an error response");
+        let header = Header::new(header_type, request.transaction_id);
+        let mut attributes = vec![
+            Value::ErrorCode(ErrorCode::new(300, "Try Alternate".into())).into_attribute(),
+            Value::AlternateServer(AlternateServer::new(alternate.address)).into_attribute(),
+        ];
+        if let Some(domain) = &alternate.domain {
+            attributes.push(
+                Value::AlternateDomain(AlternateDomain::new(domain.clone())).into_attribute(),
+            );
+        }
+        let mut message = self.response(header, attributes);
+        if let Some(key) = key {
+            message.add_integrity(key);
+        }
+        self.send(message);
+    }
+}
+
+fn requested_lifetime(message: &Message) -> Option<u32> {
+    message.get::<Lifetime>().map(|lifetime| lifetime.lifetime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::attribute::{
+        ChangeRequest, ChannelNumber, Data, DontFragment, RawAttribute, Username, XorPeerAddress,
+    };
+    #[cfg(feature = "tls")]
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        ssl::{HandshakeError, SslConnector, SslMethod},
+        x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder},
+    };
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        time::Duration,
+    };
+    #[cfg(feature = "tls")]
+    use std::{cell::RefCell, path::PathBuf};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn start_server() -> SocketAddr {
+        start_server_with(|_| {})
+    }
+
+    fn start_server_with(configure: impl FnOnce(&mut Server)) -> SocketAddr {
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.add_user("user".into(), "pass".into());
+        configure(&mut server);
+        server.spawn();
+        server.sockets[0]
+    }
+
+    fn bind() -> UdpSocket {
+        let socket = UdpSocket::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        socket
+    }
+
+    fn recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0; 2048];
+        let (amt, _) = socket.recv_from(&mut buf).ok()?;
+        Some(buf[..amt].to_vec())
+    }
+
+    /// Self-signed certificate for localhost, written out as PEM files
+    #[cfg(feature = "tls")]
+    fn self_signed() -> (PathBuf, PathBuf) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(rand::random()).unwrap();
+        cert.set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("totem-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_file, cert.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_file, key_file)
+    }
+
+    /// Start a server that also speaks TLS and DTLS, returns their address
+    /// and the certificate to trust
+    #[cfg(feature = "tls")]
+    fn start_tls_server() -> (SocketAddr, PathBuf) {
+        start_tls_server_with(|_| {})
+    }
+
+    #[cfg(feature = "tls")]
+    fn start_tls_server_with(configure: impl FnOnce(&mut Server)) -> (SocketAddr, PathBuf) {
+        let (cert, key) = self_signed();
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.add_user("user".into(), "pass".into());
+        let certificate = Certificate::from_pem_files(&cert, &key).unwrap();
+        server.enable_tls(SocketAddr::new(LOCALHOST, 0), certificate);
+        configure(&mut server);
+        server.spawn();
+        (server.tls_addr().unwrap(), cert)
+    }
+
+    /// A connected UDP socket for DTLS to run on
+    #[cfg(feature = "tls")]
+    #[derive(Debug)]
+    struct Datagrams(UdpSocket);
+
+    #[cfg(feature = "tls")]
+    impl Read for Datagrams {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    impl Write for Datagrams {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The client end of any transport, frames are read off a stream one
+    /// at a time
+    enum Conn {
+        Udp(UdpSocket, SocketAddr),
+        Tcp(TcpStream),
+        #[cfg(feature = "tls")]
+        Tls(RefCell<SslStream<TcpStream>>),
+        #[cfg(feature = "tls")]
+        Dtls(RefCell<SslStream<Datagrams>>),
+    }
+
+    impl Conn {
+        fn udp(server: SocketAddr) -> Self {
+            Conn::Udp(bind(), server)
+        }
+
+        fn tcp(server: SocketAddr) -> Self {
+            let stream = TcpStream::connect(server).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            Conn::Tcp(stream)
+        }
+
+        #[cfg(feature = "tls")]
+        fn tls(server: SocketAddr, ca_file: &PathBuf) -> Self {
+            let Conn::Tcp(stream) = Conn::tcp(server) else {
+                unreachable!()
+            };
+            let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+            connector.set_ca_file(ca_file).unwrap();
+            let stream = connector.build().connect("localhost", stream).unwrap();
+            Conn::Tls(RefCell::new(stream))
+        }
+
+        #[cfg(feature = "tls")]
+        fn dtls(server: SocketAddr, ca_file: &PathBuf) -> Self {
+            Self::try_dtls(server, ca_file).unwrap()
+        }
+
+        #[cfg(feature = "tls")]
+        fn try_dtls(server: SocketAddr, ca_file: &PathBuf) -> Option<Self> {
+            let socket = bind();
+            socket.connect(server).unwrap();
+            let mut connector = SslConnector::builder(SslMethod::dtls()).unwrap();
+            connector.set_ca_file(ca_file).unwrap();
+            let stream = connector
+                .build()
+                .connect("localhost", Datagrams(socket))
+                .ok()?;
+            Some(Conn::Dtls(RefCell::new(stream)))
+        }
+
+        fn send(&self, data: &[u8]) {
+            match self {
+                Conn::Udp(socket, server) => {
+                    socket.send_to(data, server).unwrap();
+                }
+                Conn::Tcp(stream) => (&*stream).write_all(data).unwrap(),
+                #[cfg(feature = "tls")]
+                Conn::Tls(stream) => stream.borrow_mut().write_all(data).unwrap(),
+                #[cfg(feature = "tls")]
+                Conn::Dtls(stream) => stream.borrow_mut().write_all(data).unwrap(),
+            }
+        }
+
+        fn recv(&self) -> Option<Vec<u8>> {
+            let read_frame = |stream: &mut dyn Read| {
+                let mut frame = vec![0; 4];
+                stream.read_exact(&mut frame).ok()?;
+                frame.resize(Packet::frame_len(&frame)?, 0);
+                stream.read_exact(&mut frame[4..]).ok()?;
+                Some(frame)
+            };
+            match self {
+                Conn::Udp(socket, _) => recv(socket),
+                Conn::Tcp(stream) => read_frame(&mut &*stream),
+                #[cfg(feature = "tls")]
+                Conn::Tls(stream) => read_frame(&mut *stream.borrow_mut()),
+                #[cfg(feature = "tls")]
+                Conn::Dtls(stream) => {
+                    let mut buf = [0; 2048];
+                    let amt = stream.borrow_mut().read(&mut buf).ok()?;
+                    Some(buf[..amt].to_vec())
+                }
+            }
+        }
+    }
+
+    fn error_code(message: &Message) -> Option<u16> {
+        message.get::<ErrorCode>().map(|error| error.code)
+    }
+
+    /// A TURN client with a long-term credential, just enough to drive
+    /// the server from the tests
+    struct TestClient {
+        conn: Conn,
+        password: &'static str,
+        nonce: String,
+    }
+
+    impl TestClient {
+        fn new(server: SocketAddr, password: &'static str) -> Self {
+            Self::connect(Conn::udp(server), password)
+        }
+
+        fn connect(conn: Conn, password: &'static str) -> Self {
+            let header = Header::with_random_id(HeaderType::AllocateRequest);
+            let challenge = Message::new(header, vec![]);
+            conn.send(&challenge.encode());
+            let response = Message::decode(&conn.recv().unwrap()).unwrap();
+            assert_eq!(error_code(&response), Some(401));
+            let nonce = response
+                .get::<Nonce>()
+                .map(|nonce| nonce.nonce.clone())
+                .expect("401 carries a nonce");
+
+            Self {
+                conn,
+                password,
+                nonce,
+            }
+        }
+
+        /// Send an authenticated request, `attributes` gets the transaction
+        /// id to XOR addresses with
+        fn request(
+            &self,
+            header_type: HeaderType,
+            attributes: impl FnOnce(&[u8; 16]) -> Vec<Value>,
+        ) -> Message {
+            let header = Header::with_random_id(header_type);
+            let attributes = attributes(&header.transaction_id);
+            let mut attributes: Vec<_> =
+                attributes.into_iter().map(Value::into_attribute).collect();
+            attributes.extend([
+                Value::Username(Username::new("user".into())).into_attribute(),
+                Value::Realm(Realm::new(turn::REALM.into())).into_attribute(),
+                Value::Nonce(Nonce::new(self.nonce.clone())).into_attribute(),
+            ]);
+            let mut message = Message::new(header, attributes);
+            message.add_integrity(&MessageIntegrity::long_term_key(
+                "user",
+                turn::REALM,
+                self.password,
+            ));
+            self.conn.send(&message.encode());
+            Message::decode(&self.conn.recv().unwrap()).unwrap()
+        }
+
+        fn allocate(&self, protocol: u8) -> SocketAddr {
+            let transport = RequestedTransport::new(protocol);
+            let response = self.request(HeaderType::AllocateRequest, |_| {
+                vec![Value::RequestedTransport(transport)]
+            });
+            assert_eq!(response.header.header_type, HeaderType::AllocateResponse);
+            let tx_id = &response.header.transaction_id;
+            response
+                .get::<XorRelayedAddress>()
+                .map(|relayed| relayed.addr(tx_id))
+                .expect("relayed address")
+        }
+
+        fn send(&self, peer: SocketAddr, data: &[u8]) {
+            let header = Header::with_random_id(HeaderType::SendIndication);
+            let peer = XorPeerAddress::new(peer, &header.transaction_id);
+            let attributes = vec![
+                Value::XorPeerAddress(peer).into_attribute(),
+                Value::Data(Data::new(data.to_vec())).into_attribute(),
+            ];
+            let message = Message::new(header, attributes);
+            self.conn.send(&message.encode());
+        }
+    }
+
+    #[test]
+    fn test_allocate_requires_credentials() {
+        let server = start_server();
+        let client = TestClient::new(server, "wrong");
+        let transport = RequestedTransport::new(RequestedTransport::UDP);
+        let response = client.request(HeaderType::AllocateRequest, |_| {
+            vec![Value::RequestedTransport(transport)]
+        });
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::AllocateErrorResponse
+        );
+        assert_eq!(error_code(&response), Some(401));
+    }
+
+    #[test]
+    fn test_nonce_after_integrity_is_ignored() {
+        let server = start_server();
+        let client = TestClient::new(server, "pass");
+
+        // A request signed with a stale nonce, replayed with a fresh one
+        // appended after MESSAGE-INTEGRITY
+        let header = Header::with_random_id(HeaderType::AllocateRequest);
+        let attributes = vec![
+            Value::RequestedTransport(RequestedTransport::new(RequestedTransport::UDP)),
+            Value::Username(Username::new("user".into())),
+            Value::Realm(Realm::new(turn::REALM.into())),
+            Value::Nonce(Nonce::new(format!("{:016x}{:016x}", 1, 0))),
+        ];
+        let attributes = attributes.into_iter().map(Value::into_attribute).collect();
+        let mut message = Message::new(header, attributes);
+        message.add_integrity(&MessageIntegrity::long_term_key(
+            "user",
+            turn::REALM,
+            "pass",
+        ));
+        message
+            .attributes
+            .push(Value::Nonce(Nonce::new(client.nonce.clone())).into_attribute());
+        client.conn.send(&message.encode());
+
+        let response = Message::decode(&client.conn.recv().unwrap()).unwrap();
+        assert_eq!(error_code(&response), Some(438));
+    }
+
+    #[test]
+    fn test_allocate_rejects_unsupported_options() {
+        let server = start_server();
+        let client = TestClient::new(server, "pass");
+
+        let response = client.request(HeaderType::AllocateRequest, |_| {
+            vec![
+                Value::RequestedTransport(RequestedTransport::new(RequestedTransport::UDP)),
+                Value::RequestedAddressFamily(RequestedAddressFamily::new(
+                    RequestedAddressFamily::IPV6,
+                )),
+            ]
+        });
+        assert_eq!(error_code(&response), Some(440));
+
+        let response = client.request(HeaderType::AllocateRequest, |_| {
+            vec![
+                Value::RequestedTransport(RequestedTransport::new(RequestedTransport::UDP)),
+                Value::DontFragment(DontFragment::new()),
+            ]
+        });
+        assert_eq!(error_code(&response), Some(420));
+    }
+
+    #[test]
+    fn test_turn_relay_end_to_end() {
+        let server = start_server();
+        let client = TestClient::new(server, "pass");
+        let relayed = client.allocate(RequestedTransport::UDP);
+        let peer = bind();
+        let peer_addr = peer.local_addr().unwrap();
+
+        // Nothing is relayed before a permission is installed
+        client.send(peer_addr, b"dropped");
+        assert!(recv(&peer).is_none());
+
+        let response = client.request(HeaderType::CreatePermissionRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id))]
+        });
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::CreatePermissionResponse
+        );
+        assert!(Message::verify_integrity(
+            &response.encode(),
+            &MessageIntegrity::long_term_key("user", turn::REALM, "pass")
+        ));
+
+        client.send(peer_addr, b"hello");
+        assert_eq!(recv(&peer).unwrap(), b"hello");
+
+        // Peer to client goes back as a Data indication
+        peer.send_to(b"world", relayed).unwrap();
+        let indication = Message::decode(&client.conn.recv().unwrap()).unwrap();
+        assert_eq!(indication.header.header_type, HeaderType::DataIndication);
+        let tx_id = &indication.header.transaction_id;
+        for attr in &indication.attributes {
+            match &attr.value {
+                Value::XorPeerAddress(from) => assert_eq!(from.addr(tx_id), peer_addr),
+                Value::Data(data) => assert_eq!(data.data, b"world"),
+                value => panic!("unexpected attribute {:?}", value),
+            }
+        }
+
+        // Once a channel is bound, both directions use ChannelData
+        let response = client.request(HeaderType::ChannelBindRequest, |tx_id| {
+            vec![
+                Value::ChannelNumber(ChannelNumber::new(0x4000)),
+                Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id)),
+            ]
+        });
+        assert_eq!(response.header.header_type, HeaderType::ChannelBindResponse);
+
+        peer.send_to(b"over channel", relayed).unwrap();
+        let channel_data = client.conn.recv().unwrap();
+        assert_eq!(&channel_data[..4], &[0x40, 0x00, 0, 12]);
+        assert_eq!(&channel_data[4..], b"over channel");
+
+        client
+            .conn
+            .send(&[0x40, 0x00, 0, 4, b'p', b'i', b'n', b'g']);
+        assert_eq!(recv(&peer).unwrap(), b"ping");
+
+        // A zero lifetime refresh deletes the allocation
+        let response = client.request(HeaderType::RefreshRequest, |_| {
+            vec![Value::Lifetime(Lifetime::new(0))]
+        });
+        assert_eq!(response.header.header_type, HeaderType::RefreshResponse);
+        let response = client.request(HeaderType::CreatePermissionRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id))]
+        });
+        assert_eq!(error_code(&response), Some(437));
+    }
+
+    /// A server on tokio, served for as long as the runtime is kept
+    #[cfg(feature = "async")]
+    fn start_async_server() -> (tokio::runtime::Runtime, [SocketAddr; 4]) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.add_user("user".into(), "pass".into());
+        runtime.block_on(server.spawn_async());
+        (runtime, server.local_addrs())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_turn_relay() {
+        let (_runtime, addrs) = start_async_server();
+        for conn in [Conn::udp(addrs[0]), Conn::tcp(addrs[1])] {
+            // Allocations are made on tokio's blocking threads
+            let client = TestClient::connect(conn, "pass");
+            let relayed = client.allocate(RequestedTransport::UDP);
+            let peer = bind();
+            let peer_addr = peer.local_addr().unwrap();
+            let response = client.request(HeaderType::CreatePermissionRequest, |tx_id| {
+                vec![Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id))]
+            });
+            assert_eq!(
+                response.header.header_type,
+                HeaderType::CreatePermissionResponse
+            );
+            client.send(peer_addr, b"hello");
+            assert_eq!(recv(&peer).unwrap(), b"hello");
+
+            // Sent back from the relay's thread, outside the runtime
+            peer.send_to(b"world", relayed).unwrap();
+            let indication = Message::decode(&client.conn.recv().unwrap()).unwrap();
+            assert_eq!(indication.header.header_type, HeaderType::DataIndication);
+            let data = indication.get::<Data>().map(|data| data.data.clone());
+            assert_eq!(data.as_deref(), Some(&b"world"[..]));
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_tcp_refuses_tcp_allocations() {
+        let (_runtime, addrs) = start_async_server();
+        let client = TestClient::connect(Conn::tcp(addrs[0]), "pass");
+        let response = client.request(HeaderType::AllocateRequest, |_| {
+            vec![Value::RequestedTransport(RequestedTransport::new(
+                RequestedTransport::TCP,
+            ))]
+        });
+        assert_eq!(error_code(&response), Some(400));
+
+        // Nor is a peer connected that could never be bound
+        client.allocate(RequestedTransport::UDP);
+        let peer = TcpListener::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let response = client.request(HeaderType::ConnectRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id))]
+        });
+        assert_eq!(error_code(&response), Some(400));
+        assert_eq!(reason(&response), Some("TCP Allocations Need TCP"));
+        assert_eq!(connection_id(&response), None);
+        let response = client.request(HeaderType::ConnectionBindRequest, |_| {
+            vec![Value::ConnectionId(ConnectionId::new(1))]
+        });
+        assert_eq!(reason(&response), Some("TCP Allocations Need TCP"));
+    }
+
+    fn connection_id(message: &Message) -> Option<u32> {
+        message.get::<ConnectionId>().map(|id| id.connection_id)
+    }
+
+    /// Open a data connection and bind it to a peer connection
+    fn connection_bind(server: SocketAddr, id: u32) -> TcpStream {
+        let data = TestClient::connect(Conn::tcp(server), "pass");
+        let response = data.request(HeaderType::ConnectionBindRequest, |_| {
+            vec![Value::ConnectionId(ConnectionId::new(id))]
+        });
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::ConnectionBindResponse
+        );
+        let Conn::Tcp(stream) = data.conn else {
+            unreachable!()
+        };
+        stream
+    }
+
+    #[test]
+    fn test_turn_over_tcp() {
+        let server = start_server();
+        let client = TestClient::connect(Conn::tcp(server), "pass");
+        let relayed = client.allocate(RequestedTransport::UDP);
+        let peer = bind();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let response = client.request(HeaderType::ChannelBindRequest, |tx_id| {
+            vec![
+                Value::ChannelNumber(ChannelNumber::new(0x4000)),
+                Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id)),
+            ]
+        });
+        assert_eq!(response.header.header_type, HeaderType::ChannelBindResponse);
+
+        // ChannelData is padded on a stream, but not towards the peer
+        client.conn.send(&[0x40, 0x00, 0, 3, b'p', b'i', b'n', 0]);
+        assert_eq!(recv(&peer).unwrap(), b"pin");
+
+        peer.send_to(b"hello", relayed).unwrap();
+        let channel_data = client.conn.recv().unwrap();
+        assert_eq!(
+            channel_data,
+            [0x40, 0x00, 0, 5, b'h', b'e', b'l', b'l', b'o', 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_tcp_allocation_needs_tcp() {
+        let server = start_server();
+        let client = TestClient::new(server, "pass");
+        let response = client.request(HeaderType::AllocateRequest, |_| {
+            vec![Value::RequestedTransport(RequestedTransport::new(
+                RequestedTransport::TCP,
+            ))]
+        });
+        assert_eq!(error_code(&response), Some(400));
+    }
+
+    #[test]
+    fn test_tcp_allocation_connect() {
+        let server = start_server();
+        let client = TestClient::connect(Conn::tcp(server), "pass");
+        let relayed = client.allocate(RequestedTransport::TCP);
+        let peer = TcpListener::bind(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let response = client.request(HeaderType::ConnectRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id))]
+        });
+        assert_eq!(response.header.header_type, HeaderType::ConnectResponse);
+        let id = connection_id(&response).expect("connection id");
+        let (mut accepted, from) = peer.accept().unwrap();
+        assert_eq!(from, relayed);
+
+        // One connection per peer
+        let response = client.request(HeaderType::ConnectRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id))]
+        });
+        assert_eq!(error_code(&response), Some(446));
+
+        let mut data = connection_bind(server, id);
+        data.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        accepted.write_all(b"world").unwrap();
+        data.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+
+        // A connection can only be bound once
+        let again = TestClient::connect(Conn::tcp(server), "pass");
+        let response = again.request(HeaderType::ConnectionBindRequest, |_| {
+            vec![Value::ConnectionId(ConnectionId::new(id))]
+        });
+        assert_eq!(error_code(&response), Some(400));
+    }
+
+    #[test]
+    fn test_tcp_allocation_connection_attempt() {
+        let server = start_server();
+        let client = TestClient::connect(Conn::tcp(server), "pass");
+        let relayed = client.allocate(RequestedTransport::TCP);
+
+        let response = client.request(HeaderType::CreatePermissionRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(
+                SocketAddr::new(LOCALHOST, 0),
+                tx_id,
+            ))]
+        });
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::CreatePermissionResponse
+        );
+
+        let mut peer = TcpStream::connect(relayed).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let attempt = Message::decode(&client.conn.recv().unwrap()).unwrap();
+        assert_eq!(
+            attempt.header.header_type,
+            HeaderType::ConnectionAttemptIndication
+        );
+        let tx_id = &attempt.header.transaction_id;
+        assert!(attempt.attributes.iter().any(|attr| matches!(
+            &attr.value,
+            Value::XorPeerAddress(from) if from.addr(tx_id) == peer_addr
+        )));
+        let id = connection_id(&attempt).expect("connection id");
+
+        // Data the peer sent before the bind is held for the client
+        peer.write_all(b"early").unwrap();
+        let mut data = connection_bind(server, id);
+        let mut buf = [0; 5];
+        data.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"early");
+        data.write_all(b"reply").unwrap();
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"reply");
+    }
+
+    fn binding_request() -> Vec<u8> {
+        Message::new(Header::with_random_id(HeaderType::BindingRequest), vec![]).encode()
+    }
+
+    #[test]
+    fn test_stun_over_tcp_reassembles_frames() {
+        let server = start_server();
+        let conn = Conn::tcp(server);
+        let Conn::Tcp(stream) = &conn else {
+            unreachable!()
+        };
+        let local = stream.local_addr().unwrap();
+
+        // One request split across reads
+        let request = binding_request();
+        for byte in &request {
+            conn.send(&[*byte]);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+        assert_eq!(
+            response.header.transaction_id,
+            Message::decode(&request).unwrap().header.transaction_id
+        );
+        assert!(response.attributes.iter().any(|attr| matches!(
+            &attr.value,
+            Value::MappedAddress(mapped) if mapped.port == local.port()
+        )));
+
+        // Two requests in one read
+        let (first, second) = (binding_request(), binding_request());
+        conn.send(&[first.clone(), second.clone()].concat());
+        for request in [first, second] {
+            let response = Message::decode(&conn.recv().unwrap()).unwrap();
+            assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+            assert_eq!(response.header.transaction_id[4..], request[8..20]);
+        }
     }
 
-    fn handle_shared(&self, _message: Message) {
-        todo!("handle shared")
+    #[test]
+    fn test_tcp_idle_timeout() {
+        let server = start_server_with(|server| {
+            server.set_idle_timeout(Duration::from_millis(100));
+        });
+        let conn = Conn::tcp(server);
+        std::thread::sleep(Duration::from_millis(300));
+
+        let Conn::Tcp(mut stream) = conn else {
+            unreachable!()
+        };
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tcp_connection_limit() {
+        let server = start_server_with(|server| server.set_max_connections(1));
+        let first = Conn::tcp(server);
+        first.send(&binding_request());
+        assert!(first.recv().is_some());
+
+        let Conn::Tcp(mut second) = Conn::tcp(server) else {
+            unreachable!()
+        };
+        let mut buf = [0; 1];
+        assert_eq!(second.read(&mut buf).unwrap(), 0);
+
+        // The slot frees up once the first connection goes away
+        drop(first);
+        std::thread::sleep(Duration::from_millis(100));
+        let third = Conn::tcp(server);
+        third.send(&binding_request());
+        assert!(third.recv().is_some());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_turn_over_tls() {
+        let (server, ca_file) = start_tls_server();
+        let client = TestClient::connect(Conn::tls(server, &ca_file), "pass");
+        let relayed = client.allocate(RequestedTransport::UDP);
+        let peer = bind();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let response = client.request(HeaderType::CreatePermissionRequest, |tx_id| {
+            vec![Value::XorPeerAddress(XorPeerAddress::new(peer_addr, tx_id))]
+        });
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::CreatePermissionResponse
+        );
+        client.send(peer_addr, b"hello");
+        assert_eq!(recv(&peer).unwrap(), b"hello");
+
+        peer.send_to(b"world", relayed).unwrap();
+        let indication = Message::decode(&client.conn.recv().unwrap()).unwrap();
+        assert_eq!(indication.header.header_type, HeaderType::DataIndication);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_stun_over_dtls() {
+        let (server, ca_file) = start_tls_server();
+        let conn = Conn::dtls(server, &ca_file);
+        let Conn::Dtls(stream) = &conn else {
+            unreachable!()
+        };
+        let local = stream.borrow().get_ref().0.local_addr().unwrap();
+
+        conn.send(&binding_request());
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+        assert!(response.attributes.iter().any(|attr| matches!(
+            &attr.value,
+            Value::MappedAddress(mapped) if mapped.port == local.port()
+        )));
+
+        // TURN works the same once the association is up
+        let client = TestClient::connect(conn, "pass");
+        client.allocate(RequestedTransport::UDP);
+    }
+
+    /// Keeps what's written and has nothing to read
+    #[cfg(feature = "tls")]
+    struct Capture(Vec<Vec<u8>>);
+
+    #[cfg(feature = "tls")]
+    impl Read for Capture {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_dtls_cookie_exchange() {
+        let (server, ca_file) = start_tls_server_with(|server| server.set_max_connections(1));
+
+        // A ClientHello from anywhere only gets a HelloVerifyRequest back,
+        // smaller than itself
+        let connector = SslConnector::builder(SslMethod::dtls()).unwrap().build();
+        let Err(HandshakeError::WouldBlock(handshake)) =
+            connector.connect("localhost", Capture(vec![]))
+        else {
+            panic!("handshake didn't wait for the server");
+        };
+        let hello = handshake.get_ref().0[0].clone();
+        let socket = bind();
+        socket.send_to(&hello, server).unwrap();
+        let mut buf = [0; 2048];
+        let (amt, _) = socket.recv_from(&mut buf).unwrap();
+        // A handshake record holding a hello_verify_request
+        assert_eq!((buf[0], buf[13]), (22, 3));
+        assert!(amt < hello.len());
+
+        // Sessions count as connections
+        let first = Conn::dtls(server, &ca_file);
+        first.send(&binding_request());
+        assert!(first.recv().is_some());
+        assert!(Conn::try_dtls(server, &ca_file).is_none());
+    }
+
+    fn alternate_server(message: &Message) -> Option<SocketAddr> {
+        message
+            .get::<AlternateServer>()
+            .map(|alternate| alternate.address)
+    }
+
+    #[test]
+    fn test_binding_redirect_in_maintenance() {
+        let mut alternate: Alternate = "192.0.2.1:3478".parse().unwrap();
+        alternate.domain = Some("stun2.example.com".into());
+        let server =
+            start_server_with(|server| server.set_redirect(Redirect::Maintenance(alternate)));
+        let conn = Conn::udp(server);
+        conn.send(&binding_request());
+
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::BindingErrorResponse
+        );
+        assert_eq!(error_code(&response), Some(300));
+        assert_eq!(
+            alternate_server(&response),
+            Some("192.0.2.1:3478".parse().unwrap())
+        );
+        assert!(response.attributes.iter().any(|attr| matches!(
+            &attr.value,
+            Value::AlternateDomain(domain) if domain.domain == "stun2.example.com"
+        )));
+    }
+
+    #[test]
+    fn test_allocate_redirect_when_overloaded() {
+        let server = start_server_with(|server| {
+            server.set_redirect(Redirect::Overload {
+                max_allocations: 1,
+                alternate: "192.0.2.1:3478".parse().unwrap(),
+            })
+        });
+        // Binding requests aren't affected by the load
+        let conn = Conn::udp(server);
+        conn.send(&binding_request());
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+
+        TestClient::new(server, "pass").allocate(RequestedTransport::UDP);
+        let client = TestClient::new(server, "pass");
+        let transport = RequestedTransport::new(RequestedTransport::UDP);
+        let response = client.request(HeaderType::AllocateRequest, |_| {
+            vec![Value::RequestedTransport(transport)]
+        });
+        assert_eq!(error_code(&response), Some(300));
+        assert_eq!(
+            alternate_server(&response),
+            Some("192.0.2.1:3478".parse().unwrap())
+        );
+        // Authenticated, as the request was
+        assert!(response
+            .attributes
+            .iter()
+            .any(|attr| matches!(attr.value, Value::MessageIntegrity(_))));
+    }
+
+    fn software(message: &Message) -> Option<&str> {
+        message
+            .get::<Software>()
+            .map(|software| software.software.as_str())
+    }
+
+    #[test]
+    fn test_responses_carry_software() {
+        let server = start_server();
+        let conn = Conn::udp(server);
+        conn.send(&binding_request());
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(software(&response), Some(SOFTWARE));
+
+        // Ahead of MESSAGE-INTEGRITY, which has to come last
+        let client = TestClient::new(server, "pass");
+        let response = client.request(HeaderType::RefreshRequest, |_| vec![]);
+        assert_eq!(error_code(&response), Some(437));
+        assert_eq!(software(&response), Some(SOFTWARE));
+        client.allocate(RequestedTransport::UDP);
+        let response = client.request(HeaderType::RefreshRequest, |_| vec![]);
+        let last = response.attributes.last().map(|attr| &attr.value);
+        assert!(matches!(last, Some(Value::MessageIntegrity(_))));
+        assert_eq!(software(&response), Some(SOFTWARE));
+
+        let server = start_server_with(|server| {
+            assert!(server.set_software(Some("x".repeat(128))).is_err());
+            server.set_software(None).unwrap();
+        });
+        let conn = Conn::udp(server);
+        conn.send(&binding_request());
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(software(&response), None);
+    }
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Counts allocations on threads that ask for it with `allocations`
+    struct CountingAlloc;
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if COUNTING.with(Cell::get) {
+                ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            }
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations(run: impl FnOnce()) -> usize {
+        ALLOCATIONS.with(|count| count.set(0));
+        COUNTING.with(|counting| counting.set(true));
+        run();
+        COUNTING.with(|counting| counting.set(false));
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    #[test]
+    fn test_binding_allocates_nothing() {
+        let sockets: Vec<Arc<dyn transport::Transport>> = (0..4)
+            .map(|_| {
+                let sock: Arc<dyn transport::Transport> = Arc::new(bind());
+                sock
+            })
+            .collect();
+        let server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        let turn = server.turn(sockets.clone());
+        let reply = Reply::Udp(sockets[0].clone());
+        let client = bind();
+        let local = sockets[0].local_addr().unwrap();
+        let request = Request::new(&reply, &turn, local, client.local_addr().unwrap());
+
+        let binding = binding_request();
+        assert_eq!(allocations(|| request.handle(&binding)), 0);
+        let response = Message::decode(&recv(&client).unwrap()).unwrap();
+        assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+        assert_eq!(software(&response), Some(SOFTWARE));
+        assert!(response
+            .attributes
+            .iter()
+            .any(|attr| matches!(attr.value, Value::ChangedAddress(_))));
+    }
+
+    #[test]
+    fn test_workers_share_address() {
+        let server = start_server_with(|server| server.set_workers(4));
+        // Clients are spread across the workers by address, each gets its
+        // answers from the one address
+        let sockets: Vec<_> = (0..16).map(|_| bind()).collect();
+        let requests: Vec<_> = sockets.iter().map(|_| binding_request()).collect();
+        for (socket, request) in sockets.iter().zip(&requests) {
+            socket.send_to(request, server).unwrap();
+        }
+        let mut buf = [0; 2048];
+        for (socket, request) in sockets.iter().zip(&requests) {
+            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(src, server);
+            let response = Message::decode(&buf[..amt]).unwrap();
+            assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+            assert_eq!(response.header.transaction_id[4..], request[8..20]);
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_workers_share_address() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.set_workers(4);
+        server.enable_rendezvous(SocketAddr::new(LOCALHOST, 0));
+        // A task for each worker, its backlog, each TCP listener and the
+        // rendezvous thread
+        let tasks = runtime.block_on(server.spawn_async());
+        assert_eq!(tasks.len(), 4 * (4 * 2 + 1) + 1);
+        let server = server.local_addrs()[0];
+
+        let sockets: Vec<_> = (0..16).map(|_| bind()).collect();
+        let requests: Vec<_> = sockets.iter().map(|_| binding_request()).collect();
+        for (socket, request) in sockets.iter().zip(&requests) {
+            socket.send_to(request, server).unwrap();
+        }
+        let mut buf = [0; 2048];
+        for (socket, request) in sockets.iter().zip(&requests) {
+            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(src, server);
+            let response = Message::decode(&buf[..amt]).unwrap();
+            assert_eq!(response.header.transaction_id[4..], request[8..20]);
+        }
+        // Dropping it would wait for the rendezvous thread
+        runtime.shutdown_background();
+    }
+
+    fn change_request(change_ip: bool, change_port: bool) -> Vec<u8> {
+        let change = Value::ChangeRequest(ChangeRequest::new(change_ip, change_port));
+        let header = Header::with_random_id(HeaderType::BindingRequest);
+        Message::new(header, vec![change.into_attribute()]).encode()
+    }
+
+    #[test]
+    fn test_change_request() {
+        let mut server = Server::new([SocketAddr::new(LOCALHOST, 0); 4]);
+        server.spawn();
+        let addrs = server.local_addrs();
+
+        let socket = bind();
+        let mut buf = [0; 2048];
+        for (change_ip, change_port, from) in [
+            (false, false, addrs[0]),
+            (false, true, addrs[1]),
+            (true, false, addrs[2]),
+            (true, true, addrs[3]),
+        ] {
+            socket
+                .send_to(&change_request(change_ip, change_port), addrs[0])
+                .unwrap();
+            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(src, from);
+            let response = Message::decode(&buf[..amt]).unwrap();
+            assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+            assert!(response.attributes.iter().any(|attr| matches!(
+                &attr.value,
+                Value::ChangedAddress(changed) if changed.port == addrs[3].port()
+            )));
+        }
+
+        // Only datagrams can come back from another address
+        let conn = Conn::tcp(addrs[0]);
+        conn.send(&change_request(true, true));
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(error_code(&response), Some(400));
+    }
+
+    fn reason(message: &Message) -> Option<&str> {
+        message
+            .get::<ErrorCode>()
+            .map(|error| error.reason.as_str())
+    }
+
+    #[test]
+    fn test_invalid_requests_rejected() {
+        // An XOR-PEER-ADDRESS of no family, first after the header
+        let peer = Value::XorPeerAddress(XorPeerAddress::new(
+            "192.0.2.1:9".parse().unwrap(),
+            &[0; 16],
+        ));
+        let header = Header::with_random_id(HeaderType::BindingRequest);
+        let mut request = Message::new(header, vec![peer.into_attribute()]).encode();
+        request[25] = 0x03;
+
+        let conn = Conn::udp(start_server());
+        conn.send(&request);
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::BindingErrorResponse
+        );
+        assert_eq!(error_code(&response), Some(400));
+        assert_eq!(
+            reason(&response),
+            Some("address family 0x03 in attribute 0x0012")
+        );
+
+        // RFC 3489 transaction ids are only taken when lenient
+        let mut request = binding_request();
+        request[4..8].fill(0);
+        conn.send(&request);
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+
+        let server = start_server_with(|server| server.set_policy(Policy::Strict));
+        let conn = Conn::udp(server);
+        conn.send(&request);
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(error_code(&response), Some(400));
+        assert_eq!(reason(&response), Some("no magic cookie"));
+        conn.send(&binding_request());
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(response.header.header_type, HeaderType::BindingResponse);
+    }
+
+    #[test]
+    fn test_unknown_attributes_answered_with_420() {
+        let unknown = |header_type| {
+            let attributes = vec![
+                Value::Raw(RawAttribute::new(0x0031, vec![1, 2, 3, 4])).into_attribute(),
+                // Comprehension-optional, ignored
+                Value::Raw(RawAttribute::new(0x8031, vec![1, 2, 3, 4])).into_attribute(),
+            ];
+            Message::new(Header::with_random_id(header_type), attributes).encode()
+        };
+        let unknown_attributes = |message: &Message| {
+            message
+                .get::<UnknownAttributes>()
+                .map(|unknown| unknown.attributes.clone())
+        };
+
+        let conn = Conn::udp(start_server());
+        for (request, error) in [
+            (HeaderType::BindingRequest, HeaderType::BindingErrorResponse),
+            (
+                HeaderType::AllocateRequest,
+                HeaderType::AllocateErrorResponse,
+            ),
+        ] {
+            conn.send(&unknown(request));
+            let response = Message::decode(&conn.recv().unwrap()).unwrap();
+            assert_eq!(response.header.header_type, error);
+            assert_eq!(error_code(&response), Some(420));
+            assert_eq!(unknown_attributes(&response), Some(vec![0x0031]));
+        }
+
+        // Indications are never answered
+        conn.send(&unknown(HeaderType::SendIndication));
+        assert!(conn.recv().is_none());
+    }
+
+    #[test]
+    fn test_shared_secret_request_refused() {
+        let conn = Conn::udp(start_server());
+        let header = Header::with_random_id(HeaderType::SharedSecretRequest);
+        conn.send(&Message::new(header, vec![]).encode());
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(
+            response.header.header_type,
+            HeaderType::SharedSecretErrorResponse
+        );
+        assert_eq!(error_code(&response), Some(400));
+
+        // The listener is still there
+        conn.send(&binding_request());
+        let response = Message::decode(&conn.recv().unwrap()).unwrap();
+        assert_eq!(response.header.header_type, HeaderType::BindingResponse);
     }
 }
@@ -0,0 +1,211 @@
+use std::{
+    collections::VecDeque,
+    ffi::{c_int, c_void},
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use foreign_types_shared::ForeignTypeRef;
+use openssl::{
+    ex_data::Index,
+    hash::MessageDigest,
+    memcmp,
+    pkey::PKey,
+    sign::Signer,
+    ssl::{ErrorCode, Ssl, SslContext, SslFiletype, SslMethod, SslOptions, SslRef, SslStream},
+};
+
+// Not bound by openssl-sys, a BIO_ADDR is only passed around
+extern "C" {
+    fn DTLSv1_listen(ssl: *mut openssl_sys::SSL, client: *mut c_void) -> c_int;
+    fn BIO_ADDR_new() -> *mut c_void;
+    fn BIO_ADDR_free(addr: *mut c_void);
+}
+
+/// Path MTU assumed for DTLS records, small enough to get through tunnels
+const DTLS_MTU: u32 = 1200;
+
+/// Where a DTLS client says it is, for its cookie
+fn peer_index() -> Index<Ssl, SocketAddr> {
+    static INDEX: OnceLock<Index<Ssl, SocketAddr>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("ex data index"))
+}
+
+/// HMAC of the client's address, which only a client that receives at it
+/// can send back
+fn cookie(secret: &[u8], ssl: &SslRef) -> Option<Vec<u8>> {
+    let peer = ssl.ex_data(peer_index())?;
+    let key = PKey::hmac(secret).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(peer.to_string().as_bytes()).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+/// Certificate and private key the server presents over TLS and DTLS
+pub struct Certificate {
+    pub(crate) tls: SslContext,
+    pub(crate) dtls: SslContext,
+}
+
+impl Certificate {
+    /// Load a certificate chain and its key from PEM files
+    pub fn from_pem_files(cert: &Path, key: &Path) -> io::Result<Self> {
+        let builder = |method| {
+            let mut builder = SslContext::builder(method)?;
+            builder.set_certificate_chain_file(cert)?;
+            builder.set_private_key_file(key, SslFiletype::PEM)?;
+            builder.check_private_key()?;
+            Ok::<_, openssl::error::ErrorStack>(builder)
+        };
+        let tls = builder(SslMethod::tls_server()).map_err(io::Error::other)?;
+
+        // A ClientHello without a valid cookie only gets a HelloVerifyRequest
+        // back, RFC 6347 section 4.2.1, so a spoofed one can't be used to
+        // send the certificate flight to somebody else
+        let mut builder = builder(SslMethod::dtls()).map_err(io::Error::other)?;
+        builder.set_options(SslOptions::COOKIE_EXCHANGE);
+        let secret: Arc<[u8; 32]> = Arc::new(rand::random());
+        let generate = secret.clone();
+        builder.set_cookie_generate_cb(move |ssl, buf| {
+            let cookie = cookie(&*generate, ssl).ok_or_else(openssl::error::ErrorStack::get)?;
+            let len = cookie.len().min(buf.len());
+            buf[..len].copy_from_slice(&cookie[..len]);
+            Ok(len)
+        });
+        builder.set_cookie_verify_cb(move |ssl, received| {
+            cookie(&*secret, ssl).is_some_and(|cookie| {
+                cookie.len() == received.len() && memcmp::eq(&cookie, received)
+            })
+        });
+        Ok(Self {
+            tls: tls.build(),
+            dtls: builder.build(),
+        })
+    }
+}
+
+/// The transport under a server side session. Its reader hands received
+/// bytes in with `receive`, so the session is only locked while they're
+/// decrypted and never while waiting on the network.
+pub struct Channel {
+    incoming: VecDeque<Vec<u8>>,
+    output: Output,
+}
+
+enum Output {
+    Stream(TcpStream),
+    Datagram(Arc<UdpSocket>, SocketAddr),
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(mut data) = self.incoming.pop_front() else {
+            return Err(ErrorKind::WouldBlock.into());
+        };
+        let amt = data.len().min(buf.len());
+        buf[..amt].copy_from_slice(&data[..amt]);
+        // What doesn't fit is kept for the next read on a stream, but
+        // truncated like any datagram would be
+        if amt < data.len() && matches!(self.output, Output::Stream(_)) {
+            data.drain(..amt);
+            self.incoming.push_front(data);
+        }
+        Ok(amt)
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.output {
+            Output::Stream(stream) => stream.write_all(buf)?,
+            Output::Datagram(socket, peer) => {
+                socket.send_to(buf, *peer)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Server side of a TLS connection, `stream` is where records go out
+pub fn tls_session(context: &SslContext, stream: TcpStream) -> io::Result<SslStream<Channel>> {
+    let channel = Channel {
+        incoming: VecDeque::new(),
+        output: Output::Stream(stream),
+    };
+    SslStream::new(Ssl::new(context)?, channel).map_err(io::Error::other)
+}
+
+/// Server side of a DTLS association with the client at `peer`, if
+/// `datagram` is a ClientHello with the cookie it was given. Nothing is
+/// kept otherwise, a ClientHello without one is answered with a
+/// HelloVerifyRequest and anything else dropped.
+pub fn dtls_accept(
+    context: &SslContext,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    datagram: Vec<u8>,
+) -> io::Result<Option<SslStream<Channel>>> {
+    let mut ssl = Ssl::new(context)?;
+    ssl.set_mtu(DTLS_MTU)?;
+    ssl.set_ex_data(peer_index(), peer);
+    let channel = Channel {
+        incoming: VecDeque::from([datagram]),
+        output: Output::Datagram(socket, peer),
+    };
+    let mut session = SslStream::new(ssl, channel).map_err(io::Error::other)?;
+
+    // Safety: the session outlives the call, and the address is only
+    // written to before it's freed
+    let listened = unsafe {
+        let client = BIO_ADDR_new();
+        if client.is_null() {
+            return Err(io::Error::other(openssl::error::ErrorStack::get()));
+        }
+        let listened = DTLSv1_listen(session.ssl().as_ptr(), client);
+        BIO_ADDR_free(client);
+        listened
+    };
+    match listened {
+        1 => {}
+        0 => return Ok(None),
+        _ => return Err(io::Error::other(openssl::error::ErrorStack::get())),
+    }
+    // The ClientHello is kept by the session, answer it
+    match session.accept() {
+        Ok(()) => Ok(Some(session)),
+        Err(err) if err.code() == ErrorCode::WANT_READ => Ok(Some(session)),
+        Err(err) => Err(io::Error::other(err)),
+    }
+}
+
+/// Feed bytes received from the client to its session and return the
+/// records they decrypt to, moving the handshake along while it's going
+pub fn receive(session: &mut SslStream<Channel>, data: Vec<u8>) -> io::Result<Vec<Vec<u8>>> {
+    session.get_mut().incoming.push_back(data);
+    let mut records = Vec::new();
+    if !session.ssl().is_init_finished() {
+        match session.accept() {
+            Ok(()) => {}
+            Err(err) if err.code() == ErrorCode::WANT_READ => return Ok(records),
+            Err(err) => return Err(io::Error::other(err)),
+        }
+    }
+
+    let mut buf = [0; 4096];
+    loop {
+        match session.ssl_read(&mut buf) {
+            Ok(amt) => records.push(buf[..amt].to_vec()),
+            Err(err) if err.code() == ErrorCode::WANT_READ => return Ok(records),
+            Err(err) if err.code() == ErrorCode::ZERO_RETURN => {
+                return Err(ErrorKind::UnexpectedEof.into())
+            }
+            Err(err) => return Err(io::Error::other(err)),
+        }
+    }
+}
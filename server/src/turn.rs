@@ -0,0 +1,552 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use message::{
+    attribute::{ConnectionId, Data, MessageIntegrity, Value, XorPeerAddress},
+    header::{Header, HeaderType},
+    packet::Packet,
+    transport,
+    validate::Policy,
+    Message,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    redirect::{Alternate, Redirect},
+    server::{Reply, UserMap},
+};
+
+pub const REALM: &str = "totem";
+
+/// Allocation lifetimes in seconds, RFC 8656 section 3.2
+pub const DEFAULT_LIFETIME: u32 = 600;
+pub const MAX_LIFETIME: u32 = 3600;
+
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+const NONCE_LIFETIME: u64 = 3600;
+
+/// How long a peer connection waits for the client's ConnectionBind, and
+/// how long the server tries to reach a peer, RFC 6062 section 5
+const CONNECTION_BIND_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often expired allocations are dropped, until then lookups skip them
+const SWEEP: Duration = Duration::from_secs(10);
+
+/// How often relay threads wake up to notice their allocation is gone
+const RELAY_POLL: Duration = Duration::from_secs(1);
+/// Listeners can't time out, so TCP relays poll for connections instead
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    #[cfg(feature = "tls")]
+    Tls,
+    #[cfg(feature = "tls")]
+    Dtls,
+}
+
+/// Identifies an allocation by the client and server ends of the
+/// connection and the transport between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FiveTuple {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub transport: Transport,
+}
+
+pub enum Relay {
+    Udp(UdpSocket),
+    /// RFC 6062, peers are reached over connections to and from the
+    /// relayed address
+    Tcp(SocketAddr),
+}
+
+pub struct Allocation {
+    pub relay: Relay,
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+}
+
+impl Allocation {
+    pub fn new(relay: Relay, lifetime: u32) -> Self {
+        Self {
+            relay,
+            expires: Instant::now() + Duration::from_secs(lifetime.into()),
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+        }
+    }
+
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires > now
+    }
+
+    pub fn refresh(&mut self, lifetime: u32) {
+        self.expires = Instant::now() + Duration::from_secs(lifetime.into());
+    }
+
+    pub fn has_permission(&self, ip: IpAddr) -> bool {
+        self.permissions
+            .get(&ip)
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+
+    pub fn add_permission(&mut self, ip: IpAddr) {
+        self.permissions
+            .insert(ip, Instant::now() + PERMISSION_LIFETIME);
+    }
+
+    /// Bind or refresh a channel, returns false if either the channel or
+    /// the peer is already bound to something else
+    pub fn bind_channel(&mut self, number: u16, peer: SocketAddr) -> bool {
+        let now = Instant::now();
+        let conflict = self.channels.iter().any(|(bound, (bound_peer, expires))| {
+            *expires > now && ((*bound == number) != (*bound_peer == peer))
+        });
+        if conflict {
+            return false;
+        }
+        self.channels.insert(number, (peer, now + CHANNEL_LIFETIME));
+        self.add_permission(peer.ip());
+        true
+    }
+
+    pub fn channel_peer(&self, number: u16) -> Option<SocketAddr> {
+        self.channels
+            .get(&number)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(peer, _)| *peer)
+    }
+
+    pub fn peer_channel(&self, peer: SocketAddr) -> Option<u16> {
+        let now = Instant::now();
+        self.channels
+            .iter()
+            .find(|(_, (bound_peer, expires))| *bound_peer == peer && *expires > now)
+            .map(|(number, _)| *number)
+    }
+}
+
+/// Allocations by five-tuple. Expired ones stay until `sweep` drops them,
+/// lookups go by the one entry's expiry instead of purging them all.
+#[derive(Default)]
+pub struct Allocations(HashMap<FiveTuple, Allocation>);
+
+impl Allocations {
+    pub fn get(&self, five_tuple: &FiveTuple) -> Option<&Allocation> {
+        let now = Instant::now();
+        self.0
+            .get(five_tuple)
+            .filter(|allocation| allocation.is_live(now))
+    }
+
+    pub fn get_mut(&mut self, five_tuple: &FiveTuple) -> Option<&mut Allocation> {
+        let now = Instant::now();
+        self.0
+            .get_mut(five_tuple)
+            .filter(|allocation| allocation.is_live(now))
+    }
+
+    pub fn contains_key(&self, five_tuple: &FiveTuple) -> bool {
+        self.get(five_tuple).is_some()
+    }
+
+    /// Replaces an expired allocation on the same five-tuple
+    pub fn insert(&mut self, five_tuple: FiveTuple, allocation: Allocation) {
+        self.0.insert(five_tuple, allocation);
+    }
+
+    pub fn remove(&mut self, five_tuple: &FiveTuple) -> Option<Allocation> {
+        self.0.remove(five_tuple)
+    }
+
+    /// How many allocations are live, which walks all of them
+    pub fn count(&self) -> usize {
+        let now = Instant::now();
+        self.0
+            .values()
+            .filter(|allocation| allocation.is_live(now))
+            .count()
+    }
+
+    fn purge(&mut self) {
+        let now = Instant::now();
+        self.0.retain(|_, allocation| allocation.is_live(now));
+    }
+}
+
+/// A peer connection of a TCP allocation, RFC 6062 section 5
+struct Connection {
+    five_tuple: FiveTuple,
+    peer: SocketAddr,
+    /// The peer's stream and when it was opened, until the client binds a
+    /// data connection to it
+    pending: Option<(TcpStream, Instant)>,
+}
+
+pub struct Turn {
+    users: UserMap,
+    redirect: Option<Redirect>,
+    software: Option<String>,
+    policy: Policy,
+    /// The UDP sockets on the server's addresses, in the order they were
+    /// configured, each with its address looked up once
+    udp: Vec<(SocketAddr, Arc<dyn transport::Transport>)>,
+    nonce_key: [u8; 20],
+    allocations: Mutex<Allocations>,
+    connections: Mutex<HashMap<u32, Connection>>,
+}
+
+impl Turn {
+    pub fn new(
+        users: UserMap,
+        redirect: Option<Redirect>,
+        software: Option<String>,
+        policy: Policy,
+        udp: Vec<Arc<dyn transport::Transport>>,
+    ) -> Self {
+        Self {
+            users,
+            redirect,
+            software,
+            policy,
+            udp: udp
+                .into_iter()
+                .map(|sock| (sock.local_addr().expect("local addr"), sock))
+                .collect(),
+            nonce_key: rand::random(),
+            allocations: Mutex::new(Allocations::default()),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn password(&self, username: &str) -> Option<String> {
+        self.users.read().unwrap().get(username).cloned()
+    }
+
+    pub fn software(&self) -> Option<&str> {
+        self.software.as_deref()
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// The UDP socket to answer a CHANGE-REQUEST received on `local` from.
+    /// Addresses are configured primary port first then alternate port, on
+    /// the primary IP then the alternate one, so flipping the low bit of
+    /// the index changes the port and the next bit the IP. Returns the
+    /// socket's address along with it.
+    pub fn changed_socket(
+        &self,
+        local: SocketAddr,
+        change_ip: bool,
+        change_port: bool,
+    ) -> Option<(SocketAddr, &dyn transport::Transport)> {
+        let index = self.udp.iter().position(|(addr, _)| *addr == local)?;
+        let flip = usize::from(change_ip) << 1 | usize::from(change_port);
+        self.udp
+            .get(index ^ flip)
+            .map(|(addr, sock)| (*addr, &**sock))
+    }
+
+    /// Where to send the client at `client` instead of serving it, if
+    /// anywhere. `allocating` if it asks for a new allocation.
+    pub fn alternate(&self, client: IpAddr, allocating: bool) -> Option<&Alternate> {
+        let allocations = allocating.then(|| self.allocations().count());
+        self.redirect.as_ref()?.alternate(client, allocations)
+    }
+
+    pub fn allocations(&self) -> MutexGuard<'_, Allocations> {
+        self.allocations.lock().unwrap()
+    }
+
+    /// Peer connections, the ones nobody bound in time are closed on the way
+    fn connections(&self) -> MutexGuard<'_, HashMap<u32, Connection>> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, connection| match &connection.pending {
+            Some((_, opened)) => opened.elapsed() < CONNECTION_BIND_TIMEOUT,
+            None => true,
+        });
+        connections
+    }
+
+    /// Remember a new peer connection until the client binds it, returns
+    /// its CONNECTION-ID
+    pub fn add_connection(
+        &self,
+        five_tuple: FiveTuple,
+        peer: SocketAddr,
+        stream: TcpStream,
+    ) -> u32 {
+        let mut connections = self.connections();
+        let mut id = rand::random();
+        while connections.contains_key(&id) {
+            id = rand::random();
+        }
+        let connection = Connection {
+            five_tuple,
+            peer,
+            pending: Some((stream, Instant::now())),
+        };
+        connections.insert(id, connection);
+        id
+    }
+
+    pub fn has_connection(&self, five_tuple: FiveTuple, peer: SocketAddr) -> bool {
+        self.connections()
+            .values()
+            .any(|connection| connection.five_tuple == five_tuple && connection.peer == peer)
+    }
+
+    /// Hand out a pending peer connection to the data connection the
+    /// client bound to it, each connection can only be bound once
+    pub fn bind_connection(&self, id: u32) -> Option<TcpStream> {
+        let mut connections = self.connections();
+        let (stream, _) = connections.get_mut(&id)?.pending.take()?;
+        Some(stream)
+    }
+
+    pub fn close_connection(&self, id: u32) {
+        self.connections().remove(&id);
+    }
+
+    /// Nonces carry their own expiry and a MAC over it, so the server
+    /// doesn't have to remember the ones it handed out
+    pub fn nonce(&self) -> String {
+        let expiry = unix_time() + NONCE_LIFETIME;
+        format!("{:016x}{}", expiry, self.nonce_mac(expiry))
+    }
+
+    pub fn nonce_is_valid(&self, nonce: &str) -> bool {
+        let Some(expiry) = nonce
+            .get(..16)
+            .and_then(|e| u64::from_str_radix(e, 16).ok())
+        else {
+            return false;
+        };
+        expiry > unix_time() && nonce[16..] == self.nonce_mac(expiry)
+    }
+
+    fn nonce_mac(&self, expiry: u64) -> String {
+        let mac = MessageIntegrity::compute(&self.nonce_key, &expiry.to_be_bytes());
+        mac.integrity[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs()
+}
+
+/// Drop expired allocations every `SWEEP`, until the server is gone
+pub fn sweep(turn: Weak<Turn>) {
+    loop {
+        thread::sleep(SWEEP);
+        let Some(turn) = turn.upgrade() else {
+            return;
+        };
+        turn.allocations().purge();
+    }
+}
+
+/// Forward datagrams from peers arriving on an allocation's relay socket to
+/// the client, until the allocation expires or is deleted
+pub fn relay(turn: Arc<Turn>, five_tuple: FiveTuple, relay: UdpSocket, reply: Reply) {
+    relay
+        .set_read_timeout(Some(RELAY_POLL))
+        .expect("read timeout");
+
+    let mut buf = [0; 2048];
+    loop {
+        let (amt, peer) = match relay.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if !turn.allocations().contains_key(&five_tuple) {
+                    return;
+                }
+                continue;
+            }
+            Err(err) => {
+                eprintln!("Relay for {} failed: {}", five_tuple.client, err);
+                return;
+            }
+        };
+
+        let channel = {
+            let allocations = turn.allocations();
+            let Some(allocation) = allocations.get(&five_tuple) else {
+                return;
+            };
+            if !allocation.has_permission(peer.ip()) {
+                continue;
+            }
+            allocation.peer_channel(peer)
+        };
+
+        let packet = match channel {
+            Some(channel) => Packet::ChannelData {
+                channel,
+                payload: &buf[..amt],
+            },
+            None => {
+                let header = Header::with_random_id(HeaderType::DataIndication);
+                let peer = XorPeerAddress::new(peer, &header.transaction_id);
+                let attributes = vec![
+                    Value::XorPeerAddress(peer).into_attribute(),
+                    Value::Data(Data::new(buf[..amt].to_vec())).into_attribute(),
+                ];
+                Packet::Stun(Message::new(header, attributes))
+            }
+        };
+        if let Err(err) = reply.send(packet, five_tuple.client) {
+            eprintln!("Relaying to {} failed: {}", five_tuple.client, err);
+        }
+    }
+}
+
+/// Listen for peers on a TCP allocation's relayed address. Outgoing
+/// connections are opened from the same address, so both set SO_REUSEPORT.
+pub fn relay_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = relay_socket(addr)?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Open a connection from a TCP allocation's relayed address to a peer
+pub fn connect(relayed: SocketAddr, peer: SocketAddr) -> io::Result<TcpStream> {
+    let socket = relay_socket(relayed)?;
+    socket.connect_timeout(&peer.into(), CONNECT_TIMEOUT)?;
+    Ok(socket.into())
+}
+
+fn relay_socket(addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Accept connections from permitted peers on a TCP allocation's relayed
+/// address and offer each to the client with a ConnectionAttempt
+/// indication, until the allocation expires or is deleted
+pub fn accept(turn: Arc<Turn>, five_tuple: FiveTuple, listener: TcpListener, reply: Reply) {
+    listener.set_nonblocking(true).expect("nonblocking");
+
+    loop {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                if !turn.allocations().contains_key(&five_tuple) {
+                    return;
+                }
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(err) => {
+                eprintln!("Relay for {} failed: {}", five_tuple.client, err);
+                return;
+            }
+        };
+
+        {
+            let allocations = turn.allocations();
+            let Some(allocation) = allocations.get(&five_tuple) else {
+                return;
+            };
+            if !allocation.has_permission(peer.ip()) {
+                continue;
+            }
+        }
+        if stream.set_nonblocking(false).is_err() {
+            continue;
+        }
+
+        let id = turn.add_connection(five_tuple, peer, stream);
+        let header = Header::with_random_id(HeaderType::ConnectionAttemptIndication);
+        let attributes = vec![
+            Value::XorPeerAddress(XorPeerAddress::new(peer, &header.transaction_id))
+                .into_attribute(),
+            Value::ConnectionId(ConnectionId::new(id)).into_attribute(),
+        ];
+        let message = Message::new(header, attributes);
+        if let Err(err) = reply.send(Packet::Stun(message), five_tuple.client) {
+            eprintln!(
+                "Offering connection to {} failed: {}",
+                five_tuple.client, err
+            );
+        }
+    }
+}
+
+/// Splice a client's data connection to the peer connection it bound,
+/// RFC 6062 section 5.5. Whatever the client sent right after its
+/// ConnectionBind request is already `buffered` and goes out first.
+pub fn pipe(
+    turn: Arc<Turn>,
+    id: u32,
+    mut client: TcpStream,
+    buffered: Vec<u8>,
+    mut peer: TcpStream,
+) {
+    let (Ok(mut to_client), Ok(mut from_peer)) = (client.try_clone(), peer.try_clone()) else {
+        return turn.close_connection(id);
+    };
+    let downstream = thread::spawn(move || {
+        let _ = io::copy(&mut from_peer, &mut to_client);
+        let _ = to_client.shutdown(Shutdown::Write);
+    });
+
+    if peer.write_all(&buffered).is_ok() {
+        let _ = io::copy(&mut client, &mut peer);
+    }
+    let _ = peer.shutdown(Shutdown::Write);
+    let _ = downstream.join();
+    turn.close_connection(id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_expired_allocations_are_skipped_then_swept() {
+        let five_tuple = |port| FiveTuple {
+            client: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            server: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 3478),
+            transport: Transport::Udp,
+        };
+        let relay = || Relay::Tcp(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+        let mut allocations = Allocations::default();
+        allocations.insert(five_tuple(1), Allocation::new(relay(), DEFAULT_LIFETIME));
+        allocations.insert(five_tuple(2), Allocation::new(relay(), 0));
+
+        assert!(allocations.contains_key(&five_tuple(1)));
+        assert!(allocations.get_mut(&five_tuple(2)).is_none());
+        assert_eq!(allocations.count(), 1);
+        assert_eq!(allocations.0.len(), 2);
+
+        allocations.purge();
+        assert_eq!(allocations.0.len(), 1);
+        assert!(allocations.contains_key(&five_tuple(1)));
+    }
+}